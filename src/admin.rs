@@ -0,0 +1,422 @@
+use crate::{request, response, routing, ProxyState};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves the admin API on the given listener until the process exits.
+pub async fn serve(listener: TcpListener, state: Arc<ProxyState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                log::error!("Failed to accept new admin connection: {}", err);
+                continue;
+            }
+        };
+        tokio::spawn(handle_connection(stream, state.clone()));
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<ProxyState>) {
+    loop {
+        let request = match request::read_from_stream(
+            &mut stream,
+            request::DuplicateHeaderPolicy::Reject,
+            request::HeaderLimits::default(),
+            request::ReadTimeouts::default(),
+            request::DEFAULT_MAX_BODY_SIZE,
+            0,
+        )
+        .await
+        {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &mut stream).await;
+                return;
+            }
+        };
+        let response = route(&request, state.as_ref());
+        log::info!(
+            "admin: {} -> {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(err) = response::write_to_stream(&response, &mut stream).await {
+            log::warn!("Failed to send admin response: {}", err);
+            return;
+        }
+    }
+}
+
+/// Dispatches an admin request to the matching handler.
+fn route(request: &http::Request<Vec<u8>>, state: &ProxyState) -> http::Response<Vec<u8>> {
+    let segments: Vec<&str> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    match (request.method(), segments.as_slice()) {
+        (&http::Method::GET, ["connections"]) => {
+            json_response(http::StatusCode::OK, &state.connections.snapshot())
+        }
+        // Close every connection from a client IP: DELETE /connections?ip=<ip>
+        (&http::Method::DELETE, ["connections"]) => {
+            match query_param(request.uri(), "ip").and_then(|ip| ip.parse().ok()) {
+                Some(ip) => {
+                    let closed = state.connections.close_ip(ip);
+                    log::warn!("admin: closing {} connection(s) from {}", closed, ip);
+                    json_response(
+                        http::StatusCode::OK,
+                        &serde_json::json!({ "closed": closed }),
+                    )
+                }
+                None => response::make_http_error(http::StatusCode::BAD_REQUEST),
+            }
+        }
+        // Close a single connection: DELETE /connections/<id>
+        (&http::Method::DELETE, ["connections", id]) => match id.parse::<u64>() {
+            Ok(id) if state.connections.close(id) => {
+                log::warn!("admin: closing connection {}", id);
+                json_response(http::StatusCode::OK, &serde_json::json!({ "closed": 1 }))
+            }
+            Ok(_) => response::make_http_error(http::StatusCode::NOT_FOUND),
+            Err(_) => response::make_http_error(http::StatusCode::BAD_REQUEST),
+        },
+        (&http::Method::GET, ["penalties"]) => {
+            json_response(http::StatusCode::OK, &state.penalties.snapshot())
+        }
+        // Lift a client's penalties: DELETE /penalties?ip=<ip>
+        (&http::Method::DELETE, ["penalties"]) => {
+            match query_param(request.uri(), "ip").and_then(|ip| ip.parse().ok()) {
+                Some(ip) if state.penalties.pardon(ip) => {
+                    log::warn!("admin: lifting penalties for {}", ip);
+                    json_response(http::StatusCode::OK, &serde_json::json!({ "pardoned": ip }))
+                }
+                Some(_) => response::make_http_error(http::StatusCode::NOT_FOUND),
+                None => response::make_http_error(http::StatusCode::BAD_REQUEST),
+            }
+        }
+        (&http::Method::GET, ["upstreams"]) => {
+            json_response(http::StatusCode::OK, &upstream_statuses(state))
+        }
+        // Each pool's saturation signals over the last interval, for autoscalers
+        (&http::Method::GET, ["saturation"]) => {
+            json_response(http::StatusCode::OK, &state.saturation.snapshot())
+        }
+        // Stop routing requests to an upstream, e.g. before redeploying it:
+        // POST /drain?upstream=<address>
+        (&http::Method::POST, ["drain"]) => set_draining(request, state, true),
+        // Put a drained upstream back into rotation: DELETE /drain?upstream=<address>
+        (&http::Method::DELETE, ["drain"]) => set_draining(request, state, false),
+        (&http::Method::GET, ["splits"]) => {
+            let splits: Vec<serde_json::Value> = state
+                .router
+                .splits()
+                .into_iter()
+                .map(|(name, shares)| {
+                    let weights: serde_json::Map<String, serde_json::Value> = shares
+                        .into_iter()
+                        .map(|(pool, weight)| (pool.to_string(), weight.into()))
+                        .collect();
+                    serde_json::json!({ "name": name, "weights": weights })
+                })
+                .collect();
+            json_response(http::StatusCode::OK, &splits)
+        }
+        // Change how a split divides requests between its pools:
+        // PUT /splits/<name>?<pool>=<weight>&<pool>=<weight>...
+        (&http::Method::PUT, ["splits", name]) => set_split_weights(request, state, name),
+        (&http::Method::GET, ["switches"]) => {
+            let switches: Vec<serde_json::Value> = state
+                .router
+                .switches()
+                .into_iter()
+                .map(|(name, live, idle)| {
+                    serde_json::json!({ "name": name, "live": live, "idle": idle })
+                })
+                .collect();
+            json_response(http::StatusCode::OK, &switches)
+        }
+        // Make a blue/green switch's other pool live, or the pool given:
+        // POST /switches/<name>[?live=<pool>]
+        (&http::Method::POST, ["switches", name]) => flip_switch(request, state, name),
+        // Start a graceful shutdown, as if the process had received SIGTERM
+        (&http::Method::POST, ["shutdown"]) => {
+            let started = state.shutdown.trigger("admin request");
+            json_response(
+                http::StatusCode::ACCEPTED,
+                &serde_json::json!({ "already_shutting_down": !started }),
+            )
+        }
+        (&http::Method::GET, ["metrics"]) => {
+            let mut body = state.metrics.render(state.in_flight.in_flight());
+            body += &state.saturation.render();
+            let body = body.into_bytes();
+            http::Response::builder()
+                .status(http::StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .header("Content-Length", body.len().to_string())
+                .version(http::Version::HTTP_11)
+                .body(body)
+                .unwrap()
+        }
+        (_, ["connections"])
+        | (_, ["connections", _])
+        | (_, ["penalties"])
+        | (_, ["upstreams"])
+        | (_, ["saturation"])
+        | (_, ["drain"])
+        | (_, ["splits"])
+        | (_, ["splits", _])
+        | (_, ["switches"])
+        | (_, ["switches", _])
+        | (_, ["shutdown"])
+        | (_, ["metrics"]) => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// What the admin API reports about each upstream.
+#[derive(Serialize)]
+struct UpstreamStatus<'a> {
+    address: &'a str,
+    draining: bool,
+    active_connections: usize,
+    // Failing its active health checks
+    dead: bool,
+    // Ejected by outlier detection
+    ejected: bool,
+    circuit_open: bool,
+}
+
+fn upstream_statuses(state: &ProxyState) -> Vec<UpstreamStatus<'_>> {
+    state
+        .upstreams
+        .iter()
+        .enumerate()
+        .map(|(idx, upstream)| UpstreamStatus {
+            address: &upstream.address,
+            draining: state.draining[idx].load(Ordering::Relaxed),
+            active_connections: state.active_connections[idx].load(Ordering::Relaxed),
+            dead: state.health.is_dead(idx),
+            ejected: state.outliers.is_ejected(idx),
+            circuit_open: !state.breakers.is_available(idx),
+        })
+        .collect()
+}
+
+/// Marks the upstream named by the `upstream` query parameter as draining (or not), including every
+/// other entry for the same address. Connections to a draining upstream finish their in-flight
+/// request, then move to another upstream.
+fn set_draining(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+    draining: bool,
+) -> http::Response<Vec<u8>> {
+    let address = match query_param(request.uri(), "upstream") {
+        Some(address) => address,
+        None => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    let indexes: Vec<usize> = state
+        .upstreams
+        .iter()
+        .enumerate()
+        .filter(|(_, upstream)| upstream.address == address)
+        .map(|(idx, _)| idx)
+        .collect();
+    if indexes.is_empty() {
+        return response::make_http_error(http::StatusCode::NOT_FOUND);
+    }
+    let mut changed = false;
+    for &idx in &indexes {
+        changed |= state.draining[idx].swap(draining, Ordering::Relaxed) != draining;
+    }
+    if changed {
+        if draining {
+            log::warn!("admin: draining upstream {} ({:?})", address, indexes);
+        } else {
+            log::warn!(
+                "admin: putting upstream {} ({:?}) back into rotation",
+                address,
+                indexes
+            );
+        }
+    }
+    json_response(
+        http::StatusCode::OK,
+        &serde_json::json!({
+            "upstream": address,
+            "draining": draining,
+            "indexes": indexes,
+            "active_connections": indexes
+                .iter()
+                .map(|&idx| state.active_connections[idx].load(Ordering::Relaxed))
+                .sum::<usize>(),
+        }),
+    )
+}
+
+/// Sets the weights of the named split's pools from the query string, which gives the weight of
+/// each pool that is to change.
+fn set_split_weights(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+    name: &str,
+) -> http::Response<Vec<u8>> {
+    let weights: Option<Vec<(String, u32)>> = query_pairs(request.uri()).and_then(|pairs| {
+        pairs
+            .into_iter()
+            .map(|(pool, weight)| Some((pool, weight.parse().ok()?)))
+            .collect()
+    });
+    let Some(weights) = weights.filter(|weights| !weights.is_empty()) else {
+        return response::make_http_error(http::StatusCode::BAD_REQUEST);
+    };
+    match state.router.set_split_weights(name, &weights) {
+        Ok(()) => {
+            log::warn!("admin: split {} now has weights {:?}", name, weights);
+            let split = state
+                .router
+                .splits()
+                .into_iter()
+                .find(|(split, _)| *split == name)
+                .map(|(_, shares)| shares)
+                .unwrap_or_default();
+            let weights: serde_json::Map<String, serde_json::Value> = split
+                .into_iter()
+                .map(|(pool, weight)| (pool.to_string(), weight.into()))
+                .collect();
+            json_response(
+                http::StatusCode::OK,
+                &serde_json::json!({ "name": name, "weights": weights }),
+            )
+        }
+        Err(err) => {
+            log::debug!("admin: not changing split {}: {:?}", name, err);
+            let (status, error) = match err {
+                routing::SplitUpdateError::UnknownSplit => (
+                    http::StatusCode::NOT_FOUND,
+                    format!("no split is named {:?}", name),
+                ),
+                routing::SplitUpdateError::UnknownPool(pool) => (
+                    http::StatusCode::BAD_REQUEST,
+                    format!("split {:?} has no pool {:?}", name, pool),
+                ),
+                routing::SplitUpdateError::NoWeight => (
+                    http::StatusCode::BAD_REQUEST,
+                    "every weight would be zero".to_string(),
+                ),
+            };
+            json_response(status, &serde_json::json!({ "error": error }))
+        }
+    }
+}
+
+/// Flips the named blue/green switch. Requests in flight on the pool that was live finish there,
+/// and its upstreams keep being health checked so that flipping back is instant; the response says
+/// how many connections to them are still active.
+fn flip_switch(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+    name: &str,
+) -> http::Response<Vec<u8>> {
+    let live = query_param(request.uri(), "live");
+    if let Err(err) = state.router.flip_switch(name, live.as_deref()) {
+        log::debug!("admin: not flipping switch {}: {:?}", name, err);
+        let (status, error) = match err {
+            routing::SwitchFlipError::UnknownSwitch => (
+                http::StatusCode::NOT_FOUND,
+                format!("no switch is named {:?}", name),
+            ),
+            routing::SwitchFlipError::UnknownPool(pool) => (
+                http::StatusCode::BAD_REQUEST,
+                format!("switch {:?} has no pool {:?}", name, pool),
+            ),
+        };
+        return json_response(status, &serde_json::json!({ "error": error }));
+    }
+    let Some((_, live, idle)) = state
+        .router
+        .switches()
+        .into_iter()
+        .find(|(switch, _, _)| *switch == name)
+    else {
+        return response::make_http_error(http::StatusCode::NOT_FOUND);
+    };
+    log::warn!("admin: switch {} now sends requests to pool {}", name, live);
+    let draining_connections: usize = state
+        .upstreams
+        .iter()
+        .enumerate()
+        .filter(|(_, upstream)| upstream.pool.as_deref().unwrap_or(routing::DEFAULT_POOL) == idle)
+        .map(|(idx, _)| state.active_connections[idx].load(Ordering::Relaxed))
+        .sum();
+    json_response(
+        http::StatusCode::OK,
+        &serde_json::json!({
+            "name": name,
+            "live": live,
+            "idle": idle,
+            "draining_connections": draining_connections,
+        }),
+    )
+}
+
+/// Returns the value of a query string parameter, if present.
+fn query_param(uri: &http::Uri, name: &str) -> Option<String> {
+    query_pairs(uri)?
+        .into_iter()
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// Returns the names and values in the query string, decoded as in an HTML form (`%XX` escapes,
+/// and `+` for a space), or None if any of them can't be decoded.
+fn query_pairs(uri: &http::Uri) -> Option<Vec<(String, String)>> {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((form_decode(key)?, form_decode(value)?))
+        })
+        .collect()
+}
+
+fn form_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                rest = &rest[2..];
+                u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn json_response<T: Serialize>(status: http::StatusCode, value: &T) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec(value).unwrap();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
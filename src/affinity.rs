@@ -0,0 +1,123 @@
+use crate::hash_ring::hash_bytes;
+use crate::request;
+use crate::upstream::Upstream;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Name of the cookie that pins a client's session to an upstream.
+pub const COOKIE_NAME: &str = "lb-affinity";
+
+/// Sticky sessions based on a cookie that we set on responses. The cookie holds an opaque ID
+/// derived from the upstream's address rather than its position in --upstream, so sessions
+/// survive upstreams being added or reordered.
+pub struct CookieAffinity {
+    // Cookie value for each upstream, by upstream index
+    ids: Vec<String>,
+    // Cookie value -> upstream index
+    upstreams_by_id: HashMap<String, usize>,
+}
+
+impl CookieAffinity {
+    pub fn new(upstreams: &[Upstream]) -> CookieAffinity {
+        let ids: Vec<String> = upstreams
+            .iter()
+            .map(|upstream| format!("{:016x}", hash_bytes(upstream.address.as_bytes())))
+            .collect();
+        let upstreams_by_id = ids
+            .iter()
+            .enumerate()
+            .map(|(idx, id)| (id.clone(), idx))
+            .collect();
+        CookieAffinity {
+            ids,
+            upstreams_by_id,
+        }
+    }
+
+    /// Returns the upstream the request's session is pinned to, if it carries a valid cookie.
+    pub fn lookup(&self, request: &http::Request<Vec<u8>>) -> Option<usize> {
+        let id = request::get_cookie(request, COOKIE_NAME)?;
+        self.upstreams_by_id.get(&id).copied()
+    }
+
+    /// Returns the cookie value that pins a session to the given upstream.
+    pub fn cookie_value(&self, idx: usize) -> &str {
+        &self.ids[idx]
+    }
+}
+
+/// How sessions pinned by cookie to an upstream that is being drained are moved off it.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum DrainMigration {
+    /// Send the session's next request to another upstream, and pin the session there
+    Immediate,
+    /// Serve the session's next request on the draining upstream one last time, and pin the
+    /// session to another upstream in that response's cookie, so the request after goes there
+    NextResponse,
+}
+
+struct IpAssignment {
+    upstream_idx: usize,
+    last_used: Instant,
+}
+
+/// A table pinning each client IP to the upstream it was first balanced to. Unlike the ip_hash
+/// strategy, an assignment doesn't move when the upstream pool changes; it lasts until the client
+/// has been idle for the TTL, or until its upstream goes down and it is reassigned.
+pub struct IpAffinity {
+    ttl: Duration,
+    assignments: Mutex<HashMap<IpAddr, IpAssignment>>,
+    // When expired assignments were last swept out of the table
+    last_pruned: Mutex<Instant>,
+}
+
+impl IpAffinity {
+    pub fn new(ttl: Duration) -> IpAffinity {
+        IpAffinity {
+            ttl,
+            assignments: Mutex::new(HashMap::new()),
+            last_pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns the upstream the client is pinned to, if its assignment hasn't expired.
+    pub fn lookup(&self, client_ip: IpAddr) -> Option<usize> {
+        let mut assignments = self.assignments.lock();
+        let assignment = assignments.get_mut(&client_ip)?;
+        if assignment.last_used.elapsed() >= self.ttl {
+            assignments.remove(&client_ip);
+            return None;
+        }
+        assignment.last_used = Instant::now();
+        Some(assignment.upstream_idx)
+    }
+
+    /// Pins the client to the given upstream, replacing any previous assignment.
+    pub fn assign(&self, client_ip: IpAddr, upstream_idx: usize) {
+        let now = Instant::now();
+        let mut assignments = self.assignments.lock();
+        let mut last_pruned = self.last_pruned.lock();
+        if now - *last_pruned >= self.ttl {
+            assignments.retain(|_, assignment| now - assignment.last_used < self.ttl);
+            *last_pruned = now;
+        }
+        let previous = assignments.insert(
+            client_ip,
+            IpAssignment {
+                upstream_idx,
+                last_used: now,
+            },
+        );
+        if let Some(previous) = previous.filter(|previous| previous.upstream_idx != upstream_idx) {
+            log::info!(
+                "Reassigned {} from upstream {} to {}",
+                client_ip,
+                previous.upstream_idx,
+                upstream_idx
+            );
+        }
+    }
+}
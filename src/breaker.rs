@@ -0,0 +1,166 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+enum State {
+    // Requests flow normally; counts consecutive failed requests
+    Closed { failures: usize },
+    // Requests avoid the upstream until the cooldown has passed
+    Open { since: Instant },
+    // A single trial request is in flight, and its outcome decides whether to close the breaker
+    HalfOpen { since: Instant },
+}
+
+/// A breaker that wasn't closed, as saved across restarts (see persist.rs). Times are relative to
+/// when it was saved.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedBreaker {
+    half_open: bool,
+    // How long until a trial request was due
+    cooldown_secs_left: f64,
+}
+
+/// A circuit breaker around each upstream, driven by live traffic alone (independently of the
+/// active health checks). After `threshold` consecutive failed requests the breaker opens and
+/// requests avoid the upstream. Once `cooldown` has passed, one trial request is let through: if
+/// it succeeds the breaker closes again, and if it fails the breaker stays open for another
+/// cooldown.
+pub struct CircuitBreakers {
+    // 0 = disabled
+    threshold: usize,
+    cooldown: Duration,
+    upstreams: Vec<Mutex<State>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(num_upstreams: usize, threshold: usize, cooldown: Duration) -> CircuitBreakers {
+        CircuitBreakers {
+            threshold,
+            cooldown,
+            upstreams: (0..num_upstreams)
+                .map(|_| Mutex::new(State::Closed { failures: 0 }))
+                .collect(),
+        }
+    }
+
+    /// Returns whether a request may be sent to the upstream: its breaker is closed, or it has
+    /// been open for long enough that a trial request is due. A trial that never reported back
+    /// (e.g. because the client went away) is replaced after another cooldown.
+    pub fn is_available(&self, idx: usize) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        match *self.upstreams[idx].lock() {
+            State::Closed { .. } => true,
+            State::Open { since } | State::HalfOpen { since } => since.elapsed() >= self.cooldown,
+        }
+    }
+
+    /// Records that a request is about to be sent to the upstream, which makes it the trial
+    /// request if one is due.
+    pub fn record_attempt(&self, idx: usize, address: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.upstreams[idx].lock();
+        if let State::Open { since } | State::HalfOpen { since } = *state {
+            if since.elapsed() >= self.cooldown {
+                log::info!("Sending a trial request to upstream {}", address);
+                *state = State::HalfOpen {
+                    since: Instant::now(),
+                };
+            }
+        }
+    }
+
+    pub fn record_success(&self, idx: usize, address: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.upstreams[idx].lock();
+        match *state {
+            State::Closed { .. } => *state = State::Closed { failures: 0 },
+            State::HalfOpen { .. } => {
+                log::info!(
+                    "Trial request to upstream {} succeeded, closing its circuit breaker",
+                    address
+                );
+                *state = State::Closed { failures: 0 };
+            }
+            // A request that was already in flight when the breaker opened doesn't close it
+            State::Open { .. } => {}
+        }
+    }
+
+    pub fn record_failure(&self, idx: usize, address: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.upstreams[idx].lock();
+        match *state {
+            State::Closed { failures } if failures + 1 >= self.threshold => {
+                log::warn!(
+                    "Opening the circuit breaker for upstream {} after {} consecutive failures",
+                    address,
+                    failures + 1
+                );
+                *state = State::Open {
+                    since: Instant::now(),
+                };
+            }
+            State::Closed { failures } => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            State::HalfOpen { .. } => {
+                log::warn!(
+                    "Trial request to upstream {} failed, keeping its circuit breaker open",
+                    address
+                );
+                *state = State::Open {
+                    since: Instant::now(),
+                };
+            }
+            State::Open { .. } => {}
+        }
+    }
+
+    /// Returns the state of the upstream's breaker if it is open or half-open.
+    pub fn save(&self, idx: usize) -> Option<SavedBreaker> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let (half_open, since) = match *self.upstreams[idx].lock() {
+            State::Closed { .. } => return None,
+            State::Open { since } => (false, since),
+            State::HalfOpen { since } => (true, since),
+        };
+        Some(SavedBreaker {
+            half_open,
+            cooldown_secs_left: self.cooldown.saturating_sub(since.elapsed()).as_secs_f64(),
+        })
+    }
+
+    /// Restores a saved breaker, aged by `elapsed` (the time since it was saved), so its cooldown
+    /// keeps running while the balancer is down. A trial request that was in flight then never
+    /// reports back, so it is replaced once the cooldown has passed. A cooldown that isn't a valid
+    /// duration is skipped.
+    pub fn restore(&self, idx: usize, saved: &SavedBreaker, elapsed: Duration) {
+        if self.threshold == 0 {
+            return;
+        }
+        let Ok(left) = Duration::try_from_secs_f64(saved.cooldown_secs_left) else {
+            return;
+        };
+        let left = left.saturating_sub(elapsed);
+        let now = Instant::now();
+        let since = now
+            .checked_sub(self.cooldown.saturating_sub(left))
+            .unwrap_or(now);
+        *self.upstreams[idx].lock() = match saved.half_open {
+            true => State::HalfOpen { since },
+            false => State::Open { since },
+        };
+    }
+}
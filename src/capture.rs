@@ -0,0 +1,218 @@
+use crate::{cidr, request, response, routing};
+use rand::Rng;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Headers whose values are replaced before an exchange is written to disk, since they carry
+/// credentials.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+const REDACTED: &str = "[redacted]";
+
+/// A header that a request must carry to be captured, parsed from `NAME` (any value) or
+/// `NAME:VALUE`.
+#[derive(Clone, Debug)]
+pub struct HeaderMatch {
+    name: http::HeaderName,
+    value: Option<String>,
+}
+
+impl std::str::FromStr for HeaderMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderMatch, String> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value.trim().to_string())),
+            None => (s, None),
+        };
+        let name = http::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", name))?;
+        Ok(HeaderMatch { name, value })
+    }
+}
+
+/// Which requests get captured. Every condition that is set must match.
+pub struct Filter {
+    pub path_prefix: Option<String>,
+    pub header: Option<HeaderMatch>,
+    pub client_cidrs: Vec<cidr::Cidr>,
+}
+
+impl Filter {
+    fn matches(&self, request: &http::Request<Vec<u8>>, client_ip: IpAddr) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if routing::strip_path_prefix(request.uri().path(), prefix).is_none() {
+                return false;
+            }
+        }
+        if let Some(header) = &self.header {
+            let matched = request.headers().get_all(&header.name).iter().any(|value| {
+                header
+                    .value
+                    .as_ref()
+                    .is_none_or(|expected| value.as_bytes() == expected.as_bytes())
+            });
+            if !matched {
+                return false;
+            }
+        }
+        self.client_cidrs.is_empty() || cidr::any_contains(&self.client_cidrs, client_ip)
+    }
+}
+
+/// What we know about a captured exchange besides the request and response themselves.
+#[derive(Serialize)]
+struct Metadata<'a> {
+    client: String,
+    upstream: &'a str,
+    captured_at_ms: u128,
+    // Original body sizes, set if the body was cut down to the size cap
+    request_body_truncated_from: Option<usize>,
+    response_body_truncated_from: Option<usize>,
+}
+
+/// Records a sample of request/response exchanges to disk, so that a backend bug only seen
+/// through the balancer can be reproduced. Each exchange is written as `<id>.request`, the raw
+/// HTTP request as forwarded to the upstream (replayable with e.g. `nc host port < <id>.request`),
+/// `<id>.response`, the raw response the upstream sent, and `<id>.json`, the client, upstream and
+/// time. Credentials are redacted and bodies are cut down to `max_body` bytes.
+pub struct Capture {
+    dir: PathBuf,
+    filter: Filter,
+    // Fraction of matching exchanges to record
+    sample_rate: f64,
+    max_body: usize,
+    // Stop recording after this many exchanges (0 = never stop)
+    limit: usize,
+    // Header names redacted on top of SENSITIVE_HEADERS, e.g. injected internal auth tokens
+    redact: Vec<http::HeaderName>,
+    captured: AtomicUsize,
+}
+
+impl Capture {
+    /// Creates the capture directory if it doesn't exist yet.
+    pub fn new(
+        dir: PathBuf,
+        filter: Filter,
+        sample_rate: f64,
+        max_body: usize,
+        limit: usize,
+        redact: Vec<http::HeaderName>,
+    ) -> Result<Capture, String> {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(format!(
+                "sample rate must be between 0 and 1, got {}",
+                sample_rate
+            ));
+        }
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| format!("could not create {}: {}", dir.display(), err))?;
+        Ok(Capture {
+            dir,
+            filter,
+            sample_rate,
+            max_body,
+            limit,
+            redact,
+            captured: AtomicUsize::new(0),
+        })
+    }
+
+    /// Writes the exchange to disk (in the background) if it matches the filter and is sampled.
+    pub fn record(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        client_ip: IpAddr,
+        upstream: &str,
+    ) {
+        if !self.filter.matches(request, client_ip)
+            || !rand::thread_rng().gen_bool(self.sample_rate)
+        {
+            return;
+        }
+        let seq = self.captured.fetch_add(1, Ordering::Relaxed);
+        if self.limit > 0 && seq >= self.limit {
+            if seq == self.limit {
+                log::info!("Captured {} exchanges; not capturing any more", self.limit);
+            }
+            return;
+        }
+
+        let captured_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let (request_body, request_truncated) = self.truncate(request.body());
+        let request_bytes = self.serialize(
+            request::format_request_line(request),
+            request.headers(),
+            request_body,
+        );
+        let (response_body, response_truncated) = self.truncate(response.body());
+        let response_bytes = self.serialize(
+            response::format_response_line(response),
+            response.headers(),
+            response_body,
+        );
+        let metadata = serde_json::to_vec_pretty(&Metadata {
+            client: client_ip.to_string(),
+            upstream,
+            captured_at_ms,
+            request_body_truncated_from: request_truncated.then_some(request.body().len()),
+            response_body_truncated_from: response_truncated.then_some(response.body().len()),
+        })
+        .unwrap();
+
+        let base = self.dir.join(format!("{}-{}", captured_at_ms, seq));
+        tokio::task::spawn_blocking(move || {
+            let written = std::fs::write(base.with_extension("request"), request_bytes)
+                .and_then(|()| std::fs::write(base.with_extension("response"), response_bytes))
+                .and_then(|()| std::fs::write(base.with_extension("json"), metadata));
+            if let Err(err) = written {
+                log::warn!("Could not write capture {}: {}", base.display(), err);
+            }
+        });
+    }
+
+    /// Returns the body cut down to the size cap, and whether it had to be cut.
+    fn truncate<'a>(&self, body: &'a [u8]) -> (&'a [u8], bool) {
+        if body.len() > self.max_body {
+            (&body[..self.max_body], true)
+        } else {
+            (body, false)
+        }
+    }
+
+    fn is_redacted(&self, name: &http::HeaderName) -> bool {
+        SENSITIVE_HEADERS.contains(&name.as_str()) || self.redact.contains(name)
+    }
+
+    /// Serializes a message the way it went over the wire, with credentials redacted and
+    /// Content-Length matching the (possibly truncated) body so that the result still parses.
+    fn serialize(&self, mut head: String, headers: &http::HeaderMap, body: &[u8]) -> Vec<u8> {
+        head.push_str("\r\n");
+        for (name, value) in headers {
+            let value = if self.is_redacted(name) {
+                REDACTED.to_string()
+            } else if name == http::header::CONTENT_LENGTH {
+                body.len().to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+}
@@ -0,0 +1,340 @@
+use crate::routing;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// A cap on the number of concurrent in-flight requests whose path starts with `prefix`. Parsed
+/// from command-line values of the form `/api/expensive=10`.
+#[derive(Clone, Debug)]
+pub struct RouteLimit {
+    pub prefix: String,
+    pub max_concurrent: usize,
+}
+
+impl std::str::FromStr for RouteLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RouteLimit, String> {
+        let (prefix, limit) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH_PREFIX=LIMIT, got {:?}", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix {:?} must start with '/'", prefix));
+        }
+        let max_concurrent = limit
+            .parse::<usize>()
+            .map_err(|err| format!("invalid limit {:?}: {}", limit, err))?;
+        if max_concurrent == 0 {
+            return Err("route concurrency limit must be at least 1".to_string());
+        }
+        Ok(RouteLimit {
+            prefix: prefix.to_string(),
+            max_concurrent,
+        })
+    }
+}
+
+/// How much a request matters when capacity runs short. Waiting high priority requests get a freed
+/// route slot or upstream connection before normal ones, low priority requests are rejected as
+/// soon as their route is saturated instead of queueing, and load shedding keeps headroom for the
+/// higher classes, so health checks and interactive traffic get through an overload ahead of batch
+/// clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Priority, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("expected high, normal or low, got {:?}", s)),
+        }
+    }
+}
+
+/// What a priority rule matches on.
+#[derive(Clone, Debug)]
+enum PriorityMatch {
+    PathPrefix(String),
+    Header(http::HeaderName, Option<String>),
+}
+
+/// Assigns a priority class to matching requests. Parsed from `PATH_PREFIX=CLASS` or
+/// `header:NAME[:VALUE]=CLASS`, e.g. `/healthz=high` or `header:x-batch-job=low`.
+#[derive(Clone, Debug)]
+pub struct PriorityRule {
+    matcher: PriorityMatch,
+    priority: Priority,
+}
+
+impl std::str::FromStr for PriorityRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PriorityRule, String> {
+        let (matcher, priority) = s.rsplit_once('=').ok_or_else(|| {
+            format!(
+                "expected PATH_PREFIX=CLASS or header:NAME=CLASS, got {:?}",
+                s
+            )
+        })?;
+        let matcher = if let Some(header) = matcher.strip_prefix("header:") {
+            let (name, value) = match header.split_once(':') {
+                Some((name, value)) => (name, Some(value.trim().to_string())),
+                None => (header, None),
+            };
+            let name = http::HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("invalid header name {:?}", name))?;
+            PriorityMatch::Header(name, value)
+        } else if matcher.starts_with('/') {
+            PriorityMatch::PathPrefix(matcher.to_string())
+        } else {
+            return Err(format!(
+                "{:?} is neither a path prefix nor header:NAME",
+                matcher
+            ));
+        };
+        Ok(PriorityRule {
+            matcher,
+            priority: priority.parse()?,
+        })
+    }
+}
+
+impl PriorityRule {
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        match &self.matcher {
+            PriorityMatch::PathPrefix(prefix) => {
+                routing::strip_path_prefix(request.uri().path(), prefix).is_some()
+            }
+            PriorityMatch::Header(name, expected) => {
+                request.headers().get_all(name).iter().any(|value| {
+                    expected
+                        .as_ref()
+                        .is_none_or(|expected| value.as_bytes() == expected.as_bytes())
+                })
+            }
+        }
+    }
+}
+
+/// Returns the priority of the first rule matching the request, or normal if none does.
+pub fn classify(rules: &[PriorityRule], request: &http::Request<Vec<u8>>) -> Priority {
+    rules
+        .iter()
+        .find(|rule| rule.matches(request))
+        .map_or(Priority::Normal, |rule| rule.priority)
+}
+
+struct GateState {
+    available: usize,
+    // Requests waiting for a slot, by priority (indexed by `Priority as usize`)
+    waiting: [VecDeque<oneshot::Sender<Permit>>; 3],
+}
+
+/// A counting semaphore whose slots go to the highest priority waiter first (and first come, first
+/// served within a priority). Used for route concurrency limits and upstream connection limits.
+pub struct PriorityGate {
+    state: Mutex<GateState>,
+}
+
+impl PriorityGate {
+    pub fn new(slots: usize) -> PriorityGate {
+        PriorityGate {
+            state: Mutex::new(GateState {
+                available: slots,
+                waiting: Default::default(),
+            }),
+        }
+    }
+
+    /// Takes a free slot if there is one, or else joins the queue for the first slot freed up.
+    fn try_acquire(
+        self: &Arc<Self>,
+        priority: Priority,
+        queue: bool,
+    ) -> Result<Permit, Option<oneshot::Receiver<Permit>>> {
+        let mut state = self.state.lock();
+        if state.available > 0 {
+            state.available -= 1;
+            return Ok(Permit {
+                gate: Some(self.clone()),
+            });
+        }
+        if !queue {
+            return Err(None);
+        }
+        let (sender, receiver) = oneshot::channel();
+        state.waiting[priority as usize].push_back(sender);
+        Err(Some(receiver))
+    }
+
+    /// Reserves a slot, waiting for one to free up until `deadline` if `queue` is set and none is
+    /// free. Returns None if no slot could be had.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        priority: Priority,
+        queue: bool,
+        deadline: Instant,
+    ) -> Option<Permit> {
+        match self.try_acquire(priority, queue) {
+            Ok(permit) => Some(permit),
+            Err(None) => None,
+            Err(Some(waiting)) => tokio::time::timeout_at(deadline.into(), waiting)
+                .await
+                .ok()
+                .and_then(|permit| permit.ok()),
+        }
+    }
+
+    /// Hands a freed slot to the highest priority request still waiting, or returns it to the pool.
+    fn release(self: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock();
+                match state
+                    .waiting
+                    .iter_mut()
+                    .rev()
+                    .find_map(|waiting| waiting.pop_front())
+                {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                }
+            };
+            let permit = Permit {
+                gate: Some(self.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // The waiter gave up; the slot is still ours to hand on, so don't release it again
+                Err(mut permit) => permit.gate = None,
+            }
+        }
+    }
+}
+
+/// A reserved slot in a PriorityGate, released when dropped.
+pub struct Permit {
+    gate: Option<Arc<PriorityGate>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(gate) = self.gate.take() {
+            gate.release();
+        }
+    }
+}
+
+/// Limits the number of requests that may be in flight at once for each configured route, so that
+/// a single expensive endpoint can't tie up all of the upstream capacity.
+pub struct RouteLimiter {
+    // Routes sorted longest-prefix-first, so the most specific route wins
+    routes: Vec<(String, Arc<PriorityGate>)>,
+    // How long a request may wait for a free slot before being rejected (zero = reject immediately)
+    queue_timeout: Duration,
+}
+
+impl RouteLimiter {
+    pub fn new(limits: &[RouteLimit], queue_timeout: Duration) -> RouteLimiter {
+        let mut routes: Vec<(String, Arc<PriorityGate>)> = limits
+            .iter()
+            .map(|limit| {
+                (
+                    limit.prefix.clone(),
+                    Arc::new(PriorityGate::new(limit.max_concurrent)),
+                )
+            })
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        RouteLimiter {
+            routes,
+            queue_timeout,
+        }
+    }
+
+    /// Reserves a slot for a request to `path`. Returns Ok(None) if no limit applies to the path,
+    /// Ok(Some(permit)) if a slot was reserved (the slot is released when the permit is dropped),
+    /// or Err(prefix) naming the saturated route if no slot became free within the queue timeout.
+    /// Low priority requests never wait for a slot.
+    pub async fn acquire(&self, path: &str, priority: Priority) -> Result<Option<Permit>, String> {
+        let (prefix, gate) = match self
+            .routes
+            .iter()
+            .find(|(prefix, _)| routing::strip_path_prefix(path, prefix).is_some())
+        {
+            Some(route) => route,
+            None => return Ok(None),
+        };
+
+        let queue = !self.queue_timeout.is_zero() && priority > Priority::Low;
+        gate.acquire(priority, queue, Instant::now() + self.queue_timeout)
+            .await
+            .map(Some)
+            .ok_or_else(|| prefix.clone())
+    }
+}
+
+/// Caps the number of proxied requests in flight across the whole balancer. Requests over the cap
+/// are shed straight away rather than queued, so that an overload costs some 503s instead of
+/// memory for a growing backlog. Part of the cap is kept as headroom for higher priorities: normal
+/// requests are shed once all but `headroom` slots are taken, and low priority ones once all but
+/// twice that are, so high priority requests still get through. A cap of 0 only counts requests.
+pub struct InFlightLimit {
+    max: usize,
+    headroom: usize,
+    in_flight: AtomicUsize,
+}
+
+impl InFlightLimit {
+    /// Reserves `headroom_percent` of the cap for each priority above normal and low.
+    pub fn new(max: usize, headroom_percent: f64) -> InFlightLimit {
+        InFlightLimit {
+            max,
+            headroom: (max as f64 * headroom_percent / 100.0) as usize,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of requests in flight at which requests of `priority` start being shed.
+    fn cap(&self, priority: Priority) -> usize {
+        let classes_above = Priority::High as usize - priority as usize;
+        self.max.saturating_sub(self.headroom * classes_above)
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped, or returns None if the
+    /// cap for its priority has been reached.
+    pub fn try_start(&self, priority: Priority) -> Option<InFlightRequest<'_>> {
+        let previous = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let request = InFlightRequest { limit: self };
+        (self.max == 0 || previous < self.cap(priority)).then_some(request)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// A request counted by an InFlightLimit.
+pub struct InFlightRequest<'a> {
+    limit: &'a InFlightLimit,
+}
+
+impl Drop for InFlightRequest<'_> {
+    fn drop(&mut self) {
+        self.limit.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,161 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// Live bookkeeping for a single open client connection.
+struct ConnectionInfo {
+    peer: SocketAddr,
+    opened_at: Instant,
+    requests_served: AtomicUsize,
+    // Address of the upstream this connection is currently being proxied to, if any
+    upstream: Mutex<Option<String>>,
+    // Notified when an operator asks for this connection to be closed
+    close_signal: Notify,
+}
+
+/// A point-in-time view of an open connection, as reported by the admin API.
+#[derive(Serialize, Debug)]
+pub struct ConnectionSummary {
+    id: u64,
+    peer: String,
+    age_secs: u64,
+    requests_served: usize,
+    upstream: Option<String>,
+}
+
+/// Tracks every open client connection so that operators can see who is connected and forcibly
+/// close connections (e.g. during an abuse incident).
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<ConnectionInfo>>>,
+    // Number of open connections by client IP
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> ConnectionRegistry {
+        ConnectionRegistry {
+            next_id: AtomicU64::new(1),
+            connections: Mutex::new(HashMap::new()),
+            per_ip: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a new connection, unless its IP already has `max_per_ip` connections open (0 =
+    /// no limit). The connection is deregistered when the returned handle is dropped.
+    pub fn register(
+        self: &Arc<Self>,
+        peer: SocketAddr,
+        max_per_ip: usize,
+    ) -> Option<ConnectionHandle> {
+        {
+            let mut per_ip = self.per_ip.lock();
+            let open = per_ip.entry(peer.ip()).or_insert(0);
+            if max_per_ip > 0 && *open >= max_per_ip {
+                return None;
+            }
+            *open += 1;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = Arc::new(ConnectionInfo {
+            peer,
+            opened_at: Instant::now(),
+            requests_served: AtomicUsize::new(0),
+            upstream: Mutex::new(None),
+            close_signal: Notify::new(),
+        });
+        self.connections.lock().insert(id, info.clone());
+        Some(ConnectionHandle {
+            registry: self.clone(),
+            id,
+            info,
+        })
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionSummary> {
+        let mut summaries: Vec<ConnectionSummary> = self
+            .connections
+            .lock()
+            .iter()
+            .map(|(id, info)| ConnectionSummary {
+                id: *id,
+                peer: info.peer.to_string(),
+                age_secs: info.opened_at.elapsed().as_secs(),
+                requests_served: info.requests_served.load(Ordering::Relaxed),
+                upstream: info.upstream.lock().clone(),
+            })
+            .collect();
+        summaries.sort_by_key(|summary| summary.id);
+        summaries
+    }
+
+    /// Returns the number of open connections.
+    pub fn len(&self) -> usize {
+        self.connections.lock().len()
+    }
+
+    /// Asks the connection with the given id to close. Returns false if there is no such
+    /// connection.
+    pub fn close(&self, id: u64) -> bool {
+        match self.connections.lock().get(&id) {
+            Some(info) => {
+                info.close_signal.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Asks every connection from the given IP to close, returning how many were found.
+    pub fn close_ip(&self, ip: IpAddr) -> usize {
+        let connections = self.connections.lock();
+        let mut closed = 0;
+        for info in connections.values().filter(|info| info.peer.ip() == ip) {
+            info.close_signal.notify_one();
+            closed += 1;
+        }
+        closed
+    }
+}
+
+/// Handle held by the task serving a connection. Dropping it removes the connection from the
+/// registry.
+pub struct ConnectionHandle {
+    registry: Arc<ConnectionRegistry>,
+    id: u64,
+    info: Arc<ConnectionInfo>,
+}
+
+impl ConnectionHandle {
+    pub fn set_upstream(&self, upstream: Option<String>) {
+        *self.info.upstream.lock() = upstream;
+    }
+
+    pub fn record_request(&self) {
+        self.info.requests_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Resolves once an operator has asked for this connection to be closed.
+    pub async fn closed(&self) {
+        self.info.close_signal.notified().await
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.registry.connections.lock().remove(&self.id);
+        let ip = self.info.peer.ip();
+        let mut per_ip = self.registry.per_ip.lock();
+        if let Some(open) = per_ip.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
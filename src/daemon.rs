@@ -0,0 +1,73 @@
+use nix::unistd::{Group, User};
+use std::path::{Path, PathBuf};
+
+/// Detaches from the terminal and carries on in the background, for init systems that expect
+/// services to fork. Must be called before the async runtime (or any other thread) is started,
+/// since only the calling thread survives the fork. The working directory is kept, so relative
+/// paths in the options still work, and so are stdout and stderr, which the init script should
+/// point at a log file.
+pub fn daemonize() -> Result<(), String> {
+    nix::unistd::daemon(true, true).map_err(|err| format!("could not daemonize: {}", err))
+}
+
+/// A file holding our process ID, for init scripts and log rotation tools that signal the
+/// balancer. It is written once we've bound our sockets, so its presence also means we're up.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<PidFile, String> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|err| format!("could not write {}: {}", path.display(), err))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Deletes the file on the way out. This may fail if privileges were dropped since it was
+    /// written, in which case the stale file is left behind for the init system to clean up.
+    pub fn remove(self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Could not remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Switches to an unprivileged user and/or group, e.g. after binding a port below 1024 as root.
+/// If only a user is given, we switch to its primary group. Returns a description of who we are
+/// now running as.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<String, String> {
+    let user = user
+        .map(|name| {
+            User::from_name(name)
+                .map_err(|err| format!("could not look up user {}: {}", name, err))?
+                .ok_or_else(|| format!("no such user {}", name))
+        })
+        .transpose()?;
+    let group = match group {
+        Some(name) => Some(
+            Group::from_name(name)
+                .map_err(|err| format!("could not look up group {}: {}", name, err))?
+                .ok_or_else(|| format!("no such group {}", name))?
+                .gid,
+        ),
+        None => user.as_ref().map(|user| user.gid),
+    };
+
+    // The group has to go first: once we've given up root we're no longer allowed to change it
+    if let Some(gid) = group {
+        nix::unistd::setgroups(&[gid])
+            .and_then(|()| nix::unistd::setgid(gid))
+            .map_err(|err| format!("could not switch to group {}: {}", gid, err))?;
+    }
+    if let Some(user) = &user {
+        nix::unistd::setuid(user.uid)
+            .map_err(|err| format!("could not switch to user {}: {}", user.name, err))?;
+    }
+    Ok(format!(
+        "uid {}, gid {}",
+        nix::unistd::getuid(),
+        nix::unistd::getgid()
+    ))
+}
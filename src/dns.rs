@@ -0,0 +1,115 @@
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// An asynchronous, caching DNS resolver used to look up upstream addresses. Unlike
+/// `TcpStream::connect`, which resolves hostnames with a blocking getaddrinfo call on every
+/// connection attempt, this resolver caches answers (including negative answers) and enforces a
+/// timeout, so a slow nameserver doesn't translate directly into request latency.
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+    ip_preference: IpPreference,
+}
+
+/// Which address family to try first when an upstream hostname resolves to both IPv4 and IPv6
+/// addresses. The other family is still tried if connecting over the preferred one fails.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum IpPreference {
+    /// Use addresses in the order the resolver returns them
+    System,
+    /// Try IPv4 addresses first
+    Ipv4,
+    /// Try IPv6 addresses first
+    Ipv6,
+}
+
+/// Parses a nameserver given as `IP` or `IP:PORT` (port 53 is used if no port is given).
+pub fn parse_nameserver(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    s.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| format!("invalid nameserver {:?}, expected IP or IP:PORT", s))
+}
+
+impl Resolver {
+    /// Creates a resolver. If no nameservers are given, the system resolver configuration (e.g.
+    /// /etc/resolv.conf) is used. Cached answers are kept for at least `min_ttl` and at most
+    /// `max_ttl`, regardless of the TTL in the DNS response; failed lookups are cached for
+    /// `negative_ttl`.
+    pub fn new(
+        nameservers: &[SocketAddr],
+        timeout: Duration,
+        min_ttl: Duration,
+        max_ttl: Duration,
+        negative_ttl: Duration,
+        ip_preference: IpPreference,
+    ) -> Result<Resolver, String> {
+        let (config, mut opts) = if nameservers.is_empty() {
+            hickory_resolver::system_conf::read_system_conf()
+                .map_err(|err| format!("could not read system DNS configuration: {}", err))?
+        } else {
+            let mut group = NameServerConfigGroup::new();
+            for nameserver in nameservers {
+                group.merge(NameServerConfigGroup::from_ips_clear(
+                    &[nameserver.ip()],
+                    nameserver.port(),
+                    true,
+                ));
+            }
+            (
+                ResolverConfig::from_parts(None, vec![], group),
+                ResolverOpts::default(),
+            )
+        };
+        opts.timeout = timeout;
+        opts.positive_min_ttl = Some(min_ttl);
+        opts.positive_max_ttl = Some(max_ttl.max(min_ttl));
+        opts.negative_min_ttl = Some(negative_ttl);
+        opts.negative_max_ttl = Some(negative_ttl);
+        if ip_preference != IpPreference::System {
+            // Look up both families so there is something to prefer between
+            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        }
+        Ok(Resolver {
+            inner: TokioAsyncResolver::tokio(config, opts),
+            ip_preference,
+        })
+    }
+
+    /// Resolves an address of the form `host:port` into one or more socket addresses. IP literals
+    /// are returned directly without a DNS lookup.
+    pub async fn resolve(&self, addr: &str) -> Result<Vec<SocketAddr>, std::io::Error> {
+        if let Ok(addr) = addr.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        let (host, port) = addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid address {:?}, expected HOST:PORT", addr),
+                )
+            })?;
+        let lookup = self.inner.lookup_ip(host).await.map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("could not resolve {}: {}", host, err),
+            )
+        })?;
+        let mut addrs: Vec<SocketAddr> =
+            lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+        match self.ip_preference {
+            IpPreference::System => {}
+            IpPreference::Ipv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+        Ok(addrs)
+    }
+}
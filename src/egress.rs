@@ -0,0 +1,214 @@
+use crate::dns;
+use base64::Engine;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest proxy response header we are willing to read in reply to a CONNECT request.
+const MAX_CONNECT_RESPONSE_SIZE: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    Socks5,
+    HttpConnect,
+}
+
+/// An egress proxy that upstream connections are tunnelled through, parsed from a URL of the
+/// form `socks5://[user:pass@]host:port` or `http://[user:pass@]host:port`. Upstream hostnames
+/// are passed to the proxy unresolved, since in locked-down networks the proxy is often the only
+/// thing that can resolve them.
+#[derive(Clone, Debug)]
+pub struct EgressProxy {
+    protocol: ProxyProtocol,
+    // host:port of the proxy itself
+    pub address: String,
+    credentials: Option<(String, String)>,
+}
+
+impl std::str::FromStr for EgressProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EgressProxy, String> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| format!("invalid egress proxy {:?}, expected SCHEME://HOST:PORT", s))?;
+        let protocol = match scheme {
+            "socks5" => ProxyProtocol::Socks5,
+            "http" => ProxyProtocol::HttpConnect,
+            _ => {
+                return Err(format!(
+                    "unsupported egress proxy scheme {:?}, expected socks5 or http",
+                    scheme
+                ))
+            }
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, address) = match rest.rsplit_once('@') {
+            Some((userinfo, address)) => {
+                let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), pass.to_string())), address)
+            }
+            None => (None, rest),
+        };
+        if address
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .is_none()
+        {
+            return Err(format!("egress proxy {:?} must include a port", s));
+        }
+        if protocol == ProxyProtocol::Socks5 {
+            if let Some((user, pass)) = &credentials {
+                // RFC 1929 encodes each field's length in a single byte
+                if user.is_empty() || user.len() > 255 || pass.len() > 255 {
+                    return Err("SOCKS5 username and password must be 1-255 bytes".to_string());
+                }
+            }
+        }
+        Ok(EgressProxy {
+            protocol,
+            address: address.to_string(),
+            credentials,
+        })
+    }
+}
+
+impl EgressProxy {
+    /// Opens a connection to `target` (a `host:port` string) through the proxy.
+    pub async fn connect(
+        &self,
+        resolver: &dns::Resolver,
+        target: &str,
+    ) -> Result<TcpStream, Error> {
+        let addrs = resolver.resolve(&self.address).await?;
+        let mut stream = TcpStream::connect(&addrs[..]).await?;
+        match self.protocol {
+            ProxyProtocol::Socks5 => self.socks5_handshake(&mut stream, target).await?,
+            ProxyProtocol::HttpConnect => self.http_connect(&mut stream, target).await?,
+        }
+        Ok(stream)
+    }
+
+    /// Performs a SOCKS5 (RFC 1928) CONNECT, authenticating with username/password (RFC 1929) if
+    /// credentials were configured.
+    async fn socks5_handshake(&self, stream: &mut TcpStream, target: &str) -> Result<(), Error> {
+        let (host, port) = split_host_port(target)?;
+
+        // Greeting: offer username/password authentication only if we have credentials
+        let method = if self.credentials.is_some() {
+            0x02
+        } else {
+            0x00
+        };
+        stream.write_all(&[0x05, 0x01, method]).await?;
+        let mut reply = [0_u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(proxy_error(
+                "SOCKS5 proxy rejected our authentication methods",
+            ));
+        }
+        if let Some((user, pass)) = &self.credentials {
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            stream.write_all(&auth).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0x00 {
+                return Err(proxy_error("SOCKS5 proxy rejected our credentials"));
+            }
+        }
+
+        // Connect request, passing hostnames through for the proxy to resolve
+        let mut request = vec![0x05, 0x01, 0x00];
+        if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        } else if let Ok(ip) = host.parse::<Ipv6Addr>() {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        } else {
+            if host.len() > 255 {
+                return Err(proxy_error("hostname is too long for SOCKS5"));
+            }
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut header = [0_u8; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != 0x00 {
+            return Err(proxy_error(&format!(
+                "SOCKS5 proxy could not connect to {} (reply code {})",
+                target, header[1]
+            )));
+        }
+        // Skip over the bound address that the proxy reports back
+        let address_len = match header[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            _ => return Err(proxy_error("SOCKS5 proxy sent an invalid reply")),
+        };
+        let mut bound_address = vec![0_u8; address_len + 2];
+        stream.read_exact(&mut bound_address).await?;
+        Ok(())
+    }
+
+    /// Opens a tunnel with an HTTP CONNECT request, using Basic proxy authentication if
+    /// credentials were configured.
+    async fn http_connect(&self, stream: &mut TcpStream, target: &str) -> Result<(), Error> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, pass)) = &self.credentials {
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+            request += &format!("Proxy-Authorization: Basic {}\r\n", token);
+        }
+        request += "\r\n";
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the proxy's response header a byte at a time, so that we don't consume any of the
+        // upstream's bytes that may follow it
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE_SIZE {
+                return Err(proxy_error("HTTP proxy sent an oversized CONNECT response"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        match parsed.parse(&response) {
+            Ok(httparse::Status::Complete(_)) if parsed.code == Some(200) => Ok(()),
+            Ok(httparse::Status::Complete(_)) => Err(proxy_error(&format!(
+                "HTTP proxy refused CONNECT to {} with status {}",
+                target,
+                parsed.code.unwrap_or(0)
+            ))),
+            _ => Err(proxy_error("HTTP proxy sent a malformed CONNECT response")),
+        }
+    }
+}
+
+fn split_host_port(addr: &str) -> Result<(&str, u16), Error> {
+    addr.rsplit_once(':')
+        .and_then(|(host, port)| {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            Some((host, port.parse::<u16>().ok()?))
+        })
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid address {:?}, expected HOST:PORT", addr),
+            )
+        })
+}
+
+fn proxy_error(message: &str) -> Error {
+    Error::new(ErrorKind::ConnectionRefused, message.to_string())
+}
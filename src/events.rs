@@ -0,0 +1,157 @@
+use crate::{request, response};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+
+/// How long a webhook or command gets to handle an event before we give up on it.
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A plain-HTTP URL that health events are POSTed to, parsed from `http://HOST[:PORT]/PATH`.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    // host:port to connect to
+    address: String,
+    host: String,
+    path: String,
+}
+
+impl std::str::FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Webhook, String> {
+        let uri = s
+            .parse::<http::Uri>()
+            .map_err(|err| format!("invalid URL {:?}: {}", s, err))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("webhook URL {:?} must start with http://", s));
+        }
+        let authority = uri
+            .authority()
+            .ok_or_else(|| format!("webhook URL {:?} has no host", s))?;
+        Ok(Webhook {
+            address: format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(80)
+            ),
+            host: authority.to_string(),
+            path: uri
+                .path_and_query()
+                .map_or("/".to_string(), |path| path.to_string()),
+        })
+    }
+}
+
+/// An upstream moving in or out of rotation.
+#[derive(Serialize, Debug)]
+pub struct HealthEvent {
+    pub upstream: String,
+    pub healthy: bool,
+    pub reason: String,
+    pub timestamp_unix_ms: u64,
+}
+
+struct Hooks {
+    webhook: Option<Webhook>,
+    // Run with `sh -c`, with the event in LB_UPSTREAM, LB_HEALTHY, LB_REASON and LB_TIMESTAMP
+    command: Option<String>,
+}
+
+/// Tells the outside world when upstreams change health, so that backend failures can page
+/// someone without scraping our logs. Every event is POSTed as JSON to the webhook and/or passed
+/// to the command, in the background; delivery failures are logged and otherwise ignored.
+#[derive(Clone)]
+pub struct EventHooks {
+    hooks: Arc<Hooks>,
+}
+
+impl EventHooks {
+    pub fn new(webhook: Option<Webhook>, command: Option<String>) -> EventHooks {
+        EventHooks {
+            hooks: Arc::new(Hooks { webhook, command }),
+        }
+    }
+
+    pub fn upstream_changed(&self, address: &str, healthy: bool, reason: &dyn std::fmt::Display) {
+        if self.hooks.webhook.is_none() && self.hooks.command.is_none() {
+            return;
+        }
+        let event = HealthEvent {
+            upstream: address.to_string(),
+            healthy,
+            reason: reason.to_string(),
+            timestamp_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        let hooks = self.hooks.clone();
+        tokio::spawn(async move {
+            if let Some(webhook) = &hooks.webhook {
+                let body = serde_json::to_vec(&event).unwrap();
+                match tokio::time::timeout(DELIVERY_TIMEOUT, post(webhook, body)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => log::warn!("Health event webhook failed: {}", err),
+                    Err(_) => log::warn!("Health event webhook timed out"),
+                }
+            }
+            if let Some(command) = &hooks.command {
+                match tokio::time::timeout(DELIVERY_TIMEOUT, run(command, &event)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => log::warn!("Health event command failed: {}", err),
+                    Err(_) => log::warn!("Health event command timed out"),
+                }
+            }
+        });
+    }
+}
+
+/// POSTs a JSON body to the webhook, failing unless it answers with a success status.
+pub async fn post(webhook: &Webhook, body: Vec<u8>) -> Result<(), String> {
+    let hook_request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(&webhook.path)
+        .version(http::Version::HTTP_11)
+        .header("host", &webhook.host)
+        .header("content-type", "application/json")
+        .header("content-length", body.len().to_string())
+        .header("connection", "close")
+        .body(body)
+        .unwrap();
+    let mut stream = TcpStream::connect(&webhook.address)
+        .await
+        .map_err(|err| format!("could not connect to {}: {}", webhook.address, err))?;
+    request::write_to_stream(&hook_request, &mut stream)
+        .await
+        .map_err(|err| err.to_string())?;
+    let hook_response = response::read_from_stream(
+        &mut stream,
+        hook_request.method(),
+        response::DEFAULT_MAX_BODY_SIZE,
+    )
+    .await
+    .map_err(|err| format!("invalid response: {:?}", err))?;
+    if !hook_response.status().is_success() {
+        return Err(format!("webhook returned {}", hook_response.status()));
+    }
+    Ok(())
+}
+
+async fn run(command: &str, event: &HealthEvent) -> Result<(), String> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("LB_UPSTREAM", &event.upstream)
+        .env("LB_HEALTHY", event.healthy.to_string())
+        .env("LB_REASON", &event.reason)
+        .env("LB_TIMESTAMP", event.timestamp_unix_ms.to_string())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !status.success() {
+        return Err(format!("{:?} exited with {}", command, status));
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+/// A static page served instead of a bare error when a request can't reach any upstream, e.g. a
+/// branded "we'll be right back" page. The Content-Type is guessed from the file's extension.
+pub struct FallbackResponse {
+    status: http::StatusCode,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl FallbackResponse {
+    /// Reads the page into memory up front, so that serving it doesn't depend on the disk.
+    pub fn load(path: &Path, status: http::StatusCode) -> Result<FallbackResponse, String> {
+        let body = std::fs::read(path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html" | "htm") => "text/html; charset=utf-8",
+            Some("json") => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        Ok(FallbackResponse {
+            status,
+            content_type,
+            body,
+        })
+    }
+
+    pub fn to_response(&self) -> http::Response<Vec<u8>> {
+        http::Response::builder()
+            .status(self.status)
+            .header("Content-Type", self.content_type)
+            .header("Content-Length", self.body.len().to_string())
+            // So that caches in front of us don't keep serving it once the upstreams are back
+            .header("Cache-Control", "no-store")
+            .version(http::Version::HTTP_11)
+            .body(self.body.clone())
+            .unwrap()
+    }
+}
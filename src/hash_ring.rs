@@ -0,0 +1,141 @@
+use crate::request;
+use crate::upstream::Upstream;
+use std::net::IpAddr;
+
+/// Number of points each unit of upstream weight gets on the ring. More points spread keys more
+/// evenly between upstreams.
+const POINTS_PER_WEIGHT: usize = 100;
+
+/// A consistent-hash ring over the upstreams. Each upstream is hashed onto the ring at several
+/// points, and a key is served by the upstream owning the first point at or after the key's hash.
+/// Adding or removing an upstream only moves the keys adjacent to its points, rather than
+/// reshuffling every key the way `hash % n` would.
+pub struct HashRing {
+    // (point on the ring, upstream index), sorted by point
+    points: Vec<(u64, usize)>,
+}
+
+/// The request field that the hash strategy hashes onto the ring. Parsed from `ip`,
+/// `header:NAME` or `cookie:NAME`.
+#[derive(Clone, Debug)]
+pub enum HashKey {
+    ClientIp,
+    Header(http::HeaderName),
+    Cookie(String),
+}
+
+impl std::str::FromStr for HashKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashKey, String> {
+        match s.split_once(':') {
+            None if s == "ip" => Ok(HashKey::ClientIp),
+            Some(("header", name)) => http::HeaderName::from_bytes(name.as_bytes())
+                .map(HashKey::Header)
+                .map_err(|_| format!("invalid header name {:?}", name)),
+            Some(("cookie", name)) if !name.is_empty() => Ok(HashKey::Cookie(name.to_string())),
+            _ => Err(format!(
+                "invalid hash key {:?}, expected ip, header:NAME or cookie:NAME",
+                s
+            )),
+        }
+    }
+}
+
+/// How the hash strategies place requests on the ring.
+#[derive(Clone, Debug)]
+pub struct HashOptions {
+    // The request field the hash strategy hashes (ip_hash always hashes the client IP)
+    pub key: HashKey,
+    // Most connections an upstream may have, as a multiple of the average over the upstreams a
+    // request could go to; keys over the bound spill to the next upstream on the ring (0 = no
+    // bound)
+    pub load_factor: f64,
+}
+
+/// Parses a bounded-load factor, which must be at least 1 (or 0, for no bound), since a bound
+/// below the average load can't be met by every upstream at once.
+pub fn parse_load_factor(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor == 0.0 || factor >= 1.0 => Ok(factor),
+        _ => Err(format!(
+            "invalid load factor {:?}, expected 0 or a number of at least 1",
+            s
+        )),
+    }
+}
+
+impl HashKey {
+    /// Returns the bytes to hash for a request. Requests that don't carry the configured header
+    /// or cookie fall back to hashing the client IP.
+    pub fn extract(&self, request: &http::Request<Vec<u8>>, client_ip: IpAddr) -> Vec<u8> {
+        let value = match self {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => request
+                .headers()
+                .get(name)
+                .map(|value| value.as_bytes().to_vec()),
+            HashKey::Cookie(name) => {
+                request::get_cookie(request, name).map(|value| value.into_bytes())
+            }
+        };
+        value.unwrap_or_else(|| client_ip.to_string().into_bytes())
+    }
+}
+
+/// 64-bit FNV-1a. We use our own hash function (rather than std's DefaultHasher) so that key
+/// placement is stable across Rust releases and balancer restarts.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl HashRing {
+    /// Builds the ring over the upstreams that are `members` of a pool.
+    pub fn new(upstreams: &[Upstream], members: &[bool]) -> HashRing {
+        let mut points = Vec::new();
+        for (idx, upstream) in upstreams.iter().enumerate() {
+            if !members[idx] {
+                continue;
+            }
+            for replica in 0..upstream.weight * POINTS_PER_WEIGHT {
+                let point = hash_bytes(format!("{}-{}", upstream.address, replica).as_bytes());
+                points.push((point, idx));
+            }
+        }
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// Returns the index of the upstream responsible for the given key, skipping clockwise past
+    /// upstreams that aren't `candidates` (so that keys only move off upstreams that are excluded).
+    pub fn lookup(&self, key: &[u8], candidates: &[bool]) -> usize {
+        self.lookup_bounded(key, candidates, |_| true)
+    }
+
+    /// Like `lookup`, but also skips past candidates that are at their load bound (`has_room`
+    /// returns false), so that a key spills over to the next upstream clockwise with room for it
+    /// (the "consistent hashing with bounded loads" scheme). If no candidate has room, the key goes
+    /// where `lookup` would send it.
+    pub fn lookup_bounded(
+        &self,
+        key: &[u8],
+        candidates: &[bool],
+        has_room: impl Fn(usize) -> bool,
+    ) -> usize {
+        let hash = hash_bytes(key);
+        let start = self.points.partition_point(|(point, _)| *point < hash);
+        let clockwise = || {
+            (0..self.points.len())
+                .map(move |offset| self.points[(start + offset) % self.points.len()].1)
+        };
+        clockwise()
+            .find(|&idx| candidates[idx] && has_room(idx))
+            .or_else(|| clockwise().find(|&idx| candidates[idx]))
+            .unwrap_or(self.points[start % self.points.len()].1)
+    }
+}
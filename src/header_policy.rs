@@ -0,0 +1,86 @@
+/// A response header name and value, parsed from `NAME:VALUE`. VALUE may be `env:VAR` to take it
+/// from an environment variable at startup (e.g. `X-Served-By:env:HOSTNAME`).
+#[derive(Clone, Debug)]
+pub struct HeaderSpec {
+    name: http::HeaderName,
+    value: String,
+}
+
+impl std::str::FromStr for HeaderSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderSpec, String> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME:VALUE, got {:?}", s))?;
+        let name = http::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", name))?;
+        Ok(HeaderSpec {
+            name,
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+impl HeaderSpec {
+    pub fn resolve(&self) -> Result<(http::HeaderName, http::HeaderValue), String> {
+        let value = match self.value.strip_prefix("env:") {
+            Some(var) => std::env::var(var)
+                .map_err(|_| format!("environment variable {} is not set", var))?,
+            None => self.value.clone(),
+        };
+        let value = http::HeaderValue::from_str(&value)
+            .map_err(|_| format!("{:?} is not a valid value for {}", value, self.name))?;
+        Ok((self.name.clone(), value))
+    }
+}
+
+/// Platform-wide rules applied to every proxied response, after everything else has had its say:
+/// headers to strip (e.g. X-Powered-By), headers to always set, replacing whatever the upstream
+/// sent, and headers to add only if the upstream didn't send them.
+pub struct ResponseHeaderPolicy {
+    remove: Vec<http::HeaderName>,
+    set: Vec<(http::HeaderName, http::HeaderValue)>,
+    defaults: Vec<(http::HeaderName, http::HeaderValue)>,
+}
+
+impl ResponseHeaderPolicy {
+    /// Resolves every value. Fails if an environment variable is unset or a value isn't valid in
+    /// a header.
+    pub fn new(
+        remove: Vec<http::HeaderName>,
+        set: &[HeaderSpec],
+        defaults: &[HeaderSpec],
+    ) -> Result<ResponseHeaderPolicy, String> {
+        Ok(ResponseHeaderPolicy {
+            remove,
+            set: set
+                .iter()
+                .map(HeaderSpec::resolve)
+                .collect::<Result<_, _>>()?,
+            defaults: defaults
+                .iter()
+                .map(HeaderSpec::resolve)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.set.is_empty() && self.defaults.is_empty()
+    }
+
+    pub fn apply(&self, response: &mut http::Response<Vec<u8>>) {
+        let headers = response.headers_mut();
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name, value.clone());
+        }
+        for (name, value) in &self.defaults {
+            if !headers.contains_key(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use crate::stream::UpstreamStream;
+use crate::upstream::Protocol;
+use crate::{events, request, response, ProxyState};
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Whether each upstream is considered up, according to the active health checks. Upstreams start
+/// out healthy, so that traffic flows before the first round of checks has finished. An upstream
+/// only changes state after `unhealthy_threshold` consecutive failed checks (or
+/// `healthy_threshold` consecutive passing ones), so that a single blip doesn't eject it.
+pub struct UpstreamHealth {
+    dead: Vec<AtomicBool>,
+    // Number of consecutive checks, by upstream index, whose result disagreed with its state
+    streaks: Vec<AtomicUsize>,
+    healthy_threshold: usize,
+    unhealthy_threshold: usize,
+    // Whether active checks are running, i.e. whether anything will bring a dead upstream back
+    active_checks: bool,
+    events: events::EventHooks,
+}
+
+impl UpstreamHealth {
+    pub fn new(
+        num_upstreams: usize,
+        active_checks: bool,
+        healthy_threshold: usize,
+        unhealthy_threshold: usize,
+        events: events::EventHooks,
+    ) -> UpstreamHealth {
+        UpstreamHealth {
+            dead: (0..num_upstreams).map(|_| AtomicBool::new(false)).collect(),
+            streaks: (0..num_upstreams).map(|_| AtomicUsize::new(0)).collect(),
+            healthy_threshold: healthy_threshold.max(1),
+            unhealthy_threshold: unhealthy_threshold.max(1),
+            active_checks,
+            events,
+        }
+    }
+
+    pub fn is_dead(&self, idx: usize) -> bool {
+        self.dead[idx].load(Ordering::Relaxed)
+    }
+
+    /// Restores an upstream's state from before a restart. Only dead upstreams are restored, and
+    /// only if active checks will bring them back.
+    pub fn restore_dead(&self, idx: usize) {
+        if self.active_checks {
+            self.set_dead(idx, true);
+        }
+    }
+
+    /// Takes an upstream out of rotation after live traffic failed to reach it, without waiting
+    /// for the next health check; the next passing check puts it back. Without active checks
+    /// nothing would ever restore it, so this is a no-op and the failed upstream cooldown applies
+    /// instead.
+    pub fn report_failure(&self, idx: usize, address: &str, err: &dyn std::fmt::Display) {
+        if self.active_checks && self.set_dead(idx, true).is_some() {
+            log::warn!(
+                "Upstream {} is out of rotation until it passes a health check: {}",
+                address,
+                err
+            );
+            self.events.upstream_changed(address, false, err);
+        }
+    }
+
+    /// Returns the previous state if it changed.
+    fn set_dead(&self, idx: usize, dead: bool) -> Option<bool> {
+        self.streaks[idx].store(0, Ordering::Relaxed);
+        let was_dead = self.dead[idx].swap(dead, Ordering::Relaxed);
+        (was_dead != dead).then_some(was_dead)
+    }
+
+    /// Records the result of a health check. Returns the previous state if this result tipped the
+    /// upstream over its threshold and changed its state.
+    fn record_check(&self, idx: usize, passed: bool) -> Option<bool> {
+        if passed != self.is_dead(idx) {
+            // The check agrees with the current state, which breaks any streak
+            self.streaks[idx].store(0, Ordering::Relaxed);
+            return None;
+        }
+        let threshold = if passed {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        };
+        if self.streaks[idx].fetch_add(1, Ordering::Relaxed) + 1 < threshold {
+            return None;
+        }
+        self.set_dead(idx, !passed)
+    }
+}
+
+/// Fails open once too much of a pool is out of rotation. If health checks and outlier detection
+/// would eject more than `max_ejected_percent` of the pool's upstreams, something is more likely
+/// wrong with the checks (or the whole pool is struggling) than with that many upstreams, and
+/// routing to every upstream in the pool beats answering every request with a 502. Each pool has
+/// its own, so one pool going down doesn't make the others fail open.
+pub struct PanicThreshold {
+    // Name of the pool, for logs
+    pool: String,
+    max_ejected_percent: f64,
+    // Whether we're currently failing open, so that we only log when that changes
+    active: AtomicBool,
+}
+
+impl PanicThreshold {
+    pub fn new(pool: &str, max_ejected_percent: f64) -> PanicThreshold {
+        PanicThreshold {
+            pool: pool.to_string(),
+            max_ejected_percent,
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns true if ejections should be ignored because `ejected` of `total` upstreams are out.
+    pub fn check(&self, ejected: usize, total: usize) -> bool {
+        let ejected_percent = ejected as f64 * 100.0 / total.max(1) as f64;
+        let panic = ejected > 0 && ejected_percent > self.max_ejected_percent;
+        if self.active.swap(panic, Ordering::Relaxed) != panic {
+            if panic {
+                log::error!(
+                    "PANIC: {} of {} upstreams ({:.0}%) in pool {} are out of rotation, more than \
+                    the {}% threshold. Ignoring health checks and routing to every upstream in it",
+                    ejected,
+                    total,
+                    ejected_percent,
+                    self.pool,
+                    self.max_ejected_percent
+                );
+            } else {
+                log::warn!(
+                    "{} of {} upstreams in pool {} are out of rotation; health checks apply again",
+                    ejected,
+                    total,
+                    self.pool
+                );
+            }
+        }
+        panic
+    }
+}
+
+/// The status codes a health check response may have to count as passing, parsed from a
+/// comma-separated list of codes and ranges, e.g. `200,204` or `200-299,301`.
+#[derive(Clone, Debug)]
+pub struct StatusSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl std::str::FromStr for StatusSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatusSet, String> {
+        let parse_code = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(|| format!("invalid status code {:?}", code))
+        };
+        let ranges = s
+            .split(',')
+            .map(|part| match part.split_once('-') {
+                Some((low, high)) => Ok((parse_code(low)?, parse_code(high)?)),
+                None => parse_code(part).map(|code| (code, code)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(StatusSet { ranges })
+    }
+}
+
+impl StatusSet {
+    fn contains(&self, status: http::StatusCode) -> bool {
+        let status = status.as_u16();
+        self.ranges
+            .iter()
+            .any(|(low, high)| (*low..=*high).contains(&status))
+    }
+}
+
+/// What an active health check sends, and what the response must look like to pass.
+pub struct CheckConfig {
+    pub method: http::Method,
+    pub path: String,
+    // Host header to send (the upstream's address if not set)
+    pub host: Option<String>,
+    // Extra headers to send, e.g. an auth token the health endpoint requires
+    pub headers: Vec<(http::HeaderName, http::HeaderValue)>,
+    // How long the upstream has to accept the connection and answer
+    pub timeout: Duration,
+    // Status codes that count as passing (any 2xx if not set)
+    pub expect_status: Option<StatusSet>,
+    // A pattern the response body must match, e.g. to catch an error page served with a 200
+    pub expect_body: Option<regex::Regex>,
+}
+
+impl CheckConfig {
+    fn evaluate(&self, response: &http::Response<Vec<u8>>) -> Result<(), String> {
+        let status_ok = match &self.expect_status {
+            Some(expected) => expected.contains(response.status()),
+            None => response.status().is_success(),
+        };
+        if !status_ok {
+            return Err(format!("health check returned {}", response.status()));
+        }
+        if let Some(pattern) = &self.expect_body {
+            if !pattern.is_match(&String::from_utf8_lossy(response.body())) {
+                return Err(format!(
+                    "health check response body doesn't match {:?}",
+                    pattern.as_str()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends the configured health check request to the upstream (or to its own health endpoint, if it
+/// has one) and checks the response against the
+/// configured expectations. An upstream that speaks HTTP/2 is checked over its shared connection,
+/// though its own health endpoint, if any, is expected to speak HTTP/1.1.
+async fn check(state: &ProxyState, idx: usize, config: &CheckConfig) -> Result<(), String> {
+    let upstream = &state.upstreams[idx];
+    let address = upstream
+        .health_address
+        .as_ref()
+        .unwrap_or(&upstream.address);
+    let dial = crate::dial_upstream(state, address);
+    let mut stream = match upstream.protocol {
+        Protocol::H2c if upstream.health_address.is_none() => UpstreamStream::Bridged(
+            state
+                .h2_connections
+                .open(idx, address, async {
+                    dial.await.map_err(std::io::Error::other)
+                })
+                .await
+                .map_err(|err| err.to_string())?,
+        ),
+        _ => UpstreamStream::Tcp(dial.await?),
+    };
+    let mut health_request = http::Request::builder()
+        .method(config.method.clone())
+        .uri(upstream.health_path.as_ref().unwrap_or(&config.path))
+        .version(http::Version::HTTP_11)
+        .header("host", config.host.as_deref().unwrap_or(address))
+        .header("user-agent", "loadbalancer-health-check")
+        .header("connection", "close")
+        .body(Vec::new())
+        .map_err(|err| format!("invalid health check request: {}", err))?;
+    for (name, value) in &config.headers {
+        health_request
+            .headers_mut()
+            .insert(name.clone(), value.clone());
+    }
+    request::write_to_stream(&health_request, &mut stream)
+        .await
+        .map_err(|err| format!("could not send health check: {}", err))?;
+    let health_response =
+        response::read_from_stream(&mut stream, &config.method, response::DEFAULT_MAX_BODY_SIZE)
+            .await
+            .map_err(|err| format!("invalid health check response: {:?}", err))?;
+    config.evaluate(&health_response)
+}
+
+/// Checks every upstream on `interval`, forever. Upstreams that keep failing are taken out of
+/// rotation until they keep passing again, at which point they go through slow start like any
+/// other recovered upstream.
+///
+/// Each upstream is checked by its own task, so a hung upstream only delays its own checks, and
+/// each wait is randomly stretched or shrunk by up to `jitter` (a fraction of the interval) so
+/// that the upstreams aren't all probed in lockstep.
+///
+/// While an upstream is dead, each failed check doubles the wait before the next one, up to
+/// `max_backoff` (no backoff if that isn't longer than `interval`), so that a dead upstream, whose
+/// checks may each take the full timeout, isn't probed as hard as a live one. The wait goes back
+/// to `interval` as soon as a check passes.
+pub async fn run(
+    state: Arc<ProxyState>,
+    interval: Duration,
+    jitter: f64,
+    max_backoff: Duration,
+    config: CheckConfig,
+) {
+    let config = Arc::new(config);
+    let mut checkers = tokio::task::JoinSet::new();
+    for idx in 0..state.upstreams.len() {
+        checkers.spawn(check_forever(
+            state.clone(),
+            idx,
+            interval,
+            jitter,
+            max_backoff,
+            config.clone(),
+        ));
+    }
+    while checkers.join_next().await.is_some() {}
+}
+
+async fn check_forever(
+    state: Arc<ProxyState>,
+    idx: usize,
+    interval: Duration,
+    jitter: f64,
+    max_backoff: Duration,
+    config: Arc<CheckConfig>,
+) {
+    let address = &state.upstreams[idx].address;
+    let jitter = jitter.clamp(0.0, 1.0);
+    let mut wait = interval;
+    loop {
+        // The first check also waits, so that upstreams get a moment to come up
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+        tokio::time::sleep(wait.mul_f64(factor)).await;
+        let result = match tokio::time::timeout(config.timeout, check(&state, idx, &config)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "no response within {}ms",
+                config.timeout.as_millis()
+            )),
+        };
+        match (state.health.record_check(idx, result.is_ok()), result) {
+            (Some(false), Err(err)) => {
+                log::warn!("Upstream {} failed its health check: {}", address, err);
+                state.events.upstream_changed(address, false, &err);
+            }
+            (Some(true), Ok(())) => {
+                log::info!("Upstream {} passed its health check again", address);
+                state
+                    .events
+                    .upstream_changed(address, true, &"passed its health check");
+                state.recent_failures.record_success(idx);
+                state.slow_start.mark_recovered(idx);
+            }
+            _ => {}
+        }
+        wait = if state.health.is_dead(idx) && max_backoff > interval {
+            (wait * 2).min(max_backoff)
+        } else {
+            interval
+        };
+    }
+}
@@ -0,0 +1,698 @@
+use crate::connections::ConnectionHandle;
+use crate::response::{self, ChunkParser, Streamed};
+use crate::stream::ClientStream;
+use crate::upstream::Protocol;
+use crate::{cidr, request, ProxyState};
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::conn::SendRequest;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+/// The bytes every HTTP/2 connection opens with.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How much of a stream's request or response the pipe it is bridged over holds.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Limits for reading a request that is being bridged to an upstream. It has already been checked
+/// against the client-facing limits, so these only guard against a runaway head.
+const BRIDGED_HEADER_LIMITS: request::HeaderLimits = request::HeaderLimits {
+    max_bytes: 1024 * 1024,
+    max_line: 1024 * 1024,
+    max_count: 10000,
+};
+
+/// Headers that only mean something on an HTTP/1.1 connection, and that HTTP/2 forbids.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Returns whether the client has opened its connection with the HTTP/2 preface, i.e. speaks
+/// HTTP/2 with prior knowledge. If only part of the preface has arrived, waits briefly for the
+/// rest. Nothing is consumed, so the connection can still be read as HTTP/1.1 if not.
+pub async fn has_preface(client_conn: &ClientStream, state: &ProxyState) -> bool {
+    let ClientStream::Tcp(stream) = client_conn else {
+        return false;
+    };
+    let mut buffer = [0_u8; PREFACE.len()];
+    for _ in 0..100 {
+        let peeked = tokio::select! {
+            biased;
+            peeked = stream.peek(&mut buffer) => match peeked {
+                Ok(peeked) => peeked,
+                Err(_) => return false,
+            },
+            // Leave it to the HTTP/1.1 path to let an idle client go
+            _ = state.shutdown.wait() => return false,
+        };
+        if peeked == 0 || buffer[..peeked] != PREFACE[..peeked] {
+            return false;
+        }
+        if peeked == PREFACE.len() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
+
+/// Serves an HTTP/2 connection until the client closes it. Each stream is bridged to
+/// proxy_connection as though it were an HTTP/1.1 connection carrying just that one request, so
+/// it is limited, routed, retried and so on like any other request. Once we start shutting down,
+/// the client is sent a GOAWAY and the streams already open are finished.
+pub async fn serve(
+    client_conn: ClientStream,
+    state: Arc<ProxyState>,
+    conn: Arc<ConnectionHandle>,
+    client_addr: SocketAddr,
+) {
+    log::info!("HTTP/2 connection received from {}", client_addr.ip());
+    let service = {
+        let state = state.clone();
+        hyper::service::service_fn(move |request| {
+            proxy_stream(request, state.clone(), conn.clone(), client_addr)
+        })
+    };
+    let connection = hyper::server::conn::Http::new()
+        .http2_only(true)
+        .serve_connection(client_conn, service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = &mut connection => result,
+        _ = state.shutdown.wait() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        log::debug!("HTTP/2 connection from {} failed: {}", client_addr, err);
+    }
+}
+
+async fn proxy_stream(
+    request: hyper::Request<hyper::Body>,
+    state: Arc<ProxyState>,
+    conn: Arc<ConnectionHandle>,
+    client_addr: SocketAddr,
+) -> Result<hyper::Response<hyper::Body>, Infallible> {
+    if state.grpc && is_grpc(&request) {
+        return Ok(proxy_grpc(request, state, conn, client_addr).await);
+    }
+    let (pipe, bridged) = tokio::io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        let mut upstream = None;
+        let bridged = ClientStream::Bridged {
+            pipe: bridged,
+            peer: client_addr,
+        };
+        crate::proxy_connection(bridged, &state, &conn, &mut upstream).await;
+        if let Some(upstream) = upstream {
+            crate::release_upstream(&state, upstream);
+        }
+    });
+    let (mut reader, writer) = tokio::io::split(pipe);
+    let method = request.method().clone();
+    tokio::spawn(write_request(writer, request));
+
+    let head = match response::read_headers(&mut reader).await {
+        Ok(head) => head,
+        Err(err) => {
+            log::warn!(
+                "No response to an HTTP/2 request from {}: {:?}",
+                client_addr,
+                err
+            );
+            let mut response = hyper::Response::new(hyper::Body::empty());
+            *response.status_mut() = http::StatusCode::BAD_GATEWAY;
+            return Ok(response);
+        }
+    };
+    // The bridged connection closes after the response, so only a chunked body needs decoding;
+    // any other ends where the pipe does
+    let mut parser =
+        (response::streamed(&method, &head, 0) == Some(Streamed::Chunked)).then(ChunkParser::new);
+    let (parts, prefix) = head.into_parts();
+    let (mut sender, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        let mut pending = prefix;
+        let mut buffer = vec![0_u8; 16 * 1024];
+        loop {
+            let data = match parser.as_mut() {
+                Some(parser) => {
+                    let mut data = Vec::new();
+                    if parser.feed(&pending, Some(&mut data)).is_err() {
+                        sender.abort();
+                        return;
+                    }
+                    data
+                }
+                None => std::mem::take(&mut pending),
+            };
+            // An error means the client has reset the stream
+            if !data.is_empty() && sender.send_data(Bytes::from(data)).await.is_err() {
+                return;
+            }
+            if let Some(parser) = parser.as_mut().filter(|parser| parser.is_done()) {
+                let trailers = parser.take_trailers();
+                if !trailers.is_empty() {
+                    let _ = sender.send_trailers(trailers).await;
+                }
+                return;
+            }
+            match reader.read(&mut buffer).await {
+                // A chunked body that stops short of its last chunk is truncated
+                Ok(0) if parser.is_some() => return sender.abort(),
+                Ok(0) => return,
+                Ok(n) => pending = buffer[..n].to_vec(),
+                Err(_) => return sender.abort(),
+            }
+        }
+    });
+
+    let mut response = hyper::Response::new(body);
+    *response.status_mut() = parts.status;
+    for (name, value) in &parts.headers {
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    Ok(response)
+}
+
+/// Writes an HTTP/2 request to the pipe as HTTP/1.1, then closes it, so that proxy_connection sees
+/// the request followed by the end of the connection. A body without a Content-Length is sent
+/// chunked.
+async fn write_request(
+    mut pipe: WriteHalf<DuplexStream>,
+    request: hyper::Request<hyper::Body>,
+) -> std::io::Result<()> {
+    let (mut parts, mut body) = request.into_parts();
+    if !parts.headers.contains_key(http::header::HOST) {
+        if let Some(authority) = parts
+            .uri
+            .authority()
+            .and_then(|authority| http::HeaderValue::from_str(authority.as_str()).ok())
+        {
+            parts.headers.insert(http::header::HOST, authority);
+        }
+    }
+    let target = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    let chunked =
+        !body.is_end_stream() && !parts.headers.contains_key(http::header::CONTENT_LENGTH);
+    let head = format_head(
+        format!("{} {} HTTP/1.1", parts.method, target),
+        &parts.headers,
+        chunked,
+    );
+    let written = async {
+        pipe.write_all(&head).await?;
+        write_body(&mut pipe, &mut body, chunked).await
+    }
+    .await;
+    // Even a request cut short must end the bridged connection, or it would wait for the rest
+    let _ = pipe.shutdown().await;
+    written
+}
+
+/// Serializes an HTTP/1.1 head from a start line and HTTP/2 headers, saying the body is chunked if
+/// `chunked`.
+fn format_head(start_line: String, headers: &http::HeaderMap, chunked: bool) -> Vec<u8> {
+    let mut head = start_line.into_bytes();
+    head.extend_from_slice(b"\r\n");
+    for (name, value) in headers {
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+    }
+    if chunked {
+        head.extend_from_slice(b"transfer-encoding: chunked\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Copies an HTTP/2 body to an HTTP/1.1 connection as it arrives, in chunks if `chunked`. Trailers
+/// can only follow a chunked body, so are dropped otherwise.
+async fn write_body(
+    pipe: &mut (impl AsyncWrite + Unpin),
+    body: &mut hyper::Body,
+    chunked: bool,
+) -> std::io::Result<()> {
+    while let Some(data) = body.data().await {
+        let data = data.map_err(std::io::Error::other)?;
+        if !chunked {
+            pipe.write_all(&data).await?;
+        } else if !data.is_empty() {
+            pipe.write_all(format!("{:x}\r\n", data.len()).as_bytes())
+                .await?;
+            pipe.write_all(&data).await?;
+            pipe.write_all(b"\r\n").await?;
+        }
+    }
+    if chunked {
+        pipe.write_all(b"0\r\n").await?;
+        if let Some(trailers) = body.trailers().await.map_err(std::io::Error::other)? {
+            pipe.write_all(&request::format_trailers(&trailers)).await?;
+        }
+        pipe.write_all(b"\r\n").await?;
+    }
+    Ok(())
+}
+
+/// An upstream's shared HTTP/2 connection, if one is open. The lock is held while a connection is
+/// opened, so that only one is, and while a request is handed to it.
+type SharedConnection = Arc<tokio::sync::Mutex<Option<SendRequest<hyper::Body>>>>;
+
+/// The HTTP/2 connection shared by all requests to each upstream that speaks HTTP/2, opened when
+/// first needed and reopened once it closes.
+pub struct UpstreamConnections {
+    // By upstream index
+    connections: Vec<SharedConnection>,
+}
+
+impl UpstreamConnections {
+    pub fn new(num_upstreams: usize) -> UpstreamConnections {
+        UpstreamConnections {
+            connections: (0..num_upstreams)
+                .map(|_| SharedConnection::default())
+                .collect(),
+        }
+    }
+
+    /// Returns a pipe to exchange HTTP/1.1 requests and responses with the upstream over, each of
+    /// which is bridged to a stream on the upstream's shared connection. If that connection isn't
+    /// open, it is opened over the TCP connection `dial` makes.
+    pub async fn open(
+        &self,
+        idx: usize,
+        address: &str,
+        dial: impl Future<Output = std::io::Result<TcpStream>>,
+    ) -> std::io::Result<DuplexStream> {
+        let shared = self.connect(idx, address, dial).await?;
+        let (pipe, bridged) = tokio::io::duplex(PIPE_SIZE);
+        tokio::spawn(bridge_upstream(bridged, shared, address.to_string()));
+        Ok(pipe)
+    }
+
+    /// Sends a request to the upstream as a stream on its shared connection, opening that over
+    /// the TCP connection `dial` makes if need be, and returns the response as it arrives.
+    pub async fn send(
+        &self,
+        idx: usize,
+        address: &str,
+        dial: impl Future<Output = std::io::Result<TcpStream>>,
+        request: hyper::Request<hyper::Body>,
+    ) -> std::io::Result<hyper::Response<hyper::Body>> {
+        let shared = self.connect(idx, address, dial).await?;
+        let response = send_request(&shared, request).await?;
+        response.await.map_err(std::io::Error::other)
+    }
+
+    /// Returns the upstream's shared connection, first opening it if it isn't open.
+    async fn connect(
+        &self,
+        idx: usize,
+        address: &str,
+        dial: impl Future<Output = std::io::Result<TcpStream>>,
+    ) -> std::io::Result<SharedConnection> {
+        let shared = &self.connections[idx];
+        {
+            let mut sender = shared.lock().await;
+            let is_open = match sender.as_mut() {
+                Some(sender) => ready(sender).await.is_ok(),
+                None => false,
+            };
+            if !is_open {
+                let stream = dial.await?;
+                let (new_sender, connection) = hyper::client::conn::Builder::new()
+                    .http2_only(true)
+                    .handshake(stream)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                log::debug!("Opened an HTTP/2 connection to upstream {}", address);
+                let address = address.to_string();
+                tokio::spawn(async move {
+                    if let Err(err) = connection.await {
+                        log::warn!("HTTP/2 connection to upstream {} failed: {}", address, err);
+                    }
+                });
+                *sender = Some(new_sender);
+            }
+        }
+        Ok(shared.clone())
+    }
+}
+
+/// Hands a request to the shared connection, once it can take one. The response future resolves
+/// independently, so that the connection isn't held up waiting for it.
+async fn send_request(
+    shared: &SharedConnection,
+    request: hyper::Request<hyper::Body>,
+) -> std::io::Result<hyper::client::conn::ResponseFuture> {
+    let mut sender = shared.lock().await;
+    let sender = sender
+        .as_mut()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected))?;
+    ready(sender).await.map_err(std::io::Error::other)?;
+    Ok(sender.send_request(request))
+}
+
+/// Waits until the connection can take another request, failing if it has closed.
+async fn ready(sender: &mut SendRequest<hyper::Body>) -> hyper::Result<()> {
+    std::future::poll_fn(|cx| sender.poll_ready(cx)).await
+}
+
+/// Sends each HTTP/1.1 request read from the pipe to the upstream as a stream on its shared
+/// connection, and writes the response back as HTTP/1.1, until the pipe closes. Should the shared
+/// connection fail, the pipe is closed, which the exchange on the other end sees as the upstream
+/// hanging up.
+async fn bridge_upstream(pipe: DuplexStream, shared: SharedConnection, address: String) {
+    let (mut reader, mut writer) = tokio::io::split(pipe);
+    loop {
+        let request = match request::read_from_stream(
+            &mut reader,
+            request::DuplicateHeaderPolicy::Merge,
+            BRIDGED_HEADER_LIMITS,
+            request::ReadTimeouts::default(),
+            usize::MAX,
+            PIPE_SIZE,
+        )
+        .await
+        {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) => return,
+            Err(err) => {
+                log::warn!("Bad request bridged to upstream {}: {:?}", address, err);
+                return;
+            }
+        };
+        let unread_body = request::unread_body_len(&request);
+        let (mut parts, prefix) = request.into_parts();
+        let trailers = parts.extensions.remove::<request::Trailers>();
+        let authority = parts
+            .headers
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or(&address);
+        let target = parts
+            .uri
+            .path_and_query()
+            .map_or("/", |target| target.as_str());
+        let mut upstream_request = hyper::Request::new(hyper::Body::empty());
+        *upstream_request.method_mut() = parts.method.clone();
+        match format!("http://{}{}", authority, target).parse() {
+            Ok(uri) => *upstream_request.uri_mut() = uri,
+            Err(err) => {
+                log::warn!("Can't bridge request for {}{}: {}", authority, target, err);
+                return;
+            }
+        }
+        for (name, value) in &parts.headers {
+            // HTTP/2 carries the Host header as the URI's authority
+            if !CONNECTION_HEADERS.contains(&name.as_str()) && name != http::header::HOST {
+                upstream_request.headers_mut().append(name, value.clone());
+            }
+        }
+        // A large body is still on the pipe, and is passed on as it arrives
+        let mut body_sender = None;
+        *upstream_request.body_mut() = if unread_body == 0 && trailers.is_none() {
+            hyper::Body::from(prefix)
+        } else {
+            let (sender, body) = hyper::Body::channel();
+            body_sender = Some((sender, prefix));
+            body
+        };
+
+        let response = match send_request(&shared, upstream_request).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("HTTP/2 connection to upstream {} closed: {}", address, err);
+                return;
+            }
+        };
+        let pump_body = async {
+            let Some((mut sender, prefix)) = body_sender else {
+                return Ok(());
+            };
+            sender
+                .send_data(Bytes::from(prefix))
+                .await
+                .map_err(std::io::Error::other)?;
+            let mut remaining = unread_body;
+            let mut buffer = vec![0_u8; 16 * 1024];
+            while remaining > 0 {
+                let want = remaining.min(buffer.len());
+                let bytes_read = reader.read(&mut buffer[..want]).await?;
+                if bytes_read == 0 {
+                    sender.abort();
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                sender
+                    .send_data(Bytes::copy_from_slice(&buffer[..bytes_read]))
+                    .await
+                    .map_err(std::io::Error::other)?;
+                remaining -= bytes_read;
+            }
+            if let Some(request::Trailers(trailers)) = trailers {
+                sender
+                    .send_trailers(trailers)
+                    .await
+                    .map_err(std::io::Error::other)?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        let (response, pumped) = tokio::join!(response, pump_body);
+        let response = match (response, pumped) {
+            (Ok(response), Ok(())) => response,
+            (Err(err), _) => {
+                log::warn!("HTTP/2 request to upstream {} failed: {}", address, err);
+                return;
+            }
+            (_, Err(err)) => {
+                log::debug!(
+                    "Request body bridged to upstream {} cut short: {}",
+                    address,
+                    err
+                );
+                return;
+            }
+        };
+
+        let (response_parts, mut body) = response.into_parts();
+        let status = response_parts.status;
+        let has_length = response_parts
+            .headers
+            .contains_key(http::header::CONTENT_LENGTH);
+        let chunked = !body.is_end_stream() && !has_length;
+        let mut headers = response_parts.headers;
+        // Without a length, an empty body would be read until the connection closes
+        if body.is_end_stream()
+            && !has_length
+            && parts.method != http::Method::HEAD
+            && !status.is_informational()
+            && status != http::StatusCode::NO_CONTENT
+            && status != http::StatusCode::NOT_MODIFIED
+        {
+            headers.insert(http::header::CONTENT_LENGTH, 0.into());
+        }
+        let head = format_head(
+            format!(
+                "HTTP/1.1 {} {}",
+                status.as_str(),
+                status.canonical_reason().unwrap_or("")
+            ),
+            &headers,
+            chunked,
+        );
+        let written = async {
+            writer.write_all(&head).await?;
+            write_body(&mut writer, &mut body, chunked).await
+        }
+        .await;
+        if let Err(err) = written {
+            log::debug!(
+                "Could not bridge response from upstream {}: {}",
+                address,
+                err
+            );
+            return;
+        }
+    }
+}
+
+/// Whether a request is a gRPC call.
+fn is_grpc(request: &hyper::Request<hyper::Body>) -> bool {
+    request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type == "application/grpc"
+                || content_type.starts_with("application/grpc+")
+                || content_type.starts_with("application/grpc;")
+        })
+}
+
+/// Proxies a gRPC call straight to an upstream that speaks HTTP/2, as a stream on its shared
+/// connection, so that the call can stream in both directions and its trailers (which carry the
+/// call's status) reach the client. Each call is balanced on its own: it goes where the route's
+/// strategy picks, if that upstream speaks HTTP/2, or to another available one that does. Calls are
+/// admitted, counted against their upstream and recorded like any other request, but bypass the
+/// HTTP/1.1 path, and with it per-request features such as retries.
+async fn proxy_grpc(
+    request: hyper::Request<hyper::Body>,
+    state: Arc<ProxyState>,
+    conn: Arc<ConnectionHandle>,
+    client_addr: SocketAddr,
+) -> hyper::Response<hyper::Body> {
+    // The call holds its place in the limits until its response body has been forwarded, which
+    // happens after we return the response head, so it runs on a task of its own
+    let (respond, response) = oneshot::channel();
+    tokio::spawn(async move { forward_grpc(request, &state, &conn, client_addr, respond).await });
+    response
+        .await
+        .unwrap_or_else(|_| grpc_unavailable("call failed"))
+}
+
+/// Forwards a gRPC call, sending the response head to `respond` and then relaying the response
+/// body and trailers.
+async fn forward_grpc(
+    request: hyper::Request<hyper::Body>,
+    state: &ProxyState,
+    conn: &ConnectionHandle,
+    client_addr: SocketAddr,
+    respond: oneshot::Sender<hyper::Response<hyper::Body>>,
+) {
+    let (parts, body) = request.into_parts();
+    let mut head = http::Request::from_parts(parts, Vec::new());
+    if !crate::apply_penalty(state, client_addr).await {
+        let _ = respond.send(grpc_unavailable("client is banned"));
+        return;
+    }
+    let admission = match crate::admit(state, &head, client_addr).await {
+        Ok(admission) => admission,
+        Err(response) => {
+            let _ = respond.send(response.map(hyper::Body::from));
+            return;
+        }
+    };
+    let trusted = cidr::any_contains(&state.trusted_proxies, client_addr.ip());
+    request::set_forwarding_headers(&mut head, client_addr.ip(), trusted);
+    let speaks_http2 = |idx: usize| state.upstreams[idx].protocol == Protocol::H2c;
+    let balancer = state.router.route(&mut head);
+    if let Some(pool) = state.router.routed_pool(&head) {
+        state.rewriter.apply(&mut head, pool);
+    }
+    let picked = balancer.pick(&head, client_addr.ip(), state);
+    let upstream_idx = if speaks_http2(picked) {
+        Some(picked)
+    } else {
+        let http1: Vec<usize> = (0..state.upstreams.len())
+            .filter(|idx| !speaks_http2(*idx))
+            .collect();
+        balancer.untried(state, &http1)
+    };
+    let Some(upstream_idx) = upstream_idx else {
+        log::warn!("No HTTP/2 upstream for gRPC call {}", head.uri().path());
+        let _ = respond.send(grpc_unavailable("no upstream available"));
+        return;
+    };
+    conn.record_request();
+    let Ok(_active) =
+        crate::reserve_connection(state, upstream_idx, admission.priority, None).await
+    else {
+        let _ = respond.send(grpc_unavailable("upstream at its connection limit"));
+        return;
+    };
+    let address = &state.upstreams[upstream_idx].address;
+    log::debug!("Sending gRPC call {} to {}", head.uri().path(), address);
+    state.breakers.record_attempt(upstream_idx, address);
+    let _load = state.request_load[upstream_idx].start(state.request_load_decay);
+    let dial = async {
+        let deadline = crate::deadline_after(Instant::now(), state.try_timeout);
+        match crate::before(deadline, crate::dial_upstream(state, address)).await {
+            Some(dialed) => dialed.map_err(std::io::Error::other),
+            None => Err(crate::timed_out()),
+        }
+    };
+    let (parts, _) = head.into_parts();
+    let upstream_request = hyper::Request::from_parts(parts, body);
+    let response = match state
+        .h2_connections
+        .send(upstream_idx, address, dial, upstream_request)
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            log::warn!("gRPC call to upstream {} failed: {}", address, err);
+            crate::record_connect_failure(state, upstream_idx, &err);
+            let _ = respond.send(grpc_unavailable("upstream unavailable"));
+            return;
+        }
+    };
+    crate::record_connect_success(state, upstream_idx);
+    // A failed call is usually answered 200 with its status in the trailers, so this only sees
+    // upstreams that fail at the HTTP level
+    state
+        .outliers
+        .record(upstream_idx, address, response.status());
+    if response.status().is_server_error() {
+        state.breakers.record_failure(upstream_idx, address);
+    } else {
+        state.breakers.record_success(upstream_idx, address);
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let (mut sender, client_body) = hyper::Body::channel();
+    if respond
+        .send(hyper::Response::from_parts(parts, client_body))
+        .is_err()
+    {
+        return;
+    }
+    while let Some(data) = body.data().await {
+        let Ok(data) = data else {
+            return sender.abort();
+        };
+        // An error means the client has reset the stream
+        if sender.send_data(data).await.is_err() {
+            return;
+        }
+    }
+    match body.trailers().await {
+        Ok(Some(trailers)) => {
+            let _ = sender.send_trailers(trailers).await;
+        }
+        Ok(None) => {}
+        Err(_) => sender.abort(),
+    }
+}
+
+/// A trailers-only gRPC response failing the call with status UNAVAILABLE.
+fn grpc_unavailable(message: &'static str) -> hyper::Response<hyper::Body> {
+    let mut response = hyper::Response::new(hyper::Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", http::HeaderValue::from_static("14"));
+    headers.insert("grpc-message", http::HeaderValue::from_static(message));
+    response
+}
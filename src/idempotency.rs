@@ -0,0 +1,196 @@
+use clap::ValueEnum;
+use parking_lot::Mutex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// What to do with a request whose Idempotency-Key has already been seen.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum Mode {
+    /// Ignore Idempotency-Key headers
+    Off,
+    /// Answer duplicates with the response to the original request
+    Replay,
+    /// Answer duplicates with 409 Conflict
+    Reject,
+}
+
+/// A copy of a response that can be sent again. (http::Response can't be cloned, since its
+/// extensions might not be.)
+struct StoredResponse {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+}
+
+enum Entry {
+    // The original request hasn't been answered yet
+    InFlight,
+    Done(StoredResponse),
+}
+
+struct Entries {
+    by_key: HashMap<String, (Instant, Entry)>,
+    // Keys in the order they were first seen, for evicting the oldest
+    order: VecDeque<String>,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if self.by_key.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+/// What to do with a request, as decided by `IdempotencyStore::check`.
+pub enum Check<'a> {
+    /// Forward the request. The reservation should be completed with the upstream's response.
+    Forward(Reservation<'a>),
+    /// The request duplicates one that has already been answered; send this response instead.
+    Replay(http::Response<Vec<u8>>),
+    /// The request duplicates one that is still in flight, or duplicates are being rejected.
+    Duplicate,
+}
+
+/// A bounded record of recently seen Idempotency-Key values on non-idempotent requests, so that
+/// a double-submitted POST (e.g. retried by a client or another proxy) reaches the upstreams only
+/// once.
+pub struct IdempotencyStore {
+    mode: Mode,
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+/// Marks a key as in flight until the response to its request is stored. If it's dropped without
+/// being completed (because the upstream failed), the key is forgotten so the client can retry.
+pub struct Reservation<'a> {
+    store: &'a IdempotencyStore,
+    key: Option<String>,
+}
+
+/// Keys are only meaningful for the client and endpoint they were sent by and to, so they're
+/// scoped by client IP, a hash of the client's credentials (so that clients behind the same proxy
+/// don't see each other's responses), method and path.
+fn scoped_key(request: &http::Request<Vec<u8>>, client_ip: IpAddr) -> Option<String> {
+    if request.method().is_idempotent() {
+        return None;
+    }
+    let key = request.headers().get("idempotency-key")?.to_str().ok()?;
+    let mut credentials = DefaultHasher::new();
+    for name in [http::header::AUTHORIZATION, http::header::COOKIE] {
+        for value in request.headers().get_all(name) {
+            value.as_bytes().hash(&mut credentials);
+        }
+    }
+    Some(format!(
+        "{} {:x} {} {} {}",
+        client_ip,
+        credentials.finish(),
+        request.method(),
+        request.uri().path(),
+        key
+    ))
+}
+
+impl IdempotencyStore {
+    pub fn new(mode: Mode, capacity: usize, ttl: Duration) -> IdempotencyStore {
+        IdempotencyStore {
+            mode,
+            capacity,
+            ttl,
+            entries: Mutex::new(Entries {
+                by_key: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn check(&self, request: &http::Request<Vec<u8>>, client_ip: IpAddr) -> Check<'_> {
+        let key = match scoped_key(request, client_ip) {
+            Some(key) if self.mode != Mode::Off && self.capacity > 0 => key,
+            _ => {
+                return Check::Forward(Reservation {
+                    store: self,
+                    key: None,
+                })
+            }
+        };
+        let mut entries = self.entries.lock();
+        match entries.by_key.get(&key) {
+            Some((seen_at, entry)) if seen_at.elapsed() < self.ttl => {
+                return match (entry, self.mode) {
+                    (Entry::Done(stored), Mode::Replay) => {
+                        let mut response = http::Response::builder()
+                            .status(stored.status)
+                            .version(http::Version::HTTP_11)
+                            .body(stored.body.clone())
+                            .unwrap();
+                        *response.headers_mut() = stored.headers.clone();
+                        response.headers_mut().insert(
+                            "idempotent-replayed",
+                            http::HeaderValue::from_static("true"),
+                        );
+                        Check::Replay(response)
+                    }
+                    _ => Check::Duplicate,
+                };
+            }
+            Some(_) => {
+                // Expired; it gets re-added at the back of the eviction order below
+                entries.remove(&key);
+            }
+            None => {}
+        }
+        while entries.by_key.len() >= self.capacity {
+            match entries.order.pop_front() {
+                Some(oldest) => entries.by_key.remove(&oldest),
+                None => break,
+            };
+        }
+        entries
+            .by_key
+            .insert(key.clone(), (Instant::now(), Entry::InFlight));
+        entries.order.push_back(key.clone());
+        Check::Forward(Reservation {
+            store: self,
+            key: Some(key),
+        })
+    }
+}
+
+impl Reservation<'_> {
+    /// Stores the response for replaying to duplicates. Server errors aren't stored, since a retry
+    /// may well succeed, and neither are cookies the response sets, which belong to the session
+    /// that made the original request.
+    pub fn complete(mut self, response: &http::Response<Vec<u8>>) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        if response.status().is_server_error() {
+            self.store.entries.lock().remove(&key);
+            return;
+        }
+        if let Some((_, entry)) = self.store.entries.lock().by_key.get_mut(&key) {
+            let mut headers = response.headers().clone();
+            headers.remove(http::header::SET_COOKIE);
+            *entry = Entry::Done(StoredResponse {
+                status: response.status(),
+                headers,
+                body: response.body().clone(),
+            });
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.entries.lock().remove(&key);
+        }
+    }
+}
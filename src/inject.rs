@@ -0,0 +1,180 @@
+use crate::routing;
+use parking_lot::Mutex;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often we check whether a header value file has changed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where an injected header's value comes from.
+#[derive(Clone, Debug)]
+enum ValueSource {
+    Literal(String),
+    // Read once at startup
+    Env(String),
+    // Re-read whenever the file changes, so secrets can be rotated without a restart
+    File(PathBuf),
+}
+
+/// A header to add to requests whose path starts with `prefix` before they are forwarded, parsed
+/// from `[PATH_PREFIX=]NAME:VALUE`. VALUE may be `env:VAR` or `file:PATH` to keep secrets such as
+/// internal auth tokens off the command line.
+#[derive(Clone, Debug)]
+pub struct HeaderInjection {
+    prefix: String,
+    name: http::HeaderName,
+    source: ValueSource,
+}
+
+impl std::str::FromStr for HeaderInjection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderInjection, String> {
+        let (prefix, header) = match s.split_once('=') {
+            Some((prefix, header)) if prefix.starts_with('/') => (prefix, header),
+            _ => ("/", s),
+        };
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format!("expected [PATH_PREFIX=]NAME:VALUE, got {:?}", s))?;
+        let name = http::HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", name))?;
+        let value = value.trim_start();
+        let source = if let Some(var) = value.strip_prefix("env:") {
+            ValueSource::Env(var.to_string())
+        } else if let Some(path) = value.strip_prefix("file:") {
+            ValueSource::File(PathBuf::from(path))
+        } else {
+            ValueSource::Literal(value.to_string())
+        };
+        Ok(HeaderInjection {
+            prefix: prefix.to_string(),
+            name,
+            source,
+        })
+    }
+}
+
+/// The current value of a file-backed header, and when we last looked at the file.
+struct FileValue {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked_at: Instant,
+    value: http::HeaderValue,
+}
+
+enum Value {
+    Fixed(http::HeaderValue),
+    File(Mutex<FileValue>),
+}
+
+fn parse_value(value: &str, origin: &str) -> Result<http::HeaderValue, String> {
+    http::HeaderValue::from_str(value.trim_end())
+        .map_err(|_| format!("{} is not a valid header value", origin))
+}
+
+fn read_file(path: &PathBuf) -> Result<(Option<SystemTime>, http::HeaderValue), String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+    Ok((
+        modified,
+        parse_value(&contents, &path.display().to_string())?,
+    ))
+}
+
+impl FileValue {
+    /// Returns the header value, re-reading the file first if it has changed since we last read
+    /// it. If the file can't be read, the previous value is kept.
+    fn current(&mut self) -> http::HeaderValue {
+        if self.checked_at.elapsed() >= RELOAD_CHECK_INTERVAL {
+            self.checked_at = Instant::now();
+            let modified = std::fs::metadata(&self.path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if modified != self.modified {
+                match read_file(&self.path) {
+                    Ok((modified, value)) => {
+                        log::info!("Reloaded header value from {}", self.path.display());
+                        self.modified = modified;
+                        self.value = value;
+                    }
+                    Err(err) => log::warn!("Keeping previous header value: {}", err),
+                }
+            }
+        }
+        self.value.clone()
+    }
+}
+
+/// Adds configured headers to forwarded requests. Injected headers replace any header of the same
+/// name sent by the client, so clients can't spoof values that backends rely on.
+pub struct HeaderInjector {
+    // Rules sorted longest-prefix-first; every matching rule applies
+    rules: Vec<(String, http::HeaderName, Value)>,
+}
+
+impl HeaderInjector {
+    /// Resolves every injection's value. Fails if an environment variable is unset or a file
+    /// can't be read.
+    pub fn new(injections: &[HeaderInjection]) -> Result<HeaderInjector, String> {
+        let mut rules = Vec::new();
+        for injection in injections {
+            let value = match &injection.source {
+                ValueSource::Literal(value) => Value::Fixed(parse_value(
+                    value,
+                    &format!("value for {}", injection.name),
+                )?),
+                ValueSource::Env(var) => {
+                    let value = std::env::var(var)
+                        .map_err(|_| format!("environment variable {} is not set", var))?;
+                    Value::Fixed(parse_value(&value, &format!("${}", var))?)
+                }
+                ValueSource::File(path) => {
+                    let (modified, value) = read_file(path)?;
+                    Value::File(Mutex::new(FileValue {
+                        path: path.clone(),
+                        modified,
+                        checked_at: Instant::now(),
+                        value,
+                    }))
+                }
+            };
+            rules.push((injection.prefix.clone(), injection.name.clone(), value));
+        }
+        rules.sort_by_key(|(prefix, _, _)| std::cmp::Reverse(prefix.len()));
+        Ok(HeaderInjector { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Names of the headers this injector may add.
+    pub fn header_names(&self) -> Vec<http::HeaderName> {
+        let mut names: Vec<http::HeaderName> =
+            self.rules.iter().map(|(_, name, _)| name.clone()).collect();
+        names.dedup();
+        names
+    }
+
+    /// Adds the headers for every rule matching the request's path. Where several rules set the
+    /// same header, the one with the most specific prefix wins.
+    pub fn apply(&self, request: &mut http::Request<Vec<u8>>) {
+        let path = request.uri().path().to_string();
+        let mut applied: Vec<&http::HeaderName> = Vec::new();
+        for (prefix, name, value) in &self.rules {
+            if routing::strip_path_prefix(&path, prefix).is_none() || applied.contains(&name) {
+                continue;
+            }
+            let value = match value {
+                Value::Fixed(value) => value.clone(),
+                Value::File(file) => file.lock().current(),
+            };
+            request.headers_mut().insert(name, value);
+            applied.push(name);
+        }
+    }
+}
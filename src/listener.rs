@@ -0,0 +1,117 @@
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Binds a listening socket with SO_REUSEADDR set, so that we can re-bind right after a crash
+/// even if sockets from the previous process are lingering in TIME_WAIT. If the address is still
+/// in use, keep retrying with exponential backoff until `retry_window` has elapsed.
+///
+/// Binding to port 0 picks a free port; use `TcpListener::local_addr` to find out which.
+///
+/// IPv6 sockets accept IPv4 connections too (so `[::]:1100` is a dual-stack wildcard) unless
+/// `ipv6_only` is set. We set this explicitly rather than relying on the `bindv6only` sysctl, so
+/// the balancer behaves the same on every host.
+///
+/// `backlog` caps the number of connections the kernel queues for us before they're accepted
+/// (the kernel may cap it further, e.g. at `net.core.somaxconn`).
+pub async fn bind(
+    addr: &str,
+    retry_window: Duration,
+    ipv6_only: bool,
+    backlog: u32,
+) -> Result<TcpListener, std::io::Error> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    let deadline = Instant::now() + retry_window;
+    let mut delay = INITIAL_RETRY_DELAY;
+    loop {
+        let mut last_err = None;
+        for addr in &addrs {
+            match bind_once(*addr, ipv6_only, backlog) {
+                Ok(listener) => return Ok(listener),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        let err = last_err.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} did not resolve to any address", addr),
+            )
+        });
+        if err.kind() != std::io::ErrorKind::AddrInUse || Instant::now() + delay > deadline {
+            return Err(err);
+        }
+        log::warn!(
+            "{} is already in use, retrying in {}ms",
+            addr,
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+fn bind_once(
+    addr: SocketAddr,
+    ipv6_only: bool,
+    backlog: u32,
+) -> Result<TcpListener, std::io::Error> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Formats an address the way /proc/net/tcp{,6} does: each 32-bit word of the address in host
+/// byte order, as hex, followed by the port.
+fn proc_net_address(addr: SocketAddr) -> String {
+    let words: Vec<String> = match addr.ip() {
+        IpAddr::V4(ip) => vec![format!("{:08X}", u32::from_ne_bytes(ip.octets()))],
+        IpAddr::V6(ip) => ip
+            .octets()
+            .chunks(4)
+            .map(|word| format!("{:08X}", u32::from_ne_bytes(word.try_into().unwrap())))
+            .collect(),
+    };
+    format!("{}:{:04X}", words.concat(), addr.port())
+}
+
+/// Returns the number of connections the kernel has completed for the listening socket at `addr`
+/// that we haven't accepted yet. A queue that keeps growing towards the backlog means we're
+/// accepting too slowly, and that clients will soon see connections dropped or reset.
+///
+/// This is read from /proc/net/tcp{,6}, where the receive queue of a listening socket is its
+/// accept queue, so it's only available on Linux.
+pub fn accept_queue_depth(addr: SocketAddr) -> Option<usize> {
+    const TCP_LISTEN: &str = "0A";
+    let table = if addr.is_ipv4() {
+        "/proc/net/tcp"
+    } else {
+        "/proc/net/tcp6"
+    };
+    let local_address = proc_net_address(addr);
+    std::fs::read_to_string(table)
+        .ok()?
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, local, _, state, queues, ..]
+                    if *local == local_address && *state == TCP_LISTEN =>
+                {
+                    let (_, rx_queue) = queues.split_once(':')?;
+                    usize::from_str_radix(rx_queue, 16).ok()
+                }
+                _ => None,
+            }
+        })
+}
@@ -1,9 +1,50 @@
+mod admin;
+mod affinity;
+mod breaker;
+mod capture;
+mod cidr;
+mod concurrency;
+mod connections;
+mod daemon;
+mod dns;
+mod egress;
+mod events;
+mod fallback;
+mod hash_ring;
+mod header_policy;
+mod health;
+mod http2;
+mod idempotency;
+mod inject;
+mod listener;
+mod maintenance;
+mod metrics;
+mod outlier;
+mod penalty;
+mod persist;
+mod pool;
+mod ratelimit;
+mod redis;
 mod request;
 mod response;
+mod retry;
+mod rewrite;
+mod routing;
+mod saturation;
+mod selfcheck;
+mod shutdown;
+mod strategy;
+mod stream;
+mod timing;
+mod upstream;
 
 use clap::Parser;
-use rand::{Rng, SeedableRng};
+use metrics::CloseReason;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stream::{ClientStream, UpstreamStream};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 
 #[derive(Parser, Debug)]
@@ -11,148 +52,2292 @@ use tokio::net::{TcpListener, TcpStream};
 struct CmdOptions {
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    // Upstream host to forward requests to.
+    // Keep retrying for up to this many seconds if the bind address is already in use
+    #[arg(long, default_value = "0")]
+    bind_retry: u64,
+    // Only accept IPv6 connections on an IPv6 bind address (by default, [::] is dual-stack)
+    #[arg(long)]
+    ipv6_only: bool,
+    // Maximum number of connections the kernel queues for us before they are accepted
+    #[arg(long, default_value = "1024")]
+    listen_backlog: u32,
+    // Upstream host to forward requests to, as host:port or host:port=weight, optionally followed
+    // by ,weight=N, ,zone=NAME, ,backup=BOOL, ,health=[HOST:PORT][/PATH], ,protocol=h2c (send
+    // requests as streams on one shared HTTP/2 connection) and ,pool=NAME (the pool it serves
+    // requests for, if not the default one) attributes
     #[arg(short, long)]
-    upstream: Vec<String>,
-    // Perform active health checks on this interval (in seconds)
+    upstream: Vec<upstream::Upstream>,
+    // Zone the balancer runs in. Upstreams in this zone are preferred over all others
+    #[arg(long)]
+    local_zone: Option<String>,
+    // Spill over to other zones once every local upstream has this many active connections
+    // (0 = only when no local upstream is healthy)
+    #[arg(long, default_value = "0")]
+    local_zone_max_connections: usize,
+    // Load balancing strategy used to pick an upstream
+    #[arg(long, value_enum, default_value = "random")]
+    strategy: strategy::Strategy,
+    // Use a different strategy for a path prefix, as PATH_PREFIX=STRATEGY (repeatable)
+    #[arg(long)]
+    route_strategy: Vec<strategy::RouteStrategy>,
+    // Send requests for a host to a pool of upstreams, as HOST=POOL, where HOST may be *.DOMAIN to
+    // match its subdomains (repeatable)
+    #[arg(long)]
+    host_route: Vec<routing::HostRoute>,
+    // Send requests whose path starts with a prefix to a pool of upstreams, as
+    // PATH_PREFIX=POOL[,strip_prefix], where strip_prefix removes the prefix from the path the
+    // request is forwarded with (repeatable; the longest matching prefix wins)
+    #[arg(long)]
+    path_route: Vec<routing::PathRoute>,
+    // Send requests whose path matches a regular expression to a pool of upstreams, as
+    // POOL=REGEX[ REWRITE], where REWRITE is the path to forward the request with and may refer to
+    // capture groups as $1 or ${name} (repeatable; checked in order, after host routes and before
+    // path prefix routes)
+    #[arg(long)]
+    regex_route: Vec<routing::RegexRoute>,
+    // Send requests that meet a set of conditions to a pool of upstreams, as
+    // CONDITION[&&CONDITION...]->POOL, where each condition is NAME=VALUE, NAME^=VALUE (prefix) or
+    // NAME~REGEX and NAME is host, path, method or a header name, or time=HH:MM-HH:MM for a daily
+    // window (repeatable; checked in order, before every other kind of route)
+    #[arg(long)]
+    match_route: Vec<routing::MatchRoute>,
+    // Timezone that the time windows of match routes are given in, as UTC, +HH:MM or -HH:MM
+    #[arg(long, default_value = "UTC")]
+    route_timezone: maintenance::UtcOffset,
+    // Split the requests sent to a name between pools by weight, as NAME=POOL:WEIGHT,POOL:WEIGHT...,
+    // e.g. web=stable:95,canary:5; routes and --default-pool may then name the split as though it
+    // were a pool, and the admin API can change its weights (repeatable)
+    #[arg(long)]
+    split: Vec<routing::SplitRule>,
+    // Send the requests sent to a name to whichever of two pools is live, as NAME=BLUE,GREEN, with
+    // the blue pool live until the admin API flips the switch; routes and --default-pool may name
+    // the switch as though it were a pool (repeatable)
+    #[arg(long)]
+    blue_green: Vec<routing::SwitchRule>,
+    // Pool that serves requests no route sends elsewhere
+    #[arg(long, default_value = routing::DEFAULT_POOL)]
+    default_pool: String,
+    // Speak a protocol to every upstream in a pool, as POOL=http1|h2c; upstreams in the pool that
+    // set a different protocol are a configuration error (repeatable)
+    #[arg(long)]
+    pool_protocol: Vec<upstream::PoolProtocol>,
+    // Request field hashed by the hash strategy: ip, header:NAME or cookie:NAME
+    #[arg(long, default_value = "ip")]
+    hash_key: hash_ring::HashKey,
+    // Keep each upstream under this multiple of the average number of connections with the hash
+    // and ip_hash strategies, e.g. 1.25, moving keys over the bound to the next upstream on the
+    // ring (0 = no bound)
+    #[arg(long, default_value = "0", value_parser = hash_ring::parse_load_factor)]
+    hash_load_factor: f64,
+    // How quickly (in milliseconds) old latency samples stop counting for the ewma strategy
+    #[arg(long, default_value = "10000")]
+    ewma_decay_ms: u64,
+    // How quickly (in milliseconds) past requests stop counting for the least_request strategy
+    #[arg(long, default_value = "10000")]
+    least_request_decay_ms: u64,
+    // Skip upstreams that failed to connect within this many seconds when choosing at random
+    #[arg(long, default_value = "10")]
+    failed_upstream_cooldown: u64,
+    // Open an upstream's circuit breaker after this many consecutive failed requests (0 = disabled)
+    #[arg(long, default_value = "0")]
+    circuit_breaker_failures: usize,
+    // How long (in seconds) an open circuit breaker waits before letting a trial request through
+    #[arg(long, default_value = "30")]
+    circuit_breaker_cooldown: u64,
+    // If an idempotent request's response headers haven't arrived within this many milliseconds,
+    // send it to a second upstream too and use whichever answers first (0 = disabled)
+    #[arg(long, default_value = "0")]
+    hedge_delay_ms: u64,
+    // Retry a failed attempt on another upstream up to this many times per request
+    #[arg(long, default_value = "2")]
+    max_retries: usize,
+    // Wait this long (in milliseconds) before the first retry of a request, doubling for each
+    // retry after that (0 = retry immediately)
+    #[arg(long, default_value = "0")]
+    retry_backoff_ms: u64,
+    // Retries may add at most this percentage on top of the recent request rate
+    #[arg(long, default_value = "20")]
+    retry_budget_percent: f64,
+    // Give each attempt at most this many milliseconds to connect to its upstream and receive the
+    // response headers; an attempt that runs out of time counts as failed (0 = no limit)
+    #[arg(long, default_value = "0")]
+    upstream_try_timeout_ms: u64,
+    // Give up on a request that hasn't been answered within this many milliseconds, across all of
+    // its attempts and the backoff between them, and answer it with a 504 (0 = no limit)
+    #[arg(long, default_value = "0")]
+    request_timeout_ms: u64,
+    // Which failed attempts to retry: connect-failure, reset, timeout, 5xx, gateway-error (502, 503 and
+    // 504) or specific status codes, comma-separated. Only idempotent requests are retried after
+    // they have been sent
+    #[arg(long, default_value = "connect-failure")]
+    retry_on: retry::RetryOn,
+    // Once more than this percentage of a pool's upstreams would be ejected (by failing health
+    // checks, outlier detection or open circuit breakers), stop ejecting them and route to all of
+    // the pool's upstreams (the default of 100 means this never happens)
+    #[arg(long, default_value = "100")]
+    panic_threshold: f64,
+    // Eject an upstream once more than this percentage of its recent responses are 5xx
+    // (0 = disabled)
+    #[arg(long, default_value = "0")]
+    outlier_5xx_percent: f64,
+    // How far back (in seconds) responses count towards an upstream's 5xx rate
+    #[arg(long, default_value = "30")]
+    outlier_window: u64,
+    // Only eject an upstream once it has sent at least this many responses within the window
+    #[arg(long, default_value = "10")]
+    outlier_min_requests: usize,
+    // How long (in seconds) an ejected upstream stays out of rotation
+    #[arg(long, default_value = "30")]
+    outlier_ejection: u64,
+    // Take an upstream that answers 503 with a Retry-After header out of rotation for that long,
+    // up to this many seconds, and retry the request elsewhere (0 = ignore Retry-After)
+    #[arg(long, default_value = "60")]
+    max_retry_after: u64,
+    // Pin each client session to an upstream with an lb-affinity cookie
+    #[arg(long)]
+    sticky_sessions: bool,
+    // How sticky sessions are moved off an upstream that is being drained
+    #[arg(long, value_enum, default_value = "next_response")]
+    drain_migration: affinity::DrainMigration,
+    // Pin each client IP to its first upstream until it has been idle this many seconds, moving it
+    // only if that upstream goes down (0 = disabled)
+    #[arg(long, default_value = "0")]
+    ip_affinity_ttl: u64,
+    // Ramp traffic to a recovered upstream up over this many seconds (0 = disabled)
+    #[arg(long, default_value = "0")]
+    slow_start: u64,
+    // Perform active health checks on this interval (in seconds; 0 = disabled)
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     // Path to send request to for active health checks.
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    // HTTP method to send health checks with
+    #[arg(long, default_value = "GET")]
+    health_check_method: http::Method,
+    // Host header to send with health checks (defaults to the upstream's address)
+    #[arg(long)]
+    health_check_host: Option<String>,
+    // Add a header to health checks, as NAME:VALUE where VALUE may be env:VAR (repeatable)
+    #[arg(long)]
+    health_check_header: Vec<header_policy::HeaderSpec>,
+    // How long (in milliseconds) an upstream has to answer a health check before it fails
+    #[arg(long, default_value = "5000")]
+    health_check_timeout_ms: u64,
+    // Randomly lengthen or shorten each wait between health checks by up to this fraction of the
+    // interval, so that upstreams aren't all checked at once
+    #[arg(long, default_value = "0.1")]
+    health_check_jitter: f64,
+    // Double the wait between checks of a dead upstream after each failed check, up to this many
+    // seconds (0 = check dead upstreams every interval too)
+    #[arg(long, default_value = "0")]
+    health_check_max_backoff: u64,
+    // Status codes a health check response may have, as a list of codes and ranges (e.g.
+    // 200,204 or 200-299; defaults to any 2xx)
+    #[arg(long)]
+    health_expect_status: Option<health::StatusSet>,
+    // Regular expression the body of a health check response must match
+    #[arg(long)]
+    health_expect_body: Option<regex::Regex>,
+    // Mark a dead upstream alive again after this many consecutive passing health checks
+    #[arg(long, default_value = "1")]
+    healthy_threshold: usize,
+    // Mark an upstream dead after this many consecutive failed health checks
+    #[arg(long, default_value = "1")]
+    unhealthy_threshold: usize,
     // Maximum number of requests to accept per IP per minute (0 = unlimited)
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    // Keep the --max-requests-per-minute counts in the Redis server at this address, so that every
+    // balancer instance using it enforces one limit together
+    #[arg(long)]
+    rate_limit_redis: Option<String>,
+    // How long (in milliseconds) to wait for Redis before counting a request locally
+    #[arg(long, default_value = "100")]
+    rate_limit_redis_timeout_ms: u64,
+    // Prefix for the keys the request counts are kept under in Redis
+    #[arg(long, default_value = "loadbalancer:ratelimit:")]
+    rate_limit_redis_prefix: String,
+    // Sustained number of requests per second to allow per IP, with bursts of up to
+    // --rate-limit-burst on top (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    rate_limit_rps: f64,
+    // Number of requests per IP that may arrive at once before --rate-limit-rps kicks in (0 = one
+    // second's worth)
+    #[arg(long, default_value = "0")]
+    rate_limit_burst: usize,
+    // Rate limit for a route, applied to each IP separately, as [METHOD:]PATH_PREFIX=RPS[/BURST],
+    // e.g. POST:/login=10 (repeatable; the most specific matching route applies)
+    #[arg(long)]
+    route_rate_limit: Vec<ratelimit::RouteRateLimit>,
+    // Refuse new connections from a client IP that already has this many open (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    max_connections_per_ip: usize,
+    // How long (in milliseconds) a request may wait for a connection to an upstream that is at its
+    // max_conns limit before trying another upstream (0 = don't wait)
+    #[arg(long, default_value = "0")]
+    upstream_queue_timeout_ms: u64,
+    // Idle connections to keep open to each upstream for reuse by later client connections
+    // (0 = close an upstream connection along with its client connection)
+    #[arg(long, default_value = "0")]
+    upstream_pool_size: usize,
+    // How long (in milliseconds) a pooled upstream connection may sit idle before it is closed
+    // instead of reused. Keep this under the upstreams' own keep-alive timeouts
+    #[arg(long, default_value = "4000")]
+    upstream_pool_idle_timeout_ms: u64,
+    // Shed requests with a 503 while this many are already being proxied (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    max_concurrent_requests: usize,
+    // Largest request body, in bytes, we accept; bigger ones get a 413
+    #[arg(long, default_value = "10000000")]
+    max_request_body_bytes: usize,
+    // Largest response body, in bytes, we relay; bigger ones are answered with a 502
+    #[arg(long, default_value = "10000000")]
+    max_response_body_bytes: usize,
+    // Request and response bodies with a Content-Length over this many bytes are piped through a
+    // buffer at a time instead of being read in full first. Streamed requests can't be retried or
+    // hedged, and streamed exchanges aren't captured or stored for idempotent replay (0 = buffer
+    // every body with a Content-Length)
+    #[arg(long, default_value = "1000000")]
+    stream_body_threshold_bytes: usize,
+    // Cut off a response with no set length (chunked, ending when the upstream closes, or a
+    // text/event-stream) once it has streamed for this many milliseconds (0 = no limit). Such
+    // responses, e.g. server-sent events and long polls, aren't subject to the request timeout
+    #[arg(long, default_value = "0")]
+    max_stream_duration_ms: u64,
+    // Largest request head, in bytes, we accept; bigger ones get a 431
+    #[arg(long, default_value = "8000")]
+    max_request_header_bytes: usize,
+    // Longest line, in bytes, we accept in a request head (request line included)
+    #[arg(long, default_value = "8000")]
+    max_request_header_line_bytes: usize,
+    // Largest number of headers we accept in a request
+    #[arg(long, default_value = "32")]
+    max_request_headers: usize,
+    // How long (in milliseconds) a client has to send a complete request head, once it starts
+    // sending it, before we answer 408 and close the connection (0 = no limit)
+    #[arg(long, default_value = "0")]
+    request_header_timeout_ms: u64,
+    // How long (in milliseconds) a client may go without sending any bytes partway through a
+    // request before we answer 408 and close the connection (0 = no limit)
+    #[arg(long, default_value = "0")]
+    request_idle_timeout_ms: u64,
+    // Delay requests from a client whose offense score (malformed requests, upstream 401/403s,
+    // rate-limit hits) reaches this (0 = disabled)
+    #[arg(long, default_value = "0")]
+    penalty_tarpit_threshold: f64,
+    // Tarpit delay (in milliseconds) per offense point at or above the tarpit threshold
+    #[arg(long, default_value = "500")]
+    penalty_tarpit_delay_ms: u64,
+    // Ban a client whose offense score reaches this (0 = disabled)
+    #[arg(long, default_value = "0")]
+    penalty_ban_threshold: f64,
+    // How long (in seconds) a client's first ban lasts; each later ban lasts twice as long
+    #[arg(long, default_value = "60")]
+    penalty_ban_duration: u64,
+    // How long (in seconds) it takes for a client's offense score to halve
+    #[arg(long, default_value = "60")]
+    penalty_half_life: u64,
+    // On SIGTERM/SIGINT, wait up to this many seconds for open connections to finish their
+    // in-flight requests before exiting
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+    // Save upstream health (including circuit breakers), client penalties and rate limit state to
+    // this file on shutdown, and restore them from it on startup
+    #[arg(long)]
+    state_file: Option<std::path::PathBuf>,
+    // POST a JSON event to this http:// URL whenever an upstream goes in or out of rotation
+    #[arg(long)]
+    health_webhook: Option<events::Webhook>,
+    // Run this shell command whenever an upstream goes in or out of rotation, with the event in the
+    // LB_UPSTREAM, LB_HEALTHY, LB_REASON and LB_TIMESTAMP environment variables
+    #[arg(long)]
+    health_event_command: Option<String>,
+    // How often (in seconds) each pool's saturation signals are worked out (0 = never)
+    #[arg(long, default_value = "10")]
+    saturation_interval: u64,
+    // Count a pool as saturated while more than this percentage of attempts to reach its upstreams
+    // time out (0 = disabled)
+    #[arg(long, default_value = "0")]
+    saturation_timeout_percent: f64,
+    // Count a pool as saturated while attempts wait longer than this many milliseconds on average
+    // for a connection under its upstreams' max_conns limits (0 = disabled)
+    #[arg(long, default_value = "0")]
+    saturation_queue_ms: u64,
+    // Count a pool as saturated while more than this percentage of its upstreams' max_conns
+    // connections are open (0 = disabled)
+    #[arg(long, default_value = "0")]
+    saturation_utilization_percent: f64,
+    // POST a JSON event to this http:// URL whenever a pool becomes saturated or stops being so
+    #[arg(long)]
+    saturation_webhook: Option<events::Webhook>,
+    // Don't restore upstream health from a state file saved more than this many seconds ago
+    #[arg(long, default_value = "300")]
+    state_file_max_age: u64,
+    // Write a JSON report of the startup self-check to this file
+    #[arg(long)]
+    readiness_report: Option<String>,
+    // Detach from the terminal and run in the background
+    #[arg(long)]
+    daemonize: bool,
+    // Write our process ID to this file once the listeners are bound, and remove it on exit
+    #[arg(long)]
+    pidfile: Option<std::path::PathBuf>,
+    // Switch to this user once the listeners are bound (e.g. after binding port 80 as root)
+    #[arg(long)]
+    user: Option<String>,
+    // Switch to this group once the listeners are bound (the user's primary group by default)
+    #[arg(long)]
+    group: Option<String>,
+    // Maximum concurrent in-flight requests for a path prefix, as PATH_PREFIX=LIMIT (repeatable)
+    #[arg(long)]
+    route_concurrency_limit: Vec<concurrency::RouteLimit>,
+    // How long (in milliseconds) a request may wait for a route concurrency slot (0 = reject)
+    #[arg(long, default_value = "0")]
+    route_queue_timeout_ms: u64,
+    // Priority class (high, normal or low) for matching requests, as PATH_PREFIX=CLASS or
+    // header:NAME[:VALUE]=CLASS (repeatable, first match wins, default normal)
+    #[arg(long)]
+    priority_rule: Vec<concurrency::PriorityRule>,
+    // Percentage of --max-concurrent-requests kept free for each priority class above normal and
+    // low: normal requests are shed once all but this share is in flight, and low ones once all
+    // but twice this share is (has no effect without --priority-rule)
+    #[arg(long, default_value = "10")]
+    priority_headroom_percent: f64,
+    // Answer requests with 503 during a daily window, as [PATH_PREFIX=]HH:MM-HH:MM (repeatable)
+    #[arg(long)]
+    maintenance_window: Vec<maintenance::MaintenanceWindow>,
+    // Timezone that maintenance windows are given in, as UTC, +HH:MM or -HH:MM
+    #[arg(long, default_value = "UTC")]
+    maintenance_timezone: maintenance::UtcOffset,
+    // Add a header to forwarded requests, as [PATH_PREFIX=]NAME:VALUE, where VALUE may be
+    // env:VAR or file:PATH (repeatable; files are re-read when they change)
+    #[arg(long)]
+    inject_header: Vec<inject::HeaderInjection>,
+    // Rewrite requests once they have been routed, as prefix /OLD [/NEW] (replace or strip a
+    // path prefix), regex REGEX REPLACEMENT (replace the first match in the path) or host HOST
+    // (override the Host header), each optionally followed by pool=NAME to only rewrite requests
+    // routed to that pool (repeatable; applied in order)
+    #[arg(long)]
+    rewrite: Vec<rewrite::Rewrite>,
+    // What to do with a POST/PATCH whose Idempotency-Key header repeats a recent request's
+    #[arg(long, value_enum, default_value = "off")]
+    idempotency_keys: idempotency::Mode,
+    // Maximum number of Idempotency-Key values to remember (the oldest are forgotten first)
+    #[arg(long, default_value = "10000")]
+    idempotency_key_capacity: usize,
+    // How long (in seconds) to remember an Idempotency-Key value
+    #[arg(long, default_value = "300")]
+    idempotency_key_ttl: u64,
+    // How to handle requests that repeat a header that may only appear once (e.g. Host)
+    #[arg(long, value_enum, default_value = "reject")]
+    duplicate_header_policy: request::DuplicateHeaderPolicy,
+    // Remove this header from every proxied response (repeatable)
+    #[arg(long)]
+    response_header_remove: Vec<http::HeaderName>,
+    // Set a header on every proxied response, as NAME:VALUE where VALUE may be env:VAR, replacing
+    // any value the upstream sent (repeatable)
+    #[arg(long)]
+    response_header_set: Vec<header_policy::HeaderSpec>,
+    // Add a header to every proxied response that doesn't already have it, as NAME:VALUE where
+    // VALUE may be env:VAR (repeatable)
+    #[arg(long)]
+    response_header_default: Vec<header_policy::HeaderSpec>,
+    // Add a Server-Timing response header breaking down where each request's time went
+    #[arg(long)]
+    server_timing: bool,
+    // Add RateLimit-Limit/-Remaining/-Reset headers to proxied responses, not only to 429s, so
+    // that clients can slow down before they are throttled
+    #[arg(long)]
+    rate_limit_headers: bool,
+    // Add X-LB-* response headers describing how each request was balanced
+    #[arg(long)]
+    debug_headers: bool,
+    // Accept HTTP/2 from clients that open with its connection preface (h2c with prior knowledge).
+    // Each stream is proxied as though it were a request on an HTTP/1.1 connection of its own
+    #[arg(long)]
+    http2: bool,
+    // With --http2, proxy gRPC calls (content-type application/grpc) straight to protocol=h2c
+    // upstreams, streaming both ways with trailers intact, balancing each call on its own
+    #[arg(long)]
+    grpc: bool,
+    // Name we add to the Via header of forwarded requests and responses, and answer 508 Loop
+    // Detected to requests whose Via header already has it (empty = no Via header)
+    #[arg(long, default_value = "loadbalancer", value_parser = request::parse_via_name)]
+    via_name: String,
+    // Answer 405 Method Not Allowed to requests whose method isn't one of these, e.g.
+    // GET,HEAD,POST (case-sensitive; repeatable or comma-separated; default is any method)
+    #[arg(long, value_delimiter = ',')]
+    allowed_methods: Vec<http::Method>,
+    // Clients in this network are proxies whose Forwarded and X-Forwarded-* headers are kept and
+    // added to; everyone else's are replaced (repeatable or comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxies: Vec<cidr::Cidr>,
+    // Only accept connections from clients in this network (repeatable; default is everyone)
+    #[arg(long)]
+    allow_cidr: Vec<cidr::Cidr>,
+    // Refuse connections from clients in this network, even if it is also allowed (repeatable)
+    #[arg(long)]
+    deny_cidr: Vec<cidr::Cidr>,
+    // Answer CONNECT requests by tunneling to the host and port they name, rather than forwarding
+    // them to an upstream
+    #[arg(long)]
+    allow_connect: bool,
+    // A port CONNECT tunnels may go to (repeatable; default 443)
+    #[arg(long)]
+    connect_allowed_port: Vec<u16>,
+    // Only add X-LB-* debug headers for clients in this network (repeatable)
+    #[arg(long)]
+    debug_headers_cidr: Vec<cidr::Cidr>,
+    // Serve this file (HTML, JSON or plain text, going by its extension) instead of a bare error
+    // when a request can't reach any upstream
+    #[arg(long)]
+    fallback_response_file: Option<std::path::PathBuf>,
+    // Status code to serve the fallback response with
+    #[arg(long, default_value = "503")]
+    fallback_response_status: http::StatusCode,
+    // Record sampled request/response exchanges to this directory, for reproducing backend bugs
+    // (disabled if not set)
+    #[arg(long)]
+    capture_dir: Option<std::path::PathBuf>,
+    // Fraction (0 to 1) of matching exchanges to record
+    #[arg(long, default_value = "1.0")]
+    capture_sample_rate: f64,
+    // Only record requests whose path starts with this prefix
+    #[arg(long)]
+    capture_path_prefix: Option<String>,
+    // Only record requests carrying this header, as NAME or NAME:VALUE
+    #[arg(long)]
+    capture_header: Option<capture::HeaderMatch>,
+    // Only record requests from clients in this network (repeatable)
+    #[arg(long)]
+    capture_client_cidr: Vec<cidr::Cidr>,
+    // Cut recorded request and response bodies down to this many bytes
+    #[arg(long, default_value = "65536")]
+    capture_max_body: usize,
+    // Stop recording after this many exchanges (0 = never stop)
+    #[arg(long, default_value = "1000")]
+    capture_limit: usize,
+    // Serve the admin API on this address (disabled if not set)
+    #[arg(long)]
+    admin_bind: Option<String>,
+    // Nameserver to resolve upstream hostnames with, as IP or IP:PORT (repeatable; defaults to
+    // the system resolver configuration)
+    #[arg(long, value_parser = dns::parse_nameserver)]
+    dns_nameserver: Vec<std::net::SocketAddr>,
+    // Timeout (in milliseconds) for a single DNS query
+    #[arg(long, default_value = "2000")]
+    dns_timeout_ms: u64,
+    // Minimum time (in seconds) to cache a DNS answer, regardless of its TTL
+    #[arg(long, default_value = "0")]
+    dns_min_ttl: u64,
+    // Maximum time (in seconds) to cache a DNS answer, regardless of its TTL
+    #[arg(long, default_value = "86400")]
+    dns_max_ttl: u64,
+    // How long (in seconds) to cache a failed DNS lookup
+    #[arg(long, default_value = "5")]
+    dns_negative_ttl: u64,
+    // Address family to try first when an upstream hostname has both IPv4 and IPv6 addresses
+    #[arg(long, value_enum, default_value = "system")]
+    upstream_ip_preference: dns::IpPreference,
+    // Make all upstream connections through this proxy, as socks5://[USER:PASS@]HOST:PORT or
+    // http://[USER:PASS@]HOST:PORT (HTTP CONNECT)
+    #[arg(long)]
+    egress_proxy: Option<egress::EgressProxy>,
 }
 
 struct ProxyState {
     // How frequently we check whether upstream servers are alive
-    #[allow(dead_code)]
     active_health_check_interval: usize,
     // Where we should send requests when doing active health checks
-    #[allow(dead_code)]
     active_health_check_path: String,
-    // Maximum number of requests an individual IP can make in a minute
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
-    // Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
+    // Results of the most recent active health checks
+    health: health::UpstreamHealth,
+    // Caps the number of requests an individual IP can make in a minute, and its request rate
+    rate_limiter: ratelimit::RateLimiter,
+    // Maximum number of connections a client IP may hold open (0 = unlimited)
+    max_connections_per_ip: usize,
+    // Which client addresses may connect at all
+    access_list: cidr::AccessList,
+    // Ports CONNECT requests may tunnel to, if CONNECT tunneling is enabled
+    connect_ports: Option<Vec<u16>>,
+    // Number of requests being proxied, capped at --max-concurrent-requests
+    in_flight: concurrency::InFlightLimit,
+    // Per-route rate limits, by client IP
+    route_rate_limiter: ratelimit::RouteRateLimiter,
+    // Servers that we are proxying to
+    upstreams: Vec<upstream::Upstream>,
+    // How we choose which upstream to send a request to, by pool and route
+    router: routing::Router,
+    // Preference for upstreams in our own zone
+    zones: upstream::ZonePreference,
+    // Number of client connections currently proxied to each upstream
+    active_connections: Vec<AtomicUsize>,
+    // Each upstream's max_conns limit, which hands freed connections to waiting requests by
+    // priority (None if it has no limit)
+    connection_gates: Vec<Option<Arc<concurrency::PriorityGate>>>,
+    // Upstreams an operator is taking out of rotation (see the admin API): connections move off
+    // them once their in-flight request is answered, and no new ones are routed there
+    draining: Vec<AtomicBool>,
+    // Cookie-based sticky sessions, if enabled
+    cookie_affinity: Option<affinity::CookieAffinity>,
+    // How sticky sessions are moved off draining upstreams
+    drain_migration: affinity::DrainMigration,
+    // Client IP -> upstream assignments, if enabled
+    ip_affinity: Option<affinity::IpAffinity>,
+    // Upstreams that we recently failed to connect to
+    recent_failures: upstream::RecentFailures,
+    // Notifies the outside world of upstream health changes
+    events: events::EventHooks,
+    // Upstreams ejected for answering with too many 5xx responses
+    outliers: outlier::OutlierDetector,
+    // Send idempotent requests to a second upstream if the first hasn't answered within this long
+    // (zero = never)
+    hedge_delay: Duration,
+    // How long to wait for a connection slot on an upstream at its connection limit
+    upstream_queue_timeout: Duration,
+    // Idle upstream connections available for reuse
+    pool: pool::ConnectionPool,
+    // Shared connections to the upstreams that speak HTTP/2
+    h2_connections: http2::UpstreamConnections,
+    // Time limits for each attempt at a request, and for the request as a whole (zero = no limit)
+    try_timeout: Duration,
+    request_timeout: Duration,
+    // Limits on retrying failed attempts
+    retries: retry::RetryPolicy,
+    // Upstreams that keep failing live requests
+    breakers: breaker::CircuitBreakers,
+    // Traffic ramp-up for upstreams that have just recovered
+    slow_start: upstream::SlowStart,
+    // Moving average of each upstream's response latency, used by the ewma strategy
+    latency: Vec<upstream::LatencyEwma>,
+    // How quickly old latency samples decay out of the moving averages
+    ewma_decay: Duration,
+    // In-flight and recent requests per upstream, used by the least_request strategy
+    request_load: Vec<upstream::RequestLoad>,
+    // How quickly recent requests decay out of the request loads
+    request_load_decay: Duration,
+    // Per-route caps on concurrent in-flight requests
+    route_limiter: concurrency::RouteLimiter,
+    priority_rules: Vec<concurrency::PriorityRule>,
+    // Offense scores and penalties for misbehaving clients
+    penalties: penalty::PenaltyBox,
+    // Set once a graceful shutdown begins
+    shutdown: shutdown::Shutdown,
+    // Currently open client connections
+    connections: Arc<connections::ConnectionRegistry>,
+    // Counters exported through the admin API
+    metrics: metrics::Metrics,
+    // How close each pool is to running out of room
+    saturation: saturation::PoolSaturation,
+    // Resolver used to look up upstream addresses
+    resolver: dns::Resolver,
+    // Proxy that upstream connections are tunnelled through, if any
+    egress_proxy: Option<egress::EgressProxy>,
+    // Scheduled windows during which requests get a maintenance response
+    maintenance: maintenance::MaintenanceSchedule,
+    // Headers added to requests before they are forwarded
+    header_injector: inject::HeaderInjector,
+    // Rewrites made to requests once they have been routed
+    rewriter: rewrite::Rewriter,
+    // Recently seen Idempotency-Key values, and the responses to their requests
+    idempotency: idempotency::IdempotencyStore,
+    // How repeated singleton request headers are handled
+    duplicate_header_policy: request::DuplicateHeaderPolicy,
+    // How big request heads may be
+    header_limits: request::HeaderLimits,
+    // Largest request and response bodies we accept
+    max_request_body: usize,
+    max_response_body: usize,
+    // Bodies with a Content-Length over this are streamed rather than buffered (0 = never)
+    stream_body_threshold: usize,
+    // How long an open-ended response may stream for (zero = no limit)
+    max_stream_duration: Duration,
+    // How long clients have to send their requests
+    read_timeouts: request::ReadTimeouts,
+    // Served when a request can't reach any upstream, if configured
+    fallback: Option<fallback::FallbackResponse>,
+    // Where sampled exchanges are recorded, if capturing is enabled
+    capture: Option<capture::Capture>,
+    // Headers removed from, set on or defaulted on every proxied response
+    response_headers: header_policy::ResponseHeaderPolicy,
+    // Whether to add a Server-Timing header to every response
+    server_timing: bool,
+    // Whether to add RateLimit-* headers to every response
+    rate_limit_headers: bool,
+    // Whether to add X-LB-* debug headers to every response
+    debug_headers: bool,
+    // Clients that get X-LB-* debug headers even if they aren't enabled for everyone
+    debug_headers_cidrs: Vec<cidr::Cidr>,
+    // Whether to accept prior-knowledge HTTP/2 from clients
+    http2: bool,
+    // Whether to pass gRPC calls from HTTP/2 clients straight through to HTTP/2 upstreams
+    grpc: bool,
+    // What we go by in Via headers, if we add them
+    via_name: Option<String>,
+    // Clients whose forwarding headers are passed on rather than replaced
+    trusted_proxies: Vec<cidr::Cidr>,
+    // Methods requests may have, if only some are allowed
+    allowed_methods: Vec<http::Method>,
 }
 
-#[tokio::main]
-async fn main() {
-    if let Err(_) = std::env::var("RUST_LOG") {
+fn main() {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
+    // Forking only carries the calling thread over, so this has to happen before the runtime
+    // starts its worker threads
+    if options.daemonize {
+        if let Err(err) = daemon::daemonize() {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    tokio::runtime::Runtime::new()
+        .expect("Could not start the async runtime")
+        .block_on(run(options));
+}
+
+async fn run(mut options: CmdOptions) {
+    let report_path = options.readiness_report.as_deref();
+    let mut self_check = selfcheck::Report::new();
+    if options.upstream.is_empty() {
         log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+        self_check.record("config", Err("no upstream servers specified".to_string()));
+        self_check.abort(report_path);
+    }
+    if let Err(err) = upstream::apply_pool_protocols(&mut options.upstream, &options.pool_protocol)
+    {
+        log::error!("Invalid pool protocol: {}", err);
+        self_check.record("config", Err(err));
+        self_check.abort(report_path);
+    }
+    let router = match routing::Router::new(
+        &options.upstream,
+        options.strategy,
+        &options.route_strategy,
+        &hash_ring::HashOptions {
+            key: options.hash_key.clone(),
+            load_factor: options.hash_load_factor,
+        },
+        options.panic_threshold,
+        &routing::Rules {
+            match_routes: options.match_route.clone(),
+            host_routes: options.host_route.clone(),
+            path_routes: options.path_route.clone(),
+            regex_routes: options.regex_route.clone(),
+            splits: options.split.clone(),
+            switches: options.blue_green.clone(),
+            default_pool: options.default_pool.clone(),
+            timezone: options.route_timezone,
+        },
+    ) {
+        Ok(router) => router,
+        Err(err) => {
+            log::error!("Invalid routing configuration: {}", err);
+            self_check.record("config", Err(err));
+            self_check.abort(report_path);
+        }
+    };
+    if let Some(pool) = options
+        .rewrite
+        .iter()
+        .filter_map(|rule| rule.pool.as_deref())
+        .find(|pool| !router.has_pool(pool))
+    {
+        log::error!(
+            "A --rewrite rule names pool {:?}, which has no upstreams",
+            pool
+        );
+        self_check.record(
+            "config",
+            Err(format!("no upstreams are in pool {:?}", pool)),
+        );
+        self_check.abort(report_path);
+    }
+    self_check.record(
+        "config",
+        Ok(format!("{} upstream(s) configured", options.upstream.len())),
+    );
+    if let Some(local_zone) = &options.local_zone {
+        if !options
+            .upstream
+            .iter()
+            .any(|upstream| upstream.zone.as_deref() == Some(local_zone))
+        {
+            log::warn!(
+                "No upstreams are in local zone {}, so every zone will be used",
+                local_zone
+            );
+        }
     }
 
-    let listener = match TcpListener::bind(&options.bind).await {
+    if options.grpc
+        && (!options.http2
+            || !options
+                .upstream
+                .iter()
+                .any(|upstream| upstream.protocol == upstream::Protocol::H2c))
+    {
+        log::warn!(
+            "--grpc needs --http2 and at least one protocol=h2c upstream, so gRPC calls will \
+            not be passed through"
+        );
+    }
+
+    let listener = match listener::bind(
+        &options.bind,
+        Duration::from_secs(options.bind_retry),
+        options.ipv6_only,
+        options.listen_backlog,
+    )
+    .await
+    {
         Ok(listener) => listener,
         Err(err) => {
             log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
+            self_check.record("bind", Err(format!("{}: {}", options.bind, err)));
+            self_check.abort(report_path);
+        }
+    };
+    // Log the address we actually bound to, which may differ from --bind if it used port 0
+    let listen_addr = listener.local_addr().unwrap();
+    log::info!("Listening for requests on {}", listen_addr);
+    self_check.set_listen_address(listen_addr);
+    self_check.record("bind", Ok(listen_addr.to_string()));
+
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                let admin_addr = listener.local_addr().unwrap();
+                log::info!("Serving admin API on {}", admin_addr);
+                self_check.set_admin_address(admin_addr);
+                self_check.record("admin bind", Ok(admin_addr.to_string()));
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind admin API to {}: {}", admin_bind, err);
+                self_check.record("admin bind", Err(format!("{}: {}", admin_bind, err)));
+                self_check.abort(report_path);
+            }
+        },
+        None => None,
+    };
+
+    // Everything that needs root is done once the sockets are bound
+    let pidfile = match &options.pidfile {
+        Some(path) => match daemon::PidFile::create(path) {
+            Ok(pidfile) => Some(pidfile),
+            Err(err) => {
+                log::error!("Could not create PID file: {}", err);
+                self_check.record("pidfile", Err(err));
+                self_check.abort(report_path);
+            }
+        },
+        None => None,
+    };
+    if options.user.is_some() || options.group.is_some() {
+        match daemon::drop_privileges(options.user.as_deref(), options.group.as_deref()) {
+            Ok(running_as) => {
+                log::info!("Dropped privileges, now running as {}", running_as);
+                self_check.record("privileges", Ok(running_as));
+            }
+            Err(err) => {
+                log::error!("Could not drop privileges: {}", err);
+                self_check.record("privileges", Err(err));
+                self_check.abort(report_path);
+            }
+        }
+    }
+
+    let resolver = match dns::Resolver::new(
+        &options.dns_nameserver,
+        Duration::from_millis(options.dns_timeout_ms),
+        Duration::from_secs(options.dns_min_ttl),
+        Duration::from_secs(options.dns_max_ttl),
+        Duration::from_secs(options.dns_negative_ttl),
+        options.upstream_ip_preference,
+    ) {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            log::error!("Could not set up DNS resolver: {}", err);
+            self_check.record("dns", Err(err));
+            self_check.abort(report_path);
+        }
+    };
+
+    let header_injector = match inject::HeaderInjector::new(&options.inject_header) {
+        Ok(injector) => injector,
+        Err(err) => {
+            log::error!("Could not load injected headers: {}", err);
+            self_check.record("header injection", Err(err));
+            self_check.abort(report_path);
+        }
+    };
+    if !header_injector.is_empty() {
+        self_check.record(
+            "header injection",
+            Ok(format!("{} header(s)", options.inject_header.len())),
+        );
+    }
+
+    let response_headers = match header_policy::ResponseHeaderPolicy::new(
+        options.response_header_remove,
+        &options.response_header_set,
+        &options.response_header_default,
+    ) {
+        Ok(policy) => policy,
+        Err(err) => {
+            log::error!("Could not load response header policy: {}", err);
+            self_check.record("response headers", Err(err));
+            self_check.abort(report_path);
+        }
+    };
+    if !response_headers.is_empty() {
+        self_check.record("response headers", Ok("configured".to_string()));
+    }
+
+    let health_check_headers = match options
+        .health_check_header
+        .iter()
+        .map(header_policy::HeaderSpec::resolve)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(headers) => headers,
+        Err(err) => {
+            log::error!("Could not load health check headers: {}", err);
+            self_check.record("health check headers", Err(err));
+            self_check.abort(report_path);
+        }
+    };
+
+    let fallback = match &options.fallback_response_file {
+        Some(path) => {
+            match fallback::FallbackResponse::load(path, options.fallback_response_status) {
+                Ok(fallback) => {
+                    self_check.record("fallback response", Ok(path.display().to_string()));
+                    Some(fallback)
+                }
+                Err(err) => {
+                    log::error!("Could not load fallback response: {}", err);
+                    self_check.record("fallback response", Err(err));
+                    self_check.abort(report_path);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let capture = match options.capture_dir {
+        Some(dir) => {
+            let filter = capture::Filter {
+                path_prefix: options.capture_path_prefix,
+                header: options.capture_header,
+                client_cidrs: options.capture_client_cidr,
+            };
+            match capture::Capture::new(
+                dir.clone(),
+                filter,
+                options.capture_sample_rate,
+                options.capture_max_body,
+                options.capture_limit,
+                header_injector.header_names(),
+            ) {
+                Ok(capture) => {
+                    self_check.record("capture", Ok(dir.display().to_string()));
+                    Some(capture)
+                }
+                Err(err) => {
+                    log::error!("Could not set up exchange capture: {}", err);
+                    self_check.record("capture", Err(err));
+                    self_check.abort(report_path);
+                }
+            }
         }
+        None => None,
     };
-    log::info!("Listening for requests on {}", options.bind);
 
+    // Resolve each upstream once up front. An unresolvable upstream isn't fatal (it may become
+    // resolvable later), but it does mean we didn't start up in a fully healthy state. With an
+    // egress proxy, upstream names are resolved by the proxy, so we only check the proxy itself.
+    let addresses_to_resolve: Vec<&str> = match &options.egress_proxy {
+        Some(proxy) => vec![&proxy.address],
+        None => options
+            .upstream
+            .iter()
+            .map(|upstream| upstream.address.as_str())
+            .collect(),
+    };
+    for address in addresses_to_resolve {
+        let result = match resolver.resolve(address).await {
+            Ok(addrs) => Ok(addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")),
+            Err(err) => Err(err.to_string()),
+        };
+        self_check.record(&format!("resolve {}", address), result);
+    }
+    self_check.finish(report_path);
+    let shutdown_timeout = Duration::from_secs(options.shutdown_timeout);
+    let state_file = options.state_file;
+    let events = events::EventHooks::new(options.health_webhook, options.health_event_command);
+    let state_file_max_age = Duration::from_secs(options.state_file_max_age);
+
+    let health_check_timeout = Duration::from_millis(options.health_check_timeout_ms);
+    let health_check_jitter = options.health_check_jitter;
+    let health_check_max_backoff = Duration::from_secs(options.health_check_max_backoff);
+    let health_check_method = options.health_check_method;
+    let health_check_host = options.health_check_host;
+    let health_expect_status = options.health_expect_status;
+    let health_expect_body = options.health_expect_body;
     let state = Arc::new(ProxyState {
-        upstream_addresses: options.upstream,
+        router,
+        zones: upstream::ZonePreference::new(
+            &options.upstream,
+            options.local_zone.as_deref(),
+            options.local_zone_max_connections,
+        ),
+        active_connections: (0..options.upstream.len())
+            .map(|_| AtomicUsize::new(0))
+            .collect(),
+        connection_gates: options
+            .upstream
+            .iter()
+            .map(|upstream| {
+                (upstream.max_connections > 0)
+                    .then(|| Arc::new(concurrency::PriorityGate::new(upstream.max_connections)))
+            })
+            .collect(),
+        draining: (0..options.upstream.len())
+            .map(|_| AtomicBool::new(false))
+            .collect(),
+        latency: (0..options.upstream.len())
+            .map(|_| upstream::LatencyEwma::new())
+            .collect(),
+        ewma_decay: Duration::from_millis(options.ewma_decay_ms),
+        request_load: (0..options.upstream.len())
+            .map(|_| upstream::RequestLoad::new())
+            .collect(),
+        request_load_decay: Duration::from_millis(options.least_request_decay_ms),
+        cookie_affinity: options
+            .sticky_sessions
+            .then(|| affinity::CookieAffinity::new(&options.upstream)),
+        drain_migration: options.drain_migration,
+        ip_affinity: (options.ip_affinity_ttl > 0)
+            .then(|| affinity::IpAffinity::new(Duration::from_secs(options.ip_affinity_ttl))),
+        slow_start: upstream::SlowStart::new(Duration::from_secs(options.slow_start)),
+        recent_failures: upstream::RecentFailures::new(Duration::from_secs(
+            options.failed_upstream_cooldown,
+        )),
+        outliers: outlier::OutlierDetector::new(
+            options.upstream.len(),
+            options.outlier_5xx_percent,
+            Duration::from_secs(options.outlier_window),
+            options.outlier_min_requests,
+            Duration::from_secs(options.outlier_ejection),
+            Duration::from_secs(options.max_retry_after),
+            events.clone(),
+        ),
+        hedge_delay: Duration::from_millis(options.hedge_delay_ms),
+        upstream_queue_timeout: Duration::from_millis(options.upstream_queue_timeout_ms),
+        pool: pool::ConnectionPool::new(
+            options.upstream.len(),
+            options.upstream_pool_size,
+            Duration::from_millis(options.upstream_pool_idle_timeout_ms),
+        ),
+        h2_connections: http2::UpstreamConnections::new(options.upstream.len()),
+        try_timeout: Duration::from_millis(options.upstream_try_timeout_ms),
+        request_timeout: Duration::from_millis(options.request_timeout_ms),
+        retries: retry::RetryPolicy::new(
+            options.max_retries,
+            Duration::from_millis(options.retry_backoff_ms),
+            options.retry_budget_percent,
+            options.retry_on.clone(),
+        ),
+        breakers: breaker::CircuitBreakers::new(
+            options.upstream.len(),
+            options.circuit_breaker_failures,
+            Duration::from_secs(options.circuit_breaker_cooldown),
+        ),
+        health: health::UpstreamHealth::new(
+            options.upstream.len(),
+            options.active_health_check_interval > 0,
+            options.healthy_threshold,
+            options.unhealthy_threshold,
+            events.clone(),
+        ),
+        events,
+        saturation: saturation::PoolSaturation::new(
+            &options.upstream,
+            saturation::Thresholds {
+                timeout_percent: options.saturation_timeout_percent,
+                queue_time: Duration::from_millis(options.saturation_queue_ms),
+                utilization_percent: options.saturation_utilization_percent,
+            },
+            options.saturation_webhook,
+        ),
+        upstreams: options.upstream,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
+        rate_limiter: ratelimit::RateLimiter::new(
+            options.max_requests_per_minute,
+            options.rate_limit_rps,
+            options.rate_limit_burst,
+            options.rate_limit_redis.map(|address| {
+                ratelimit::SharedWindow::new(
+                    redis::Client::new(
+                        address,
+                        Duration::from_millis(options.rate_limit_redis_timeout_ms),
+                    ),
+                    options.rate_limit_redis_prefix,
+                )
+            }),
+        ),
+        max_connections_per_ip: options.max_connections_per_ip,
+        access_list: cidr::AccessList::new(options.allow_cidr, options.deny_cidr),
+        connect_ports: options.allow_connect.then(|| {
+            if options.connect_allowed_port.is_empty() {
+                vec![443]
+            } else {
+                options.connect_allowed_port.clone()
+            }
+        }),
+        in_flight: concurrency::InFlightLimit::new(
+            options.max_concurrent_requests,
+            if options.priority_rule.is_empty() {
+                0.0
+            } else {
+                options.priority_headroom_percent
+            },
+        ),
+        route_rate_limiter: ratelimit::RouteRateLimiter::new(&options.route_rate_limit),
+        route_limiter: concurrency::RouteLimiter::new(
+            &options.route_concurrency_limit,
+            Duration::from_millis(options.route_queue_timeout_ms),
+        ),
+        priority_rules: options.priority_rule,
+        penalties: penalty::PenaltyBox::new(
+            options.penalty_tarpit_threshold,
+            Duration::from_millis(options.penalty_tarpit_delay_ms),
+            options.penalty_ban_threshold,
+            Duration::from_secs(options.penalty_ban_duration),
+            Duration::from_secs(options.penalty_half_life),
+        ),
+        shutdown: shutdown::Shutdown::new(),
+        connections: Arc::new(connections::ConnectionRegistry::new()),
+        metrics: metrics::Metrics::new(listen_addr, options.listen_backlog),
+        resolver,
+        egress_proxy: options.egress_proxy,
+        maintenance: maintenance::MaintenanceSchedule::new(
+            options.maintenance_window,
+            options.maintenance_timezone,
+        ),
+        header_injector,
+        rewriter: rewrite::Rewriter::new(&options.rewrite),
+        idempotency: idempotency::IdempotencyStore::new(
+            options.idempotency_keys,
+            options.idempotency_key_capacity,
+            Duration::from_secs(options.idempotency_key_ttl),
+        ),
+        duplicate_header_policy: options.duplicate_header_policy,
+        max_request_body: options.max_request_body_bytes,
+        max_response_body: options.max_response_body_bytes,
+        stream_body_threshold: options.stream_body_threshold_bytes,
+        max_stream_duration: Duration::from_millis(options.max_stream_duration_ms),
+        header_limits: request::HeaderLimits {
+            max_bytes: options.max_request_header_bytes,
+            max_line: options.max_request_header_line_bytes,
+            max_count: options.max_request_headers,
+        },
+        read_timeouts: request::ReadTimeouts {
+            head: Duration::from_millis(options.request_header_timeout_ms),
+            idle: Duration::from_millis(options.request_idle_timeout_ms),
+        },
+        fallback,
+        capture,
+        response_headers,
+        server_timing: options.server_timing,
+        rate_limit_headers: options.rate_limit_headers,
+        debug_headers: options.debug_headers,
+        debug_headers_cidrs: options.debug_headers_cidr,
+        http2: options.http2,
+        grpc: options.grpc,
+        via_name: Some(options.via_name).filter(|name| !name.is_empty()),
+        trusted_proxies: options.trusted_proxies,
+        allowed_methods: options.allowed_methods,
     });
 
+    if let Some(admin_listener) = admin_listener {
+        tokio::spawn(admin::serve(admin_listener, state.clone()));
+    }
+
+    if state.active_health_check_interval > 0 {
+        tokio::spawn(health::run(
+            state.clone(),
+            Duration::from_secs(state.active_health_check_interval as u64),
+            health_check_jitter,
+            health_check_max_backoff,
+            health::CheckConfig {
+                method: health_check_method,
+                path: state.active_health_check_path.clone(),
+                host: health_check_host,
+                headers: health_check_headers,
+                timeout: health_check_timeout,
+                expect_status: health_expect_status,
+                expect_body: health_expect_body,
+            },
+        ));
+    }
+
+    if options.saturation_interval > 0 {
+        tokio::spawn(saturation::run(
+            state.clone(),
+            Duration::from_secs(options.saturation_interval),
+        ));
+    }
+
+    if let Some(path) = &state_file {
+        if let Err(err) = persist::load(&state, path, state_file_max_age) {
+            log::warn!("Starting without saved state: {}", err);
+        }
+    }
+
+    let signal_state = state.clone();
+    tokio::spawn(async move { shutdown::watch_signals(&signal_state.shutdown).await });
+
     loop {
-        let stream = match listener.accept().await {
-            Ok((stream, _)) => stream,
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = state.shutdown.wait() => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
             Err(e) => {
                 log::error!("Failed to accept new connection: {}", e);
                 std::process::exit(1);
             }
         };
+        if !state.access_list.permits(peer.ip().to_canonical()) {
+            log::debug!("Refusing connection from {}: not allowed", peer);
+            state.metrics.record_denied_connection();
+            continue;
+        }
 
         let state = state.clone();
-        tokio::spawn(handle_connection(stream, state));
+        tokio::spawn(handle_connection(stream, state, Instant::now()));
+    }
+
+    // Stop accepting connections, and give the open ones a chance to finish what they're doing
+    drop(listener);
+    log::info!(
+        "Waiting up to {}s for {} connection(s) to drain",
+        shutdown_timeout.as_secs(),
+        state.connections.len()
+    );
+    let remaining = shutdown::drain(&state.connections, shutdown_timeout).await;
+    for _ in 0..remaining {
+        state.metrics.record_termination(CloseReason::Shutdown);
+    }
+    if remaining > 0 {
+        log::warn!("Exiting with {} connection(s) still open", remaining);
+    } else {
+        log::info!("All connections drained, exiting");
+    }
+    if let Some(path) = &state_file {
+        match persist::save(&state, path) {
+            Ok(()) => log::info!("Saved state to {}", path.display()),
+            Err(err) => log::error!("Could not save state: {}", err),
+        }
+    }
+    if let Some(pidfile) = pidfile {
+        pidfile.remove();
+    }
+}
+
+// An open connection to an upstream server
+struct UpstreamConnection<'a> {
+    stream: UpstreamStream,
+    // Index of the upstream in ProxyState.upstreams
+    idx: usize,
+    // Number of connection attempts it took to get this connection (including the successful one)
+    attempts: usize,
+    // How long it took to establish the connection
+    connect_time: Duration,
+    // When the attempt that opened the connection started, until the connection is first used:
+    // the try timeout covers connecting as well as waiting for the response
+    attempt_start: Option<Instant>,
+    // Whether the connection is between exchanges and the upstream will keep it open, so that it
+    // can go back to the pool
+    reusable: bool,
+    // Counts this connection against the upstream for as long as it is open
+    _active: upstream::ActiveConnection<'a>,
+}
+
+// Open a TCP connection to an upstream address, through the egress proxy if there is one. Errors
+// describe which step failed.
+async fn dial_upstream(state: &ProxyState, address: &str) -> Result<TcpStream, String> {
+    match &state.egress_proxy {
+        Some(proxy) => proxy
+            .connect(&state.resolver, address)
+            .await
+            .map_err(|err| format!("via egress proxy {}: {}", proxy.address, err)),
+        None => {
+            let addrs = state
+                .resolver
+                .resolve(address)
+                .await
+                .map_err(|err| format!("could not resolve: {}", err))?;
+            TcpStream::connect(&addrs[..])
+                .await
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+// The time `timeout` after `start`, or None if there is no limit
+fn deadline_after(start: Instant, timeout: Duration) -> Option<Instant> {
+    (!timeout.is_zero()).then(|| start + timeout)
+}
+
+// Whichever of two deadlines comes first
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Run `future` to completion, or until `deadline` passes, in which case return None
+async fn before<F: std::future::Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")
+}
+
+// Count a connection to the given upstream. If the upstream is at its connection limit, wait for up
+// to the queue timeout (or until `deadline`) for a connection to free up, behind any waiting
+// requests of higher `priority` (low priority requests don't wait), failing with ResourceBusy if
+// none does.
+async fn reserve_connection(
+    state: &ProxyState,
+    upstream_idx: usize,
+    priority: concurrency::Priority,
+    deadline: Option<Instant>,
+) -> Result<upstream::ActiveConnection<'_>, std::io::Error> {
+    let upstream = &state.upstreams[upstream_idx];
+    let queue_deadline = Instant::now() + state.upstream_queue_timeout;
+    let queue_deadline = deadline.map_or(queue_deadline, |deadline| deadline.min(queue_deadline));
+    let queue_start = Instant::now();
+    let active = upstream::ActiveConnection::acquire(
+        &state.active_connections[upstream_idx],
+        state.connection_gates[upstream_idx].as_ref(),
+        queue_deadline,
+        priority,
+    )
+    .await;
+    state
+        .saturation
+        .record_queue_wait(upstream_idx, queue_start.elapsed());
+    active.ok_or_else(|| {
+        log::warn!(
+            "Upstream {} is at its limit of {} connections",
+            upstream.address,
+            upstream.max_connections
+        );
+        std::io::Error::new(std::io::ErrorKind::ResourceBusy, "at its connection limit")
+    })
+}
+
+// Record a failure to connect (or send a request) to the given upstream
+fn record_connect_failure(state: &ProxyState, upstream_idx: usize, err: &std::io::Error) {
+    let upstream_ip = &state.upstreams[upstream_idx].address;
+    log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+    let timed_out = err.kind() == std::io::ErrorKind::TimedOut;
+    state.saturation.record_attempt(upstream_idx, timed_out);
+    state.recent_failures.record_failure(upstream_idx);
+    state.health.report_failure(upstream_idx, upstream_ip, err);
+    state.breakers.record_failure(upstream_idx, upstream_ip);
+}
+
+// Record that the given upstream was reached, noting its recovery if it had recently failed
+fn record_connect_success(state: &ProxyState, upstream_idx: usize) {
+    if state.recent_failures.record_success(upstream_idx) {
+        log::info!(
+            "Upstream {} has recovered",
+            state.upstreams[upstream_idx].address
+        );
+        state.slow_start.mark_recovered(upstream_idx);
     }
 }
 
-// Open a connection to a random destination server
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let upstream_idx = rng.gen_range(0..state.upstream_addresses.len());
-    let upstream_ip = &state.upstream_addresses[upstream_idx];
-    TcpStream::connect(upstream_ip).await.or_else(|err| {
-        log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-        Err(err)
+// Open a connection to the given upstream server, giving up at the try timeout or `deadline`,
+// whichever comes first. If the upstream is at its connection limit, wait for a connection to free
+// up first (see reserve_connection).
+async fn connect_to_upstream(
+    state: &ProxyState,
+    upstream_idx: usize,
+    priority: concurrency::Priority,
+    deadline: Option<Instant>,
+) -> Result<UpstreamConnection<'_>, std::io::Error> {
+    let upstream = &state.upstreams[upstream_idx];
+    let upstream_ip = &upstream.address;
+    let active = reserve_connection(state, upstream_idx, priority, deadline).await?;
+    let connect_start = Instant::now();
+    if let Some(stream) = state.pool.take(upstream_idx) {
+        log::debug!("Reusing an idle connection to upstream {}", upstream_ip);
+        state.metrics.record_pooled_connection_reuse();
+        return Ok(UpstreamConnection {
+            stream: UpstreamStream::Tcp(stream),
+            idx: upstream_idx,
+            attempts: 1,
+            connect_time: connect_start.elapsed(),
+            attempt_start: Some(connect_start),
+            reusable: true,
+            _active: active,
+        });
+    }
+    let deadline = earliest(deadline_after(connect_start, state.try_timeout), deadline);
+    let dial = async {
+        match before(deadline, dial_upstream(state, upstream_ip)).await {
+            Some(dialed) => dialed.map_err(std::io::Error::other),
+            None => Err(timed_out()),
+        }
+    };
+    let dialed = match upstream.protocol {
+        upstream::Protocol::Http1 => dial.await.map(UpstreamStream::Tcp),
+        upstream::Protocol::H2c => state
+            .h2_connections
+            .open(upstream_idx, upstream_ip, dial)
+            .await
+            .map(UpstreamStream::Bridged),
+    };
+    let stream = dialed.inspect_err(|err| record_connect_failure(state, upstream_idx, err))?;
+    record_connect_success(state, upstream_idx);
+    Ok(UpstreamConnection {
+        stream,
+        idx: upstream_idx,
+        attempts: 1,
+        connect_time: connect_start.elapsed(),
+        attempt_start: Some(connect_start),
+        reusable: true,
+        _active: active,
     })
+}
+
+// Return a connection the client is done with to the pool, if it is between exchanges, or close it
+fn release_upstream(state: &ProxyState, connection: UpstreamConnection) {
+    if let UpstreamStream::Tcp(stream) = connection.stream {
+        if connection.reusable && !state.draining[connection.idx].load(Ordering::Relaxed) {
+            state.pool.put(connection.idx, stream);
+        }
+    }
+}
+
+// Whether the upstream will keep its connection open after this exchange, going by the Connection
+// header on its response (we never ask it to close)
+fn upstream_keeps_alive(response: &http::Response<Vec<u8>>) -> bool {
+    !request::connection_options(response.headers()).contains(&"close".to_string())
+}
+
+// Connect to an upstream the request hasn't tried yet, on behalf of a failed attempt: whichever
+// the strategy picks now that the failed one is avoided, or if it picks one we already tried, any
+// other upstream. Upstreams that refuse the connection are skipped over if connect failures are
+// retried. Gives up once every upstream has been tried, the retry policy won't let us try again,
+// or the request's `deadline` would pass before the next try; the error is the last connect
+// failure, if there was one, or a timeout if we ran out of time.
+async fn connect_elsewhere<'a>(
+    state: &'a ProxyState,
+    balancer: &strategy::Balancer,
+    request: &http::Request<Vec<u8>>,
+    client_ip: std::net::IpAddr,
+    tried: &mut Vec<usize>,
+    deadline: Option<Instant>,
+) -> Result<UpstreamConnection<'a>, Option<std::io::Error>> {
+    let mut last_error = None;
+    loop {
+        let picked = balancer.pick(request, client_ip, state);
+        let Some(idx) = (!tried.contains(&picked))
+            .then_some(picked)
+            .or_else(|| balancer.untried(state, tried))
+        else {
+            return Err(last_error);
+        };
+        let retry = tried.len();
+        let backoff = state.retries.backoff(retry);
+        if deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+            log::warn!("Not retrying request from {}: out of time", client_ip);
+            return Err(Some(timed_out()));
+        }
+        let allowed = state.retries.allow(retry);
+        state.metrics.record_retry(allowed);
+        if let Err(denied) = allowed {
+            log::warn!("Not retrying request from {}: {:?}", client_ip, denied);
+            return Err(last_error);
+        }
+        tokio::time::sleep(backoff).await;
+        tried.push(idx);
+        let priority = concurrency::classify(&state.priority_rules, request);
+        match connect_to_upstream(state, idx, priority, deadline).await {
+            Ok(mut connection) => {
+                connection.attempts = tried.len();
+                return Ok(connection);
+            }
+            Err(error) if state.retries.retry_on().connect_failure() => last_error = Some(error),
+            Err(error) => return Err(Some(error)),
+        }
+    }
+}
+
+// Send the request to `current` and read the whole response, hedging to a second upstream if the
+// headers are slow to arrive (`current` is replaced by the hedge's connection if it answers first).
+// Chunked, read-until-close and large bodies aren't read here; they are streamed to the client
+// afterwards. A large request body still on `client_conn` is piped to the upstream after the head.
+// Failures are reported to health checks and circuit breakers. The headers must arrive within the
+// try timeout and the whole response by the request's `deadline`; running out of time is a
+// TimedOut connection error.
+async fn exchange<'a>(
+    state: &'a ProxyState,
+    request: &http::Request<Vec<u8>>,
+    current: &mut UpstreamConnection<'a>,
+    client_conn: &mut ClientStream,
+    conn: &connections::ConnectionHandle,
+    timings: &mut timing::RequestTimings,
+    deadline: Option<Instant>,
+) -> Result<http::Response<Vec<u8>>, response::Error> {
+    let upstream_start = Instant::now();
+    let attempt_start = current.attempt_start.take().unwrap_or(upstream_start);
+    current.reusable = false;
+    let try_deadline = earliest(deadline_after(attempt_start, state.try_timeout), deadline);
+    let upstream_ip = &state.upstreams[current.idx].address;
+    let written = before(
+        try_deadline,
+        request::write_to_stream(request, &mut current.stream),
+    )
+    .await
+    .unwrap_or_else(|| Err(timed_out()));
+    let unread_body = request::unread_body_len(request);
+    let written = match written {
+        Ok(()) if unread_body > 0 => {
+            let body = request::forward_body(
+                client_conn,
+                &mut current.stream,
+                unread_body,
+                state.read_timeouts.idle,
+            );
+            match before(deadline, body).await {
+                Some(Ok(())) => Ok(()),
+                Some(Err(request::StreamError::Upstream(error))) => Err(error),
+                Some(Err(request::StreamError::Client(error))) => {
+                    log::info!("Client stopped sending its request body: {:?}", error);
+                    return Err(response::Error::ClientAborted(error));
+                }
+                None => Err(timed_out()),
+            }
+        }
+        written => written,
+    };
+    if let Err(error) = written {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_ip,
+            error
+        );
+        state
+            .health
+            .report_failure(current.idx, upstream_ip, &error);
+        state.breakers.record_failure(current.idx, upstream_ip);
+        let timed_out = error.kind() == std::io::ErrorKind::TimedOut;
+        state.saturation.record_attempt(current.idx, timed_out);
+        return Err(response::Error::ConnectionError(error));
+    }
+    timings.upstream_write = upstream_start.elapsed();
+    log::debug!("Forwarded request to server");
+
+    // Read the server's response
+    let response_start = Instant::now();
+    let (headers, hedge) = before(try_deadline, read_headers_hedged(state, request, current))
+        .await
+        .unwrap_or_else(|| (Err(response::Error::ConnectionError(timed_out())), None));
+    if let Some(hedge) = hedge {
+        // The hedge answered first, so the original connection is stuck mid-exchange
+        log::info!(
+            "Hedged request to {} answered before {}",
+            state.upstreams[hedge.idx].address,
+            upstream_ip
+        );
+        conn.set_upstream(Some(state.upstreams[hedge.idx].address.clone()));
+        *current = hedge;
+    }
+    let upstream_ip = &state.upstreams[current.idx].address;
+    let response = match headers {
+        Ok(mut response) => {
+            timings.ttfb = response_start.elapsed();
+            match response::streamed(request.method(), &response, state.stream_body_threshold) {
+                Some(response::Streamed::Length(len)) if len > state.max_response_body => {
+                    Err(response::Error::ResponseBodyTooLarge)
+                }
+                Some(_) => Ok(response),
+                None => {
+                    let body = response::read_remaining_body(
+                        &mut current.stream,
+                        request.method(),
+                        &mut response,
+                        state.max_response_body,
+                    );
+                    before(deadline, body)
+                        .await
+                        .unwrap_or_else(|| Err(response::Error::ConnectionError(timed_out())))
+                        .map(|()| response)
+                }
+            }
+        }
+        Err(error) => Err(error),
+    };
+    let timed_out = matches!(&response, Err(response::Error::ConnectionError(err))
+        if err.kind() == std::io::ErrorKind::TimedOut);
+    state.saturation.record_attempt(current.idx, timed_out);
+    match response {
+        Ok(response) => {
+            timings.transfer = response_start.elapsed() - timings.ttfb;
+            Ok(response)
+        }
+        Err(error) => {
+            log::error!("Error reading response from server: {:?}", error);
+            // A broken connection says the upstream is in trouble; a garbled response alone
+            // doesn't, so it doesn't take the upstream out of rotation
+            if let response::Error::ConnectionError(err) = &error {
+                state.health.report_failure(current.idx, upstream_ip, err);
+            }
+            state.breakers.record_failure(current.idx, upstream_ip);
+            Err(error)
+        }
+    }
+}
+
+// Read the response headers for a request that has been sent to `current`. If the request is
+// idempotent and the headers haven't arrived within the hedge delay, also send it to another
+// upstream, and take whichever answers first; the other attempt is abandoned, closing its
+// connection. Returns the headers, and the hedge's connection if it was the one to answer.
+async fn read_headers_hedged<'a>(
+    state: &'a ProxyState,
+    request: &http::Request<Vec<u8>>,
+    current: &mut UpstreamConnection<'a>,
+) -> (
+    Result<http::Response<Vec<u8>>, response::Error>,
+    Option<UpstreamConnection<'a>>,
+) {
+    let current_idx = current.idx;
+    let primary = response::read_headers(&mut current.stream);
+    tokio::pin!(primary);
+    // A streamed body has been used up, so it can't be sent to a second upstream
+    if state.hedge_delay.is_zero()
+        || !request.method().is_idempotent()
+        || request::unread_body_len(request) > 0
+    {
+        return (primary.await, None);
+    }
+    tokio::select! {
+        result = &mut primary => return (result, None),
+        _ = tokio::time::sleep(state.hedge_delay) => {}
+    }
+    // The hedge goes to another upstream in the same pool
+    let balancer = state.router.for_request(request);
+    let hedge_idx = match balancer.untried(state, &[current_idx]) {
+        Some(idx) => idx,
+        None => return (primary.await, None),
+    };
+    log::debug!(
+        "No response within {}ms, hedging to {}",
+        state.hedge_delay.as_millis(),
+        state.upstreams[hedge_idx].address
+    );
+    state.metrics.record_hedge(false);
+    let hedge = async {
+        let priority = concurrency::classify(&state.priority_rules, request);
+        let mut hedge = connect_to_upstream(state, hedge_idx, priority, None)
+            .await
+            .ok()?;
+        hedge.reusable = false;
+        request::write_to_stream(request, &mut hedge.stream)
+            .await
+            .ok()?;
+        let headers = response::read_headers(&mut hedge.stream).await.ok()?;
+        Some((headers, hedge))
+    };
+    tokio::pin!(hedge);
 
-    // TODO: implement failover
+    // If one attempt fails, keep waiting for the other
+    let mut primary_error = None;
+    let mut hedge_failed = false;
+    loop {
+        tokio::select! {
+            result = &mut primary, if primary_error.is_none() => match result {
+                Err(error) if !hedge_failed => primary_error = Some(error),
+                result => return (result, None),
+            },
+            outcome = &mut hedge, if !hedge_failed => match (outcome, primary_error.take()) {
+                (Some((headers, hedge)), _) => {
+                    state.metrics.record_hedge(true);
+                    return (Ok(headers), Some(hedge));
+                }
+                (None, Some(error)) => return (Err(error), None),
+                (None, None) => hedge_failed = true,
+            },
+        }
+    }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+// Add X-LB-* headers to a response describing how its request was balanced
+fn add_debug_headers(
+    response: &mut http::Response<Vec<u8>>,
+    state: &ProxyState,
+    upstream: &UpstreamConnection,
+    strategy: strategy::Strategy,
+    upstream_time: Duration,
+) {
+    let headers = [
+        (
+            "x-lb-upstream",
+            state.upstreams[upstream.idx].address.clone(),
+        ),
+        ("x-lb-strategy", strategy.name()),
+        ("x-lb-attempts", upstream.attempts.to_string()),
+        (
+            "x-lb-timing",
+            format!(
+                "connect={:.3}ms, upstream={:.3}ms",
+                upstream.connect_time.as_secs_f64() * 1000.0,
+                upstream_time.as_secs_f64() * 1000.0
+            ),
+        ),
+    ];
+    for (name, value) in headers {
+        response
+            .headers_mut()
+            .insert(name, http::HeaderValue::from_str(&value).unwrap());
+    }
+}
+
+// Returns false if the response could not be delivered
+async fn send_response(client_conn: &mut ClientStream, response: &http::Response<Vec<u8>>) -> bool {
+    let client_ip = client_address(client_conn).ip().to_string();
     log::info!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
 
-    if let Err(err) = response::write_to_stream(&response, client_conn).await {
+    if let Err(err) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", err);
-        return;
+        return false;
     }
+    true
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {client_ip}");
-
-    let mut upstream_conn = match connect_to_upstream(state.as_ref()).await {
-        Ok(stream) => stream,
-        Err(_) => {
+// Answer a CONNECT request by connecting to the host and port it names, if that port is allowed,
+// and relaying bytes both ways until either side hangs up
+async fn tunnel(
+    state: &ProxyState,
+    client_conn: &mut ClientStream,
+    request: &http::Request<Vec<u8>>,
+    client_ip: &str,
+    ports: &[u16],
+) {
+    let Some((target, port)) = request
+        .uri()
+        .authority()
+        .and_then(|target| Some((target.as_str(), target.port_u16()?)))
+    else {
+        log::info!("{} sent a CONNECT without a host and port", client_ip);
+        record_termination(state, client_ip, CloseReason::ProtocolError);
+        let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+        send_response(client_conn, &response).await;
+        return;
+    };
+    if !ports.contains(&port) {
+        log::warn!("Refusing to tunnel {} to {}", client_ip, target);
+        record_termination(state, client_ip, CloseReason::ConnectDenied);
+        let response = response::make_http_error(http::StatusCode::FORBIDDEN);
+        send_response(client_conn, &response).await;
+        return;
+    }
+    let deadline = deadline_after(Instant::now(), state.try_timeout);
+    let dialed = before(deadline, dial_upstream(state, target))
+        .await
+        .unwrap_or_else(|| Err("timed out".to_string()));
+    let mut target_conn = match dialed {
+        Ok(target_conn) => target_conn,
+        Err(err) => {
+            log::warn!("Failed to open a tunnel to {}: {}", target, err);
+            record_termination(state, client_ip, CloseReason::UpstreamConnectFail);
             let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+            send_response(client_conn, &response).await;
             return;
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    let established = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .body(Vec::new())
+        .unwrap();
+    if !send_response(client_conn, &established).await {
+        return;
+    }
+    // The client may not have waited for our answer before starting to send tunneled bytes
+    if let Err(err) = target_conn.write_all(request.body()).await {
+        log::info!("Tunnel from {} to {} failed: {}", client_ip, target, err);
+        return;
+    }
+    match tokio::io::copy_bidirectional(client_conn, &mut target_conn).await {
+        Ok((to_target, to_client)) => log::info!(
+            "Tunnel from {} to {} closed after {} bytes up and {} bytes down",
+            client_ip,
+            target,
+            to_target,
+            to_client
+        ),
+        Err(err) => log::info!("Tunnel from {} to {} failed: {}", client_ip, target, err),
+    }
+}
+
+// Log and count an exchange that ended without an upstream response reaching the client
+fn record_termination(state: &ProxyState, client_ip: &str, reason: CloseReason) {
+    log::warn!("{} exchange terminated: reason={}", client_ip, reason);
+    state.metrics.record_termination(reason);
+}
+
+// The client's address, with IPv4-mapped IPv6 addresses (from a dual-stack listener) turned back
+// into plain IPv4 addresses
+fn client_address(client_conn: &ClientStream) -> std::net::SocketAddr {
+    let mut addr = client_conn.peer_addr().unwrap();
+    addr.set_ip(addr.ip().to_canonical());
+    addr
+}
+
+async fn handle_connection(client_conn: TcpStream, state: Arc<ProxyState>, accepted_at: Instant) {
+    state.metrics.record_accept(accepted_at.elapsed());
+    let client_conn = ClientStream::Tcp(client_conn);
+    let client_addr = client_address(&client_conn);
+    if let penalty::Penalty::Banned(remaining) = state.penalties.check(client_addr.ip()) {
+        log::debug!(
+            "Refusing connection from banned client {} ({}s remaining)",
+            client_addr,
+            remaining.as_secs()
+        );
+        record_termination(&state, &client_addr.ip().to_string(), CloseReason::Banned);
+        return;
+    }
+    let Some(conn) = state
+        .connections
+        .register(client_addr, state.max_connections_per_ip)
+    else {
+        log::warn!(
+            "Refusing connection from {}: too many connections from that IP",
+            client_addr
+        );
+        record_termination(
+            &state,
+            &client_addr.ip().to_string(),
+            CloseReason::RateLimited,
+        );
+        state
+            .penalties
+            .record(client_addr.ip(), penalty::Offense::RateLimited);
+        return;
+    };
+    if state.http2 && http2::has_preface(&client_conn, &state).await {
+        let conn = Arc::new(conn);
+        tokio::select! {
+            _ = http2::serve(client_conn, state.clone(), conn.clone(), client_addr) => {}
+            _ = conn.closed() => {
+                log::warn!("Closing connection from {} at operator request", client_addr);
+                record_termination(&state, &client_addr.ip().to_string(), CloseReason::AdminClose);
+            }
+        }
+        return;
+    }
+    // The upstream connection outlives the client connection, so that it can go back to the pool
+    let mut upstream = None;
+    tokio::select! {
+        _ = proxy_connection(client_conn, &state, &conn, &mut upstream) => {}
+        _ = conn.closed() => {
+            log::warn!("Closing connection from {} at operator request", client_addr);
+            record_termination(&state, &client_addr.ip().to_string(), CloseReason::AdminClose);
+        }
+    }
+    if let Some(upstream) = upstream {
+        release_upstream(&state, upstream);
+    }
+}
+
+/// Slows down a client that has been misbehaving, before its next request is read. Returns false
+/// if the client is banned, and should be hung up on.
+async fn apply_penalty(state: &ProxyState, client_addr: std::net::SocketAddr) -> bool {
+    match state.penalties.check(client_addr.ip()) {
+        penalty::Penalty::None => true,
+        penalty::Penalty::Tarpit(delay) => {
+            log::debug!(
+                "Tarpitting {} for {}ms",
+                client_addr.ip(),
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            true
+        }
+        penalty::Penalty::Banned(_) => {
+            record_termination(state, &client_addr.ip().to_string(), CloseReason::Banned);
+            false
+        }
+    }
+}
+
+/// A request let through by `admit`. It counts as in flight, and holds a slot on its route if the
+/// route is limited, until this is dropped.
+struct Admission<'a> {
+    priority: concurrency::Priority,
+    // What is left of the client's rate limit quota, if it has one
+    quota: Option<ratelimit::Quota>,
+    _in_flight: concurrency::InFlightRequest<'a>,
+    _route_permit: Option<concurrency::Permit>,
+}
+
+/// Decides whether a request may be proxied at all, checking maintenance windows, rate limits,
+/// allowed methods, the in-flight cap and route concurrency limits, in that order. Every request
+/// goes through this, whether it is proxied over HTTP/1.1 or as a gRPC call. A rejected request is
+/// recorded, and the response to answer it with is returned.
+async fn admit<'a>(
+    state: &'a ProxyState,
+    request: &http::Request<Vec<u8>>,
+    client_addr: std::net::SocketAddr,
+) -> Result<Admission<'a>, http::Response<Vec<u8>>> {
+    let client_ip = client_addr.ip().to_string();
+    if let Some(retry_after) = state
+        .maintenance
+        .check(request.uri().path(), std::time::SystemTime::now())
+    {
+        record_termination(state, &client_ip, CloseReason::Maintenance);
+        let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+        response
+            .headers_mut()
+            .insert("retry-after", http::HeaderValue::from(retry_after));
+        return Err(response);
+    }
+
+    let rate_limited = match state.rate_limiter.check(client_addr.ip()).await {
+        Ok(quota) => state
+            .route_rate_limiter
+            .check(client_addr.ip(), request)
+            .map(|route_quota| ratelimit::Quota::tightest([quota, route_quota]))
+            .map_err(|(prefix, throttled)| {
+                log::warn!("Rate limiting {} for route {}", client_ip, prefix);
+                throttled
+            }),
+        Err(throttled) => {
+            log::warn!("Rate limiting {}", client_ip);
+            Err(throttled)
+        }
+    };
+    let quota = match rate_limited {
+        Ok(quota) => quota,
+        Err(throttled) => {
+            record_termination(state, &client_ip, CloseReason::RateLimited);
+            state
+                .penalties
+                .record(client_addr.ip(), penalty::Offense::RateLimited);
+            let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            response.headers_mut().insert(
+                "retry-after",
+                http::HeaderValue::from(throttled.retry_after.as_secs_f64().ceil().max(1.0) as u64),
+            );
+            throttled.quota.add_headers(response.headers_mut());
+            return Err(response);
+        }
+    };
+
+    if !state.allowed_methods.is_empty() && !state.allowed_methods.contains(request.method()) {
+        log::info!(
+            "Rejecting a {} request from {}, as the method isn't allowed",
+            request.method(),
+            client_ip
+        );
+        record_termination(state, &client_ip, CloseReason::MethodNotAllowed);
+        let mut response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+        let allow: Vec<&str> = state.allowed_methods.iter().map(|m| m.as_str()).collect();
+        if let Ok(allow) = http::HeaderValue::from_str(&allow.join(", ")) {
+            response.headers_mut().insert(http::header::ALLOW, allow);
+        }
+        return Err(response);
+    }
+
+    // Count the request as in flight until its response has been forwarded
+    let priority = concurrency::classify(&state.priority_rules, request);
+    let Some(in_flight) = state.in_flight.try_start(priority) else {
+        log::warn!(
+            "Too many requests in flight, shedding {:?} priority request from {}",
+            priority,
+            client_ip
+        );
+        record_termination(state, &client_ip, CloseReason::LoadShed);
+        return Err(response::make_http_error(
+            http::StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    };
+
+    // Hold a slot for this request's route until its response has been forwarded, so that one
+    // expensive endpoint can't consume all of the upstream capacity.
+    let route_permit = match state
+        .route_limiter
+        .acquire(request.uri().path(), priority)
+        .await
+    {
+        Ok(permit) => permit,
+        Err(prefix) => {
+            log::warn!(
+                "Too many concurrent requests for route {}, rejecting {:?} priority request",
+                prefix,
+                priority
+            );
+            record_termination(state, &client_ip, CloseReason::RateLimited);
+            state
+                .penalties
+                .record(client_addr.ip(), penalty::Offense::RateLimited);
+            return Err(response::make_http_error(
+                http::StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        }
+    };
+
+    Ok(Admission {
+        priority,
+        quota,
+        _in_flight: in_flight,
+        _route_permit: route_permit,
+    })
+}
+
+async fn proxy_connection<'a>(
+    mut client_conn: ClientStream,
+    state: &'a ProxyState,
+    conn: &connections::ConnectionHandle,
+    upstream: &mut Option<UpstreamConnection<'a>>,
+) {
+    let client_addr = client_address(&client_conn);
+    let client_ip = client_addr.ip().to_string();
+    log::info!("Connection received from {client_ip}");
+    let show_debug_headers =
+        state.debug_headers || cidr::any_contains(&state.debug_headers_cidrs, client_addr.ip());
+
+    // We don't connect to an upstream until the first request has been parsed, since some
+    // strategies choose an upstream based on the contents of the request.
+    // The balancer that chose the current upstream
+    let mut chosen_by: Option<&strategy::Balancer> = None;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    // Set while a request whose body was left on the connection for streaming hasn't been
+    // forwarded, e.g. because it was answered with a 429
+    let mut unforwarded_body = false;
     loop {
+        // The unread body can't be told apart from the next request, so give up on the connection
+        if unforwarded_body {
+            log::debug!(
+                "Closing connection from {}: a streamed request body was never forwarded",
+                client_ip
+            );
+            return;
+        }
+
+        // Once we're shutting down, let the client go between requests. If it has already started
+        // sending another request, that request is still served.
+        tokio::select! {
+            biased;
+            _ = client_conn.readable() => {}
+            _ = state.shutdown.wait() => {
+                log::debug!("Closing idle connection from {} for shutdown", client_ip);
+                return;
+            }
+        }
+
+        // Slow down or drop clients that have been misbehaving, before reading their next request
+        if !apply_penalty(state, client_addr).await {
+            return;
+        }
+
         // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        let mut timings = timing::RequestTimings::default();
+        let read_start = Instant::now();
+        let mut request = match request::read_from_stream(
+            &mut client_conn,
+            state.duplicate_header_policy,
+            state.header_limits,
+            state.read_timeouts,
+            state.max_request_body,
+            state.stream_body_threshold,
+        )
+        .await
+        {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests.
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
+            // Answer a client that is too slow to send its request, then hang up on it, since the
+            // rest of the request may still be on its way
+            Err(request::Error::RequestTimeout) => {
+                log::info!("Timed out reading request from {}", client_ip);
+                record_termination(state, &client_ip, CloseReason::ClientTimeout);
+                let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                response
+                    .headers_mut()
+                    .insert("connection", http::HeaderValue::from_static("close"));
+                send_response(&mut client_conn, &response).await;
+                return;
+            }
+            // We stopped reading partway through an oversized head, so the rest of it can't be
+            // told apart from the next request; answer, then hang up
+            Err(request::Error::HeaderFieldsTooLarge) => {
+                log::info!("Request head from {} is over our limits", client_ip);
+                record_termination(state, &client_ip, CloseReason::ProtocolError);
+                let mut response =
+                    response::make_http_error(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                response
+                    .headers_mut()
+                    .insert("connection", http::HeaderValue::from_static("close"));
+                send_response(&mut client_conn, &response).await;
+                return;
+            }
             // Handle I/O error in reading from the client
             Err(request::Error::ConnectionError(io_err)) => {
                 log::info!("Error reading request from client stream: {}", io_err);
+                record_termination(state, &client_ip, CloseReason::ClientAbort);
                 return;
             }
             Err(error) => {
                 log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
+                // We didn't read the whole body, or can't tell where it ends, so the rest of it
+                // can't be told apart from the next request; answer, then hang up
+                let hang_up = matches!(
+                    error,
+                    request::Error::RequestBodyTooLarge
+                        | request::Error::AmbiguousLength
+                        | request::Error::AmbiguousFraming(_)
+                        | request::Error::UnsupportedTransferEncoding
+                        | request::Error::InvalidChunkedBody
+                );
+                let (status, reason) = match error {
+                    // The client hung up partway through sending the request
+                    request::Error::IncompleteRequest(_) => {
+                        (http::StatusCode::BAD_REQUEST, CloseReason::ClientAbort)
+                    }
+                    request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::HeaderFieldsTooLarge
+                    | request::Error::AmbiguousLength
+                    | request::Error::AmbiguousFraming(_)
+                    | request::Error::InvalidChunkedBody
+                    | request::Error::DuplicateHeader(_) => {
+                        (http::StatusCode::BAD_REQUEST, CloseReason::ProtocolError)
+                    }
+                    request::Error::UnsupportedTransferEncoding => (
+                        http::StatusCode::NOT_IMPLEMENTED,
+                        CloseReason::ProtocolError,
+                    ),
+                    request::Error::RequestBodyTooLarge => (
+                        http::StatusCode::PAYLOAD_TOO_LARGE,
+                        CloseReason::BodyTooLarge,
+                    ),
+                    request::Error::ConnectionError(_) | request::Error::RequestTimeout => (
+                        http::StatusCode::SERVICE_UNAVAILABLE,
+                        CloseReason::ClientAbort,
+                    ),
+                };
+                record_termination(state, &client_ip, reason);
+                if reason == CloseReason::ProtocolError {
+                    state
+                        .penalties
+                        .record(client_addr.ip(), penalty::Offense::MalformedRequest);
+                }
+                let mut response = response::make_http_error(status);
+                if hang_up {
+                    response
+                        .headers_mut()
+                        .insert("connection", http::HeaderValue::from_static("close"));
+                    send_response(&mut client_conn, &response).await;
+                    return;
+                }
+                send_response(&mut client_conn, &response).await;
+                continue;
+            }
+        };
+        // Once a streamed request body has been sent, there is no copy of it to send again
+        let body_streamed = request::unread_body_len(&request) > 0;
+        unforwarded_body = body_streamed;
+        let admission = match admit(state, &request, client_addr).await {
+            Ok(admission) => admission,
+            Err(response) => {
                 send_response(&mut client_conn, &response).await;
                 continue;
             }
         };
+        let priority = admission.priority;
+
+        if request.method() == http::Method::CONNECT {
+            if let Some(ports) = &state.connect_ports {
+                // A tunnel isn't a request in flight, and may stay open for as long as the client
+                // likes
+                drop(admission);
+                tunnel(state, &mut client_conn, &request, &client_ip, ports).await;
+                return;
+            }
+        }
+
+        // A request that has been through us before was sent back to us by an upstream, and would
+        // keep coming back
+        if let Some(via_name) = &state.via_name {
+            if request::via_includes(request.headers(), via_name) {
+                log::warn!("Request from {} has already been through us", client_ip);
+                record_termination(state, &client_ip, CloseReason::LoopDetected);
+                let response = response::make_http_error(http::StatusCode::LOOP_DETECTED);
+                send_response(&mut client_conn, &response).await;
+                continue;
+            }
+        }
+
+        // A retried non-idempotent request that reuses an Idempotency-Key must not reach the
+        // upstreams a second time.
+        let idempotency_reservation = match state.idempotency.check(&request, client_addr.ip()) {
+            idempotency::Check::Forward(reservation) => reservation,
+            idempotency::Check::Replay(response) => {
+                log::info!(
+                    "Replaying response to a duplicate request from {}",
+                    client_ip
+                );
+                if !send_response(&mut client_conn, &response).await {
+                    record_termination(state, &client_ip, CloseReason::ClientAbort);
+                    return;
+                }
+                continue;
+            }
+            idempotency::Check::Duplicate => {
+                record_termination(state, &client_ip, CloseReason::DuplicateRequest);
+                let response = response::make_http_error(http::StatusCode::CONFLICT);
+                send_response(&mut client_conn, &response).await;
+                continue;
+            }
+        };
+
+        // A connection normally stays with the upstream that its first request went to, but
+        // per-request strategies may move it if this request maps to a different upstream, and
+        // a request for a route with its own strategy is balanced by that strategy.
+        // A request carrying a sticky-session cookie, or from a client IP with an affinity
+        // assignment, goes back to its upstream. Either way, an upstream that is known to be down,
+        // has been ejected, has its circuit breaker open or is draining is given up on, except that
+        // with --drain-migration next_response a cookie keeps its draining upstream for one more
+        // request, whose response moves the cookie elsewhere.
+        let balancer = state.router.route(&mut request);
+        let available = |idx: usize| {
+            balancer.includes(idx)
+                && !state.recent_failures.is_recently_failed(idx)
+                && !state.health.is_dead(idx)
+                && !state.outliers.is_ejected(idx)
+                && state.breakers.is_available(idx)
+        };
+        let usable = |idx: usize| available(idx) && !state.draining[idx].load(Ordering::Relaxed);
+        let cookie_idx = state
+            .cookie_affinity
+            .as_ref()
+            .and_then(|affinity| affinity.lookup(&request));
+        let pinned_idx = cookie_idx
+            .or_else(|| {
+                state
+                    .ip_affinity
+                    .as_ref()
+                    .and_then(|affinity| affinity.lookup(client_addr.ip()))
+            })
+            .filter(|&idx| {
+                usable(idx)
+                    || (Some(idx) == cookie_idx
+                        && state.drain_migration == affinity::DrainMigration::NextResponse
+                        && available(idx))
+            });
+        let upstream_idx = match (pinned_idx, &upstream, chosen_by) {
+            (Some(idx), _, _) => idx,
+            (None, Some(current), Some(previous))
+                if std::ptr::eq(previous, balancer)
+                    && !balancer.per_request()
+                    && usable(current.idx) =>
+            {
+                current.idx
+            }
+            _ => balancer.pick(&request, client_addr.ip(), state),
+        };
+        timings.client_read = read_start.elapsed();
+        chosen_by = Some(balancer);
+        state.retries.record_request();
+        let deadline = deadline_after(Instant::now(), state.request_timeout);
+        let mut tried = vec![upstream_idx];
+        if upstream.as_ref().map(|current| current.idx) != Some(upstream_idx) {
+            let connect_start = Instant::now();
+            let mut connected = connect_to_upstream(state, upstream_idx, priority, deadline).await;
+            if let Err(error) = connected {
+                connected = if state.retries.retry_on().connect_failure() {
+                    let client_ip = client_addr.ip();
+                    connect_elsewhere(state, balancer, &request, client_ip, &mut tried, deadline)
+                        .await
+                        .map_err(|later| later.unwrap_or(error))
+                } else {
+                    Err(error)
+                };
+            }
+            timings.connect = connect_start.elapsed();
+            match connected {
+                Ok(new_upstream) => {
+                    if let Some(affinity) = &state.ip_affinity {
+                        affinity.assign(client_addr.ip(), new_upstream.idx);
+                    }
+                    conn.set_upstream(Some(state.upstreams[new_upstream.idx].address.clone()));
+                    if let Some(previous) = upstream.replace(new_upstream) {
+                        release_upstream(state, previous);
+                    }
+                }
+                Err(error) => {
+                    let (status, reason) = match error.kind() {
+                        std::io::ErrorKind::TimedOut => (
+                            http::StatusCode::GATEWAY_TIMEOUT,
+                            CloseReason::UpstreamTimeout,
+                        ),
+                        std::io::ErrorKind::ResourceBusy => (
+                            http::StatusCode::SERVICE_UNAVAILABLE,
+                            CloseReason::UpstreamBusy,
+                        ),
+                        _ => (
+                            http::StatusCode::BAD_GATEWAY,
+                            CloseReason::UpstreamConnectFail,
+                        ),
+                    };
+                    record_termination(state, &client_ip, reason);
+                    let response = match &state.fallback {
+                        Some(fallback) => fallback.to_response(),
+                        None => response::make_http_error(status),
+                    };
+                    send_response(&mut client_conn, &response).await;
+                    return;
+                }
+            }
+        }
+        let current_upstream = upstream.as_mut().unwrap();
+        let upstream_ip = &state.upstreams[current_upstream.idx].address;
         log::info!(
             "{} -> {}: {}",
             client_ip,
@@ -160,36 +2345,317 @@ async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
             request::format_request_line(&request)
         );
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // The client's Connection header is about its connection to us, not ours to the upstream;
+        // only a protocol switch involves the upstream too
+        let client_options = request::connection_options(request.headers());
+        let client_close = client_options.contains(&"close".to_string());
+        let upgrading = request.headers().contains_key(http::header::UPGRADE)
+            && client_options.contains(&"upgrade".to_string());
+        request::strip_hop_by_hop_headers(request.headers_mut(), upgrading);
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        // Add forwarding headers so that the upstream server knows the client's IP address.
+        let trusted = cidr::any_contains(&state.trusted_proxies, client_addr.ip());
+        request::set_forwarding_headers(&mut request, client_addr.ip(), trusted);
+        if let Some(via_name) = &state.via_name {
+            request::extend_header_value(&mut request, "via", &format!("1.1 {}", via_name));
+        }
+        state.header_injector.apply(&mut request);
+        if let Some(pool) = state.router.routed_pool(&request) {
+            state.rewriter.apply(&mut request, pool);
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
+        let (mut response, upstream_time) = loop {
+            // Forward the request to the server
+            let upstream_ip = &state.upstreams[current_upstream.idx].address;
+            state
+                .breakers
+                .record_attempt(current_upstream.idx, upstream_ip);
+            let in_flight =
+                state.request_load[current_upstream.idx].start(state.request_load_decay);
+            let upstream_start = Instant::now();
+            let attempt = exchange(
+                state,
+                &request,
+                current_upstream,
+                &mut client_conn,
+                conn,
+                &mut timings,
+                deadline,
+            )
+            .await;
+            drop(in_flight);
+            let upstream_time = upstream_start.elapsed();
+            let upstream_ip = &state.upstreams[current_upstream.idx].address;
+            let mut turned_away = false;
+            if let Ok(response) = &attempt {
+                turned_away =
+                    state
+                        .outliers
+                        .record_overload(current_upstream.idx, upstream_ip, response);
+                state.latency[current_upstream.idx].record(upstream_time, state.ewma_decay);
+                state
+                    .outliers
+                    .record(current_upstream.idx, upstream_ip, response.status());
+                if response.status().is_server_error() {
+                    state
+                        .breakers
+                        .record_failure(current_upstream.idx, upstream_ip);
+                } else {
+                    state
+                        .breakers
+                        .record_success(current_upstream.idx, upstream_ip);
+                }
+            }
+
+            // Once the request has been sent, the upstream may have acted on it, so only
+            // idempotent requests are worth sending again, unless it told us it turned the
+            // request away
+            let retry_on = state.retries.retry_on();
+            let mut out_of_time = matches!(
+                &attempt,
+                Err(response::Error::ConnectionError(err))
+                    if err.kind() == std::io::ErrorKind::TimedOut
+            );
+            let worth_retrying = !body_streamed
+                && (turned_away
+                    || request.method().is_idempotent()
+                        && match &attempt {
+                            Ok(response) => retry_on.status(response.status()),
+                            Err(response::Error::ConnectionError(_)) if out_of_time => {
+                                retry_on.timeout()
+                            }
+                            Err(response::Error::ConnectionError(_)) => retry_on.reset(),
+                            Err(_) => false,
+                        });
+            if worth_retrying {
+                let client_ip = client_addr.ip();
+                match connect_elsewhere(state, balancer, &request, client_ip, &mut tried, deadline)
+                    .await
+                {
+                    Ok(next) => {
+                        log::info!(
+                            "Retrying request from {} on {}",
+                            client_ip,
+                            state.upstreams[next.idx].address
+                        );
+                        conn.set_upstream(Some(state.upstreams[next.idx].address.clone()));
+                        *current_upstream = next;
+                        continue;
+                    }
+                    Err(Some(error)) if error.kind() == std::io::ErrorKind::TimedOut => {
+                        out_of_time = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            match attempt {
+                Ok(response) => break (response, upstream_time),
+                // The client is gone, or too slow to be worth waiting for; either way the request
+                // is incomplete, so there is nothing to forward
+                Err(response::Error::ClientAborted(request::Error::RequestTimeout)) => {
+                    record_termination(state, &client_ip, CloseReason::ClientTimeout);
+                    let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                    response
+                        .headers_mut()
+                        .insert("connection", http::HeaderValue::from_static("close"));
+                    send_response(&mut client_conn, &response).await;
+                    return;
+                }
+                Err(response::Error::ClientAborted(_)) => {
+                    record_termination(state, &client_ip, CloseReason::ClientAbort);
+                    return;
+                }
+                Err(error) => {
+                    let (status, reason) = if out_of_time {
+                        (
+                            http::StatusCode::GATEWAY_TIMEOUT,
+                            CloseReason::UpstreamTimeout,
+                        )
+                    } else if matches!(error, response::Error::ResponseBodyTooLarge) {
+                        (http::StatusCode::BAD_GATEWAY, CloseReason::BodyTooLarge)
+                    } else {
+                        (http::StatusCode::BAD_GATEWAY, CloseReason::UpstreamError)
+                    };
+                    record_termination(state, &client_ip, reason);
+                    let response = response::make_http_error(status);
+                    send_response(&mut client_conn, &response).await;
+                    return;
+                }
             }
         };
+        unforwarded_body = false;
+        let upstream_ip = &state.upstreams[current_upstream.idx].address;
+        // Whether the upstream agreed to switch protocols (e.g. to WebSocket), after which the
+        // connection no longer carries HTTP
+        let upgraded = response.status() == http::StatusCode::SWITCHING_PROTOCOLS
+            && request.headers().contains_key(http::header::UPGRADE);
+        // Whether the rest of the body is still to be copied from the upstream, after the headers
+        let streamed = response::streamed(request.method(), &response, state.stream_body_threshold);
+        let keeps_alive = upstream_keeps_alive(&response);
+        request::strip_hop_by_hop_headers(response.headers_mut(), upgraded);
+        if let Some(via_name) = &state.via_name {
+            response.headers_mut().append(
+                http::header::VIA,
+                http::HeaderValue::from_str(&format!("1.1 {}", via_name)).unwrap(),
+            );
+        }
+        if matches!(
+            response.status(),
+            http::StatusCode::UNAUTHORIZED | http::StatusCode::FORBIDDEN
+        ) {
+            state
+                .penalties
+                .record(client_addr.ip(), penalty::Offense::AuthFailure);
+        }
+        if let Some(capture) = state
+            .capture
+            .as_ref()
+            .filter(|_| streamed.is_none() && !body_streamed)
+        {
+            capture.record(&request, &response, client_addr.ip(), upstream_ip);
+        }
+
+        // Pin the session to this upstream if it isn't already, or if this upstream is draining, to
+        // the one the session's next request should go to instead
+        if let Some(affinity) = &state.cookie_affinity {
+            let mut pin_to = current_upstream.idx;
+            if state.draining[pin_to].load(Ordering::Relaxed) {
+                pin_to = balancer.pick(&request, client_addr.ip(), state);
+                log::info!(
+                    "Moving a session off draining upstream {} to {}",
+                    upstream_ip,
+                    state.upstreams[pin_to].address
+                );
+            }
+            if affinity.lookup(&request) != Some(pin_to) {
+                response::add_cookie(
+                    &mut response,
+                    affinity::COOKIE_NAME,
+                    affinity.cookie_value(pin_to),
+                    "Path=/; HttpOnly",
+                );
+            }
+        }
+
+        // A streamed body isn't held in memory, so there is nothing to replay to duplicates;
+        // dropping the reservation frees the key instead
+        if streamed.is_none() {
+            idempotency_reservation.complete(&response);
+        } else {
+            drop(idempotency_reservation);
+        }
+
+        if show_debug_headers {
+            add_debug_headers(
+                &mut response,
+                state,
+                current_upstream,
+                balancer.strategy,
+                upstream_time,
+            );
+        }
+
+        if state.server_timing {
+            response.headers_mut().insert(
+                "server-timing",
+                http::HeaderValue::from_str(&timings.server_timing()).unwrap(),
+            );
+        }
+
+        if let Some(quota) = admission.quota.filter(|_| state.rate_limit_headers) {
+            quota.add_headers(response.headers_mut());
+        }
+
+        state.response_headers.apply(&mut response);
 
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
+        // A body that ends when the upstream hangs up can only be passed on by hanging up too, and
+        // a client that asked to close gets its wish once it has the response
+        let hanging_up = client_close
+            || state.shutdown.is_started()
+            || streamed == Some(response::Streamed::UntilClose);
+        if hanging_up && !upgraded {
+            response
+                .headers_mut()
+                .insert("connection", http::HeaderValue::from_static("close"));
+        }
+        if !send_response(&mut client_conn, &response).await {
+            record_termination(state, &client_ip, CloseReason::ClientAbort);
+            return;
+        }
+        // Past the 101, relay bytes both ways until either side hangs up. (Anything the upstream
+        // sent right behind its response head went out with it, as the response body.) The tunnel
+        // isn't a request in flight, and isn't subject to the request timeout.
+        if upgraded {
+            drop(admission);
+            conn.record_request();
+            log::info!("{} timing: {}", client_ip, timings.log_fields());
+            match tokio::io::copy_bidirectional(&mut client_conn, &mut current_upstream.stream)
+                .await
+            {
+                Ok((to_upstream, to_client)) => log::info!(
+                    "{} upgraded connection closed after {} bytes up and {} bytes down",
+                    client_ip,
+                    to_upstream,
+                    to_client
+                ),
+                Err(err) => log::info!("{} upgraded connection failed: {}", client_ip, err),
+            }
+            return;
+        }
+        if let Some(streamed) = streamed {
+            let stream_start = Instant::now();
+            // A response that goes on for as long as the upstream has something to say, e.g.
+            // server-sent events or a long poll, gets its own time limit instead
+            let open_ended = !matches!(streamed, response::Streamed::Length(_))
+                || response::is_event_stream(&response);
+            let stream_deadline = if open_ended {
+                deadline_after(stream_start, state.max_stream_duration)
+            } else {
+                deadline
+            };
+            let body = response::stream_body(
+                &mut current_upstream.stream,
+                &mut client_conn,
+                response.body(),
+                streamed,
+            );
+            let result = match before(stream_deadline, body).await {
+                Some(result) => result,
+                None if open_ended => {
+                    log::info!(
+                        "Cutting off response from {} to {} at the maximum stream duration",
+                        upstream_ip,
+                        client_ip
+                    );
+                    record_termination(state, &client_ip, CloseReason::StreamTimeout);
+                    return;
+                }
+                None => Err(response::StreamError::Upstream(
+                    response::Error::ConnectionError(timed_out()),
+                )),
+            };
+            timings.transfer += stream_start.elapsed();
+            match result {
+                Ok(()) => {}
+                Err(response::StreamError::Upstream(error)) => {
+                    log::error!("Error streaming response from {}: {:?}", upstream_ip, error);
+                    record_termination(state, &client_ip, CloseReason::UpstreamError);
+                    return;
+                }
+                Err(response::StreamError::Client(err)) => {
+                    log::warn!("Failed to stream response to client: {}", err);
+                    record_termination(state, &client_ip, CloseReason::ClientAbort);
+                    return;
+                }
+            }
+        }
+        current_upstream.reusable = streamed != Some(response::Streamed::UntilClose) && keeps_alive;
+        conn.record_request();
+        log::info!("{} timing: {}", client_ip, timings.log_fields());
         log::debug!("Forwarded response to client");
+        if hanging_up {
+            return;
+        }
     }
 }
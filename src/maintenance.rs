@@ -0,0 +1,145 @@
+use crate::routing;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily time window, parsed from `HH:MM-HH:MM`. The start is inclusive and the end exclusive;
+/// a window whose end is before its start wraps past midnight (e.g. `22:00-02:00`).
+#[derive(Clone, Copy, Debug)]
+pub struct TimeWindow {
+    // Minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+fn parse_time_of_day(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    let minute_of_day = hours * 60 + minutes;
+    (minutes < 60 && minute_of_day <= MINUTES_PER_DAY).then_some(minute_of_day)
+}
+
+impl std::str::FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeWindow, String> {
+        let invalid = || format!("invalid time window {:?}, expected HH:MM-HH:MM", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time_of_day(start).ok_or_else(invalid)?;
+        let end = parse_time_of_day(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("time window {:?} is empty", s));
+        }
+        Ok(TimeWindow { start, end })
+    }
+}
+
+impl TimeWindow {
+    /// If `minute_of_day` falls inside the window, returns how many minutes remain until it ends.
+    fn remaining(&self, minute_of_day: u32) -> Option<u32> {
+        let since_start = (minute_of_day + MINUTES_PER_DAY - self.start) % MINUTES_PER_DAY;
+        let length = (self.end + MINUTES_PER_DAY - self.start) % MINUTES_PER_DAY;
+        let length = if length == 0 { MINUTES_PER_DAY } else { length };
+        (since_start < length).then(|| length - since_start)
+    }
+
+    /// Whether `minute_of_day` falls inside the window.
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        self.remaining(minute_of_day).is_some()
+    }
+}
+
+/// A fixed offset from UTC, parsed from `UTC`, `Z`, `+HH:MM` or `-HH:MM`.
+#[derive(Clone, Copy, Debug)]
+pub struct UtcOffset {
+    seconds: i64,
+}
+
+impl std::str::FromStr for UtcOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UtcOffset, String> {
+        if s == "UTC" || s == "Z" {
+            return Ok(UtcOffset { seconds: 0 });
+        }
+        let invalid = || format!("invalid UTC offset {:?}, expected UTC, +HH:MM or -HH:MM", s);
+        let (sign, offset) = match s.split_at_checked(1) {
+            Some(("+", offset)) => (1, offset),
+            Some(("-", offset)) => (-1, offset),
+            _ => return Err(invalid()),
+        };
+        let minutes = parse_time_of_day(offset)
+            .filter(|minutes| *minutes <= 14 * 60)
+            .ok_or_else(invalid)?;
+        Ok(UtcOffset {
+            seconds: sign * minutes as i64 * 60,
+        })
+    }
+}
+
+impl UtcOffset {
+    /// Returns the number of seconds since midnight at `now`, in this timezone.
+    pub fn secs_of_day(&self, now: SystemTime) -> u64 {
+        let local_secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0)
+            + self.seconds;
+        local_secs.rem_euclid(MINUTES_PER_DAY as i64 * 60) as u64
+    }
+}
+
+/// A scheduled maintenance window for requests whose path starts with `prefix`, parsed from
+/// `[PATH_PREFIX=]HH:MM-HH:MM`. Without a prefix, the window applies to every request.
+#[derive(Clone, Debug)]
+pub struct MaintenanceWindow {
+    prefix: String,
+    window: TimeWindow,
+}
+
+impl std::str::FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MaintenanceWindow, String> {
+        let (prefix, window) = match s.split_once('=') {
+            Some((prefix, window)) => {
+                if !prefix.starts_with('/') {
+                    return Err(format!("route prefix {:?} must start with '/'", prefix));
+                }
+                (prefix, window)
+            }
+            None => ("/", s),
+        };
+        Ok(MaintenanceWindow {
+            prefix: prefix.to_string(),
+            window: window.parse()?,
+        })
+    }
+}
+
+/// Decides whether a request falls inside a scheduled maintenance window, so that it can be
+/// answered with a maintenance response instead of being forwarded.
+pub struct MaintenanceSchedule {
+    windows: Vec<MaintenanceWindow>,
+    // Timezone the windows are expressed in
+    offset: UtcOffset,
+}
+
+impl MaintenanceSchedule {
+    pub fn new(windows: Vec<MaintenanceWindow>, offset: UtcOffset) -> MaintenanceSchedule {
+        MaintenanceSchedule { windows, offset }
+    }
+
+    /// If a maintenance window currently applies to `path`, returns the number of seconds until
+    /// it ends (the longest remaining time, if several windows overlap).
+    pub fn check(&self, path: &str, now: SystemTime) -> Option<u64> {
+        let secs_of_day = self.offset.secs_of_day(now);
+        let minute_of_day = (secs_of_day / 60) as u32;
+        self.windows
+            .iter()
+            .filter(|window| routing::strip_path_prefix(path, &window.prefix).is_some())
+            .filter_map(|window| window.window.remaining(minute_of_day))
+            .max()
+            .map(|minutes| (minutes as u64 * 60).saturating_sub(secs_of_day % 60))
+    }
+}
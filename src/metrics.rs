@@ -0,0 +1,294 @@
+use crate::{listener, retry};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Why a request/response exchange ended without a response from an upstream being delivered to
+/// the client. Attached to log lines and used as a metrics label, so that failures can be
+/// attributed to the client, the upstreams, or the balancer's own limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    // The client hung up or errored partway through an exchange
+    ClientAbort,
+    // The client took too long to send its request
+    ClientTimeout,
+    // We could not open a connection to the chosen upstream
+    UpstreamConnectFail,
+    // The upstream connection failed while sending the request or reading the response
+    UpstreamError,
+    // An attempt, or the request as a whole, ran out of time waiting on upstreams
+    UpstreamTimeout,
+    // Every upstream we could send the request to was at its connection limit
+    UpstreamBusy,
+    // The request or response body exceeded the maximum size we are willing to buffer
+    BodyTooLarge,
+    // The request was rejected by a concurrency or rate limit
+    RateLimited,
+    // The request was shed because too many requests were in flight
+    LoadShed,
+    // The request was not valid HTTP, or violated our header rules
+    ProtocolError,
+    // An operator closed the connection through the admin API
+    AdminClose,
+    // The request arrived during a scheduled maintenance window
+    Maintenance,
+    // The request repeated the Idempotency-Key of a request that is in flight or was rejected
+    DuplicateRequest,
+    // The client was banned for repeated offenses
+    Banned,
+    // A CONNECT request named a port that tunnels may not go to
+    ConnectDenied,
+    // A streamed response was cut off at the maximum stream duration
+    StreamTimeout,
+    // The request had already passed through us, so forwarding it would go round in a loop
+    LoopDetected,
+    // The request's method isn't one we allow
+    MethodNotAllowed,
+    // The balancer shut down before the exchange could finish
+    Shutdown,
+}
+
+impl CloseReason {
+    const ALL: [CloseReason; 19] = [
+        CloseReason::ClientAbort,
+        CloseReason::ClientTimeout,
+        CloseReason::UpstreamConnectFail,
+        CloseReason::UpstreamError,
+        CloseReason::UpstreamTimeout,
+        CloseReason::UpstreamBusy,
+        CloseReason::BodyTooLarge,
+        CloseReason::RateLimited,
+        CloseReason::LoadShed,
+        CloseReason::ProtocolError,
+        CloseReason::AdminClose,
+        CloseReason::Maintenance,
+        CloseReason::DuplicateRequest,
+        CloseReason::Banned,
+        CloseReason::ConnectDenied,
+        CloseReason::StreamTimeout,
+        CloseReason::LoopDetected,
+        CloseReason::MethodNotAllowed,
+        CloseReason::Shutdown,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::ClientAbort => "client_abort",
+            CloseReason::ClientTimeout => "client_timeout",
+            CloseReason::UpstreamConnectFail => "upstream_connect_fail",
+            CloseReason::UpstreamError => "upstream_error",
+            CloseReason::UpstreamTimeout => "upstream_timeout",
+            CloseReason::UpstreamBusy => "upstream_busy",
+            CloseReason::BodyTooLarge => "body_too_large",
+            CloseReason::RateLimited => "rate_limited",
+            CloseReason::LoadShed => "load_shed",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::AdminClose => "admin_close",
+            CloseReason::Maintenance => "maintenance",
+            CloseReason::DuplicateRequest => "duplicate_request",
+            CloseReason::Banned => "banned",
+            CloseReason::ConnectDenied => "connect_denied",
+            CloseReason::StreamTimeout => "stream_timeout",
+            CloseReason::LoopDetected => "loop_detected",
+            CloseReason::MethodNotAllowed => "method_not_allowed",
+            CloseReason::Shutdown => "lb_shutdown",
+        }
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Counters exported by the admin API's /metrics endpoint, in the Prometheus text format.
+pub struct Metrics {
+    // Indexed by position in CloseReason::ALL
+    terminated_exchanges: [AtomicU64; CloseReason::ALL.len()],
+    // The client listener's address and backlog, for reporting on its accept queue
+    listen_addr: SocketAddr,
+    listen_backlog: u32,
+    accepted_connections: AtomicU64,
+    // Connections closed straight after accepting because the client's address isn't permitted
+    denied_connections: AtomicU64,
+    // Sum over accepted connections of the time until their handler started, in microseconds
+    accept_latency_micros: AtomicU64,
+    retries: AtomicU64,
+    // Retries that were called for but not made, because the request was out of retries or the
+    // retry budget was spent
+    retries_denied_max: AtomicU64,
+    retries_denied_budget: AtomicU64,
+    // Requests also sent to a second upstream, and how many of those the second one answered first
+    hedges: AtomicU64,
+    hedge_wins: AtomicU64,
+    pooled_connection_reuses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(listen_addr: SocketAddr, listen_backlog: u32) -> Metrics {
+        Metrics {
+            terminated_exchanges: Default::default(),
+            listen_addr,
+            listen_backlog,
+            accepted_connections: AtomicU64::new(0),
+            denied_connections: AtomicU64::new(0),
+            accept_latency_micros: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            retries_denied_max: AtomicU64::new(0),
+            retries_denied_budget: AtomicU64::new(0),
+            hedges: AtomicU64::new(0),
+            hedge_wins: AtomicU64::new(0),
+            pooled_connection_reuses: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a client connection whose handler started `latency` after it was accepted.
+    pub fn record_accept(&self, latency: Duration) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        self.accept_latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_denied_connection(&self) {
+        self.denied_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_termination(&self, reason: CloseReason) {
+        let idx = CloseReason::ALL.iter().position(|r| *r == reason).unwrap();
+        self.terminated_exchanges[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, outcome: Result<(), retry::Denied>) {
+        let counter = match outcome {
+            Ok(()) => &self.retries,
+            Err(retry::Denied::MaxRetries) => &self.retries_denied_max,
+            Err(retry::Denied::Budget) => &self.retries_denied_budget,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a hedged request being sent (`won` = false), or winning the race (`won` = true).
+    pub fn record_hedge(&self, won: bool) {
+        let counter = if won { &self.hedge_wins } else { &self.hedges };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_pooled_connection_reuse(&self) {
+        self.pooled_connection_reuses
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format, along with gauges kept
+    /// elsewhere.
+    pub fn render(&self, in_flight_requests: usize) -> String {
+        let mut out = String::new();
+        out += "# HELP loadbalancer_terminated_exchanges_total Exchanges that ended without \
+                an upstream response reaching the client, by reason.\n";
+        out += "# TYPE loadbalancer_terminated_exchanges_total counter\n";
+        for (reason, count) in CloseReason::ALL.iter().zip(&self.terminated_exchanges) {
+            writeln!(
+                out,
+                "loadbalancer_terminated_exchanges_total{{reason=\"{}\"}} {}",
+                reason,
+                count.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        out += "# HELP loadbalancer_accepted_connections_total Client connections accepted.\n";
+        out += "# TYPE loadbalancer_accepted_connections_total counter\n";
+        let accepted = self.accepted_connections.load(Ordering::Relaxed);
+        writeln!(out, "loadbalancer_accepted_connections_total {}", accepted).unwrap();
+        out += "# HELP loadbalancer_denied_connections_total Client connections closed \
+                because the client's address is not allowed.\n";
+        out += "# TYPE loadbalancer_denied_connections_total counter\n";
+        writeln!(
+            out,
+            "loadbalancer_denied_connections_total {}",
+            self.denied_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_accept_latency_seconds Time from accepting a client \
+                connection until its handler started running.\n";
+        out += "# TYPE loadbalancer_accept_latency_seconds summary\n";
+        let latency = self.accept_latency_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "loadbalancer_accept_latency_seconds_sum {}", latency).unwrap();
+        writeln!(
+            out,
+            "loadbalancer_accept_latency_seconds_count {}",
+            accepted
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_listen_backlog Maximum number of connections queued for \
+                accepting.\n";
+        out += "# TYPE loadbalancer_listen_backlog gauge\n";
+        writeln!(out, "loadbalancer_listen_backlog {}", self.listen_backlog).unwrap();
+        out += "# HELP loadbalancer_retries_total Requests sent to another upstream after an \
+                attempt failed.\n";
+        out += "# TYPE loadbalancer_retries_total counter\n";
+        writeln!(
+            out,
+            "loadbalancer_retries_total {}",
+            self.retries.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_retries_denied_total Retries that were not made, by reason.\n";
+        out += "# TYPE loadbalancer_retries_denied_total counter\n";
+        for (reason, counter) in [
+            ("max_retries", &self.retries_denied_max),
+            ("budget", &self.retries_denied_budget),
+        ] {
+            writeln!(
+                out,
+                "loadbalancer_retries_denied_total{{reason=\"{}\"}} {}",
+                reason,
+                counter.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        out += "# HELP loadbalancer_hedged_requests_total Requests also sent to a second \
+                upstream because the first was slow to answer.\n";
+        out += "# TYPE loadbalancer_hedged_requests_total counter\n";
+        writeln!(
+            out,
+            "loadbalancer_hedged_requests_total {}",
+            self.hedges.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_hedge_wins_total Hedged requests that the second upstream \
+                answered first.\n";
+        out += "# TYPE loadbalancer_hedge_wins_total counter\n";
+        writeln!(
+            out,
+            "loadbalancer_hedge_wins_total {}",
+            self.hedge_wins.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_pooled_connection_reuses_total Upstream connections taken \
+                from the idle pool instead of being dialed.\n";
+        out += "# TYPE loadbalancer_pooled_connection_reuses_total counter\n";
+        writeln!(
+            out,
+            "loadbalancer_pooled_connection_reuses_total {}",
+            self.pooled_connection_reuses.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out += "# HELP loadbalancer_in_flight_requests Requests being proxied right now.\n";
+        out += "# TYPE loadbalancer_in_flight_requests gauge\n";
+        writeln!(
+            out,
+            "loadbalancer_in_flight_requests {}",
+            in_flight_requests
+        )
+        .unwrap();
+        // Only available where the platform exposes it
+        if let Some(depth) = listener::accept_queue_depth(self.listen_addr) {
+            out += "# HELP loadbalancer_accept_queue_depth Connections waiting to be accepted.\n";
+            out += "# TYPE loadbalancer_accept_queue_depth gauge\n";
+            writeln!(out, "loadbalancer_accept_queue_depth {}", depth).unwrap();
+        }
+        out
+    }
+}
@@ -0,0 +1,174 @@
+use crate::events;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Responses are counted in buckets of this size, so memory doesn't grow with the request rate.
+const BUCKET: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Outcomes {
+    // (bucket start, responses, 5xx responses), oldest first
+    buckets: VecDeque<(Instant, usize, usize)>,
+    ejected_until: Option<Instant>,
+}
+
+impl Outcomes {
+    fn record(&mut self, now: Instant, window: Duration, server_error: bool) -> (usize, usize) {
+        while self
+            .buckets
+            .front()
+            .is_some_and(|(start, _, _)| now.duration_since(*start) >= window)
+        {
+            self.buckets.pop_front();
+        }
+        match self.buckets.back_mut() {
+            Some((start, total, errors)) if now.duration_since(*start) < BUCKET => {
+                *total += 1;
+                *errors += server_error as usize;
+            }
+            _ => self.buckets.push_back((now, 1, server_error as usize)),
+        }
+        self.buckets
+            .iter()
+            .fold((0, 0), |(total, errors), (_, t, e)| (total + t, errors + e))
+    }
+}
+
+/// Ejects upstreams that are up but broken: ones that accept connections and pass shallow health
+/// checks, but answer too many requests with a 5xx. An upstream whose share of 5xx responses over
+/// the last `window` exceeds `max_error_percent` is taken out of rotation for `ejection`, then
+/// re-admitted with a clean slate. Ejections are reported to the event hooks; re-admissions, which
+/// just happen once the time is up, aren't.
+///
+/// Upstreams that say they're overloaded, with a 503 and a Retry-After header, are also ejected
+/// for as long as they ask (up to `max_retry_after`), whether or not 5xx rates are being watched.
+pub struct OutlierDetector {
+    // 0 = disabled
+    max_error_percent: f64,
+    window: Duration,
+    // Responses an upstream must have sent within the window before its error rate counts, so that
+    // a couple of unlucky requests don't eject it
+    min_requests: usize,
+    ejection: Duration,
+    // 0 = ignore Retry-After
+    max_retry_after: Duration,
+    upstreams: Vec<Mutex<Outcomes>>,
+    events: events::EventHooks,
+}
+
+impl OutlierDetector {
+    pub fn new(
+        num_upstreams: usize,
+        max_error_percent: f64,
+        window: Duration,
+        min_requests: usize,
+        ejection: Duration,
+        max_retry_after: Duration,
+        events: events::EventHooks,
+    ) -> OutlierDetector {
+        OutlierDetector {
+            max_error_percent,
+            window,
+            min_requests: min_requests.max(1),
+            ejection,
+            max_retry_after,
+            upstreams: (0..num_upstreams).map(|_| Mutex::default()).collect(),
+            events,
+        }
+    }
+
+    /// Records the status of a response from the upstream, ejecting it if that tips its error
+    /// rate over the limit.
+    pub fn record(&self, idx: usize, address: &str, status: http::StatusCode) {
+        if self.max_error_percent <= 0.0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut outcomes = self.upstreams[idx].lock();
+        if outcomes.ejected_until.is_some_and(|until| now < until) {
+            return;
+        }
+        let (total, errors) = outcomes.record(now, self.window, status.is_server_error());
+        let error_percent = errors as f64 * 100.0 / total as f64;
+        if total >= self.min_requests && error_percent > self.max_error_percent {
+            log::warn!(
+                "Ejecting upstream {} for {}s: {} of its last {} responses were 5xx ({:.0}%)",
+                address,
+                self.ejection.as_secs(),
+                errors,
+                total,
+                error_percent
+            );
+            outcomes.buckets.clear();
+            outcomes.ejected_until = Some(now + self.ejection);
+            self.events.upstream_changed(
+                address,
+                false,
+                &format!("{:.0}% of its responses were 5xx", error_percent),
+            );
+        }
+    }
+
+    /// Ejects the upstream if the response is a 503 with a Retry-After header in seconds (HTTP
+    /// dates aren't supported). Returns whether it did, i.e. whether the upstream turned the
+    /// request away, so that it can be sent elsewhere.
+    pub fn record_overload(
+        &self,
+        idx: usize,
+        address: &str,
+        response: &http::Response<Vec<u8>>,
+    ) -> bool {
+        if self.max_retry_after.is_zero()
+            || response.status() != http::StatusCode::SERVICE_UNAVAILABLE
+        {
+            return false;
+        }
+        let Some(retry_after) = response
+            .headers()
+            .get(http::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+        else {
+            return false;
+        };
+        let ejection = Duration::from_secs(retry_after).min(self.max_retry_after);
+        let until = Instant::now() + ejection;
+        let mut outcomes = self.upstreams[idx].lock();
+        if outcomes
+            .ejected_until
+            .is_some_and(|current| current >= until)
+        {
+            return true;
+        }
+        log::warn!(
+            "Ejecting upstream {} for {}s: it is overloaded and asked us to retry later",
+            address,
+            ejection.as_secs()
+        );
+        outcomes.ejected_until = Some(until);
+        self.events
+            .upstream_changed(address, false, &"answered 503 with Retry-After");
+        true
+    }
+
+    /// Returns how much longer the upstream stays ejected, if it is ejected.
+    pub fn ejected_for(&self, idx: usize) -> Option<Duration> {
+        self.upstreams[idx]
+            .lock()
+            .ejected_until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+    }
+
+    /// Ejects the upstream for `remaining`, e.g. to carry an ejection over from before a restart.
+    pub fn restore_ejection(&self, idx: usize, remaining: Duration) {
+        self.upstreams[idx].lock().ejected_until = Instant::now().checked_add(remaining);
+    }
+
+    pub fn is_ejected(&self, idx: usize) -> bool {
+        self.upstreams[idx]
+            .lock()
+            .ejected_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
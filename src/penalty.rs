@@ -0,0 +1,254 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The longest a tarpitted client is made to wait before each request is handled.
+const MAX_TARPIT_DELAY: Duration = Duration::from_secs(10);
+/// Once this many clients are tracked, clients whose score has decayed away are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+/// Scores below this are treated as fully decayed.
+const NEGLIGIBLE_SCORE: f64 = 0.01;
+
+/// Whether a score has reached a threshold. A burst of N offenses has decayed very slightly by
+/// the time it is checked, so it still counts as reaching a threshold of N.
+fn reaches(score: f64, threshold: f64) -> bool {
+    threshold > 0.0 && score >= threshold - NEGLIGIBLE_SCORE
+}
+
+/// Client behavior that counts towards a penalty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offense {
+    // Sent a request that wasn't valid HTTP, or violated our header rules
+    MalformedRequest,
+    // Got a 401 or 403 from an upstream
+    AuthFailure,
+    // Was turned away by a rate or concurrency limit
+    RateLimited,
+}
+
+/// What should happen to a client's next request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Penalty {
+    None,
+    // Handle the request, but only after this delay
+    Tarpit(Duration),
+    // Refuse to serve the client for this much longer
+    Banned(Duration),
+}
+
+struct ClientRecord {
+    // Offense points, decayed as of `updated_at`
+    score: f64,
+    updated_at: Instant,
+    offenses: u64,
+    // Number of times the client has been banned; each ban lasts twice as long as the last
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+impl ClientRecord {
+    fn decayed_score(&self, half_life: Duration, now: Instant) -> f64 {
+        if half_life.is_zero() {
+            return self.score;
+        }
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.score * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+    }
+
+    fn ban_remaining(&self, now: Instant) -> Option<Duration> {
+        self.banned_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+/// A client's penalty state, as reported by the admin API.
+#[derive(Serialize, Debug)]
+pub struct PenaltySummary {
+    ip: String,
+    score: f64,
+    offenses: u64,
+    bans: u32,
+    banned_secs_remaining: Option<u64>,
+}
+
+/// A client's penalty state as saved across restarts (see persist.rs). Times are relative to when
+/// it was saved.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedClient {
+    ip: IpAddr,
+    score: f64,
+    offenses: u64,
+    bans: u32,
+    ban_remaining_secs: Option<f64>,
+}
+
+/// Keeps a decaying score of offenses per client IP and turns it into escalating penalties:
+/// past `tarpit_threshold` each request is delayed (longer the higher the score), and past
+/// `ban_threshold` the client is refused outright for a while. A threshold of 0 disables that
+/// penalty.
+pub struct PenaltyBox {
+    tarpit_threshold: f64,
+    // Delay per point of score above the tarpit threshold
+    tarpit_delay: Duration,
+    ban_threshold: f64,
+    ban_duration: Duration,
+    // How long it takes for a score to halve
+    half_life: Duration,
+    clients: Mutex<HashMap<IpAddr, ClientRecord>>,
+}
+
+impl PenaltyBox {
+    pub fn new(
+        tarpit_threshold: f64,
+        tarpit_delay: Duration,
+        ban_threshold: f64,
+        ban_duration: Duration,
+        half_life: Duration,
+    ) -> PenaltyBox {
+        PenaltyBox {
+            tarpit_threshold,
+            tarpit_delay,
+            ban_threshold,
+            ban_duration,
+            half_life,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.tarpit_threshold > 0.0 || self.ban_threshold > 0.0
+    }
+
+    pub fn record(&self, ip: IpAddr, offense: Offense) {
+        if !self.is_enabled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, record| {
+                record.ban_remaining(now).is_some()
+                    || record.decayed_score(self.half_life, now) >= NEGLIGIBLE_SCORE
+            });
+        }
+        let record = clients.entry(ip).or_insert(ClientRecord {
+            score: 0.0,
+            updated_at: now,
+            offenses: 0,
+            bans: 0,
+            banned_until: None,
+        });
+        record.score = record.decayed_score(self.half_life, now) + 1.0;
+        record.updated_at = now;
+        record.offenses += 1;
+        if reaches(record.score, self.ban_threshold) && record.ban_remaining(now).is_none() {
+            let duration = self.ban_duration * 2u32.saturating_pow(record.bans.min(16));
+            log::warn!(
+                "Banning {} for {}s after {:?} (score {:.1})",
+                ip,
+                duration.as_secs(),
+                offense,
+                record.score
+            );
+            record.bans += 1;
+            record.banned_until = Some(now + duration);
+            // The client starts over once the ban ends
+            record.score = 0.0;
+        }
+    }
+
+    /// Returns the penalty that applies to the client's next request.
+    pub fn check(&self, ip: IpAddr) -> Penalty {
+        if !self.is_enabled() {
+            return Penalty::None;
+        }
+        let now = Instant::now();
+        let clients = self.clients.lock();
+        let Some(record) = clients.get(&ip) else {
+            return Penalty::None;
+        };
+        if let Some(remaining) = record.ban_remaining(now) {
+            return Penalty::Banned(remaining);
+        }
+        let score = record.decayed_score(self.half_life, now);
+        if reaches(score, self.tarpit_threshold) {
+            let points = (score - self.tarpit_threshold).max(0.0) + 1.0;
+            return Penalty::Tarpit(self.tarpit_delay.mul_f64(points).min(MAX_TARPIT_DELAY));
+        }
+        Penalty::None
+    }
+
+    /// Lifts any penalty on the client and forgets its history. Returns false if the client had
+    /// no record.
+    pub fn pardon(&self, ip: IpAddr) -> bool {
+        self.clients.lock().remove(&ip).is_some()
+    }
+
+    /// Returns the state of every client that still has a penalty or a score worth keeping.
+    pub fn save(&self) -> Vec<SavedClient> {
+        let now = Instant::now();
+        self.clients
+            .lock()
+            .iter()
+            .filter_map(|(ip, record)| {
+                let score = record.decayed_score(self.half_life, now);
+                let ban_remaining = record.ban_remaining(now);
+                (score >= NEGLIGIBLE_SCORE || ban_remaining.is_some()).then(|| SavedClient {
+                    ip: *ip,
+                    score,
+                    offenses: record.offenses,
+                    bans: record.bans,
+                    ban_remaining_secs: ban_remaining.map(|remaining| remaining.as_secs_f64()),
+                })
+            })
+            .collect()
+    }
+
+    /// Restores saved client state, aged by `elapsed` (the time since it was saved), so scores
+    /// keep decaying and bans keep running while the balancer is down.
+    pub fn restore(&self, saved: Vec<SavedClient>, elapsed: Duration) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        for client in saved {
+            let record = ClientRecord {
+                score: client.score,
+                updated_at: now,
+                offenses: client.offenses,
+                bans: client.bans,
+                banned_until: client
+                    .ban_remaining_secs
+                    .and_then(|remaining| Duration::try_from_secs_f64(remaining).ok())
+                    .and_then(|remaining| remaining.checked_sub(elapsed))
+                    .and_then(|remaining| now.checked_add(remaining)),
+            };
+            let record = ClientRecord {
+                score: record.decayed_score(self.half_life, now + elapsed),
+                ..record
+            };
+            clients.insert(client.ip, record);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<PenaltySummary> {
+        let now = Instant::now();
+        let mut summaries: Vec<PenaltySummary> = self
+            .clients
+            .lock()
+            .iter()
+            .map(|(ip, record)| PenaltySummary {
+                ip: ip.to_string(),
+                score: record.decayed_score(self.half_life, now),
+                offenses: record.offenses,
+                bans: record.bans,
+                banned_secs_remaining: record
+                    .ban_remaining(now)
+                    .map(|remaining| remaining.as_secs()),
+            })
+            .collect();
+        summaries.sort_by(|a, b| b.score.total_cmp(&a.score));
+        summaries
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{breaker, penalty, ratelimit, ProxyState};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An upstream's health as saved across restarts. Upstreams are matched up by address, since the
+/// upstream list may have changed in between.
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedUpstream {
+    address: String,
+    dead: bool,
+    // How long before the save a connection to it last failed, if it hadn't recovered since
+    failed_secs_ago: Option<f64>,
+    // How much longer it was going to stay ejected by outlier detection, if it was ejected
+    #[serde(default)]
+    ejected_secs_left: Option<f64>,
+    // Its circuit breaker, if it was open or half-open
+    #[serde(default)]
+    breaker: Option<breaker::SavedBreaker>,
+}
+
+/// The state that should survive a quick restart: which upstreams are ejected or have an open
+/// circuit breaker, which clients are being penalized, and where clients stand against the rate
+/// limits, so that a restart doesn't put a broken upstream back in rotation or give abusive or
+/// throttled clients a fresh start.
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    saved_at_unix_ms: u64,
+    upstreams: Vec<SavedUpstream>,
+    clients: Vec<penalty::SavedClient>,
+    #[serde(default)]
+    rate_limits: Vec<ratelimit::SavedClient>,
+    #[serde(default)]
+    route_rate_limits: Vec<ratelimit::SavedRouteBucket>,
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Writes a snapshot of the state to `path`. The snapshot is written to a temporary file first and
+/// renamed into place, so a crash partway through never leaves a truncated snapshot behind.
+pub fn save(state: &ProxyState, path: &Path) -> Result<(), String> {
+    let snapshot = Snapshot {
+        saved_at_unix_ms: unix_ms(),
+        upstreams: state
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(idx, upstream)| SavedUpstream {
+                address: upstream.address.clone(),
+                dead: state.health.is_dead(idx),
+                failed_secs_ago: state
+                    .recent_failures
+                    .failed_ago(idx)
+                    .map(|ago| ago.as_secs_f64()),
+                ejected_secs_left: state
+                    .outliers
+                    .ejected_for(idx)
+                    .map(|left| left.as_secs_f64()),
+                breaker: state.breakers.save(idx),
+            })
+            .collect(),
+        clients: state.penalties.save(),
+        rate_limits: state.rate_limiter.save(),
+        route_rate_limits: state.route_rate_limiter.save(),
+    };
+    let contents = serde_json::to_vec_pretty(&snapshot).unwrap();
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, contents)
+        .and_then(|()| std::fs::rename(&temp_path, path))
+        .map_err(|err| format!("could not write {}: {}", path.display(), err))
+}
+
+/// Restores the state saved in `path`, aged by the time since it was saved. A missing snapshot
+/// isn't an error; there's just nothing to restore. Upstream health older than `max_age` isn't
+/// trusted, since the upstreams may well have been fixed in the meantime; client penalties and rate
+/// limits are still restored, as they expire on their own.
+pub fn load(state: &ProxyState, path: &Path, max_age: Duration) -> Result<(), String> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("could not read {}: {}", path.display(), err)),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&contents)
+        .map_err(|err| format!("could not parse {}: {}", path.display(), err))?;
+    let elapsed = Duration::from_millis(unix_ms().saturating_sub(snapshot.saved_at_unix_ms));
+    let upstreams = if elapsed <= max_age {
+        snapshot.upstreams.as_slice()
+    } else {
+        log::info!(
+            "Not restoring upstream health from {}: it is {}s old, more than the {}s limit",
+            path.display(),
+            elapsed.as_secs(),
+            max_age.as_secs()
+        );
+        &[]
+    };
+    let mut restored_upstreams = 0;
+    // The same address can be listed more than once (e.g. in several pools), and each of those
+    // upstreams gets the saved state
+    for (idx, upstream) in state.upstreams.iter().enumerate() {
+        let Some(saved) = upstreams
+            .iter()
+            .find(|saved| saved.address == upstream.address)
+        else {
+            continue;
+        };
+        if saved.dead {
+            state.health.restore_dead(idx);
+        }
+        // Times that aren't valid durations (negative, NaN or too large) are skipped rather than
+        // trusted
+        if let Some(ago) = saved
+            .failed_secs_ago
+            .and_then(|ago| Duration::try_from_secs_f64(ago).ok())
+        {
+            state
+                .recent_failures
+                .restore(idx, ago.saturating_add(elapsed));
+        }
+        if let Some(left) = saved
+            .ejected_secs_left
+            .and_then(|left| Duration::try_from_secs_f64(left).ok())
+            .and_then(|left| left.checked_sub(elapsed))
+        {
+            state.outliers.restore_ejection(idx, left);
+        }
+        if let Some(breaker) = &saved.breaker {
+            state.breakers.restore(idx, breaker, elapsed);
+        }
+        if state.health.is_dead(idx)
+            || state.outliers.is_ejected(idx)
+            || !state.breakers.is_available(idx)
+        {
+            restored_upstreams += 1;
+        }
+    }
+    log::info!(
+        "Restored state from {} (saved {}s ago): {} ejected upstream(s), {} penalized client(s), \
+         {} client rate limit(s)",
+        path.display(),
+        elapsed.as_secs(),
+        restored_upstreams,
+        snapshot.clients.len(),
+        snapshot.rate_limits.len() + snapshot.route_rate_limits.len()
+    );
+    state.penalties.restore(snapshot.clients, elapsed);
+    state.rate_limiter.restore(snapshot.rate_limits, elapsed);
+    state
+        .route_rate_limiter
+        .restore(snapshot.route_rate_limits, elapsed);
+    Ok(())
+}
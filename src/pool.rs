@@ -0,0 +1,56 @@
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Idle upstream connections kept open for reuse by later requests, including other clients' ones,
+/// so that a busy upstream isn't dialed afresh for every client connection. Each upstream keeps up
+/// to `max_idle` connections, and one that has sat unused for `idle_timeout` is closed rather than
+/// reused, since the upstream is likely to close it any moment (if it hasn't already).
+pub struct ConnectionPool {
+    // Idle connections by upstream index, along with when each was returned; most recent last
+    idle: Vec<Mutex<Vec<(TcpStream, Instant)>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(num_upstreams: usize, max_idle: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            idle: (0..num_upstreams).map(|_| Mutex::new(Vec::new())).collect(),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    /// Takes the most recently returned connection to the upstream that is still usable. Expired
+    /// connections, and ones the upstream has closed or sent something unexpected on, are closed.
+    pub fn take(&self, idx: usize) -> Option<TcpStream> {
+        let mut idle = self.idle[idx].lock();
+        while let Some((stream, returned_at)) = idle.pop() {
+            if returned_at.elapsed() >= self.idle_timeout {
+                // Everything older was returned before it, so has expired too
+                idle.clear();
+                return None;
+            }
+            match stream.try_read(&mut [0_u8; 1]) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Some(stream),
+                _ => log::debug!("Dropping an idle connection the upstream has closed"),
+            }
+        }
+        None
+    }
+
+    /// Keeps a connection that is between exchanges for reuse, or closes it if the upstream
+    /// already has as many idle connections as it may.
+    pub fn put(&self, idx: usize, stream: TcpStream) {
+        let mut idle = self.idle[idx].lock();
+        if idle.len() >= self.max_idle {
+            // Make room by closing the connection that is closest to expiring
+            if self.max_idle == 0 {
+                return;
+            }
+            idle.remove(0);
+        }
+        idle.push((stream, Instant::now()));
+    }
+}
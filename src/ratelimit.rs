@@ -0,0 +1,562 @@
+use crate::redis;
+use crate::routing;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long each client's per-minute request count lasts before it starts over.
+const WINDOW: Duration = Duration::from_secs(60);
+/// Once this many clients are tracked, clients that are back to a clean slate are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+/// How long to count requests locally after the shared store failed, before trying it again.
+const SHARED_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a client stands against a rate limit, as reported in the RateLimit-* response headers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: usize,
+    pub remaining: usize,
+    // Time until the quota is back to `limit`
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// The quota closest to running out, if any.
+    pub fn tightest(quotas: impl IntoIterator<Item = Option<Quota>>) -> Option<Quota> {
+        quotas
+            .into_iter()
+            .flatten()
+            .min_by_key(|quota| (quota.remaining, std::cmp::Reverse(quota.reset)))
+    }
+
+    pub fn add_headers(&self, headers: &mut http::HeaderMap) {
+        headers.insert("ratelimit-limit", http::HeaderValue::from(self.limit));
+        headers.insert(
+            "ratelimit-remaining",
+            http::HeaderValue::from(self.remaining),
+        );
+        headers.insert(
+            "ratelimit-reset",
+            http::HeaderValue::from(self.reset.as_secs_f64().ceil() as u64),
+        );
+    }
+}
+
+/// A request that was turned away by a rate limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Throttled {
+    // How long until the client may make another request
+    pub retry_after: Duration,
+    pub quota: Quota,
+}
+
+/// A token bucket holding up to `burst` tokens, refilled continuously at `rate` tokens per second,
+/// with each request taking a token.
+struct TokenBucket {
+    // Tokens left, as of `refilled_at`
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: burst,
+            refilled_at: now,
+        }
+    }
+
+    /// A bucket that held `tokens` when it was saved, refilled for the `elapsed` time since. The
+    /// tokens are kept between zero and `burst`, whatever the snapshot says.
+    fn restored(
+        tokens: f64,
+        elapsed: Duration,
+        rate: f64,
+        burst: f64,
+        now: Instant,
+    ) -> TokenBucket {
+        TokenBucket {
+            tokens: (tokens + elapsed.as_secs_f64() * rate.max(0.0))
+                .min(burst)
+                .max(0.0),
+            refilled_at: now,
+        }
+    }
+
+    /// The tokens in the bucket as of `now`.
+    fn level(&self, rate: f64, burst: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+
+    /// Takes a token for a request, or returns how long until there will be one.
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        self.tokens = self.level(rate, burst, now);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn quota(&self, rate: f64, burst: f64, now: Instant) -> Quota {
+        let tokens = self.level(rate, burst, now);
+        Quota {
+            limit: burst as usize,
+            remaining: tokens as usize,
+            reset: Duration::from_secs_f64((burst - tokens) / rate),
+        }
+    }
+
+    /// Takes a token for a request, returning the quota left, or turning the request away.
+    fn throttle(&mut self, rate: f64, burst: f64, now: Instant) -> Result<Quota, Throttled> {
+        match self.take(rate, burst, now) {
+            Ok(()) => Ok(self.quota(rate, burst, now)),
+            Err(retry_after) => Err(Throttled {
+                retry_after,
+                quota: self.quota(rate, burst, now),
+            }),
+        }
+    }
+}
+
+/// The bucket size to use when none is given: one second's worth of tokens.
+fn default_burst(rate: f64) -> f64 {
+    rate.ceil().max(1.0)
+}
+
+/// A client's rate limit state as saved across restarts (see persist.rs). Times are relative to
+/// when it was saved.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedClient {
+    ip: IpAddr,
+    // How long before the save the client's per-minute window started, and the requests counted in
+    // it
+    window_secs_ago: f64,
+    requests: usize,
+    // Tokens left in the client's bucket
+    tokens: f64,
+}
+
+struct ClientState {
+    // Start of the client's current per-minute window, and the requests counted in it
+    window_start: Instant,
+    requests: usize,
+    bucket: TokenBucket,
+}
+
+/// Limits the request rate of each client IP, in two ways that can be used together:
+///
+/// - At most `max_per_minute` requests per minute, counted in fixed windows: a client's window
+///   starts with its first request and its count resets once the minute is up.
+/// - A token bucket holding up to `burst` tokens, refilled continuously at `rate` tokens per
+///   second, with each request taking a token. Short bursts are let through, but a client can't
+///   keep up more than `rate` requests per second.
+///
+/// A limit of 0 disables it. With a shared window, the per-minute counts are kept there instead,
+/// so that every balancer instance using it enforces the limit together; token buckets are always
+/// kept per instance.
+pub struct RateLimiter {
+    max_per_minute: usize,
+    rate: f64,
+    burst: f64,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+    shared: Option<SharedWindow>,
+}
+
+impl RateLimiter {
+    /// A `burst` of 0 defaults to one second's worth of tokens.
+    pub fn new(
+        max_per_minute: usize,
+        rate: f64,
+        burst: usize,
+        shared: Option<SharedWindow>,
+    ) -> RateLimiter {
+        let burst = if burst > 0 {
+            burst as f64
+        } else {
+            default_burst(rate)
+        };
+        RateLimiter {
+            max_per_minute,
+            rate,
+            burst,
+            clients: Mutex::new(HashMap::new()),
+            shared,
+        }
+    }
+
+    /// Counts a request from the client. Returns the client's tightest quota (None if no limit is
+    /// set), or turns the request away if it is over a limit.
+    pub async fn check(&self, ip: IpAddr) -> Result<Option<Quota>, Throttled> {
+        if self.max_per_minute == 0 && self.rate <= 0.0 {
+            return Ok(None);
+        }
+        let shared = self
+            .shared
+            .as_ref()
+            .filter(|shared| self.max_per_minute > 0 && shared.available());
+        let (mut count, mut reset, bucket) = self.check_local(ip, shared.is_none())?;
+        if let Some(shared) = shared {
+            // If the shared store fails, fall back to what this instance has counted
+            if let Some(counted) = shared.count(ip).await {
+                (count, reset) = counted;
+            }
+        }
+        let window = (self.max_per_minute > 0).then_some(Quota {
+            limit: self.max_per_minute,
+            remaining: self.max_per_minute.saturating_sub(count),
+            reset,
+        });
+        if count > self.max_per_minute && self.max_per_minute > 0 {
+            return Err(Throttled {
+                retry_after: reset,
+                quota: window.unwrap(),
+            });
+        }
+        Ok(Quota::tightest([window, bucket]))
+    }
+
+    /// Applies the token bucket, and the per-minute limit if `enforce_window` is set, and counts
+    /// the request locally either way. Returns the client's count for its current window, this
+    /// request included, how long that window has left, and the bucket's quota.
+    fn check_local(
+        &self,
+        ip: IpAddr,
+        enforce_window: bool,
+    ) -> Result<(usize, Duration, Option<Quota>), Throttled> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, client| {
+                now.duration_since(client.window_start) < WINDOW
+                    || client.bucket.level(self.rate, self.burst, now) < self.burst
+            });
+        }
+        let client = clients.entry(ip).or_insert(ClientState {
+            window_start: now,
+            requests: 0,
+            bucket: TokenBucket::full(self.burst, now),
+        });
+        if now.duration_since(client.window_start) >= WINDOW {
+            client.window_start = now;
+            client.requests = 0;
+        }
+        let reset = WINDOW - now.duration_since(client.window_start);
+        if enforce_window && self.max_per_minute > 0 && client.requests >= self.max_per_minute {
+            return Err(Throttled {
+                retry_after: reset,
+                quota: Quota {
+                    limit: self.max_per_minute,
+                    remaining: 0,
+                    reset,
+                },
+            });
+        }
+        let bucket = if self.rate > 0.0 {
+            Some(client.bucket.throttle(self.rate, self.burst, now)?)
+        } else {
+            None
+        };
+        client.requests += 1;
+        Ok((client.requests, reset, bucket))
+    }
+
+    /// Returns the state of every client that isn't back to a clean slate.
+    pub fn save(&self) -> Vec<SavedClient> {
+        let now = Instant::now();
+        self.clients
+            .lock()
+            .iter()
+            .filter_map(|(ip, client)| {
+                let window_age = now.duration_since(client.window_start);
+                let tokens = client.bucket.level(self.rate, self.burst, now);
+                (window_age < WINDOW || tokens < self.burst).then_some(SavedClient {
+                    ip: *ip,
+                    window_secs_ago: window_age.as_secs_f64(),
+                    requests: client.requests,
+                    tokens,
+                })
+            })
+            .collect()
+    }
+
+    /// Restores saved client state, aged by `elapsed` (the time since it was saved), so windows
+    /// keep running and buckets keep refilling while the balancer is down. Clients whose window
+    /// start isn't a valid duration are skipped.
+    pub fn restore(&self, saved: Vec<SavedClient>, elapsed: Duration) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        for client in saved {
+            let Ok(window_age) = Duration::try_from_secs_f64(client.window_secs_ago) else {
+                continue;
+            };
+            let window_age = window_age.saturating_add(elapsed).min(WINDOW);
+            clients.insert(
+                client.ip,
+                ClientState {
+                    window_start: now.checked_sub(window_age).unwrap_or(now),
+                    requests: if window_age < WINDOW {
+                        client.requests
+                    } else {
+                        0
+                    },
+                    bucket: TokenBucket::restored(
+                        client.tokens,
+                        elapsed,
+                        self.rate,
+                        self.burst,
+                        now,
+                    ),
+                },
+            );
+        }
+    }
+}
+
+/// Per-client request counts for the current minute, kept in Redis so that every balancer instance
+/// pointed at the same Redis shares them. Windows follow the wall clock's minutes, so that the
+/// instances agree on when they start. While Redis is failing, each instance counts on its own,
+/// trying Redis again every SHARED_RETRY_INTERVAL.
+pub struct SharedWindow {
+    redis: redis::Client,
+    key_prefix: String,
+    // When to try Redis again after it failed
+    retry_at: Mutex<Option<Instant>>,
+}
+
+impl SharedWindow {
+    pub fn new(redis: redis::Client, key_prefix: String) -> SharedWindow {
+        SharedWindow {
+            redis,
+            key_prefix,
+            retry_at: Mutex::new(None),
+        }
+    }
+
+    fn available(&self) -> bool {
+        self.retry_at
+            .lock()
+            .is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    /// Counts a request from the client. Returns its count for the current minute, this request
+    /// included, and how long the minute has left, or None if Redis couldn't be reached.
+    async fn count(&self, ip: IpAddr) -> Option<(usize, Duration)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let minute = now.as_secs() / WINDOW.as_secs();
+        let remaining = WINDOW * (minute as u32 + 1) - now;
+        let key = format!("{}{}:{}", self.key_prefix, ip, minute);
+        // Keep each key a little past its minute, so that clock skew between instances doesn't
+        // restart a count early
+        let ttl = (WINDOW.as_secs() * 2).to_string();
+        let replies = self
+            .redis
+            .pipeline(&[&["INCR", &key], &["EXPIRE", &key, &ttl]])
+            .await;
+        let count = replies.and_then(|replies| match replies.as_slice() {
+            [redis::Value::Integer(count), _] => Ok(*count as usize),
+            replies => Err(format!("unexpected reply to INCR: {:?}", replies)),
+        });
+        let mut retry_at = self.retry_at.lock();
+        match count {
+            Ok(count) => {
+                if retry_at.take().is_some() {
+                    log::info!("Rate limit store is back; sharing request counts again");
+                }
+                Some((count, remaining))
+            }
+            Err(err) => {
+                if retry_at.is_none() {
+                    log::warn!(
+                        "Rate limit store failed, counting requests locally: {}",
+                        err
+                    );
+                }
+                *retry_at = Some(Instant::now() + SHARED_RETRY_INTERVAL);
+                None
+            }
+        }
+    }
+}
+
+/// A rate limit for requests whose path starts with `prefix` (and that use `method`, if set),
+/// applied to each client IP separately. Parsed from `[METHOD:]PATH_PREFIX=RPS[/BURST]`, e.g.
+/// `POST:/login=10` or `/static/=1000/2000`; without a burst, the bucket holds one second's worth
+/// of requests.
+#[derive(Clone, Debug)]
+pub struct RouteRateLimit {
+    method: Option<http::Method>,
+    prefix: String,
+    rate: f64,
+    burst: f64,
+}
+
+impl std::str::FromStr for RouteRateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RouteRateLimit, String> {
+        let (route, limit) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected [METHOD:]PATH_PREFIX=RPS[/BURST], got {:?}", s))?;
+        let (method, prefix) = match route.split_once(':') {
+            Some((method, prefix)) if !method.starts_with('/') => {
+                let method =
+                    http::Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                        .map_err(|_| format!("invalid method {:?}", method))?;
+                (Some(method), prefix)
+            }
+            _ => (None, route),
+        };
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix {:?} must start with '/'", prefix));
+        }
+        let (rate, burst) = match limit.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (limit, None),
+        };
+        let rate = rate
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| *rate > 0.0)
+            .ok_or_else(|| format!("invalid rate {:?}", rate))?;
+        let burst = match burst {
+            Some(burst) => burst
+                .parse::<usize>()
+                .ok()
+                .filter(|burst| *burst > 0)
+                .ok_or_else(|| format!("invalid burst {:?}", burst))?
+                as f64,
+            None => default_burst(rate),
+        };
+        Ok(RouteRateLimit {
+            method,
+            prefix: prefix.to_string(),
+            rate,
+            burst,
+        })
+    }
+}
+
+/// A client's token bucket for a route rate limit, as saved across restarts (see persist.rs).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedRouteBucket {
+    ip: IpAddr,
+    // The route, as [METHOD:]PATH_PREFIX, since the configured limits may have changed in between
+    route: String,
+    // Tokens left in the bucket
+    tokens: f64,
+}
+
+impl RouteRateLimit {
+    /// The route the limit applies to, as [METHOD:]PATH_PREFIX.
+    fn route(&self) -> String {
+        match &self.method {
+            Some(method) => format!("{}:{}", method, self.prefix),
+            None => self.prefix.clone(),
+        }
+    }
+
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        routing::strip_path_prefix(request.uri().path(), &self.prefix).is_some()
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| method == request.method())
+    }
+}
+
+/// Applies route rate limits, keyed on the client IP and the route. A request is counted against
+/// the most specific matching limit only: the one with the longest prefix, preferring one for the
+/// request's method over one for any method.
+pub struct RouteRateLimiter {
+    // Most specific first
+    limits: Vec<RouteRateLimit>,
+    buckets: Mutex<HashMap<(IpAddr, usize), TokenBucket>>,
+}
+
+impl RouteRateLimiter {
+    pub fn new(limits: &[RouteRateLimit]) -> RouteRateLimiter {
+        let mut limits = limits.to_vec();
+        limits.sort_by_key(|limit| std::cmp::Reverse((limit.prefix.len(), limit.method.is_some())));
+        RouteRateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from the client. Returns the client's quota for the request's route (None
+    /// if no limit applies), or, if it is over the route's limit, the route it was throttled on.
+    pub fn check(
+        &self,
+        ip: IpAddr,
+        request: &http::Request<Vec<u8>>,
+    ) -> Result<Option<Quota>, (&str, Throttled)> {
+        let Some((idx, limit)) = self
+            .limits
+            .iter()
+            .enumerate()
+            .find(|(_, limit)| limit.matches(request))
+        else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|(_, idx), bucket| {
+                let limit = &self.limits[*idx];
+                bucket.level(limit.rate, limit.burst, now) < limit.burst
+            });
+        }
+        buckets
+            .entry((ip, idx))
+            .or_insert_with(|| TokenBucket::full(limit.burst, now))
+            .throttle(limit.rate, limit.burst, now)
+            .map(Some)
+            .map_err(|throttled| (limit.prefix.as_str(), throttled))
+    }
+
+    /// Returns every bucket that isn't full.
+    pub fn save(&self) -> Vec<SavedRouteBucket> {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .iter()
+            .filter_map(|((ip, idx), bucket)| {
+                let limit = &self.limits[*idx];
+                let tokens = bucket.level(limit.rate, limit.burst, now);
+                (tokens < limit.burst).then(|| SavedRouteBucket {
+                    ip: *ip,
+                    route: limit.route(),
+                    tokens,
+                })
+            })
+            .collect()
+    }
+
+    /// Restores saved buckets, refilled for `elapsed` (the time since they were saved). Buckets for
+    /// routes that no longer have a limit are dropped.
+    pub fn restore(&self, saved: Vec<SavedRouteBucket>, elapsed: Duration) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        for bucket in saved {
+            let Some((idx, limit)) = self
+                .limits
+                .iter()
+                .enumerate()
+                .find(|(_, limit)| limit.route() == bucket.route)
+            else {
+                continue;
+            };
+            buckets.insert(
+                (bucket.ip, idx),
+                TokenBucket::restored(bucket.tokens, elapsed, limit.rate, limit.burst, now),
+            );
+        }
+    }
+}
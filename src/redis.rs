@@ -0,0 +1,113 @@
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// How many idle connections to keep open for reuse.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+/// A reply to a Redis command. Array replies aren't supported, since none of the commands we send
+/// return one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Value {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+/// A minimal Redis client, speaking just enough RESP to send a few simple commands. Connections
+/// are opened as needed and kept for reuse, and one that fails is thrown away, so the next command
+/// reconnects.
+pub struct Client {
+    address: String,
+    // How long a round trip, connecting included, may take
+    timeout: Duration,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+}
+
+impl Client {
+    pub fn new(address: String, timeout: Duration) -> Client {
+        Client {
+            address,
+            timeout,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sends the commands in one round trip and returns their replies, in order. An error reply to
+    /// any of them fails the whole call.
+    pub async fn pipeline(&self, commands: &[&[&str]]) -> Result<Vec<Value>, String> {
+        let idle = self.idle.lock().pop();
+        let round_trip = async {
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => BufReader::new(
+                    TcpStream::connect(&self.address)
+                        .await
+                        .map_err(|err| format!("could not connect to {}: {}", self.address, err))?,
+                ),
+            };
+            let mut request = Vec::new();
+            for command in commands {
+                request.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+                for arg in *command {
+                    request.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+                }
+            }
+            conn.get_mut()
+                .write_all(&request)
+                .await
+                .map_err(|err| err.to_string())?;
+            let mut replies = Vec::with_capacity(commands.len());
+            for _ in commands {
+                replies.push(read_reply(&mut conn).await?);
+            }
+            Ok::<_, String>((conn, replies))
+        };
+        let (conn, replies) = tokio::time::timeout(self.timeout, round_trip)
+            .await
+            .map_err(|_| format!("no reply within {}ms", self.timeout.as_millis()))??;
+        let mut idle = self.idle.lock();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(conn);
+        }
+        Ok(replies)
+    }
+}
+
+async fn read_line(conn: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    conn.read_line(&mut line)
+        .await
+        .map_err(|err| err.to_string())?;
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_string()),
+        None => Err("connection closed mid-reply".to_string()),
+    }
+}
+
+async fn read_reply(conn: &mut BufReader<TcpStream>) -> Result<Value, String> {
+    let line = read_line(conn).await?;
+    let parse_int = |s: &str| {
+        s.parse::<i64>()
+            .map_err(|_| format!("invalid reply {:?}", line))
+    };
+    match line.split_at_checked(1) {
+        Some(("+", status)) => Ok(Value::Status(status.to_string())),
+        Some(("-", error)) => Err(format!("Redis error: {}", error)),
+        Some((":", n)) => Ok(Value::Integer(parse_int(n)?)),
+        Some(("$", len)) => {
+            let Ok(len) = usize::try_from(parse_int(len)?) else {
+                // A negative length is a nil reply
+                return Ok(Value::Bulk(None));
+            };
+            let mut data = vec![0_u8; len + 2];
+            conn.read_exact(&mut data)
+                .await
+                .map_err(|err| err.to_string())?;
+            data.truncate(len);
+            Ok(Value::Bulk(Some(data)))
+        }
+        _ => Err(format!("unsupported reply {:?}", line)),
+    }
+}
@@ -1,12 +1,77 @@
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+/// Longest chunk-size line, or trailer section, we accept in a chunked body
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+
+/// The body size cap for requests read outside the proxy path, e.g. by the admin API
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10000000;
+
+/// Headers that must appear at most once in a request. Different frameworks disagree about which
+/// copy of a repeated singleton header wins, so forwarding them verbatim lets a client make us and
+/// the upstream see different requests.
+const SINGLETON_HEADERS: [&str; 4] = ["host", "content-length", "content-type", "authorization"];
+
+/// What to do when a request repeats a singleton header.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum DuplicateHeaderPolicy {
+    /// Reject the request with 400 Bad Request
+    Reject,
+    /// Keep the first value and drop the rest
+    FirstWins,
+    /// Collapse identical values into one; differing values are joined with ", ", except for Host
+    /// and Content-Length, which can't be merged and are rejected
+    Merge,
+}
+
+/// How big a request head may be. A request over any of these limits is rejected with 431 Request
+/// Header Fields Too Large.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderLimits {
+    /// Size of the whole head, request line included
+    pub max_bytes: usize,
+    /// Size of any one line in the head, request line included
+    pub max_line: usize,
+    /// Number of headers
+    pub max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> HeaderLimits {
+        HeaderLimits {
+            max_bytes: 8000,
+            max_line: 8000,
+            max_count: 32,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Returns true if any line in `head` is longer than max_line, counting a line that hasn't
+    /// been terminated yet.
+    fn line_too_long(&self, head: &[u8]) -> bool {
+        head.split(|byte| *byte == b'\n').any(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            line.len() > self.max_line
+        })
+    }
+}
+
+/// How long a client may take to send a request, so that a client trickling bytes can't pin a
+/// connection and its buffer forever. A zero duration means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadTimeouts {
+    /// Time allowed for the complete request head, once reading starts
+    pub head: Duration,
+    /// Time allowed for each read, in the head or the body, to return any bytes
+    pub idle: Duration,
+}
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
     /// bytes that were successfully read before the client hung up
@@ -19,12 +84,35 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header doesn't match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request body is bigger than the caller's limit
     RequestBodyTooLarge,
+    /// The request has both Content-Length and Transfer-Encoding, which a client can use to make us
+    /// and the upstream disagree about where it ends
+    AmbiguousLength,
+    /// The head could be read differently by another parser, because its Content-Length headers
+    /// disagree or a header line is folded onto the one before it (obs-fold), which a client can
+    /// use to smuggle a request past us. AmbiguousFraming says which, for debug logging.
+    #[allow(dead_code)]
+    AmbiguousFraming(&'static str),
+    /// The Transfer-Encoding header asks for something other than chunked
+    UnsupportedTransferEncoding,
+    /// The chunked body doesn't follow the chunked format
+    InvalidChunkedBody,
+    /// The request head exceeded the configured HeaderLimits
+    HeaderFieldsTooLarge,
+    /// A header that may only appear once was repeated. DuplicateHeader contains the header name,
+    /// which is only used for debug logging.
+    #[allow(dead_code)]
+    DuplicateHeader(String),
+    /// The client didn't send the request within the configured ReadTimeouts
+    RequestTimeout,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
+/// A parsed request head, along with the number of bytes it occupied in the read buffer.
+type ParsedRequest = (http::Request<Vec<u8>>, usize);
+
 /// Extracts the Content-Length header value from the provided request.
 /// Returns Ok(Some(usize)) if the Content-Length is present and valid, Ok(None) if Content-Length is not
 /// present, or Err(Error) if Content-Length is present but invalid.
@@ -50,27 +138,206 @@ pub fn extend_header_value(
     name: &'static str,
     extend_value: &str,
 ) {
-    let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
-            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
-        }
-        None => extend_value.as_bytes().to_owned(),
-    };
+    // Every copy of the header is part of the list, and they are merged into one
+    let mut values: Vec<&[u8]> = request
+        .headers()
+        .get_all(name)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    values.push(extend_value.as_bytes());
+    let new_value = values.join(&b", "[..]);
     request
         .headers_mut()
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Returns whether the value is made up of token characters only (RFC 9110 §5.6.2).
+fn is_token(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Headers in which proxies tell their upstreams about the client and what it asked for.
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+];
+
+/// Quotes a Forwarded parameter value unless it is a token (e.g. an IPv6 address, or a host with a
+/// port, has to be quoted).
+fn forwarded_value(value: &str) -> String {
+    if !value.is_empty() && is_token(value) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Tells the upstream who the client is and what host and scheme it asked for, in the Forwarded
+/// header (RFC 7239) and the X-Forwarded-For/Host/Proto headers. If the client is itself a proxy
+/// we trust, the values it passed on describe the original client, so they are kept and we add
+/// ours to the end of the lists; anyone else's are untrustworthy, so they are replaced.
+pub fn set_forwarding_headers(
+    request: &mut http::Request<Vec<u8>>,
+    client_ip: std::net::IpAddr,
+    trust_incoming: bool,
+) {
+    if !trust_incoming {
+        for name in FORWARDING_HEADERS {
+            request.headers_mut().remove(name);
+        }
+    }
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        })
+        .map(str::to_string);
+    // A proxy in front of us knows the scheme and host the client used better than we do
+    let headers = request.headers_mut();
+    if !headers.contains_key("x-forwarded-proto") {
+        headers.insert("x-forwarded-proto", http::HeaderValue::from_static("http"));
+    }
+    if let Some(host) = host
+        .as_ref()
+        .and_then(|host| http::HeaderValue::from_str(host).ok())
+    {
+        if !headers.contains_key("x-forwarded-host") {
+            headers.insert("x-forwarded-host", host);
+        }
+    }
+    extend_header_value(request, "x-forwarded-for", &client_ip.to_string());
+    let node = match client_ip {
+        std::net::IpAddr::V4(ip) => ip.to_string(),
+        std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    let mut element = format!("for={};proto=http", forwarded_value(&node));
+    if let Some(host) = &host {
+        element.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    extend_header_value(request, "forwarded", &element);
+}
+
+/// Returns the value of the named cookie from the request's Cookie header(s), if present.
+pub fn get_cookie(request: &http::Request<Vec<u8>>, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then(|| value.to_string())
+        })
+}
+
+/// Headers that only concern the connection they arrive on, so aren't passed on to the next hop
+/// (RFC 9110 §7.6.1). Transfer-Encoding is hop-by-hop too, but is left to whatever frames the body
+/// on the next hop: we relay chunked bodies in the framing it already describes.
+const HOP_BY_HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+/// Returns the options listed in the message's Connection headers, lowercased.
+pub fn connection_options(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Removes the hop-by-hop headers from a message being forwarded, along with any headers its
+/// Connection headers name, except for the ones that frame or route the message. If `upgrading`,
+/// the Upgrade header and the "upgrade" Connection option stay, so that the next hop can take part
+/// in the protocol switch. A TE header offering trailers is replaced by one of our own, since we
+/// forward them.
+pub fn strip_hop_by_hop_headers(headers: &mut http::HeaderMap, upgrading: bool) {
+    let options = connection_options(headers);
+    let accepts_trailers = headers
+        .get_all(http::header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
+    for option in &options {
+        let protected = ["content-length", "transfer-encoding", "host"].contains(&option.as_str())
+            || upgrading && option == "upgrade";
+        if !protected {
+            headers.remove(option.as_str());
+        }
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        if !(upgrading && (name == "upgrade" || name == "connection")) {
+            headers.remove(name);
+        }
+    }
+    if upgrading {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("upgrade"),
+        );
+    }
+    if accepts_trailers {
+        headers.insert(http::header::TE, http::HeaderValue::from_static("trailers"));
+    }
+}
+
+/// Parses the name we go by in Via headers, which must be a token, since it goes in a
+/// comma-separated list of space-separated fields. An empty name is allowed, and means we don't use
+/// Via at all.
+pub fn parse_via_name(name: &str) -> Result<String, String> {
+    if is_token(name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("{:?} is not a valid Via name", name))
+    }
+}
+
+/// Returns whether a proxy going by `name` is among those the Via headers say the message has passed
+/// through already.
+pub fn via_includes(headers: &http::HeaderMap, name: &str) -> bool {
+    headers
+        .get_all(http::header::VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // Each entry is a protocol version, who received the message, and maybe a comment
+        .filter_map(|entry| entry.split_whitespace().nth(1))
+        .any(|received_by| received_by.eq_ignore_ascii_case(name))
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the following:
 /// 1. If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
 /// 2. If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
 /// 3. If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+fn parse_request(buffer: &[u8], limits: HeaderLimits) -> Result<Option<ParsedRequest>, Error> {
+    if has_folded_line(buffer) {
+        return Err(Error::AmbiguousFraming("obs-fold header line"));
+    }
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_count];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::HeaderFieldsTooLarge,
+        err => Error::MalformedRequest(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -87,23 +354,68 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Returns whether a line of the (possibly partial) head after the request line starts with
+/// whitespace, which either continues the previous header line (obs-fold) or, right after the
+/// request line, hides a header from parsers that skip it.
+fn has_folded_line(buffer: &[u8]) -> bool {
+    buffer
+        .split(|byte| *byte == b'\n')
+        .skip(1)
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .any(|line| line[0] == b' ' || line[0] == b'\t')
+}
+
+/// Reads whatever bytes are available from the stream, failing with RequestTimeout if none arrive
+/// before `deadline` or within the idle timeout.
+async fn read_some(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+    deadline: Option<Instant>,
+    idle: Duration,
+) -> Result<usize, Error> {
+    let idle_deadline = (!idle.is_zero()).then(|| Instant::now() + idle);
+    let deadline = match (deadline, idle_deadline) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, stream.read(buffer))
+            .await
+            .map_err(|_| Error::RequestTimeout)?,
+        None => stream.read(buffer).await,
+    };
+    result.map_err(Error::ConnectionError)
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: HeaderLimits,
+    timeouts: ReadTimeouts,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot.
     // e.g. we might receive the first few bytes of a request, and then the rest follows later.
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    let mut request_buffer = vec![0_u8; limits.max_bytes];
     let mut bytes_read = 0;
+    let deadline = (!timeouts.head.is_zero()).then(|| Instant::now() + timeouts.head);
     loop {
+        if bytes_read == request_buffer.len() {
+            // The buffer is full and we still haven't seen the end of the head
+            return Err(Error::HeaderFieldsTooLarge);
+        }
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let new_bytes = read_some(
+            stream,
+            &mut request_buffer[bytes_read..],
+            deadline,
+            timeouts.idle,
+        )
+        .await?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
@@ -111,7 +423,12 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        let parsed = parse_request(&request_buffer[..bytes_read], limits)?;
+        let head_len = parsed.as_ref().map_or(bytes_read, |(_, len)| *len);
+        if limits.line_too_long(&request_buffer[..head_len]) {
+            return Err(Error::HeaderFieldsTooLarge);
+        }
+        if let Some((mut request, headers_len)) = parsed {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -128,19 +445,17 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
+    idle: Duration,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error
     while request.body().len() < content_length {
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
         // space to read that body.)
         let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = read_some(stream, &mut buffer, None, idle).await?;
 
         // Make sure the client is still sending us bytes.
         if bytes_read == 0 {
@@ -163,39 +478,346 @@ async fn read_body(
     Ok(())
 }
 
+/// Rejects Content-Length values that disagree, whether in separate headers or one comma-separated
+/// list, whatever the duplicate header policy, since keeping any one of them would let the client
+/// frame the body differently for an upstream that kept another. A list of identical values is
+/// collapsed to one.
+fn check_content_lengths(request: &mut http::Request<Vec<u8>>) -> Result<(), Error> {
+    let values: Vec<Vec<u8>> = request
+        .headers()
+        .get_all(http::header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|byte| *byte == b','))
+        .map(|value| value.trim_ascii().to_vec())
+        .collect();
+    if values.iter().any(|value| *value != values[0]) {
+        return Err(Error::AmbiguousFraming("conflicting Content-Length"));
+    }
+    let headers = request.headers_mut();
+    if let Some(value) = headers.get_mut(http::header::CONTENT_LENGTH) {
+        if value.as_bytes().contains(&b',') {
+            // Valid or not, a single value is checked by get_content_length later on
+            *value = http::HeaderValue::from_bytes(&values[0]).unwrap_or(value.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Applies the duplicate header policy to every singleton header that appears more than once in
+/// the request, leaving at most one copy of each. Returns Err(Error::DuplicateHeader) if the policy
+/// says the request should be rejected.
+fn normalize_duplicate_headers(
+    request: &mut http::Request<Vec<u8>>,
+    policy: DuplicateHeaderPolicy,
+) -> Result<(), Error> {
+    for name in SINGLETON_HEADERS {
+        let values: Vec<http::HeaderValue> =
+            request.headers().get_all(name).iter().cloned().collect();
+        if values.len() < 2 {
+            continue;
+        }
+        let all_identical = values.iter().all(|value| *value == values[0]);
+        let merged = match policy {
+            DuplicateHeaderPolicy::Reject => return Err(Error::DuplicateHeader(name.to_string())),
+            DuplicateHeaderPolicy::FirstWins => values[0].clone(),
+            DuplicateHeaderPolicy::Merge if all_identical => values[0].clone(),
+            DuplicateHeaderPolicy::Merge if name == "host" || name == "content-length" => {
+                return Err(Error::DuplicateHeader(name.to_string()))
+            }
+            DuplicateHeaderPolicy::Merge => {
+                let joined = values
+                    .iter()
+                    .map(|value| value.as_bytes())
+                    .collect::<Vec<&[u8]>>()
+                    .join(&b", "[..]);
+                http::HeaderValue::from_bytes(&joined).unwrap()
+            }
+        };
+        log::debug!(
+            "Normalized {} copies of the {} header ({:?})",
+            values.len(),
+            name,
+            policy
+        );
+        request.headers_mut().insert(name, merged);
+    }
+    Ok(())
+}
+
+/// Makes sure `buffer` holds at least `len` bytes, reading more from the stream as needed.
+async fn fill(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer: &mut Vec<u8>,
+    len: usize,
+    idle: Duration,
+) -> Result<(), Error> {
+    let mut chunk = [0_u8; 512];
+    while buffer.len() < len {
+        let bytes_read = read_some(stream, &mut chunk, None, idle).await?;
+        if bytes_read == 0 {
+            log::debug!("Client hung up partway through a chunked body");
+            return Err(Error::InvalidChunkedBody);
+        }
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    }
+    Ok(())
+}
+
+/// Returns the line at the start of `buffer` (without its CRLF), reading more from the stream
+/// until it is complete, and removes it from the buffer.
+async fn take_line(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer: &mut Vec<u8>,
+    idle: Duration,
+) -> Result<Vec<u8>, Error> {
+    loop {
+        if let Some(end) = buffer.windows(2).position(|window| window == b"\r\n") {
+            let line = buffer[..end].to_vec();
+            buffer.drain(..end + 2);
+            return Ok(line);
+        }
+        if buffer.len() > MAX_CHUNK_LINE_SIZE {
+            return Err(Error::InvalidChunkedBody);
+        }
+        fill(stream, buffer, buffer.len() + 1, idle).await?;
+    }
+}
+
+/// The trailer fields that followed a chunked request body. read_from_stream attaches them to the
+/// request as an extension, and write_to_stream sends them on after the body.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Parses a trailer field line. Malformed lines, and fields that can only go in the head because
+/// they frame or route the message, yield None.
+pub fn parse_trailer(line: &[u8]) -> Option<(http::HeaderName, http::HeaderValue)> {
+    let colon = line.iter().position(|byte| *byte == b':')?;
+    let name = http::HeaderName::from_bytes(&line[..colon]).ok()?;
+    let value = http::HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).ok()?;
+    let head_only = name == http::header::CONTENT_LENGTH
+        || name == http::header::TRANSFER_ENCODING
+        || name == http::header::HOST;
+    (!head_only).then_some((name, value))
+}
+
+/// Serializes trailer fields as they go after the last chunk, each line ending with a CRLF.
+pub fn format_trailers(trailers: &http::HeaderMap) -> Vec<u8> {
+    let mut formatted = Vec::new();
+    for (name, value) in trailers {
+        formatted.extend_from_slice(name.as_str().as_bytes());
+        formatted.extend_from_slice(b": ");
+        formatted.extend_from_slice(value.as_bytes());
+        formatted.extend_from_slice(b"\r\n");
+    }
+    formatted
+}
+
+/// Reads a chunked body, starting from whatever read_headers already put in the request body, and
+/// replaces it with the decoded body. Chunk extensions are dropped. A body without trailer fields
+/// is relabeled with the Content-Length of the decoded body, since we forward it whole; one with
+/// them stays chunked, so that they can follow it, and they are kept as a Trailers extension.
+async fn read_chunked_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut http::Request<Vec<u8>>,
+    max_body: usize,
+    idle: Duration,
+) -> Result<(), Error> {
+    let mut buffer = std::mem::take(request.body_mut());
+    let mut body = Vec::new();
+    loop {
+        let line = take_line(stream, &mut buffer, idle).await?;
+        let size = line.split(|byte| *byte == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+            .ok_or(Error::InvalidChunkedBody)?;
+        if size == 0 {
+            break;
+        }
+        // Compared this way round so that a huge size can't overflow; body.len() is never more
+        // than max_body
+        if size > max_body - body.len() {
+            return Err(Error::RequestBodyTooLarge);
+        }
+        fill(stream, &mut buffer, size + 2, idle).await?;
+        if &buffer[size..size + 2] != b"\r\n" {
+            return Err(Error::InvalidChunkedBody);
+        }
+        body.extend_from_slice(&buffer[..size]);
+        buffer.drain(..size + 2);
+    }
+    // Collect any trailer fields, up to the empty line that ends the body
+    let mut trailers = http::HeaderMap::new();
+    let mut trailer_size = 0;
+    loop {
+        let line = take_line(stream, &mut buffer, idle).await?;
+        if line.is_empty() {
+            break;
+        }
+        trailer_size += line.len() + 2;
+        if trailer_size > MAX_CHUNK_LINE_SIZE {
+            return Err(Error::InvalidChunkedBody);
+        }
+        if let Some((name, value)) = parse_trailer(&line) {
+            trailers.append(name, value);
+        }
+    }
+    if trailers.is_empty() {
+        let headers = request.headers_mut();
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.insert(
+            http::header::CONTENT_LENGTH,
+            http::HeaderValue::from(body.len()),
+        );
+    } else {
+        request.extensions_mut().insert(Trailers(trailers));
+    }
+    *request.body_mut() = body;
+    Ok(())
+}
+
+/// Returns whether the request has a chunked body. Fails if it both has a Transfer-Encoding and a
+/// Content-Length, or if it has a Transfer-Encoding other than chunked.
+fn is_chunked(request: &http::Request<Vec<u8>>) -> Result<bool, Error> {
+    let mut codings = request
+        .headers()
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("?").split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .peekable();
+    if codings.peek().is_none() {
+        return Ok(false);
+    }
+    if request.headers().contains_key(http::header::CONTENT_LENGTH) {
+        return Err(Error::AmbiguousLength);
+    }
+    if codings.ne(["chunked".to_string()]) {
+        return Err(Error::UnsupportedTransferEncoding);
+    }
+    Ok(true)
+}
+
 /// This function reads and returns an HTTP request from a stream, returns an Error if the client
-/// closed the connection prematurely or sends an invalid request.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    let mut request = read_headers(stream).await?;
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+/// closed the connection prematurely or sends an invalid request. Repeated singleton headers are
+/// handled according to `duplicate_policy`, and bodies over `max_body` bytes are rejected without
+/// being read.
+///
+/// A body with a Content-Length over `stream_over` bytes (unless that is 0) is left on the stream,
+/// apart from whatever arrived with the head, for forward_body to pipe to the upstream;
+/// unread_body_len says how much of it there is.
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    duplicate_policy: DuplicateHeaderPolicy,
+    limits: HeaderLimits,
+    timeouts: ReadTimeouts,
+    max_body: usize,
+    stream_over: usize,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let mut request = read_headers(stream, limits, timeouts).await?;
+    check_content_lengths(&mut request)?;
+    normalize_duplicate_headers(&mut request, duplicate_policy)?;
+    if is_chunked(&request)? {
+        read_chunked_body(stream, &mut request, max_body, timeouts.idle).await?;
+    } else if let Some(content_length) = get_content_length(&request)? {
+        if content_length > max_body {
             return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
+        } else if stream_over == 0
+            || content_length <= stream_over
+            || request.body().len() >= content_length
+        {
+            read_body(stream, &mut request, content_length, timeouts.idle).await?;
         }
     }
     Ok(request)
 }
 
+/// Returns how many bytes of the request body read_from_stream left on the client connection.
+pub fn unread_body_len(request: &http::Request<Vec<u8>>) -> usize {
+    get_content_length(request)
+        .ok()
+        .flatten()
+        .map_or(0, |content_length| {
+            content_length.saturating_sub(request.body().len())
+        })
+}
+
+/// Why piping a request body to the upstream stopped partway.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The client hung up early, or stalled for longer than the idle timeout
+    Client(Error),
+    /// The upstream connection failed
+    Upstream(std::io::Error),
+}
+
+/// Copies the `len` bytes of a request body that read_from_stream left on the client connection to
+/// the upstream, a buffer at a time, so that neither side gets ahead of the other by more than that.
+pub async fn forward_body(
+    client: &mut (impl AsyncRead + Unpin),
+    upstream: &mut (impl AsyncWrite + Unpin),
+    len: usize,
+    idle: Duration,
+) -> Result<(), StreamError> {
+    let mut buffer = vec![0_u8; min(16384, len)];
+    let mut remaining = len;
+    while remaining > 0 {
+        // Never read past the body, into a request the client has pipelined behind it
+        let want = min(buffer.len(), remaining);
+        let bytes_read = read_some(client, &mut buffer[..want], None, idle)
+            .await
+            .map_err(StreamError::Client)?;
+        if bytes_read == 0 {
+            log::debug!(
+                "Client hung up with {} of {} streamed body bytes left to send",
+                remaining,
+                len
+            );
+            return Err(StreamError::Client(Error::ContentLengthMismatch));
+        }
+        upstream
+            .write_all(&buffer[..bytes_read])
+            .await
+            .map_err(StreamError::Upstream)?;
+        remaining -= bytes_read;
+    }
+    Ok(())
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(format_request_line(request).as_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in request.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    match request.extensions().get::<Trailers>() {
+        // The body is still labeled chunked, and goes out as a single chunk
+        Some(Trailers(trailers)) => {
+            if !request.body().is_empty() {
+                stream
+                    .write_all(format!("{:x}\r\n", request.body().len()).as_bytes())
+                    .await?;
+                stream.write_all(request.body()).await?;
+                stream.write_all(b"\r\n").await?;
+            }
+            stream.write_all(b"0\r\n").await?;
+            stream.write_all(&format_trailers(trailers)).await?;
+            stream.write_all(b"\r\n").await?;
+        }
+        None if !request.body().is_empty() => stream.write_all(request.body()).await?,
+        None => {}
     }
     Ok(())
 }
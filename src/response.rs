@@ -1,11 +1,12 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
+/// The body size cap for responses read outside the proxy path, e.g. health checks
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10000000;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Client hung up before sending a complete request
     #[allow(dead_code)]
@@ -17,13 +18,21 @@ pub enum Error {
     InvalidContentLength,
     /// The Content-Length header doesn't match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The response body is bigger than the caller's limit
     ResponseBodyTooLarge,
+    /// The chunked body doesn't follow the chunked format
+    InvalidChunkedBody,
+    /// The client stopped sending a request body that was being streamed to the upstream, so the
+    /// upstream will never see a complete request
+    ClientAborted(crate::request::Error),
     /// Encountered an I/O error when reading/writing a TcpStream
     #[allow(dead_code)]
     ConnectionError(std::io::Error),
 }
 
+/// A parsed response head, along with the number of bytes it occupied in the read buffer.
+type ParsedResponse = (http::Response<Vec<u8>>, usize);
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -51,12 +60,10 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
 ///   Err(Error)
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+fn parse_response(buffer: &[u8]) -> Result<Option<ParsedResponse>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -73,11 +80,13 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. This function only reads the response line and headers; the read_remaining_body function
+/// can subsequently be called in order to read the response body.
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+pub async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -88,7 +97,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
         let new_bytes = stream
             .read(&mut response_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             // TODO
@@ -111,21 +120,26 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Fails with ResponseBodyTooLarge as soon as it is clear the body is over `max_body` bytes.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     response: &mut http::Response<Vec<u8>>,
+    max_body: usize,
 ) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    if content_length.is_some_and(|content_length| content_length > max_body) {
+        return Err(Error::ResponseBodyTooLarge);
+    }
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer)
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up
             if content_length.is_none() {
@@ -145,7 +159,7 @@ async fn read_body(
         }
 
         // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
+        if response.body().len() + bytes_read > max_body {
             return Err(Error::ResponseBodyTooLarge);
         }
 
@@ -155,48 +169,312 @@ async fn read_body(
     Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
-    request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
+/// Returns whether the response has a body.
+fn has_body(request_method: &http::Method, response: &http::Response<Vec<u8>>) -> bool {
     // A response may have a body as long as it is not responding to a HEAD request and as long as
     // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    !(request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
         || response.status() == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Reads the body of a response whose headers were read with read_headers, if it has one.
+pub async fn read_remaining_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request_method: &http::Method,
+    response: &mut http::Response<Vec<u8>>,
+    max_body: usize,
+) -> Result<(), Error> {
+    if has_body(request_method, response) {
+        read_body(stream, response, max_body).await?;
+    }
+    Ok(())
+}
+
+/// How a response body that is forwarded as it arrives, rather than read in full first, ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Streamed {
+    /// The body is chunked and ends with its last chunk, after which the connection can be reused
+    Chunked,
+    /// The body has no length and ends when the upstream closes the connection
+    UntilClose,
+    /// The body has a Content-Length of this many bytes, too many to be worth buffering
+    Length(usize),
+}
+
+/// Returns whether the response is a stream of server-sent events, which arrive as they happen
+/// rather than all at once.
+pub fn is_event_stream(response: &http::Response<Vec<u8>>) -> bool {
+    response
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Returns how the body of a response whose headers were read with read_headers should be
+/// streamed, or None if it should be read in full: if there is no body, or its Content-Length isn't
+/// over `stream_over` bytes (or `stream_over` is 0). An event stream is always streamed, since its
+/// events must reach the client as they arrive.
+pub fn streamed(
+    request_method: &http::Method,
+    response: &http::Response<Vec<u8>>,
+    stream_over: usize,
+) -> Option<Streamed> {
+    if !has_body(request_method, response) {
+        return None;
+    }
+    if !response
+        .headers()
+        .contains_key(http::header::TRANSFER_ENCODING)
     {
-        read_body(stream, &mut response).await?;
+        return match get_content_length(response) {
+            Ok(Some(len)) if stream_over > 0 && len > stream_over => Some(Streamed::Length(len)),
+            Ok(Some(len)) if is_event_stream(response) => Some(Streamed::Length(len)),
+            // An invalid length is left for read_remaining_body to report
+            Ok(Some(_)) | Err(_) => None,
+            Ok(None) => Some(Streamed::UntilClose),
+        };
+    }
+    let chunked = response
+        .headers()
+        .get_all(http::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .next_back()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+    Some(if chunked {
+        Streamed::Chunked
+    } else {
+        Streamed::UntilClose
+    })
+}
+
+/// Why streaming a response body stopped partway.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The upstream hung up early or broke the chunked format
+    Upstream(Error),
+    /// The client connection failed
+    Client(std::io::Error),
+}
+
+/// Where a chunked body parser is in the body.
+#[derive(Clone, Copy, Debug)]
+enum ChunkState {
+    // Reading a chunk-size line
+    Size,
+    // Inside a chunk's data, with this many bytes left
+    Data(usize),
+    // After a chunk's data, with this many bytes of the CRLF that must end it already seen
+    DataEnd(usize),
+    // Reading a trailer line, or the empty line that ends the body
+    Trailer,
+    Done,
+}
+
+/// Follows the chunked framing of a body as it passes through, to find where the body ends, and
+/// optionally to decode it. The trailer fields after the last chunk are kept.
+pub struct ChunkParser {
+    state: ChunkState,
+    // The line being read, in the Size and Trailer states
+    line: Vec<u8>,
+    trailers: http::HeaderMap,
+}
+
+impl ChunkParser {
+    pub fn new() -> ChunkParser {
+        ChunkParser {
+            state: ChunkState::Size,
+            line: Vec::new(),
+            trailers: http::HeaderMap::new(),
+        }
+    }
+
+    /// Takes the trailer fields read so far.
+    pub fn take_trailers(&mut self) -> http::HeaderMap {
+        std::mem::take(&mut self.trailers)
+    }
+
+    /// Consumes bytes up to the end of the body, appending the chunk data among them to `data` if
+    /// given. Returns how many of `bytes` belong to the body.
+    pub fn feed(&mut self, bytes: &[u8], mut data: Option<&mut Vec<u8>>) -> Result<usize, Error> {
+        let mut pos = 0;
+        while pos < bytes.len() {
+            if let ChunkState::Data(left) = self.state {
+                let taken = left.min(bytes.len() - pos);
+                if let Some(data) = data.as_mut() {
+                    data.extend_from_slice(&bytes[pos..pos + taken]);
+                }
+                pos += taken;
+                self.state = if taken == left {
+                    ChunkState::DataEnd(0)
+                } else {
+                    ChunkState::Data(left - taken)
+                };
+                continue;
+            }
+            if let ChunkState::DataEnd(seen) = self.state {
+                if bytes[pos] != b"\r\n"[seen] {
+                    return Err(Error::InvalidChunkedBody);
+                }
+                pos += 1;
+                self.state = match seen {
+                    0 => ChunkState::DataEnd(1),
+                    _ => ChunkState::Size,
+                };
+                continue;
+            }
+            if let ChunkState::Done = self.state {
+                break;
+            }
+            let byte = bytes[pos];
+            pos += 1;
+            if byte != b'\n' {
+                if self.line.len() >= MAX_HEADERS_SIZE {
+                    return Err(Error::InvalidChunkedBody);
+                }
+                self.line.push(byte);
+                continue;
+            }
+            let line = std::mem::take(&mut self.line);
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            self.state = match self.state {
+                ChunkState::Size => {
+                    // Chunk extensions follow the size, after a semicolon
+                    let size = line.split(|byte| *byte == b';').next().unwrap_or_default();
+                    match std::str::from_utf8(size)
+                        .ok()
+                        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                    {
+                        Some(0) => ChunkState::Trailer,
+                        Some(size) => ChunkState::Data(size),
+                        None => return Err(Error::InvalidChunkedBody),
+                    }
+                }
+                ChunkState::Trailer if line.is_empty() => ChunkState::Done,
+                _ => {
+                    if self.trailers.len() >= MAX_NUM_HEADERS {
+                        return Err(Error::InvalidChunkedBody);
+                    }
+                    if let Some((name, value)) = crate::request::parse_trailer(line) {
+                        self.trailers.append(name, value);
+                    }
+                    ChunkState::Trailer
+                }
+            };
+        }
+        Ok(pos)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done)
     }
+}
+
+/// Copies the rest of a streamed response body from the upstream to the client as it arrives.
+/// `prefix` is the part of the body that read_headers already read (and that was sent to the
+/// client along with the headers). Chunked bodies are passed through unchanged, chunk framing
+/// included. Each read is flushed to the client straight away, so that e.g. server-sent events
+/// aren't held back.
+pub async fn stream_body(
+    upstream: &mut (impl AsyncRead + Unpin),
+    client: &mut (impl AsyncWrite + Unpin),
+    prefix: &[u8],
+    streamed: Streamed,
+) -> Result<(), StreamError> {
+    let mut parser = ChunkParser::new();
+    if streamed == Streamed::Chunked {
+        parser.feed(prefix, None).map_err(StreamError::Upstream)?;
+    }
+    // Bytes left in a body with a Content-Length
+    let mut remaining = match streamed {
+        Streamed::Length(len) => Some(len.saturating_sub(prefix.len())),
+        _ => None,
+    };
+    let mut buffer = [0_u8; 16384];
+    while !parser.is_done() && remaining != Some(0) {
+        let want = remaining.map_or(buffer.len(), |remaining| remaining.min(buffer.len()));
+        let bytes_read = upstream
+            .read(&mut buffer[..want])
+            .await
+            .map_err(|err| StreamError::Upstream(Error::ConnectionError(err)))?;
+        if bytes_read == 0 {
+            return match streamed {
+                Streamed::UntilClose => Ok(()),
+                Streamed::Chunked => Err(StreamError::Upstream(Error::InvalidChunkedBody)),
+                Streamed::Length(_) => Err(StreamError::Upstream(Error::ContentLengthMismatch)),
+            };
+        }
+        let body_len = match streamed {
+            Streamed::Chunked => parser
+                .feed(&buffer[..bytes_read], None)
+                .map_err(StreamError::Upstream)?,
+            Streamed::UntilClose | Streamed::Length(_) => bytes_read,
+        };
+        if let Some(remaining) = &mut remaining {
+            *remaining -= body_len;
+        }
+        client
+            .write_all(&buffer[..body_len])
+            .await
+            .map_err(StreamError::Client)?;
+        client.flush().await.map_err(StreamError::Client)?;
+    }
+    Ok(())
+}
+
+/// This function reads and returns an HTTP response from a stream, returning an Error if the server
+/// closes the connection prematurely or sends an invalid response.
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    request_method: &http::Method,
+    max_body: usize,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    let mut response = read_headers(stream).await?;
+    read_remaining_body(stream, request_method, &mut response, max_body).await?;
     Ok(response)
 }
 
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
     stream
-        .write(&format_response_line(response).into_bytes())
+        .write_all(format_response_line(response).as_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
         stream
-            .write(&format!("{}: ", header_name).as_bytes())
+            .write_all(format!("{}: ", header_name).as_bytes())
             .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
 
+/// Adds a Set-Cookie header to the response, alongside any cookies the upstream already set.
+pub fn add_cookie(
+    response: &mut http::Response<Vec<u8>>,
+    name: &str,
+    value: &str,
+    attributes: &str,
+) {
+    let cookie = format!("{}={}; {}", name, value, attributes);
+    response
+        .headers_mut()
+        .append("set-cookie", http::HeaderValue::from_str(&cookie).unwrap());
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
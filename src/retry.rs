@@ -0,0 +1,173 @@
+use parking_lot::{Mutex, MutexGuard};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How far back the retry budget looks, and the size of the buckets it counts in.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+const BUCKET: Duration = Duration::from_secs(1);
+/// Retries always allowed per window, however few requests there were, so that a quiet balancer
+/// can still retry at all.
+const MIN_RETRIES_PER_WINDOW: usize = 10;
+
+/// Why a retry wasn't attempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denied {
+    MaxRetries,
+    Budget,
+}
+
+/// Which failed attempts get retried on another upstream, parsed from a comma-separated list of
+/// `connect-failure` (the upstream refused the connection), `reset` (the connection broke while
+/// sending the request or reading the response), `timeout` (the response didn't start within the
+/// try timeout), `5xx` (any 5xx response), `gateway-error` (a
+/// 502, 503 or 504 response) and specific status codes, e.g. `connect-failure,reset,gateway-error`.
+#[derive(Clone, Debug, Default)]
+pub struct RetryOn {
+    connect_failure: bool,
+    reset: bool,
+    timeout: bool,
+    server_error: bool,
+    gateway_error: bool,
+    statuses: Vec<http::StatusCode>,
+}
+
+impl std::str::FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RetryOn, String> {
+        let mut retry_on = RetryOn::default();
+        for condition in s.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            match condition {
+                "connect-failure" => retry_on.connect_failure = true,
+                "reset" => retry_on.reset = true,
+                "timeout" => retry_on.timeout = true,
+                "5xx" => retry_on.server_error = true,
+                "gateway-error" => retry_on.gateway_error = true,
+                code => retry_on.statuses.push(
+                    code.parse::<u16>()
+                        .ok()
+                        .and_then(|code| http::StatusCode::from_u16(code).ok())
+                        .ok_or_else(|| format!("unknown retry condition {:?}", code))?,
+                ),
+            }
+        }
+        Ok(retry_on)
+    }
+}
+
+impl RetryOn {
+    pub fn connect_failure(&self) -> bool {
+        self.connect_failure
+    }
+
+    pub fn reset(&self) -> bool {
+        self.reset
+    }
+
+    pub fn timeout(&self) -> bool {
+        self.timeout
+    }
+
+    pub fn status(&self, status: http::StatusCode) -> bool {
+        (self.server_error && status.is_server_error())
+            || (self.gateway_error
+                && matches!(
+                    status,
+                    http::StatusCode::BAD_GATEWAY
+                        | http::StatusCode::SERVICE_UNAVAILABLE
+                        | http::StatusCode::GATEWAY_TIMEOUT
+                ))
+            || self.statuses.contains(&status)
+    }
+}
+
+/// Caps how much retrying we do, so that retries can't amplify the load on upstreams that are
+/// already struggling: each request gets at most `max_retries` retries, spaced out by an
+/// exponential backoff, and across all requests retries may add at most `budget_percent` on top
+/// of the request rate.
+pub struct RetryPolicy {
+    max_retries: usize,
+    // Wait before the first retry, doubled for each one after it (0 = retry straight away)
+    backoff: Duration,
+    budget_percent: f64,
+    retry_on: RetryOn,
+    // (bucket start, requests, retries), oldest first
+    window: Mutex<VecDeque<(Instant, usize, usize)>>,
+}
+
+impl RetryPolicy {
+    pub fn new(
+        max_retries: usize,
+        backoff: Duration,
+        budget_percent: f64,
+        retry_on: RetryOn,
+    ) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff,
+            budget_percent,
+            retry_on,
+            window: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn retry_on(&self) -> &RetryOn {
+        &self.retry_on
+    }
+
+    /// Counts a request towards the budget, before its first attempt.
+    pub fn record_request(&self) {
+        self.current_window().back_mut().unwrap().1 += 1;
+    }
+
+    /// Returns whether the request may make retry number `retry` (counting from 1), and if so,
+    /// takes it out of the budget.
+    pub fn allow(&self, retry: usize) -> Result<(), Denied> {
+        if retry > self.max_retries {
+            return Err(Denied::MaxRetries);
+        }
+        let mut window = self.current_window();
+        let (requests, retries) = window
+            .iter()
+            .fold((0, 0), |(requests, retries), (_, q, r)| {
+                (requests + q, retries + r)
+            });
+        let budget = (requests as f64 * self.budget_percent / 100.0) as usize;
+        if retries >= budget.max(MIN_RETRIES_PER_WINDOW) {
+            return Err(Denied::Budget);
+        }
+        window.back_mut().unwrap().2 += 1;
+        Ok(())
+    }
+
+    /// How long to wait before retry number `retry`, with some jitter so that requests that failed
+    /// together don't all retry together.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        if self.backoff.is_zero() {
+            return Duration::ZERO;
+        }
+        let base = self.backoff * 2_u32.saturating_pow(retry.saturating_sub(1) as u32).min(64);
+        base.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Drops buckets that have left the window, and makes sure there is a current bucket at the
+    /// back.
+    fn current_window(&self) -> MutexGuard<'_, VecDeque<(Instant, usize, usize)>> {
+        let now = Instant::now();
+        let mut window = self.window.lock();
+        while window
+            .front()
+            .is_some_and(|(start, _, _)| now.duration_since(*start) >= BUDGET_WINDOW)
+        {
+            window.pop_front();
+        }
+        if window
+            .back()
+            .is_none_or(|(start, _, _)| now.duration_since(*start) >= BUCKET)
+        {
+            window.push_back((now, 0, 0));
+        }
+        window
+    }
+}
@@ -0,0 +1,191 @@
+use crate::routing;
+
+/// What a rewrite rule changes.
+#[derive(Clone, Debug)]
+enum Action {
+    // Replace a path prefix, or strip it if the replacement is empty. The prefix only matches
+    // whole segments, so /api matches /api and /api/users but not /apiary
+    Prefix {
+        from: String,
+        to: String,
+    },
+    // Replace the first match of an expression in the path, where the replacement may refer to
+    // capture groups as $1 or ${name}
+    Regex {
+        pattern: regex::Regex,
+        replacement: String,
+    },
+    // Set (or override) the Host header
+    Host(http::HeaderValue),
+}
+
+/// A change to make to requests before they are forwarded, once they have been routed. Parsed from
+/// command-line values of the form `prefix /old [/new]`, `regex REGEX REPLACEMENT` or `host HOST`,
+/// optionally followed by `pool=NAME` to only rewrite requests routed to that pool. Request paths
+/// can't contain spaces, so neither do the paths and expressions.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    action: Action,
+    pub pool: Option<String>,
+}
+
+impl std::str::FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rewrite, String> {
+        let mut words: Vec<&str> = s.split_whitespace().collect();
+        let pool = match words.last().and_then(|word| word.strip_prefix("pool=")) {
+            Some(pool) if !pool.is_empty() => {
+                let pool = pool.to_string();
+                words.pop();
+                Some(pool)
+            }
+            _ => None,
+        };
+        let action = match words.as_slice() {
+            ["prefix", from] if from.starts_with('/') => Action::Prefix {
+                from: from.to_string(),
+                to: String::new(),
+            },
+            ["prefix", from, to] if from.starts_with('/') => Action::Prefix {
+                from: from.to_string(),
+                to: to.to_string(),
+            },
+            ["regex", pattern, replacement] => Action::Regex {
+                pattern: regex::Regex::new(pattern)
+                    .map_err(|err| format!("invalid rewrite expression {:?}: {}", pattern, err))?,
+                replacement: replacement.to_string(),
+            },
+            ["host", host] => Action::Host(
+                http::HeaderValue::from_str(host)
+                    .map_err(|_| format!("invalid host {:?}", host))?,
+            ),
+            _ => {
+                return Err(format!(
+                    "expected prefix /OLD [/NEW], regex REGEX REPLACEMENT or host HOST, \
+                     optionally followed by pool=NAME, got {:?}",
+                    s
+                ))
+            }
+        };
+        Ok(Rewrite { action, pool })
+    }
+}
+
+/// Replaces the request's path, keeping its query (after any query the new path has). The new path
+/// is made to start with a slash, so that e.g. stripping `/api` from `/api` leaves `/`. A path that
+/// isn't valid in a URI leaves the request as it was.
+pub fn set_path(request: &mut http::Request<Vec<u8>>, path: &str) {
+    let uri = request.uri().clone();
+    let path = match path.starts_with('/') {
+        true => path.to_string(),
+        false => format!("/{}", path),
+    };
+    let path_and_query = match (uri.query(), path.contains('?')) {
+        (Some(query), true) => format!("{}&{}", path, query),
+        (Some(query), false) => format!("{}?{}", path, query),
+        (None, _) => path,
+    };
+    let Ok(path_and_query) = path_and_query.parse() else {
+        log::warn!(
+            "Not rewriting a path to {:?}, which is invalid",
+            path_and_query
+        );
+        return;
+    };
+    let mut parts = uri.into_parts();
+    parts.path_and_query = Some(path_and_query);
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+}
+
+/// Sets the request's Host header, and the authority of its URI if it has one (as HTTP/2 requests
+/// do), so the two agree.
+fn set_host(request: &mut http::Request<Vec<u8>>, host: &http::HeaderValue) {
+    request
+        .headers_mut()
+        .insert(http::header::HOST, host.clone());
+    if request.uri().authority().is_none() {
+        return;
+    }
+    let Some(authority) = host
+        .to_str()
+        .ok()
+        .and_then(|host| host.parse::<http::uri::Authority>().ok())
+    else {
+        return;
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.authority = Some(authority);
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+}
+
+impl Rewrite {
+    fn apply(&self, request: &mut http::Request<Vec<u8>>) {
+        match &self.action {
+            Action::Prefix { from, to } => {
+                if let Some(rest) = routing::strip_path_prefix(request.uri().path(), from) {
+                    // A prefix ending in a slash eats the one that starts the rest, so put it back
+                    // to keep exactly one slash between the replacement and the rest
+                    let path = if from.ends_with('/') {
+                        format!("{}/{}", to.trim_end_matches('/'), rest)
+                    } else {
+                        format!("{}{}", to.trim_end_matches('/'), rest)
+                    };
+                    set_path(request, &path);
+                }
+            }
+            Action::Regex {
+                pattern,
+                replacement,
+            } => {
+                let path = request.uri().path();
+                if pattern.is_match(path) {
+                    let path = pattern.replace(path, replacement.as_str()).into_owned();
+                    set_path(request, &path);
+                }
+            }
+            Action::Host(host) => set_host(request, host),
+        }
+    }
+}
+
+/// Applies the configured rewrites to forwarded requests, in the order they were given, so that a
+/// rule sees the request as the rules before it left it.
+pub struct Rewriter {
+    rules: Vec<Rewrite>,
+}
+
+impl Rewriter {
+    pub fn new(rules: &[Rewrite]) -> Rewriter {
+        Rewriter {
+            rules: rules.to_vec(),
+        }
+    }
+
+    /// Rewrites a request that was routed to `pool`, logging what changed.
+    pub fn apply(&self, request: &mut http::Request<Vec<u8>>, pool: &str) {
+        let before = (
+            request.uri().clone(),
+            request.headers().get(http::header::HOST).cloned(),
+        );
+        for rule in &self.rules {
+            if rule.pool.as_deref().is_none_or(|only| only == pool) {
+                rule.apply(request);
+            }
+        }
+        let host = request.headers().get(http::header::HOST);
+        if before.0 != *request.uri() || before.1.as_ref() != host {
+            log::debug!(
+                "Rewrote {} (Host {:?}) to {} (Host {:?})",
+                before.0,
+                before.1,
+                request.uri(),
+                host
+            );
+        }
+    }
+}
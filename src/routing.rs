@@ -0,0 +1,776 @@
+use crate::hash_ring::HashOptions;
+use crate::health::PanicThreshold;
+use crate::maintenance::{TimeWindow, UtcOffset};
+use crate::rewrite::set_path;
+use crate::strategy::{Balancer, Balancers, RouteStrategy, Strategy};
+use crate::upstream::Upstream;
+use parking_lot::RwLock;
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// The pool an upstream is in when it doesn't name one.
+pub const DEFAULT_POOL: &str = "default";
+
+/// Returns the rest of `path` if it starts with `prefix`. A prefix only matches whole segments, so
+/// /api matches /api and /api/users but not /apiary, while /api/ only matches paths below /api/.
+pub fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+/// Sends requests for a host to a named pool of upstreams. Parsed from command-line values of the
+/// form `api.example.com=api`; a host of the form `*.example.com` matches any subdomain of
+/// example.com (but not example.com itself).
+#[derive(Clone, Debug)]
+pub struct HostRoute {
+    pub host: String,
+    pub pool: String,
+}
+
+impl std::str::FromStr for HostRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HostRoute, String> {
+        let (host, pool) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOST=POOL, got {:?}", s))?;
+        if host.is_empty()
+            || pool.is_empty()
+            || host.strip_prefix("*.").unwrap_or(host).contains('*')
+        {
+            return Err(format!("expected HOST=POOL, got {:?}", s));
+        }
+        Ok(HostRoute {
+            host: host.to_ascii_lowercase(),
+            pool: pool.to_string(),
+        })
+    }
+}
+
+impl HostRoute {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix('*') {
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => host == self.host,
+        }
+    }
+}
+
+/// Sends requests whose path starts with a prefix to a named pool of upstreams, optionally
+/// stripping the prefix from the path they are forwarded with. Parsed from command-line values of
+/// the form `/api=api` or `/api/*=api,strip_prefix`, where a trailing `*` is only for readability.
+#[derive(Clone, Debug)]
+pub struct PathRoute {
+    pub prefix: String,
+    pub pool: String,
+    pub strip_prefix: bool,
+}
+
+impl std::str::FromStr for PathRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PathRoute, String> {
+        let (rule, flag) = match s.split_once(',') {
+            Some((rule, "strip_prefix")) => (rule, true),
+            Some((_, flag)) => return Err(format!("invalid path route flag {:?}", flag)),
+            None => (s, false),
+        };
+        let (prefix, pool) = rule
+            .rsplit_once('=')
+            .filter(|(_, pool)| !pool.is_empty())
+            .ok_or_else(|| format!("expected PATH_PREFIX=POOL[,strip_prefix], got {:?}", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix {:?} must start with '/'", prefix));
+        }
+        Ok(PathRoute {
+            prefix: prefix.strip_suffix('*').unwrap_or(prefix).to_string(),
+            pool: pool.to_string(),
+            strip_prefix: flag,
+        })
+    }
+}
+
+impl PathRoute {
+    /// Returns the rest of the path if the prefix matches it (see strip_path_prefix).
+    fn matches<'a>(&self, path: &'a str) -> Option<&'a str> {
+        strip_path_prefix(path, &self.prefix)
+    }
+}
+
+/// Sends requests whose path matches a regular expression to a named pool of upstreams, optionally
+/// rewriting the path they are forwarded with from a template that may refer to the expression's
+/// capture groups as `$1` or `${name}`. Parsed from command-line values of the form `api=^/v[0-9]+/`
+/// or `users=^/v([0-9]+)/users(.*)$ /users$2`, with the pool first since expressions may contain
+/// `=` themselves. Request paths can't contain spaces, so neither does the expression.
+#[derive(Clone, Debug)]
+pub struct RegexRoute {
+    pub pattern: regex::Regex,
+    pub pool: String,
+    pub rewrite: Option<String>,
+}
+
+impl std::str::FromStr for RegexRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RegexRoute, String> {
+        let (pool, rule) = s
+            .split_once('=')
+            .filter(|(pool, rule)| !pool.is_empty() && !rule.is_empty())
+            .ok_or_else(|| format!("expected POOL=REGEX[ REWRITE], got {:?}", s))?;
+        let (pattern, rewrite) = match rule.split_once(' ') {
+            Some((pattern, rewrite)) => (pattern, Some(rewrite.trim().to_string())),
+            None => (rule, None),
+        };
+        let pattern = regex::Regex::new(pattern)
+            .map_err(|err| format!("invalid route expression {:?}: {}", pattern, err))?;
+        Ok(RegexRoute {
+            pattern,
+            pool: pool.to_string(),
+            rewrite,
+        })
+    }
+}
+
+/// What part of the request a route condition looks at.
+#[derive(Clone, Debug)]
+enum Subject {
+    // The host, as request_host returns it
+    Host,
+    Path,
+    Method,
+    Header(http::header::HeaderName),
+}
+
+/// How a route condition compares a value.
+#[derive(Clone, Debug)]
+enum Test {
+    Exact(String),
+    Prefix(String),
+    Regex(regex::Regex),
+}
+
+impl Test {
+    fn passes(&self, value: &str) -> bool {
+        match self {
+            Test::Exact(expected) => value == expected,
+            Test::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            Test::Regex(pattern) => pattern.is_match(value),
+        }
+    }
+}
+
+/// One condition of a match route, of the form `SUBJECT=VALUE` (exact), `SUBJECT^=VALUE` (prefix)
+/// or `SUBJECT~REGEX`, where SUBJECT is `host`, `path`, `method` or the name of a header, or
+/// `time=HH:MM-HH:MM` for a daily window in the routing timezone.
+#[derive(Clone, Debug)]
+enum Condition {
+    Request { subject: Subject, test: Test },
+    Time(TimeWindow),
+}
+
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        if let Some((_, window)) = s
+            .split_once('=')
+            .filter(|(name, _)| name.eq_ignore_ascii_case("time"))
+        {
+            return Ok(Condition::Time(window.parse()?));
+        }
+        let at = s.find(['=', '^', '~']).ok_or_else(|| {
+            format!(
+                "expected NAME=VALUE, NAME^=VALUE or NAME~REGEX, got {:?}",
+                s
+            )
+        })?;
+        let (name, rest) = s.split_at(at);
+        let test = if let Some(value) = rest.strip_prefix("^=") {
+            Test::Prefix(value.to_string())
+        } else if let Some(value) = rest.strip_prefix('=') {
+            Test::Exact(value.to_string())
+        } else if let Some(pattern) = rest.strip_prefix('~') {
+            Test::Regex(
+                regex::Regex::new(pattern)
+                    .map_err(|err| format!("invalid expression {:?}: {}", pattern, err))?,
+            )
+        } else {
+            return Err(format!("invalid route condition {:?}", s));
+        };
+        let subject = match name.to_ascii_lowercase().as_str() {
+            "host" => Subject::Host,
+            "path" => Subject::Path,
+            "method" => Subject::Method,
+            _ => Subject::Header(
+                http::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid header name {:?}", name))?,
+            ),
+        };
+        Ok(Condition::Request { subject, test })
+    }
+}
+
+impl Condition {
+    /// Whether the request meets the condition, `minute_of_day` being the time it arrived in the
+    /// routing timezone.
+    fn holds(&self, request: &http::Request<Vec<u8>>, minute_of_day: u32) -> bool {
+        let (subject, test) = match self {
+            Condition::Request { subject, test } => (subject, test),
+            Condition::Time(window) => return window.contains(minute_of_day),
+        };
+        match subject {
+            Subject::Host => request_host(request).is_some_and(|host| test.passes(&host)),
+            Subject::Path => test.passes(request.uri().path()),
+            Subject::Method => test.passes(request.method().as_str()),
+            Subject::Header(name) => request
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .any(|value| test.passes(value)),
+        }
+    }
+}
+
+/// Sends requests that meet every one of a set of conditions on their host, path, headers and time
+/// of arrival to a named pool of upstreams. Parsed from command-line values of the form
+/// `x-tenant=acme->acme`, `user-agent~Mobile&&path^=/app/->mobile`,
+/// `method~^(GET|HEAD)$->replicas` or `time=22:00-06:00->nightly`.
+#[derive(Clone, Debug)]
+pub struct MatchRoute {
+    conditions: Vec<Condition>,
+    pub pool: String,
+}
+
+impl std::str::FromStr for MatchRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MatchRoute, String> {
+        let (conditions, pool) = s
+            .rsplit_once("->")
+            .filter(|(conditions, pool)| !conditions.is_empty() && !pool.is_empty())
+            .ok_or_else(|| format!("expected CONDITION[&&CONDITION...]->POOL, got {:?}", s))?;
+        Ok(MatchRoute {
+            conditions: conditions
+                .split("&&")
+                .map(|condition| condition.trim().parse())
+                .collect::<Result<Vec<Condition>, String>>()?,
+            pool: pool.trim().to_string(),
+        })
+    }
+}
+
+impl MatchRoute {
+    fn matches(&self, request: &http::Request<Vec<u8>>, minute_of_day: u32) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.holds(request, minute_of_day))
+    }
+}
+
+/// The index of the pool a request was routed to, attached to the request by Router::route so
+/// that later lookups find the same pool even once the path has been rewritten.
+#[derive(Clone, Copy, Debug)]
+struct RoutedTo(usize);
+
+/// Returns the host the request is for, from its Host header or absolute URI, lowercased and
+/// without a port.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        })?;
+    let host = match host.strip_prefix('[') {
+        // An IPv6 address, whose colons aren't a port separator
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Splits the requests sent to it between pools by weight, e.g. for a canary release. Routes (and
+/// the default pool) may name a split wherever they could name a pool. Parsed from command-line
+/// values of the form `web=stable:95,canary:5`.
+#[derive(Clone, Debug)]
+pub struct SplitRule {
+    pub name: String,
+    pub shares: Vec<(String, u32)>,
+}
+
+impl std::str::FromStr for SplitRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SplitRule, String> {
+        let expected = || format!("expected NAME=POOL:WEIGHT,POOL:WEIGHT..., got {:?}", s);
+        let (name, shares) = s.split_once('=').ok_or_else(expected)?;
+        let shares = shares
+            .split(',')
+            .map(|share| {
+                let (pool, weight) = share.split_once(':').ok_or_else(expected)?;
+                let weight = weight.trim().parse().map_err(|_| expected())?;
+                Ok((pool.trim().to_string(), weight))
+            })
+            .collect::<Result<Vec<(String, u32)>, String>>()?;
+        if name.is_empty() || shares.iter().any(|(pool, _)| pool.is_empty()) {
+            return Err(expected());
+        }
+        if shares.iter().all(|(_, weight)| *weight == 0) {
+            return Err(format!("split {:?} has no weight", name));
+        }
+        Ok(SplitRule {
+            name: name.to_string(),
+            shares,
+        })
+    }
+}
+
+/// Sends the requests routed to it to whichever of two pools, blue or green, is live, so that a new
+/// release can be put into the idle pool and made live (or rolled back) with a single flip. Routes
+/// (and the default pool) may name a switch wherever they could name a pool. Parsed from
+/// command-line values of the form `web=blue,green`, where the blue pool starts out live.
+#[derive(Clone, Debug)]
+pub struct SwitchRule {
+    pub name: String,
+    pub blue: String,
+    pub green: String,
+}
+
+impl std::str::FromStr for SwitchRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SwitchRule, String> {
+        let (name, blue, green) = s
+            .split_once('=')
+            .and_then(|(name, pools)| {
+                let (blue, green) = pools.split_once(',')?;
+                Some((name, blue.trim(), green.trim()))
+            })
+            .filter(|(name, blue, green)| {
+                !name.is_empty() && !blue.is_empty() && !green.is_empty() && !green.contains(',')
+            })
+            .ok_or_else(|| format!("expected NAME=BLUE_POOL,GREEN_POOL, got {:?}", s))?;
+        if blue == green {
+            return Err(format!(
+                "switch {:?} must be between two different pools",
+                name
+            ));
+        }
+        Ok(SwitchRule {
+            name: name.to_string(),
+            blue: blue.to_string(),
+            green: green.to_string(),
+        })
+    }
+}
+
+/// The rules that decide which pool serves a request. Match routes are consulted first, in order,
+/// then host routes, then regular expression routes in order, then path prefix routes, longest
+/// prefix first.
+pub struct Rules {
+    pub match_routes: Vec<MatchRoute>,
+    pub host_routes: Vec<HostRoute>,
+    pub path_routes: Vec<PathRoute>,
+    pub regex_routes: Vec<RegexRoute>,
+    pub splits: Vec<SplitRule>,
+    pub switches: Vec<SwitchRule>,
+    // Pool (or split, or switch) that serves requests no route matches
+    pub default_pool: String,
+    // Timezone that the time windows of match routes are given in
+    pub timezone: UtcOffset,
+}
+
+/// A group of upstreams that is balanced separately from the others.
+struct Pool {
+    name: String,
+    balancers: Balancers,
+}
+
+/// A split between pools whose weights can be changed while we run.
+struct Split {
+    name: String,
+    pools: Vec<usize>,
+    // Weight of each pool, by position in `pools`
+    weights: RwLock<Vec<u32>>,
+}
+
+impl Split {
+    /// Picks one of the pools, with a chance in proportion to its weight.
+    fn pick(&self) -> usize {
+        let weights = self.weights.read();
+        let total: u32 = weights.iter().sum();
+        let mut remaining = rand::thread_rng().gen_range(0..total.max(1));
+        for (pool, weight) in self.pools.iter().zip(weights.iter()) {
+            if remaining < *weight {
+                return *pool;
+            }
+            remaining -= weight;
+        }
+        self.pools[0]
+    }
+}
+
+/// A blue/green switch between two pools.
+struct Switch {
+    name: String,
+    // The blue pool, then the green one
+    pools: [usize; 2],
+    // Position in `pools` of the live pool
+    live: AtomicUsize,
+}
+
+/// What a route sends requests to.
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Pool(usize),
+    Split(usize),
+    Switch(usize),
+}
+
+/// Why the weights of a split couldn't be changed.
+#[derive(Debug)]
+pub enum SplitUpdateError {
+    UnknownSplit,
+    // The split has no share for this pool
+    UnknownPool(String),
+    // Every weight would be zero
+    NoWeight,
+}
+
+/// Why a blue/green switch couldn't be flipped.
+#[derive(Debug)]
+pub enum SwitchFlipError {
+    UnknownSwitch,
+    // The pool isn't one of the switch's two
+    UnknownPool(String),
+}
+
+/// The upstream pools, each with its own instances of the balancing strategies, and the rules that
+/// decide which pool serves a request. Upstreams join the pool they name with their pool=NAME
+/// attribute, or the default pool otherwise.
+pub struct Router {
+    pools: Vec<Pool>,
+    splits: Vec<Split>,
+    switches: Vec<Switch>,
+    // Host routes, with exact hosts ahead of wildcards and longer wildcards ahead of shorter ones,
+    // so the most specific route wins
+    host_routes: Vec<(HostRoute, Target)>,
+    // Path routes, sorted longest-prefix-first
+    path_routes: Vec<(PathRoute, Target)>,
+    // Regular expression routes, in the order they were given
+    regex_routes: Vec<(RegexRoute, Target)>,
+    // Match routes, in the order they were given
+    match_routes: Vec<(MatchRoute, Target)>,
+    timezone: UtcOffset,
+    // Where requests that match no route go
+    default: Target,
+}
+
+impl Router {
+    /// Builds the pools and checks that every route, split, switch and the default pool names a
+    /// pool that has upstreams.
+    pub fn new(
+        upstreams: &[Upstream],
+        strategy: Strategy,
+        route_strategies: &[RouteStrategy],
+        hash: &HashOptions,
+        panic_threshold: f64,
+        rules: &Rules,
+    ) -> Result<Router, String> {
+        let pool_name = |upstream: &Upstream| upstream.pool.clone().unwrap_or(DEFAULT_POOL.into());
+        let mut names: Vec<String> = Vec::new();
+        for upstream in upstreams {
+            if !names.contains(&pool_name(upstream)) {
+                names.push(pool_name(upstream));
+            }
+        }
+        let pools: Vec<Pool> = names
+            .into_iter()
+            .map(|name| {
+                let members: Vec<bool> = upstreams
+                    .iter()
+                    .map(|upstream| pool_name(upstream) == name)
+                    .collect();
+                let balancers = Balancers::new(
+                    strategy,
+                    route_strategies,
+                    upstreams,
+                    &members,
+                    hash,
+                    PanicThreshold::new(&name, panic_threshold),
+                );
+                Pool { name, balancers }
+            })
+            .collect();
+        let find_pool = |name: &str| {
+            pools
+                .iter()
+                .position(|pool| pool.name == name)
+                .ok_or_else(|| format!("no upstreams are in pool {:?}", name))
+        };
+        // Splits and switches are named like pools, so no two of them may share a name
+        let mut taken: Vec<&str> = pools.iter().map(|pool| pool.name.as_str()).collect();
+        let mut splits: Vec<Split> = Vec::new();
+        for rule in &rules.splits {
+            if taken.contains(&rule.name.as_str()) {
+                return Err(format!(
+                    "split {:?} has the name of another pool",
+                    rule.name
+                ));
+            }
+            taken.push(&rule.name);
+            splits.push(Split {
+                name: rule.name.clone(),
+                pools: rule
+                    .shares
+                    .iter()
+                    .map(|(pool, _)| find_pool(pool))
+                    .collect::<Result<Vec<usize>, String>>()?,
+                weights: RwLock::new(rule.shares.iter().map(|(_, weight)| *weight).collect()),
+            });
+        }
+        let mut switches: Vec<Switch> = Vec::new();
+        for rule in &rules.switches {
+            if taken.contains(&rule.name.as_str()) {
+                return Err(format!(
+                    "switch {:?} has the name of another pool",
+                    rule.name
+                ));
+            }
+            taken.push(&rule.name);
+            switches.push(Switch {
+                name: rule.name.clone(),
+                pools: [find_pool(&rule.blue)?, find_pool(&rule.green)?],
+                live: AtomicUsize::new(0),
+            });
+        }
+        let find = |name: &str| {
+            if let Some(split) = splits.iter().position(|split| split.name == name) {
+                Ok(Target::Split(split))
+            } else if let Some(switch) = switches.iter().position(|switch| switch.name == name) {
+                Ok(Target::Switch(switch))
+            } else {
+                find_pool(name).map(Target::Pool)
+            }
+        };
+        let mut host_routes = rules
+            .host_routes
+            .iter()
+            .map(|route| Ok((route.clone(), find(&route.pool)?)))
+            .collect::<Result<Vec<(HostRoute, Target)>, String>>()?;
+        host_routes.sort_by_key(|(route, _)| {
+            let wildcard = route.host.starts_with('*');
+            (wildcard, std::cmp::Reverse(route.host.len()))
+        });
+        let mut path_routes = rules
+            .path_routes
+            .iter()
+            .map(|route| Ok((route.clone(), find(&route.pool)?)))
+            .collect::<Result<Vec<(PathRoute, Target)>, String>>()?;
+        path_routes.sort_by_key(|(route, _)| std::cmp::Reverse(route.prefix.len()));
+        let regex_routes = rules
+            .regex_routes
+            .iter()
+            .map(|route| Ok((route.clone(), find(&route.pool)?)))
+            .collect::<Result<Vec<(RegexRoute, Target)>, String>>()?;
+        let match_routes = rules
+            .match_routes
+            .iter()
+            .map(|route| Ok((route.clone(), find(&route.pool)?)))
+            .collect::<Result<Vec<(MatchRoute, Target)>, String>>()?;
+        let default = find(&rules.default_pool)?;
+        Ok(Router {
+            pools,
+            splits,
+            switches,
+            host_routes,
+            path_routes,
+            regex_routes,
+            match_routes,
+            timezone: rules.timezone,
+            default,
+        })
+    }
+
+    /// Returns where the request should go, along with the path to forward it with if the route
+    /// that chose it rewrites it.
+    fn match_target(&self, request: &http::Request<Vec<u8>>) -> (Target, Option<String>) {
+        let minute_of_day = (self.timezone.secs_of_day(SystemTime::now()) / 60) as u32;
+        if let Some((_, target)) = self
+            .match_routes
+            .iter()
+            .find(|(route, _)| route.matches(request, minute_of_day))
+        {
+            return (*target, None);
+        }
+        let host = request_host(request);
+        if let Some((_, target)) = host.and_then(|host| {
+            self.host_routes
+                .iter()
+                .find(|(route, _)| route.matches(&host))
+        }) {
+            return (*target, None);
+        }
+        let path = request.uri().path();
+        for (route, target) in &self.regex_routes {
+            if let Some(captures) = route.pattern.captures(path) {
+                let rewritten = route.rewrite.as_ref().map(|template| {
+                    let mut rewritten = String::new();
+                    captures.expand(template, &mut rewritten);
+                    rewritten
+                });
+                return (*target, rewritten);
+            }
+        }
+        match self
+            .path_routes
+            .iter()
+            .find_map(|(route, target)| Some((route, target, route.matches(path)?)))
+        {
+            Some((route, target, rest)) => {
+                let stripped = route.strip_prefix.then(|| rest.to_string());
+                (*target, stripped)
+            }
+            None => (self.default, None),
+        }
+    }
+
+    /// Returns the index of the pool a target sends a request to, making the choice afresh for
+    /// every request if it is a split or a switch.
+    fn resolve(&self, target: Target) -> usize {
+        match target {
+            Target::Pool(pool) => pool,
+            Target::Split(split) => self.splits[split].pick(),
+            Target::Switch(switch) => {
+                let switch = &self.switches[switch];
+                switch.pools[switch.live.load(Ordering::Relaxed)]
+            }
+        }
+    }
+
+    /// Decides which pool serves the request, applying the path rewrite of the route it matched,
+    /// and returns the balancer responsible for it: the one for the path it is forwarded with, in
+    /// that pool.
+    pub fn route(&self, request: &mut http::Request<Vec<u8>>) -> &Balancer {
+        let (target, rewritten) = self.match_target(request);
+        if let Some(path) = rewritten {
+            set_path(request, &path);
+        }
+        let pool = self.resolve(target);
+        request.extensions_mut().insert(RoutedTo(pool));
+        self.pools[pool].balancers.for_path(request.uri().path())
+    }
+
+    /// Returns the balancer responsible for the request, in the pool route() sent it to, or would
+    /// send it to if it hasn't been routed yet.
+    pub fn for_request(&self, request: &http::Request<Vec<u8>>) -> &Balancer {
+        let pool = match request.extensions().get::<RoutedTo>() {
+            Some(RoutedTo(pool)) => *pool,
+            None => self.resolve(self.match_target(request).0),
+        };
+        self.pools[pool].balancers.for_path(request.uri().path())
+    }
+
+    /// Returns the name of the pool route() sent the request to, if it has been routed.
+    pub fn routed_pool(&self, request: &http::Request<Vec<u8>>) -> Option<&str> {
+        let RoutedTo(pool) = request.extensions().get::<RoutedTo>()?;
+        Some(self.pools[*pool].name.as_str())
+    }
+
+    /// Returns whether some upstream is in the named pool.
+    pub fn has_pool(&self, name: &str) -> bool {
+        self.pools.iter().any(|pool| pool.name == name)
+    }
+
+    /// Returns each split, with the pools it splits requests between and their current weights.
+    pub fn splits(&self) -> Vec<(&str, Vec<(&str, u32)>)> {
+        self.splits
+            .iter()
+            .map(|split| {
+                let weights = split.weights.read();
+                let shares = split
+                    .pools
+                    .iter()
+                    .zip(weights.iter())
+                    .map(|(pool, weight)| (self.pools[*pool].name.as_str(), *weight))
+                    .collect();
+                (split.name.as_str(), shares)
+            })
+            .collect()
+    }
+
+    /// Changes the weights of some of a split's pools, leaving the others' as they were. The new
+    /// weights apply to requests routed from then on.
+    pub fn set_split_weights(
+        &self,
+        name: &str,
+        weights: &[(String, u32)],
+    ) -> Result<(), SplitUpdateError> {
+        let split = self
+            .splits
+            .iter()
+            .find(|split| split.name == name)
+            .ok_or(SplitUpdateError::UnknownSplit)?;
+        let mut updated = split.weights.read().clone();
+        for (pool, weight) in weights {
+            let position = split
+                .pools
+                .iter()
+                .position(|idx| self.pools[*idx].name == *pool)
+                .ok_or_else(|| SplitUpdateError::UnknownPool(pool.clone()))?;
+            updated[position] = *weight;
+        }
+        if updated.iter().all(|weight| *weight == 0) {
+            return Err(SplitUpdateError::NoWeight);
+        }
+        *split.weights.write() = updated;
+        Ok(())
+    }
+
+    /// Returns each blue/green switch, with its live pool, then the other one.
+    pub fn switches(&self) -> Vec<(&str, &str, &str)> {
+        self.switches
+            .iter()
+            .map(|switch| {
+                let live = switch.live.load(Ordering::Relaxed);
+                let (live, idle) = (switch.pools[live], switch.pools[1 - live]);
+                (
+                    switch.name.as_str(),
+                    self.pools[live].name.as_str(),
+                    self.pools[idle].name.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    /// Makes the named pool the live one of a switch, or the pool that isn't live now if none is
+    /// named. Requests already sent to the other pool finish there, but every request routed from
+    /// then on goes to the live pool.
+    pub fn flip_switch(&self, name: &str, live: Option<&str>) -> Result<(), SwitchFlipError> {
+        let switch = self
+            .switches
+            .iter()
+            .find(|switch| switch.name == name)
+            .ok_or(SwitchFlipError::UnknownSwitch)?;
+        match live {
+            Some(live) => {
+                let position = switch
+                    .pools
+                    .iter()
+                    .position(|pool| self.pools[*pool].name == live)
+                    .ok_or_else(|| SwitchFlipError::UnknownPool(live.to_string()))?;
+                switch.live.store(position, Ordering::Relaxed);
+            }
+            None => {
+                switch.live.fetch_xor(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use crate::events::{self, Webhook};
+use crate::routing;
+use crate::upstream::Upstream;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Levels past which a pool counts as saturated. A level of zero is never reached, so that signal
+/// is left out.
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    // Percentage of attempts that timed out
+    pub timeout_percent: f64,
+    // Average time an attempt waited for a connection under an upstream's max_conns limit
+    pub queue_time: Duration,
+    // Percentage of the pool's connection capacity in use
+    pub utilization_percent: f64,
+}
+
+/// Running totals for a pool, from which each interval's signals are worked out.
+#[derive(Default)]
+struct Counters {
+    attempts: AtomicU64,
+    timeouts: AtomicU64,
+    // Connection acquisitions, and the total time they waited, in microseconds
+    acquisitions: AtomicU64,
+    queue_micros: AtomicU64,
+}
+
+/// A pool's saturation signals over the last interval, as the admin API and webhook report them.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Signals {
+    pub pool: String,
+    pub attempts: u64,
+    pub timeout_percent: f64,
+    pub queue_time_ms: f64,
+    // None if any of the pool's upstreams has no connection limit, so capacity is unbounded
+    pub utilization_percent: Option<f64>,
+    pub saturated: bool,
+}
+
+struct Pool {
+    name: String,
+    // Indices of the pool's upstreams
+    members: Vec<usize>,
+    // Sum of the members' max_conns, if every member has one
+    capacity: Option<usize>,
+    counters: Counters,
+    // Totals as of the end of the last interval: attempts, timeouts, acquisitions, queue_micros
+    previous: parking_lot::Mutex<[u64; 4]>,
+    latest: parking_lot::Mutex<Signals>,
+}
+
+/// Tracks how close each pool is to running out of room, so that an autoscaler can add upstreams
+/// before requests start failing: how many attempts time out, how long attempts queue for a
+/// connection, and how much of the pool's connection capacity is in use. Signals are worked out
+/// once per interval, and a webhook is told whenever a pool becomes saturated or stops being so.
+pub struct PoolSaturation {
+    pools: Vec<Pool>,
+    // The index in `pools` of each upstream's pool
+    pool_of: Vec<usize>,
+    thresholds: Thresholds,
+    webhook: Option<Webhook>,
+}
+
+/// A pool becoming saturated or recovering, as POSTed to the webhook.
+#[derive(Serialize)]
+struct SaturationEvent<'a> {
+    #[serde(flatten)]
+    signals: &'a Signals,
+    timestamp_unix_ms: u64,
+}
+
+impl PoolSaturation {
+    pub fn new(
+        upstreams: &[Upstream],
+        thresholds: Thresholds,
+        webhook: Option<Webhook>,
+    ) -> PoolSaturation {
+        let mut pools: Vec<Pool> = Vec::new();
+        let mut pool_of = Vec::with_capacity(upstreams.len());
+        for (idx, upstream) in upstreams.iter().enumerate() {
+            let name = upstream.pool.as_deref().unwrap_or(routing::DEFAULT_POOL);
+            let pool_idx = match pools.iter().position(|pool| pool.name == name) {
+                Some(pool_idx) => pool_idx,
+                None => {
+                    pools.push(Pool {
+                        name: name.to_string(),
+                        members: Vec::new(),
+                        capacity: Some(0),
+                        counters: Counters::default(),
+                        previous: parking_lot::Mutex::new([0; 4]),
+                        latest: parking_lot::Mutex::new(Signals {
+                            pool: name.to_string(),
+                            ..Signals::default()
+                        }),
+                    });
+                    pools.len() - 1
+                }
+            };
+            let pool = &mut pools[pool_idx];
+            pool.members.push(idx);
+            pool.capacity = match upstream.max_connections {
+                0 => None,
+                max => pool.capacity.map(|capacity| capacity + max),
+            };
+            pool_of.push(pool_idx);
+        }
+        PoolSaturation {
+            pools,
+            pool_of,
+            thresholds,
+            webhook,
+        }
+    }
+
+    /// Records an attempt to reach an upstream finishing, one way or another.
+    pub fn record_attempt(&self, idx: usize, timed_out: bool) {
+        let counters = &self.pools[self.pool_of[idx]].counters;
+        counters.attempts.fetch_add(1, Ordering::Relaxed);
+        if timed_out {
+            counters.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records how long an attempt waited to get a connection to an upstream under its limit.
+    pub fn record_queue_wait(&self, idx: usize, wait: Duration) {
+        let counters = &self.pools[self.pool_of[idx]].counters;
+        counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+        counters
+            .queue_micros
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Works out each pool's signals since the last update, given the number of connections open
+    /// to each upstream, and tells the webhook about pools that became saturated or recovered.
+    pub fn update(&self, active_connections: &[AtomicUsize]) {
+        for pool in &self.pools {
+            let counters = &pool.counters;
+            let totals = [
+                &counters.attempts,
+                &counters.timeouts,
+                &counters.acquisitions,
+                &counters.queue_micros,
+            ]
+            .map(|counter| counter.load(Ordering::Relaxed));
+            let previous = std::mem::replace(&mut *pool.previous.lock(), totals);
+            let [attempts, timeouts, acquisitions, queue_micros] =
+                [0, 1, 2, 3].map(|i| totals[i] - previous[i]);
+            let open: usize = pool
+                .members
+                .iter()
+                .map(|idx| active_connections[*idx].load(Ordering::Relaxed))
+                .sum();
+            let mut signals = Signals {
+                pool: pool.name.clone(),
+                attempts,
+                timeout_percent: percent(timeouts, attempts),
+                queue_time_ms: match acquisitions {
+                    0 => 0.0,
+                    n => queue_micros as f64 / n as f64 / 1000.0,
+                },
+                utilization_percent: pool
+                    .capacity
+                    .map(|capacity| percent(open as u64, capacity as u64)),
+                saturated: false,
+            };
+            signals.saturated = self.exceeded(&signals);
+            let previous = std::mem::replace(&mut *pool.latest.lock(), signals.clone());
+            if signals.saturated != previous.saturated {
+                self.notify(signals);
+            }
+        }
+    }
+
+    /// Whether any signal is past its threshold.
+    fn exceeded(&self, signals: &Signals) -> bool {
+        let thresholds = &self.thresholds;
+        (thresholds.timeout_percent > 0.0 && signals.timeout_percent > thresholds.timeout_percent)
+            || (!thresholds.queue_time.is_zero()
+                && signals.queue_time_ms > thresholds.queue_time.as_secs_f64() * 1000.0)
+            || (thresholds.utilization_percent > 0.0
+                && signals
+                    .utilization_percent
+                    .is_some_and(|utilization| utilization > thresholds.utilization_percent))
+    }
+
+    fn notify(&self, signals: Signals) {
+        match signals.saturated {
+            true => log::warn!("Pool {} is saturated: {:?}", signals.pool, signals),
+            false => log::info!("Pool {} is no longer saturated", signals.pool),
+        }
+        let Some(webhook) = self.webhook.clone() else {
+            return;
+        };
+        let event = SaturationEvent {
+            signals: &signals,
+            timestamp_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        let body = serde_json::to_vec(&event).unwrap();
+        tokio::spawn(async move {
+            match tokio::time::timeout(events::DELIVERY_TIMEOUT, events::post(&webhook, body)).await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::warn!("Saturation webhook failed: {}", err),
+                Err(_) => log::warn!("Saturation webhook timed out"),
+            }
+        });
+    }
+
+    /// Each pool's signals as of the last update.
+    pub fn snapshot(&self) -> Vec<Signals> {
+        self.pools
+            .iter()
+            .map(|pool| pool.latest.lock().clone())
+            .collect()
+    }
+
+    /// Renders the running totals and latest signals of every pool in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "loadbalancer_pool_attempts_total",
+                "Attempts to reach an upstream in the pool.",
+                (|counters| &counters.attempts) as fn(&Counters) -> &AtomicU64,
+            ),
+            (
+                "loadbalancer_pool_timeouts_total",
+                "Attempts to reach an upstream in the pool that timed out.",
+                |counters| &counters.timeouts,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for pool in &self.pools {
+                let value = counter(&pool.counters).load(Ordering::Relaxed);
+                writeln!(out, "{}{{pool=\"{}\"}} {}", name, pool.name, value).unwrap();
+            }
+        }
+        out += "# HELP loadbalancer_pool_queue_seconds Time spent waiting for a connection to an \
+                upstream in the pool under its limit.\n";
+        out += "# TYPE loadbalancer_pool_queue_seconds summary\n";
+        for pool in &self.pools {
+            let counters = &pool.counters;
+            writeln!(
+                out,
+                "loadbalancer_pool_queue_seconds_sum{{pool=\"{}\"}} {}",
+                pool.name,
+                counters.queue_micros.load(Ordering::Relaxed) as f64 / 1e6
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "loadbalancer_pool_queue_seconds_count{{pool=\"{}\"}} {}",
+                pool.name,
+                counters.acquisitions.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        let latest = self.snapshot();
+        out += "# HELP loadbalancer_pool_utilization_ratio Share of the pool's connection \
+                capacity in use at the end of the last interval, if every upstream in it has a \
+                limit.\n";
+        out += "# TYPE loadbalancer_pool_utilization_ratio gauge\n";
+        for signals in &latest {
+            if let Some(utilization) = signals.utilization_percent {
+                writeln!(
+                    out,
+                    "loadbalancer_pool_utilization_ratio{{pool=\"{}\"}} {}",
+                    signals.pool,
+                    utilization / 100.0
+                )
+                .unwrap();
+            }
+        }
+        out += "# HELP loadbalancer_pool_saturated Whether any of the pool's saturation signals \
+                was past its threshold over the last interval.\n";
+        out += "# TYPE loadbalancer_pool_saturated gauge\n";
+        for signals in &latest {
+            writeln!(
+                out,
+                "loadbalancer_pool_saturated{{pool=\"{}\"}} {}",
+                signals.pool, signals.saturated as u8
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    match whole {
+        0 => 0.0,
+        whole => part as f64 * 100.0 / whole as f64,
+    }
+}
+
+/// Updates the pools' signals every `interval` until the process exits.
+pub async fn run(state: std::sync::Arc<crate::ProxyState>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        state.saturation.update(&state.active_connections);
+    }
+}
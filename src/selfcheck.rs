@@ -0,0 +1,101 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The outcome of a single startup check.
+#[derive(Serialize, Debug)]
+pub struct Check {
+    name: String,
+    ok: bool,
+    detail: String,
+}
+
+/// A structured record of the checks performed while the load balancer starts up. The report is
+/// always logged, and can optionally be written to a JSON file so that orchestration scripts can
+/// tell whether the balancer came up healthy without having to grep through logs.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    ready: bool,
+    pid: u32,
+    // Seconds since the Unix epoch at which the report was generated
+    generated_at: u64,
+    // Address the proxy listener is bound to (useful when binding to port 0)
+    listen_address: Option<String>,
+    // Address the admin API is bound to, if it's enabled
+    admin_address: Option<String>,
+    checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            ready: true,
+            pid: std::process::id(),
+            generated_at: 0,
+            listen_address: None,
+            admin_address: None,
+            checks: Vec::new(),
+        }
+    }
+
+    pub fn set_listen_address(&mut self, addr: std::net::SocketAddr) {
+        self.listen_address = Some(addr.to_string());
+    }
+
+    pub fn set_admin_address(&mut self, addr: std::net::SocketAddr) {
+        self.admin_address = Some(addr.to_string());
+    }
+
+    /// Records the result of a check. Any failed check marks the whole report as not ready.
+    pub fn record(&mut self, name: &str, result: Result<String, String>) {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.ready &= ok;
+        self.checks.push(Check {
+            name: name.to_string(),
+            ok,
+            detail,
+        });
+    }
+
+    /// Logs every check and, if a path was given, writes the report to that path as JSON. The file
+    /// is written to a temporary path first and then renamed into place, so readers never observe
+    /// a partially-written report.
+    pub fn finish(&mut self, path: Option<&str>) {
+        self.generated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        for check in &self.checks {
+            if check.ok {
+                log::info!("Self-check {}: ok ({})", check.name, check.detail);
+            } else {
+                log::error!("Self-check {}: FAILED ({})", check.name, check.detail);
+            }
+        }
+        log::info!(
+            "Startup self-check complete: {}",
+            if self.ready { "ready" } else { "not ready" }
+        );
+
+        if let Some(path) = path {
+            if let Err(err) = self.write_to_file(path) {
+                log::error!("Could not write readiness report to {}: {}", path, err);
+            }
+        }
+    }
+
+    /// Finishes the report and exits the process. Used when a fatal check fails.
+    pub fn abort(&mut self, path: Option<&str>) -> ! {
+        self.finish(path);
+        std::process::exit(1);
+    }
+
+    fn write_to_file(&self, path: &str) -> Result<(), std::io::Error> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, contents)?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
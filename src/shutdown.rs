@@ -0,0 +1,66 @@
+use crate::connections::ConnectionRegistry;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How often we check whether every connection has drained.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Signals the start of a graceful shutdown to everything that needs to wind down: the accept loop
+/// stops taking new connections, and client connections close once their in-flight request has
+/// been answered.
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            started: watch::channel(false).0,
+        }
+    }
+
+    /// Starts shutting down. Returns false if a shutdown was already in progress.
+    pub fn trigger(&self, cause: &str) -> bool {
+        let first = self
+            .started
+            .send_if_modified(|started| !std::mem::replace(started, true));
+        if first {
+            log::warn!("Shutting down ({})", cause);
+        }
+        first
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Resolves once a shutdown has been triggered.
+    pub async fn wait(&self) {
+        let mut started = self.started.subscribe();
+        // The sender lives as long as we do, so this can't fail
+        let _ = started.wait_for(|started| *started).await;
+    }
+}
+
+/// Triggers a shutdown when the process receives SIGTERM or SIGINT.
+pub async fn watch_signals(shutdown: &Shutdown) {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Could not install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => shutdown.trigger("SIGTERM"),
+        _ = tokio::signal::ctrl_c() => shutdown.trigger("SIGINT"),
+    };
+}
+
+/// Waits for every client connection to close, for up to `timeout`. Returns the number of
+/// connections still open when we gave up.
+pub async fn drain(connections: &ConnectionRegistry, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = connections.len();
+        if open == 0 || Instant::now() >= deadline {
+            return open;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,516 @@
+use crate::hash_ring::{HashKey, HashOptions, HashRing};
+use crate::health::PanicThreshold;
+use crate::routing;
+use crate::upstream::{self, Upstream};
+use crate::ProxyState;
+use clap::ValueEnum;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{Rng, SeedableRng};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Chooses which upstream a request should be sent to. Implementations may keep their own
+/// bookkeeping (cursors, hash rings) and can read the shared per-upstream state, such as active
+/// connection counts, off `ProxyState`.
+pub trait LoadBalancingStrategy: Send + Sync {
+    /// Returns the index (into `ProxyState::upstreams`) of the upstream to use, which should be one
+    /// of the `candidates` (see candidates()).
+    fn pick(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize;
+
+    /// Whether every request should be balanced on its own. By default a client connection stays
+    /// with the upstream that its first request was sent to.
+    fn per_request(&self) -> bool {
+        false
+    }
+}
+
+/// The strategies that can be selected with `--strategy`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Pick an upstream at random, in proportion to its weight
+    Random,
+    /// Cycle through the upstreams in order (weighted round-robin if weights are given)
+    RoundRobin,
+    /// Pick the upstream with the fewest active connections relative to its weight
+    LeastConnections,
+    /// Consistently send each client IP to the same upstream
+    IpHash,
+    /// Consistently send requests with the same --hash-key value to the same upstream
+    Hash,
+    /// Prefer the upstream with the lowest recent response latency (see --ewma-decay-ms)
+    Ewma,
+    /// Pick the upstream with the fewest in-flight and recent requests relative to its weight
+    /// (see --least-request-decay-ms)
+    LeastRequest,
+}
+
+impl Strategy {
+    /// The name the strategy is selected by on the command line.
+    pub fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+
+    /// Builds the strategy for balancing between the upstreams that are `members` of a pool.
+    pub fn build(
+        &self,
+        upstreams: &[Upstream],
+        members: &[bool],
+        hash: &HashOptions,
+    ) -> Box<dyn LoadBalancingStrategy> {
+        // The schedule is built over the members alone, then mapped back to upstream indices
+        let indices: Vec<usize> = (0..upstreams.len()).filter(|&idx| members[idx]).collect();
+        let pool: Vec<Upstream> = indices.iter().map(|&idx| upstreams[idx].clone()).collect();
+        let schedule = || -> Vec<usize> {
+            upstream::weighted_schedule(&pool)
+                .into_iter()
+                .map(|slot| indices[slot])
+                .collect()
+        };
+        match self {
+            Strategy::Random => Box::new(Random {
+                schedule: schedule(),
+            }),
+            Strategy::RoundRobin => Box::new(RoundRobin {
+                schedule: schedule(),
+                cursor: AtomicUsize::new(0),
+            }),
+            Strategy::LeastConnections => Box::new(LeastConnections {
+                cursor: AtomicUsize::new(0),
+            }),
+            Strategy::IpHash => Box::new(ConsistentHash {
+                ring: HashRing::new(upstreams, members),
+                key: HashKey::ClientIp,
+                per_request: false,
+                load_factor: hash.load_factor,
+            }),
+            Strategy::Hash => Box::new(ConsistentHash {
+                ring: HashRing::new(upstreams, members),
+                key: hash.key.clone(),
+                per_request: true,
+                load_factor: hash.load_factor,
+            }),
+            Strategy::Ewma => Box::new(Ewma {
+                cursor: AtomicUsize::new(0),
+            }),
+            Strategy::LeastRequest => Box::new(LeastRequest {
+                cursor: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+/// A strategy to use for requests whose path starts with `prefix`. Parsed from command-line
+/// values of the form `/api=ip_hash`.
+#[derive(Clone, Debug)]
+pub struct RouteStrategy {
+    pub prefix: String,
+    pub strategy: Strategy,
+}
+
+impl std::str::FromStr for RouteStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RouteStrategy, String> {
+        let (prefix, strategy) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH_PREFIX=STRATEGY, got {:?}", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix {:?} must start with '/'", prefix));
+        }
+        Ok(RouteStrategy {
+            prefix: prefix.to_string(),
+            strategy: Strategy::from_str(strategy, false)?,
+        })
+    }
+}
+
+/// A built strategy, along with the name it was selected by (for logs and debug headers), which
+/// balances between the upstreams of one pool.
+pub struct Balancer {
+    pub strategy: Strategy,
+    implementation: Box<dyn LoadBalancingStrategy>,
+    // Whether each upstream is in the pool
+    members: Vec<bool>,
+    // The pool's panic threshold, shared with the pool's other balancers
+    panic_threshold: Arc<PanicThreshold>,
+}
+
+impl Balancer {
+    pub fn pick(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        client_ip: IpAddr,
+        state: &ProxyState,
+    ) -> usize {
+        let candidates = candidates(state, &self.members, &self.panic_threshold);
+        self.implementation
+            .pick(request, client_ip, state, &candidates)
+    }
+
+    pub fn per_request(&self) -> bool {
+        self.implementation.per_request()
+    }
+
+    /// Whether the upstream is in the pool this balancer balances between.
+    pub fn includes(&self, idx: usize) -> bool {
+        self.members[idx]
+    }
+
+    /// Picks an upstream in the pool to fail over to that isn't in `tried`, for when the strategy
+    /// keeps picking upstreams that already failed. Upstreams that candidates() would prefer go
+    /// first, in random order, then the rest; draining upstreams are never picked. Returns None
+    /// once every upstream in the pool has been tried.
+    pub fn untried(&self, state: &ProxyState, tried: &[usize]) -> Option<usize> {
+        let candidates = candidates(state, &self.members, &self.panic_threshold);
+        let untried: Vec<usize> = (0..state.upstreams.len())
+            .filter(|idx| {
+                self.members[*idx]
+                    && !tried.contains(idx)
+                    && !state.draining[*idx].load(Ordering::Relaxed)
+            })
+            .collect();
+        let preferred: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|idx| candidates[*idx])
+            .collect();
+        let pool = if preferred.is_empty() {
+            untried
+        } else {
+            preferred
+        };
+        (!pool.is_empty()).then(|| pool[rand::thread_rng().gen_range(0..pool.len())])
+    }
+}
+
+/// The default strategy plus any per-route overrides, for one pool of upstreams. Each route gets
+/// its own instance of its strategy, so e.g. two round-robin routes keep separate cursors.
+pub struct Balancers {
+    default: Balancer,
+    // Routes sorted longest-prefix-first, so the most specific route wins
+    routes: Vec<(String, Balancer)>,
+}
+
+impl Balancers {
+    pub fn new(
+        default: Strategy,
+        routes: &[RouteStrategy],
+        upstreams: &[Upstream],
+        members: &[bool],
+        hash: &HashOptions,
+        panic_threshold: PanicThreshold,
+    ) -> Balancers {
+        let panic_threshold = Arc::new(panic_threshold);
+        let build = |strategy: Strategy| Balancer {
+            strategy,
+            implementation: strategy.build(upstreams, members, hash),
+            members: members.to_vec(),
+            panic_threshold: panic_threshold.clone(),
+        };
+        let mut routes: Vec<(String, Balancer)> = routes
+            .iter()
+            .map(|route| (route.prefix.clone(), build(route.strategy)))
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Balancers {
+            default: build(default),
+            routes,
+        }
+    }
+
+    /// Returns the balancer responsible for requests to `path`.
+    pub fn for_path(&self, path: &str) -> &Balancer {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| routing::strip_path_prefix(path, prefix).is_some())
+            .map(|(_, balancer)| balancer)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Returns whether each upstream may be picked for this request, out of the `members` of the pool
+/// it is being balanced between. Upstreams that failed recently,
+/// are failing their health checks, were ejected for sending too many 5xx responses or have an
+/// open circuit breaker are avoided while any other upstream is left, backup upstreams are only
+/// used once no primary upstream is left, and local-zone upstreams are preferred (see
+/// upstream::ZonePreference). Ejections are ignored while too many of the pool's upstreams are
+/// ejected (see health::PanicThreshold), but draining upstreams are avoided regardless. Upstreams
+/// at their connection limit are avoided too, unless every upstream is.
+fn candidates(state: &ProxyState, members: &[bool], panic_threshold: &PanicThreshold) -> Vec<bool> {
+    let ejected: Vec<bool> = (0..state.upstreams.len())
+        .map(|idx| {
+            state.health.is_dead(idx)
+                || state.outliers.is_ejected(idx)
+                || !state.breakers.is_available(idx)
+        })
+        .collect();
+    let num_members = members.iter().filter(|member| **member).count();
+    let num_ejected = (0..state.upstreams.len())
+        .filter(|idx| members[*idx] && ejected[*idx])
+        .count();
+    let panic = panic_threshold.check(num_ejected, num_members);
+    let healthy: Vec<bool> = (0..state.upstreams.len())
+        .map(|idx| {
+            members[idx]
+                && !state.recent_failures.is_recently_failed(idx)
+                && (panic || !ejected[idx])
+                && !state.draining[idx].load(Ordering::Relaxed)
+                && !is_saturated(state, idx)
+        })
+        .collect();
+    let primaries_available = state
+        .upstreams
+        .iter()
+        .zip(&healthy)
+        .any(|(upstream, healthy)| !upstream.backup && *healthy);
+    let use_backups = !primaries_available
+        && state
+            .upstreams
+            .iter()
+            .zip(members)
+            .any(|(upstream, member)| *member && upstream.backup);
+    let tier: Vec<bool> = state
+        .upstreams
+        .iter()
+        .zip(members)
+        .map(|(upstream, member)| *member && upstream.backup == use_backups)
+        .collect();
+    let available: Vec<bool> = tier.iter().zip(&healthy).map(|(a, b)| *a && *b).collect();
+    let available = if available.contains(&true) {
+        available
+    } else {
+        tier
+    };
+    state.zones.candidates(available, &state.active_connections)
+}
+
+fn is_saturated(state: &ProxyState, idx: usize) -> bool {
+    let max = state.upstreams[idx].max_connections;
+    max > 0 && state.active_connections[idx].load(Ordering::SeqCst) >= max
+}
+
+/// Weighted random selection that avoids upstreams we just failed to connect to, and gives
+/// upstreams in slow start a reduced share.
+pub struct Random {
+    // Upstream indices, each repeated according to its weight (see upstream::weighted_schedule)
+    schedule: Vec<usize>,
+}
+
+impl LoadBalancingStrategy for Random {
+    fn pick(
+        &self,
+        _request: &http::Request<Vec<u8>>,
+        _client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let weights: Vec<f64> = state
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(idx, upstream)| {
+                if !candidates[idx] || state.recent_failures.is_recently_failed(idx) {
+                    0.0
+                } else {
+                    upstream.weight as f64 * state.slow_start.share(idx)
+                }
+            })
+            .collect();
+        match WeightedIndex::new(&weights) {
+            Ok(distribution) => distribution.sample(&mut rng),
+            // Every upstream failed recently, so there's nothing to prefer between
+            Err(_) => self.schedule[rng.gen_range(0..self.schedule.len())],
+        }
+    }
+}
+
+/// Weighted round-robin over a smooth schedule.
+pub struct RoundRobin {
+    schedule: Vec<usize>,
+    // Position of the next schedule slot to use
+    cursor: AtomicUsize,
+}
+
+impl LoadBalancingStrategy for RoundRobin {
+    fn pick(
+        &self,
+        _request: &http::Request<Vec<u8>>,
+        _client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        // Slots of upstreams that aren't candidates are skipped, and upstreams in slow start give
+        // up each of their slots with probability 1 - share
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let mut chosen = None;
+        for _ in 0..self.schedule.len() {
+            let slot = self.cursor.fetch_add(1, Ordering::Relaxed);
+            let idx = self.schedule[slot % self.schedule.len()];
+            if !candidates[idx] {
+                continue;
+            }
+            chosen = Some(idx);
+            let share = state.slow_start.share(idx);
+            if share >= 1.0 || rng.gen::<f64>() < share {
+                break;
+            }
+        }
+        chosen.unwrap_or(self.schedule[0])
+    }
+}
+
+/// Picks the upstream with the fewest active connections per unit of weight.
+pub struct LeastConnections {
+    // Rotating scan offset, so that ties are spread across upstreams
+    cursor: AtomicUsize,
+}
+
+impl LoadBalancingStrategy for LeastConnections {
+    fn pick(
+        &self,
+        _request: &http::Request<Vec<u8>>,
+        _client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        let n = state.upstreams.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        // Active connections per unit of weight. An upstream in slow start is counted as having
+        // one more connection than it does, spread over its reduced weight, so that it isn't
+        // flooded just for being idle.
+        let load = |idx: usize| {
+            let active = state.active_connections[idx].load(Ordering::SeqCst) as f64;
+            let weight = state.upstreams[idx].weight as f64;
+            match state.slow_start.share(idx) {
+                share if share < 1.0 => (active + 1.0) / (weight * share),
+                _ => active / weight,
+            }
+        };
+        (0..n)
+            .map(|offset| (start + offset) % n)
+            .filter(|&idx| candidates[idx])
+            .min_by(|&a, &b| load(a).total_cmp(&load(b)))
+            .unwrap()
+    }
+}
+
+/// Consistent hashing of a request key (the client IP, a header or a cookie) onto the upstreams.
+pub struct ConsistentHash {
+    ring: HashRing,
+    key: HashKey,
+    // Whether to rehash every request rather than only the first one on a connection
+    per_request: bool,
+    // Most connections an upstream may have, as a multiple of the average (0 = no bound)
+    load_factor: f64,
+}
+
+impl LoadBalancingStrategy for ConsistentHash {
+    fn pick(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        let key = self.key.extract(request, client_ip);
+        if self.load_factor == 0.0 {
+            return self.ring.lookup(&key, candidates);
+        }
+        // Each candidate may take its weighted share of the connections open to the candidates
+        // (counting this one), times the load factor, rounded up so that some candidate always
+        // has room
+        let load = |idx: usize| state.active_connections[idx].load(Ordering::SeqCst);
+        let weight = |idx: usize| state.upstreams[idx].weight as f64;
+        let in_play = || (0..candidates.len()).filter(|&idx| candidates[idx]);
+        let total_load = in_play().map(load).sum::<usize>() + 1;
+        let total_weight: f64 = in_play().map(weight).sum();
+        let has_room = |idx: usize| {
+            let bound = self.load_factor * total_load as f64 * weight(idx) / total_weight;
+            load(idx) < bound.ceil() as usize
+        };
+        self.ring.lookup_bounded(&key, candidates, has_room)
+    }
+
+    fn per_request(&self) -> bool {
+        self.per_request
+    }
+}
+
+/// Prefers the upstream with the lowest moving-average latency, scaled by how busy it is.
+pub struct Ewma {
+    // Rotating scan offset, so that ties are spread across upstreams
+    cursor: AtomicUsize,
+}
+
+impl LoadBalancingStrategy for Ewma {
+    fn pick(
+        &self,
+        _request: &http::Request<Vec<u8>>,
+        _client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        // Score each upstream by its average latency, scaled up by how busy it is and down by its
+        // (slow-start adjusted) weight. Upstreams we haven't measured yet score zero so that they get tried.
+        let n = state.upstreams.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let score = |idx: usize| {
+            let latency = state.latency[idx].get().unwrap_or(Duration::ZERO);
+            let active = state.active_connections[idx].load(Ordering::SeqCst);
+            let weight = state.upstreams[idx].weight as f64 * state.slow_start.share(idx);
+            latency.as_secs_f64() * (active + 1) as f64 / weight
+        };
+        (0..n)
+            .map(|offset| (start + offset) % n)
+            .filter(|&idx| candidates[idx])
+            .min_by(|&a, &b| score(a).total_cmp(&score(b)))
+            .unwrap()
+    }
+}
+
+/// Picks the upstream with the lowest request load per unit of weight, where load counts both
+/// in-flight requests and a decaying tally of recent ones (see upstream::RequestLoad). Counting
+/// recent requests, not just in-flight ones, keeps fast sequential traffic spread by weight, and
+/// letting them decay means a burst doesn't skew selection once it's over.
+pub struct LeastRequest {
+    // Rotating scan offset, so that ties are spread across upstreams
+    cursor: AtomicUsize,
+}
+
+impl LoadBalancingStrategy for LeastRequest {
+    fn pick(
+        &self,
+        _request: &http::Request<Vec<u8>>,
+        _client_ip: IpAddr,
+        state: &ProxyState,
+        candidates: &[bool],
+    ) -> usize {
+        let n = state.upstreams.len();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let cost = |idx: usize| {
+            let weight = state.upstreams[idx].weight as f64 * state.slow_start.share(idx);
+            // The +1 accounts for the request being placed, so that weight still matters between
+            // idle upstreams
+            (state.request_load[idx].cost(state.request_load_decay) + 1.0) / weight
+        };
+        (0..n)
+            .map(|offset| (start + offset) % n)
+            .filter(|&idx| candidates[idx])
+            .min_by(|&a, &b| cost(a).total_cmp(&cost(b)))
+            .unwrap()
+    }
+
+    fn per_request(&self) -> bool {
+        true
+    }
+}
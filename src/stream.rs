@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+
+/// The connection requests are read from and responses written to: either a client's TCP
+/// connection, or one stream of a client's HTTP/2 connection, bridged over an in-memory pipe as
+/// though it were an HTTP/1.1 connection of its own.
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Bridged {
+        pipe: DuplexStream,
+        // The address of the client whose HTTP/2 connection the stream belongs to
+        peer: SocketAddr,
+    },
+}
+
+impl ClientStream {
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peer_addr(),
+            ClientStream::Bridged { peer, .. } => Ok(*peer),
+        }
+    }
+
+    /// Waits until the client has sent something. A bridged stream carries a single request that
+    /// is already on its way, so there is nothing to wait for.
+    pub async fn readable(&self) -> std::io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.readable().await,
+            ClientStream::Bridged { .. } => Ok(()),
+        }
+    }
+}
+
+/// A connection requests are sent to an upstream over and responses read from: either a TCP
+/// connection of its own, or a pipe to a stream on the upstream's shared HTTP/2 connection, bridged
+/// as though it were an HTTP/1.1 connection.
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    Bridged(DuplexStream),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Bridged { pipe, .. } => Pin::new(pipe).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Bridged { pipe, .. } => Pin::new(pipe).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Bridged { pipe, .. } => Pin::new(pipe).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Bridged { pipe, .. } => Pin::new(pipe).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Bridged(pipe) => Pin::new(pipe).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Bridged(pipe) => Pin::new(pipe).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Bridged(pipe) => Pin::new(pipe).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Bridged(pipe) => Pin::new(pipe).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How long each phase of a proxied request took, so that latency can be attributed to the
+/// client, the balancer or the upstream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestTimings {
+    // Reading the request from the client
+    pub client_read: Duration,
+    // Connecting to the upstream (zero if an existing connection was reused)
+    pub connect: Duration,
+    // Writing the request to the upstream
+    pub upstream_write: Duration,
+    // From the request being sent until the upstream's response headers arrived
+    pub ttfb: Duration,
+    // Reading the rest of the response body from the upstream
+    pub transfer: Duration,
+}
+
+impl RequestTimings {
+    fn phases(&self) -> [(&'static str, Duration); 5] {
+        [
+            ("client_read", self.client_read),
+            ("connect", self.connect),
+            ("upstream_write", self.upstream_write),
+            ("ttfb", self.ttfb),
+            ("transfer", self.transfer),
+        ]
+    }
+
+    /// Formats the timings as `phase=1.234ms` fields for log lines.
+    pub fn log_fields(&self) -> String {
+        let mut fields = String::new();
+        for (name, duration) in self.phases() {
+            if !fields.is_empty() {
+                fields.push(' ');
+            }
+            write!(fields, "{}={:.3}ms", name, duration.as_secs_f64() * 1000.0).unwrap();
+        }
+        fields
+    }
+
+    /// Formats the timings as a Server-Timing header value, e.g. `connect;dur=1.234, ...`.
+    pub fn server_timing(&self) -> String {
+        self.phases()
+            .iter()
+            .map(|(name, duration)| format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
@@ -0,0 +1,503 @@
+use crate::concurrency::{Permit, Priority, PriorityGate};
+use crate::routing;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The largest weight an upstream may be given. Weights expand into a selection schedule with one
+/// slot per unit of weight, so they are capped to keep that schedule small.
+const MAX_WEIGHT: usize = 1000;
+
+/// An upstream server, parsed from a command-line value of the form `host:port` or
+/// `host:port=weight`, optionally followed by `,key=value` attributes: `weight=N`, `zone=NAME`,
+/// `backup=true`, `max_conns=N`, `health=[host:port][/path]`, `protocol=http1|h2c` or `pool=NAME`.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub address: String,
+    // Relative share of traffic this upstream should receive
+    pub weight: usize,
+    // Availability zone the upstream runs in, if known
+    pub zone: Option<String>,
+    // Only send traffic here when every primary (non-backup) upstream is unavailable
+    pub backup: bool,
+    // Most connections we may have open to this upstream at once (0 = unlimited)
+    pub max_connections: usize,
+    // Where active health checks for this upstream go, if not to `address` and the global path
+    pub health_address: Option<String>,
+    pub health_path: Option<String>,
+    // What requests are sent to the upstream over
+    pub protocol: Protocol,
+    // Whether `protocol` was set here, rather than left to the pool's --pool-protocol
+    pub protocol_given: bool,
+    // Named pool the upstream serves requests for, if not the default one (see routing::Router)
+    pub pool: Option<String>,
+}
+
+/// The protocol the balancer speaks to an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP/1.1, with a TCP connection per client connection
+    Http1,
+    /// HTTP/2 with prior knowledge, multiplexing every client's requests over one shared connection
+    H2c,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s {
+            "http1" => Ok(Protocol::Http1),
+            "h2c" => Ok(Protocol::H2c),
+            "tls" | "h2" => Err(format!(
+                "protocol {:?} needs TLS to the upstream, which the balancer doesn't support",
+                s
+            )),
+            _ => Err(format!("invalid protocol {:?}, expected http1 or h2c", s)),
+        }
+    }
+}
+
+/// The protocol every upstream in a pool is expected to speak, parsed from a command-line value of
+/// the form `POOL=http1|h2c`. The TLS variants of the two (`tls` and `h2`) are rejected when parsed,
+/// rather than failing once an upstream is dialed.
+#[derive(Clone, Debug)]
+pub struct PoolProtocol {
+    pool: String,
+    protocol: Protocol,
+}
+
+impl std::str::FromStr for PoolProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PoolProtocol, String> {
+        match s.split_once('=') {
+            Some((pool, protocol)) if !pool.is_empty() => Ok(PoolProtocol {
+                pool: pool.to_string(),
+                protocol: protocol.parse()?,
+            }),
+            _ => Err(format!("expected POOL=http1|h2c, got {:?}", s)),
+        }
+    }
+}
+
+/// Has each upstream that didn't set its own protocol speak its pool's, checking that those that
+/// did agree with it, and that each pool with a policy has upstreams and only one policy.
+pub fn apply_pool_protocols(
+    upstreams: &mut [Upstream],
+    policies: &[PoolProtocol],
+) -> Result<(), String> {
+    for (i, policy) in policies.iter().enumerate() {
+        if policies[..i].iter().any(|other| other.pool == policy.pool) {
+            return Err(format!("pool {:?} has more than one protocol", policy.pool));
+        }
+        let in_pool = upstreams.iter_mut().filter(|upstream| {
+            upstream.pool.as_deref().unwrap_or(routing::DEFAULT_POOL) == policy.pool
+        });
+        let mut found = false;
+        for upstream in in_pool {
+            found = true;
+            if upstream.protocol_given && upstream.protocol != policy.protocol {
+                return Err(format!(
+                    "upstream {} speaks {:?}, but pool {:?} expects {:?}",
+                    upstream.address, upstream.protocol, policy.pool, policy.protocol
+                ));
+            }
+            upstream.protocol = policy.protocol;
+        }
+        if !found {
+            return Err(format!(
+                "pool {:?} has a protocol but no upstreams",
+                policy.pool
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_weight(weight: &str) -> Result<usize, String> {
+    weight
+        .parse::<usize>()
+        .map_err(|err| format!("invalid weight {:?}: {}", weight, err))
+}
+
+impl std::str::FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Upstream, String> {
+        let mut parts = s.split(',');
+        let upstream = parts.next().unwrap_or_default();
+        let (address, mut weight) = match upstream.rsplit_once('=') {
+            Some((address, weight)) => (address, parse_weight(weight)?),
+            None => (upstream, 1),
+        };
+        let mut zone = None;
+        let mut backup = false;
+        let mut max_connections = 0;
+        let mut health_address = None;
+        let mut health_path = None;
+        let mut protocol = None;
+        let mut pool = None;
+        for attribute in parts {
+            match attribute.split_once('=') {
+                Some(("weight", value)) => weight = parse_weight(value)?,
+                Some(("zone", value)) if !value.is_empty() => zone = Some(value.to_string()),
+                Some(("backup", value)) => {
+                    backup = value
+                        .parse()
+                        .map_err(|_| format!("invalid backup flag {:?}", value))?
+                }
+                Some(("max_conns", value)) => {
+                    max_connections = value
+                        .parse()
+                        .map_err(|_| format!("invalid connection limit {:?}", value))?
+                }
+                Some(("health", value)) if !value.is_empty() => {
+                    let (address, path) = match value.find('/') {
+                        Some(slash) => value.split_at(slash),
+                        None => (value, ""),
+                    };
+                    health_address = (!address.is_empty()).then(|| address.to_string());
+                    health_path = (!path.is_empty()).then(|| path.to_string());
+                }
+                Some(("protocol", value)) => protocol = Some(value.parse()?),
+                Some(("pool", value)) if !value.is_empty() => pool = Some(value.to_string()),
+                _ => {
+                    return Err(format!(
+                    "invalid upstream attribute {:?}, expected weight=N, zone=NAME, backup=BOOL, \
+                    max_conns=N, health=[HOST:PORT][/PATH], protocol=http1|h2c or pool=NAME",
+                    attribute
+                ))
+                }
+            }
+        }
+        if address.is_empty() {
+            return Err("upstream address must not be empty".to_string());
+        }
+        if weight == 0 || weight > MAX_WEIGHT {
+            return Err(format!(
+                "upstream weight must be between 1 and {}, got {}",
+                MAX_WEIGHT, weight
+            ));
+        }
+        Ok(Upstream {
+            address: address.to_string(),
+            weight,
+            zone,
+            backup,
+            max_connections,
+            health_address,
+            health_path,
+            protocol: protocol.unwrap_or(Protocol::Http1),
+            protocol_given: protocol.is_some(),
+            pool,
+        })
+    }
+}
+
+/// Builds a selection schedule containing each upstream's index `weight` times, interleaved using
+/// the smooth weighted round-robin algorithm (as used by nginx), so that e.g. weights 3:1 produce
+/// A A B A rather than A A A B. Walking the schedule in order gives weighted round-robin, and
+/// picking a random slot gives weighted random selection.
+pub fn weighted_schedule(upstreams: &[Upstream]) -> Vec<usize> {
+    let total_weight: i64 = upstreams
+        .iter()
+        .map(|upstream| upstream.weight as i64)
+        .sum();
+    let mut current_weights = vec![0_i64; upstreams.len()];
+    let mut schedule = Vec::with_capacity(total_weight as usize);
+    for _ in 0..total_weight {
+        for (current, upstream) in current_weights.iter_mut().zip(upstreams) {
+            *current += upstream.weight as i64;
+        }
+        // Pick the upstream with the highest current weight (the first one, on ties)
+        let mut chosen = 0;
+        for (idx, current) in current_weights.iter().enumerate() {
+            if *current > current_weights[chosen] {
+                chosen = idx;
+            }
+        }
+        current_weights[chosen] -= total_weight;
+        schedule.push(chosen);
+    }
+    schedule
+}
+
+/// Counts an open client connection against an upstream for as long as it is held, so the
+/// least-connections strategy can see how busy each upstream is, and so that an upstream's
+/// connection limit can be enforced.
+pub struct ActiveConnection<'a> {
+    counter: &'a AtomicUsize,
+    // The slot this connection holds under the upstream's connection limit, if it has one
+    _permit: Option<Permit>,
+}
+
+impl<'a> ActiveConnection<'a> {
+    pub fn new(counter: &'a AtomicUsize) -> ActiveConnection<'a> {
+        counter.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection {
+            counter,
+            _permit: None,
+        }
+    }
+
+    /// Counts a connection, taking a slot from the upstream's connection limit `gate` if it has
+    /// one. If every slot is taken, waits for one to free up until `deadline`, behind any waiting
+    /// requests of higher priority, then gives up. Low priority requests don't wait at all.
+    pub async fn acquire(
+        counter: &'a AtomicUsize,
+        gate: Option<&Arc<PriorityGate>>,
+        deadline: Instant,
+        priority: Priority,
+    ) -> Option<ActiveConnection<'a>> {
+        let permit = match gate {
+            Some(gate) => {
+                let queue = priority > Priority::Low && Instant::now() < deadline;
+                Some(gate.acquire(priority, queue, deadline).await?)
+            }
+            None => None,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        Some(ActiveConnection {
+            counter,
+            _permit: permit,
+        })
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// An exponentially weighted moving average of an upstream's response latency. Samples decay
+/// with time rather than with sample count, so an upstream that was slow a while ago but has
+/// barely been used since isn't penalized forever: after `decay_window` has passed without new
+/// samples, the old average only carries about a third (1/e) of its original weight.
+pub struct LatencyEwma {
+    // (average latency in seconds, time of the last sample), or None if nothing was measured yet
+    state: parking_lot::Mutex<Option<(f64, Instant)>>,
+}
+
+impl LatencyEwma {
+    pub fn new() -> LatencyEwma {
+        LatencyEwma {
+            state: parking_lot::Mutex::new(None),
+        }
+    }
+
+    /// Folds a new latency sample into the average.
+    pub fn record(&self, sample: Duration, decay_window: Duration) {
+        let now = Instant::now();
+        let sample = sample.as_secs_f64();
+        let mut state = self.state.lock();
+        let average = match *state {
+            Some((average, last_update)) => {
+                let elapsed = now.duration_since(last_update).as_secs_f64();
+                let decay = (-elapsed / decay_window.as_secs_f64().max(f64::EPSILON)).exp();
+                average * decay + sample * (1.0 - decay)
+            }
+            None => sample,
+        };
+        *state = Some((average, now));
+    }
+
+    /// Returns the current average, or None if no samples have been recorded.
+    pub fn get(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .map(|(average, _)| Duration::from_secs_f64(average))
+    }
+}
+
+/// How many requests an upstream is handling right now, plus a decaying count of the requests it
+/// was recently sent, for the least-request strategy. Like LatencyEwma, the recent count decays to
+/// about a third (1/e) of its value every `decay_window`, so a brief burst stops counting against
+/// an upstream soon after it's over.
+pub struct RequestLoad {
+    in_flight: AtomicUsize,
+    // (decayed number of recent requests, time it was last updated)
+    recent: parking_lot::Mutex<(f64, Instant)>,
+}
+
+fn decayed(count: f64, since: Instant, now: Instant, decay_window: Duration) -> f64 {
+    let elapsed = now.saturating_duration_since(since).as_secs_f64();
+    count * (-elapsed / decay_window.as_secs_f64().max(f64::EPSILON)).exp()
+}
+
+impl RequestLoad {
+    pub fn new() -> RequestLoad {
+        RequestLoad {
+            in_flight: AtomicUsize::new(0),
+            recent: parking_lot::Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Counts a request against the upstream. It stays in flight until the returned guard is
+    /// dropped.
+    pub fn start(&self, decay_window: Duration) -> ActiveConnection<'_> {
+        let now = Instant::now();
+        let mut recent = self.recent.lock();
+        *recent = (decayed(recent.0, recent.1, now, decay_window) + 1.0, now);
+        ActiveConnection::new(&self.in_flight)
+    }
+
+    /// The upstream's load: in-flight requests plus the decayed count of recent ones.
+    pub fn cost(&self, decay_window: Duration) -> f64 {
+        let (count, updated_at) = *self.recent.lock();
+        self.in_flight.load(Ordering::SeqCst) as f64
+            + decayed(count, updated_at, Instant::now(), decay_window)
+    }
+}
+
+/// Remembers which upstreams we recently failed to connect to, so that selection can avoid
+/// dialing a dead host over and over until it has had `cooldown` to recover.
+pub struct RecentFailures {
+    cooldown: Duration,
+    // Upstream index -> time of the most recent failed connection attempt
+    failed_at: parking_lot::Mutex<HashMap<usize, Instant>>,
+}
+
+impl RecentFailures {
+    pub fn new(cooldown: Duration) -> RecentFailures {
+        RecentFailures {
+            cooldown,
+            failed_at: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_failure(&self, idx: usize) {
+        self.failed_at.lock().insert(idx, Instant::now());
+    }
+
+    /// Records a successful connection. Returns true if the upstream had failed before, i.e. it
+    /// has just recovered.
+    pub fn record_success(&self, idx: usize) -> bool {
+        self.failed_at.lock().remove(&idx).is_some()
+    }
+
+    /// Returns how long ago a connection to the upstream last failed, if it has failed and not
+    /// recovered since.
+    pub fn failed_ago(&self, idx: usize) -> Option<Duration> {
+        self.failed_at
+            .lock()
+            .get(&idx)
+            .map(|failed_at| failed_at.elapsed())
+    }
+
+    /// Records a failure that happened `ago`.
+    pub fn restore(&self, idx: usize, ago: Duration) {
+        let now = Instant::now();
+        self.failed_at
+            .lock()
+            .insert(idx, now.checked_sub(ago).unwrap_or(now));
+    }
+
+    /// Returns true if a connection to the upstream failed within the cooldown period.
+    pub fn is_recently_failed(&self, idx: usize) -> bool {
+        self.failed_at
+            .lock()
+            .get(&idx)
+            .is_some_and(|failed_at| failed_at.elapsed() < self.cooldown)
+    }
+}
+
+/// Keeps traffic within the balancer's own zone where possible, to avoid paying for cross-zone
+/// traffic. Upstreams in other zones (or without a zone) are only used when no local upstream is
+/// usable: every local one has failed recently, or has `max_connections` active connections.
+pub struct ZonePreference {
+    // Whether each upstream is in the local zone, by upstream index; empty if no local zone is set
+    local: Vec<bool>,
+    // 0 = a local upstream is never too busy to use
+    max_connections: usize,
+}
+
+impl ZonePreference {
+    pub fn new(
+        upstreams: &[Upstream],
+        local_zone: Option<&str>,
+        max_connections: usize,
+    ) -> ZonePreference {
+        let local = match local_zone {
+            Some(local_zone) => upstreams
+                .iter()
+                .map(|upstream| upstream.zone.as_deref() == Some(local_zone))
+                .collect(),
+            None => Vec::new(),
+        };
+        ZonePreference {
+            local,
+            max_connections,
+        }
+    }
+
+    /// Narrows `available` (the upstreams that may be picked from) down to the local upstreams
+    /// among them that aren't too busy, if there are any.
+    pub fn candidates(
+        &self,
+        available: Vec<bool>,
+        active_connections: &[AtomicUsize],
+    ) -> Vec<bool> {
+        let usable_local: Vec<bool> = self
+            .local
+            .iter()
+            .zip(&available)
+            .enumerate()
+            .map(|(idx, (local, available))| {
+                *local
+                    && *available
+                    && (self.max_connections == 0
+                        || active_connections[idx].load(Ordering::SeqCst) < self.max_connections)
+            })
+            .collect();
+        if usable_local.contains(&true) {
+            usable_local
+        } else {
+            available
+        }
+    }
+}
+
+/// The smallest share of its normal traffic that a recovering upstream gets, so that it starts
+/// warming up straight away rather than sitting idle at the start of its slow-start window.
+const MIN_SLOW_START_SHARE: f64 = 0.1;
+
+/// Ramps traffic to a recovered upstream up linearly over `window`, instead of giving a cold
+/// server its full share of traffic the moment it comes back.
+pub struct SlowStart {
+    window: Duration,
+    // Upstream index -> time the upstream recovered
+    recovered_at: parking_lot::Mutex<HashMap<usize, Instant>>,
+}
+
+impl SlowStart {
+    pub fn new(window: Duration) -> SlowStart {
+        SlowStart {
+            window,
+            recovered_at: parking_lot::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn mark_recovered(&self, idx: usize) {
+        if !self.window.is_zero() {
+            self.recovered_at.lock().insert(idx, Instant::now());
+        }
+    }
+
+    /// Returns the fraction (between MIN_SLOW_START_SHARE and 1) of its normal traffic share that
+    /// the upstream should currently get.
+    pub fn share(&self, idx: usize) -> f64 {
+        let mut recovered_at = self.recovered_at.lock();
+        let elapsed = match recovered_at.get(&idx) {
+            Some(recovered) => recovered.elapsed(),
+            None => return 1.0,
+        };
+        if elapsed >= self.window {
+            recovered_at.remove(&idx);
+            return 1.0;
+        }
+        (elapsed.as_secs_f64() / self.window.as_secs_f64()).max(MIN_SLOW_START_SHARE)
+    }
+}
@@ -0,0 +1,777 @@
+mod common;
+
+use common::{init_logging, EchoServer, LoadBalancer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup() -> (LoadBalancer, EchoServer, String) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancer, admin_address) = start_with_admin(&[&upstream.address], &[]).await;
+    (balancer, upstream, admin_address)
+}
+
+/// Starts the balancer with the admin API bound to a free port, returning the admin API's address
+/// as given in the readiness report.
+async fn start_with_admin(upstreams: &[&str], extra_args: &[&str]) -> (LoadBalancer, String) {
+    let report_path =
+        std::env::temp_dir().join(format!("loadbalancer-admin-{}.json", rand::random::<u32>()));
+    let mut args = vec![
+        "--admin-bind",
+        "127.0.0.1:0",
+        "--readiness-report",
+        report_path.to_str().unwrap(),
+    ];
+    args.extend_from_slice(extra_args);
+    let balancer = LoadBalancer::new_with_args(upstreams, &args).await;
+
+    let report = std::fs::read_to_string(&report_path).expect("Readiness report was not written");
+    let _ = std::fs::remove_file(&report_path);
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    let admin_address = report["admin_address"]
+        .as_str()
+        .expect("Readiness report is missing admin_address")
+        .to_string();
+    (balancer, admin_address)
+}
+
+/// Open a connection, make sure it shows up in the admin connection listing, then close it via the
+/// admin API and make sure the balancer hangs up on it.
+#[tokio::test]
+async fn test_list_and_close_connections() {
+    let (balancer, upstream, admin_address) = setup().await;
+
+    log::info!("Opening a keep-alive connection and sending one request");
+    let mut conn = TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"GET /held-open HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    // The response may arrive in several pieces (e.g. headers, then body), so keep reading until
+    // we've seen the echoed request
+    let mut buffer = [0_u8; 4096];
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).contains("GET /held-open HTTP/1.1") {
+        let bytes_read = conn.read(&mut buffer).await.unwrap();
+        assert!(
+            bytes_read > 0,
+            "Connection closed before the response arrived"
+        );
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    log::info!("Checking the admin connection listing");
+    let client = reqwest::Client::new();
+    let listing = client
+        .get(format!("http://{}/connections", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Connections: {}", listing);
+    assert!(listing.contains(&conn.local_addr().unwrap().to_string()));
+    assert!(listing.contains("\"requests_served\":1"));
+    assert!(listing.contains(&upstream.address));
+
+    log::info!("Closing all connections from 127.0.0.1");
+    let response = client
+        .delete(format!("http://{}/connections?ip=127.0.0.1", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("\"closed\":1"));
+
+    log::info!("Making sure the balancer hung up on the connection");
+    let bytes_read =
+        tokio::time::timeout(std::time::Duration::from_secs(2), conn.read(&mut buffer))
+            .await
+            .expect("Connection was not closed")
+            .unwrap_or(0);
+    assert_eq!(bytes_read, 0);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure exchanges that end abnormally are counted by reason in the metrics endpoint.
+#[tokio::test]
+async fn test_metrics_close_reasons() {
+    let (balancer, upstream, admin_address) = setup().await;
+
+    log::info!("Sending a request with a duplicate Host header");
+    let mut conn = TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let bytes_read = conn.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 400"));
+    drop(conn);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Metrics: {}", metrics);
+    assert!(
+        metrics.contains("loadbalancer_terminated_exchanges_total{reason=\"protocol_error\"} 1")
+    );
+    assert!(metrics.contains("loadbalancer_terminated_exchanges_total{reason=\"client_abort\"} 0"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the metrics endpoint reports on the listener: its configured backlog, how many
+/// connections were accepted, and (on Linux) how many are waiting to be accepted.
+#[tokio::test]
+async fn test_accept_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancer, admin_address) =
+        start_with_admin(&[&upstream.address], &["--listen-backlog", "64"]).await;
+
+    balancer
+        .get("/accepted")
+        .await
+        .expect("Error sending request to loadbalancer");
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Metrics: {}", metrics);
+    assert!(metrics.contains("loadbalancer_listen_backlog 64\n"));
+    assert!(metrics.contains("loadbalancer_accepted_connections_total 1\n"));
+    assert!(metrics.contains("loadbalancer_accept_latency_seconds_count 1\n"));
+    if cfg!(target_os = "linux") {
+        assert!(metrics.contains("loadbalancer_accept_queue_depth 0\n"));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a client that keeps sending malformed requests is tarpitted, then banned, shows up in
+/// the admin penalty listing, and can be pardoned.
+#[tokio::test]
+async fn test_penalties() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancer, admin_address) = start_with_admin(
+        &[&upstream.address],
+        &[
+            "--penalty-tarpit-threshold",
+            "2",
+            "--penalty-tarpit-delay-ms",
+            "300",
+            "--penalty-ban-threshold",
+            "3",
+        ],
+    )
+    .await;
+
+    let send_malformed = || async {
+        let mut conn = TcpStream::connect(&balancer.address)
+            .await
+            .expect("Could not connect to loadbalancer");
+        conn.write_all(b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buffer = [0_u8; 4096];
+        let bytes_read = conn.read(&mut buffer).await.unwrap_or(0);
+        String::from_utf8_lossy(&buffer[..bytes_read]).to_string()
+    };
+    assert!(send_malformed().await.starts_with("HTTP/1.1 400"));
+    assert!(send_malformed().await.starts_with("HTTP/1.1 400"));
+
+    log::info!("Checking that the third request is tarpitted");
+    let start = std::time::Instant::now();
+    assert!(send_malformed().await.starts_with("HTTP/1.1 400"));
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+    log::info!("Checking that the client is now banned");
+    assert_eq!(send_malformed().await, "");
+    assert!(balancer.get("/").await.is_err());
+    let client = reqwest::Client::new();
+    let listing = client
+        .get(format!("http://{}/penalties", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Penalties: {}", listing);
+    assert!(listing.contains("\"ip\":\"127.0.0.1\""));
+    assert!(listing.contains("\"offenses\":3"));
+    assert!(listing.contains("\"bans\":1"));
+
+    log::info!("Pardoning the client");
+    let response = client
+        .delete(format!("http://{}/penalties?ip=127.0.0.1", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_text = balancer
+        .get("/after-pardon")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /after-pardon HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Draining an upstream through the admin API should let its in-flight request finish, send every
+/// new request elsewhere, and undraining it should put it back into rotation.
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let (balancer, admin_address) = start_with_admin(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &["--strategy", "round_robin"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    log::info!("Sending a slow request, then draining the upstream handling it");
+    let address = balancer.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/slow", address))
+            .header("x-echo-delay-ms", "1500")
+            .send()
+            .await
+            .expect("In-flight request was not answered")
+            .status()
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let statuses = client
+        .get(format!("http://{}/upstreams", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    let statuses: serde_json::Value = serde_json::from_str(&statuses).unwrap();
+    let busy = statuses
+        .as_array()
+        .unwrap()
+        .iter()
+        .position(|status| status["active_connections"] == 1)
+        .expect("No upstream has the in-flight request");
+    let response = client
+        .post(format!(
+            "http://{}/drain?upstream={}",
+            admin_address, upstreams[busy].address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+
+    for i in 0..6 {
+        balancer
+            .get(&format!("/while-draining-{}", i))
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+    assert_eq!(in_flight.await.unwrap().as_u16(), 200);
+
+    log::info!("Putting the upstream back into rotation, naming it the way a form would encode it");
+    let response = client
+        .delete(format!(
+            "http://{}/drain?upstream=%31%32%37.0.0.1%zz",
+            admin_address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 400);
+    let response = client
+        .delete(format!(
+            "http://{}/drain?upstream={}",
+            admin_address,
+            upstreams[busy].address.replace(':', "%3A")
+        ))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(&format!("\"upstream\":\"{}\"", upstreams[busy].address)));
+    for i in 0..4 {
+        balancer
+            .get(&format!("/after-draining-{}", i))
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+
+    let [first, second] = upstreams;
+    let counts = [Box::new(first).stop().await, Box::new(second).stop().await];
+    assert!(
+        counts[1 - busy] >= 7,
+        "Requests were sent to a draining upstream: {:?}",
+        counts
+    );
+    assert!(
+        counts[busy] >= 2,
+        "The undrained upstream got no more requests: {:?}",
+        counts
+    );
+    log::info!("All done :)");
+}
+
+/// An upstream listed more than once should be drained everywhere it appears, not just in its
+/// first entry.
+#[tokio::test]
+async fn test_drain_duplicate_upstream() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let (balancer, admin_address) = start_with_admin(
+        &[
+            &upstreams[0].address,
+            &upstreams[0].address,
+            &upstreams[1].address,
+        ],
+        &["--strategy", "round_robin"],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/drain?upstream={}",
+            admin_address, upstreams[0].address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["indexes"], serde_json::json!([0, 1]));
+
+    for i in 0..6 {
+        balancer
+            .get(&format!("/duplicate-{}", i))
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+
+    let [first, second] = upstreams;
+    let counts = [Box::new(first).stop().await, Box::new(second).stop().await];
+    assert_eq!(counts, [0, 6]);
+    log::info!("All done :)");
+}
+
+/// A split's weights should decide which pool each request goes to, even on a single keep-alive
+/// connection, and changing them through the admin API should take effect straight away.
+#[tokio::test]
+async fn test_traffic_split() {
+    init_logging();
+    let stable = EchoServer::new().await;
+    let canary = EchoServer::new().await;
+    let (balancer, admin_address) = start_with_admin(
+        &[
+            &format!("{},pool=stable", stable.address),
+            &format!("{},pool=canary", canary.address),
+        ],
+        &[
+            "--split",
+            "web=stable:100,canary:0",
+            "--default-pool",
+            "web",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let send_requests = |count: usize| {
+        let client = client.clone();
+        let address = balancer.address.clone();
+        async move {
+            for _ in 0..count {
+                let response = client
+                    .get(format!("http://{}/", address))
+                    .send()
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                assert_eq!(response.status(), 200);
+            }
+        }
+    };
+    let set_weights = |query: &'static str| {
+        let client = client.clone();
+        let admin_address = admin_address.clone();
+        async move {
+            client
+                .put(format!("http://{}/splits/web?{}", admin_address, query))
+                .send()
+                .await
+                .expect("Error sending request to admin API")
+        }
+    };
+
+    send_requests(10).await;
+    let listing = client
+        .get(format!("http://{}/splits", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(listing.contains("\"canary\":0"), "{}", listing);
+    assert!(listing.contains("\"stable\":100"), "{}", listing);
+
+    log::info!("Sending everything to the canary");
+    let response = set_weights("stable=0&canary=100").await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains("\"canary\":100"));
+    send_requests(10).await;
+
+    log::info!("Splitting evenly");
+    assert_eq!(set_weights("stable=50").await.status().as_u16(), 200);
+    send_requests(200).await;
+
+    log::info!("Making bad changes");
+    assert_eq!(
+        set_weights("canary=0&stable=0").await.status().as_u16(),
+        400
+    );
+    let response = set_weights("beta=5").await;
+    assert_eq!(response.status().as_u16(), 400);
+    let body = response.text().await.unwrap();
+    assert!(body.contains(r#"has no pool \"beta\""#), "{}", body);
+    assert_eq!(set_weights("stable=lots").await.status().as_u16(), 400);
+    let response = client
+        .put(format!("http://{}/splits/api?stable=1", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 404);
+    let body = response.text().await.unwrap();
+    assert!(body.contains(r#"no split is named \"api\""#), "{}", body);
+
+    let stable_requests = Box::new(stable).stop().await;
+    let canary_requests = Box::new(canary).stop().await;
+    assert!(stable_requests > 10 + 50, "{}", stable_requests);
+    assert!(canary_requests > 10 + 50, "{}", canary_requests);
+    log::info!("All done :)");
+}
+
+/// Flipping a blue/green switch through the admin API should send the very next request to the
+/// other pool, and flipping it back should roll back just as fast.
+#[tokio::test]
+async fn test_blue_green_switch() {
+    init_logging();
+    let blue = EchoServer::new().await;
+    let green = EchoServer::new().await;
+    let (balancer, admin_address) = start_with_admin(
+        &[
+            &format!("{},pool=blue", blue.address),
+            &format!("{},pool=green", green.address),
+        ],
+        &[
+            "--blue-green",
+            "web=blue,green",
+            "--default-pool",
+            "web",
+            "--debug-headers",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    // Sends a few requests over the same keep-alive connection, returning the upstreams that served
+    // them
+    let send_requests = || {
+        let client = client.clone();
+        let address = balancer.address.clone();
+        async move {
+            let mut served_by = Vec::new();
+            for _ in 0..3 {
+                let response = client
+                    .get(format!("http://{}/", address))
+                    .send()
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                assert_eq!(response.status(), 200);
+                served_by.push(
+                    response.headers()["x-lb-upstream"]
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+            served_by
+        }
+    };
+    let flip = |query: &'static str| {
+        let client = client.clone();
+        let admin_address = admin_address.clone();
+        async move {
+            client
+                .post(format!("http://{}/switches/web{}", admin_address, query))
+                .send()
+                .await
+                .expect("Error sending request to admin API")
+        }
+    };
+
+    assert_eq!(send_requests().await, vec![blue.address.clone(); 3]);
+
+    log::info!("Flipping to green");
+    let response = flip("").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    assert!(body.contains("\"live\":\"green\""), "{}", body);
+    assert!(body.contains("\"idle\":\"blue\""), "{}", body);
+    assert_eq!(send_requests().await, vec![green.address.clone(); 3]);
+    assert_eq!(flip("?live=green").await.status().as_u16(), 200);
+    assert_eq!(send_requests().await, vec![green.address.clone(); 3]);
+    let listing = client
+        .get(format!("http://{}/switches", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(listing.contains("\"live\":\"green\""), "{}", listing);
+
+    log::info!("Rolling back to blue");
+    assert_eq!(flip("?live=blue").await.status().as_u16(), 200);
+    assert_eq!(send_requests().await, vec![blue.address.clone(); 3]);
+
+    log::info!("Making bad flips");
+    let response = flip("?live=red").await;
+    assert_eq!(response.status().as_u16(), 400);
+    let body = response.text().await.unwrap();
+    assert!(
+        body.contains(r#"switch \"web\" has no pool \"red\""#),
+        "{}",
+        body
+    );
+    let response = client
+        .post(format!("http://{}/switches/api", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 404);
+    let body = response.text().await.unwrap();
+    assert!(body.contains(r#"no switch is named \"api\""#), "{}", body);
+
+    Box::new(blue).stop().await;
+    Box::new(green).stop().await;
+    log::info!("All done :)");
+}
+
+/// A pool whose connections are all in use should be reported as saturated to
+/// --saturation-webhook, with its signals in the admin API and metrics.
+#[tokio::test]
+async fn test_pool_saturation() {
+    init_logging();
+    let busy = EchoServer::new().await;
+    let idle = EchoServer::new().await;
+    let webhook_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook_address = webhook_listener.local_addr().unwrap().to_string();
+    let webhook = tokio::spawn(async move {
+        let (mut conn, _) = webhook_listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        while !received.ends_with(b"}") {
+            let bytes_read = conn.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "Webhook connection closed early");
+            received.extend_from_slice(&buffer[..bytes_read]);
+        }
+        conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(received).unwrap()
+    });
+    let (balancer, admin_address) = start_with_admin(
+        &[
+            &format!("{},pool=busy,max_conns=1", busy.address),
+            &idle.address,
+        ],
+        &[
+            "--host-route",
+            "busy.test=busy",
+            "--upstream-queue-timeout-ms",
+            "5000",
+            "--saturation-interval",
+            "1",
+            "--saturation-utilization-percent",
+            "90",
+            "--saturation-queue-ms",
+            "200",
+            "--saturation-webhook",
+            &format!("http://{}/hooks/saturation", webhook_address),
+        ],
+    )
+    .await;
+
+    log::info!("Sending two slow requests to a pool that only takes one at a time");
+    let requests: Vec<_> = (0..2)
+        .map(|i| {
+            tokio::spawn(
+                reqwest::Client::new()
+                    .get(format!("http://{}/slow/{}", balancer.address, i))
+                    .header("host", "busy.test")
+                    .header("x-echo-delay-ms", "1500")
+                    .send(),
+            )
+        })
+        .collect();
+    let event = tokio::time::timeout(std::time::Duration::from_secs(5), webhook)
+        .await
+        .expect("Webhook was never called")
+        .unwrap();
+    log::info!("Webhook received: {}", event);
+    assert!(event.starts_with("POST /hooks/saturation HTTP/1.1"));
+    assert!(event.contains("\"pool\":\"busy\""), "{}", event);
+    assert!(event.contains("\"saturated\":true"), "{}", event);
+    for request in requests {
+        assert_eq!(request.await.unwrap().unwrap().status(), 200);
+    }
+
+    let client = reqwest::Client::new();
+    let signals = client
+        .get(format!("http://{}/saturation", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(signals.contains("\"pool\":\"busy\""), "{}", signals);
+    assert!(
+        signals.contains(
+            "\"pool\":\"default\",\"attempts\":0,\"timeout_percent\":0.0,\
+                          \"queue_time_ms\":0.0,\"utilization_percent\":null,\"saturated\":false"
+        ),
+        "{}",
+        signals
+    );
+    let metrics = client
+        .get(format!("http://{}/metrics", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("loadbalancer_pool_attempts_total{pool=\"busy\"} 2"));
+    assert!(metrics.contains("loadbalancer_pool_queue_seconds_count{pool=\"busy\"} 2"));
+    assert!(metrics.contains("loadbalancer_pool_attempts_total{pool=\"default\"} 0"));
+
+    Box::new(busy).stop().await;
+    Box::new(idle).stop().await;
+    log::info!("All done :)");
+}
+
+/// Draining an upstream with sticky sessions on it should move each session to another upstream:
+/// by default the session's next request is still served by the draining upstream, with a cookie
+/// pinning the session elsewhere, while --drain-migration immediate moves that request too.
+#[tokio::test]
+async fn test_drain_session_migration() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    for migration in ["next_response", "immediate"] {
+        log::info!("Draining with --drain-migration {}", migration);
+        let (balancer, admin_address) = start_with_admin(
+            &[&upstreams[0].address, &upstreams[1].address],
+            &[
+                "--sticky-sessions",
+                "--drain-migration",
+                migration,
+                "--debug-headers",
+            ],
+        )
+        .await;
+        let client = reqwest::Client::new();
+        // Sends a request with the given cookie, returning the upstream that served it and the
+        // cookie it was told to use from then on, if it was given a new one
+        let send_request = |cookie: String| {
+            let client = client.clone();
+            let address = balancer.address.clone();
+            async move {
+                let response = client
+                    .get(format!("http://{}/", address))
+                    .header("cookie", cookie)
+                    .send()
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                assert_eq!(response.status(), 200);
+                let served_by = response.headers()["x-lb-upstream"]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let new_cookie = response.headers().get("set-cookie").map(|cookie| {
+                    cookie
+                        .to_str()
+                        .unwrap()
+                        .split(';')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                });
+                (served_by, new_cookie)
+            }
+        };
+
+        let (pinned_to, cookie) = send_request(String::new()).await;
+        let cookie = cookie.expect("Session was not pinned");
+        let other = upstreams
+            .iter()
+            .find(|upstream| upstream.address != pinned_to)
+            .unwrap()
+            .address
+            .clone();
+        let response = client
+            .post(format!(
+                "http://{}/drain?upstream={}",
+                admin_address, pinned_to
+            ))
+            .send()
+            .await
+            .expect("Error sending request to admin API");
+        assert_eq!(response.status().as_u16(), 200);
+
+        let (served_by, new_cookie) = send_request(cookie.clone()).await;
+        match migration {
+            "next_response" => assert_eq!(served_by, pinned_to),
+            _ => assert_eq!(served_by, other),
+        }
+        let new_cookie = new_cookie.expect("Session was not moved off the draining upstream");
+        assert_ne!(new_cookie, cookie);
+        for _ in 0..3 {
+            assert_eq!(
+                send_request(new_cookie.clone()).await,
+                (other.clone(), None)
+            );
+        }
+    }
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
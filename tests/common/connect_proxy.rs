@@ -0,0 +1,90 @@
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// (target, Proxy-Authorization header) of each tunnel opened
+type TunnelLog = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+/// A minimal HTTP CONNECT proxy, used to test egress proxy support. It records the target and
+/// Proxy-Authorization header of every tunnel it opens.
+pub struct ConnectProxy {
+    #[allow(dead_code)]
+    pub address: String,
+    tunnels: TunnelLog,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+async fn open_tunnel(mut client: TcpStream, tunnels: TunnelLog) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        request.push(client.read_u8().await?);
+    }
+    let request = String::from_utf8_lossy(&request).to_string();
+    let mut lines = request.split("\r\n");
+    let target = lines
+        .next()
+        .and_then(|line| line.strip_prefix("CONNECT "))
+        .and_then(|line| line.split(' ').next())
+        .unwrap_or_default()
+        .to_string();
+    let authorization = lines.find_map(|line| {
+        let (name, value) = line.split_once(": ")?;
+        name.eq_ignore_ascii_case("proxy-authorization")
+            .then(|| value.to_string())
+    });
+    tunnels
+        .lock()
+        .unwrap()
+        .push((target.clone(), authorization));
+
+    let mut upstream = match TcpStream::connect(&target).await {
+        Ok(upstream) => upstream,
+        Err(_) => {
+            client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+    };
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+impl ConnectProxy {
+    #[allow(dead_code)]
+    pub async fn new() -> ConnectProxy {
+        let mut rng = rand::thread_rng();
+        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let listener = TcpListener::bind(&address)
+            .await
+            .expect("Could not bind CONNECT proxy");
+        let tunnels = Arc::new(Mutex::new(Vec::new()));
+        let accept_tunnels = tunnels.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                tokio::spawn(open_tunnel(client, accept_tunnels.clone()));
+            }
+        });
+        ConnectProxy {
+            address,
+            tunnels,
+            accept_task,
+        }
+    }
+
+    /// Returns (target, Proxy-Authorization header) for every tunnel opened so far.
+    #[allow(dead_code)]
+    pub fn tunnels(&self) -> Vec<(String, Option<String>)> {
+        self.tunnels.lock().unwrap().clone()
+    }
+}
+
+impl Drop for ConnectProxy {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
@@ -9,6 +9,9 @@ use tokio::sync::oneshot;
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub connections_accepted: atomic::AtomicUsize,
+    // Delay added before every response, to simulate a slow upstream
+    pub response_delay: std::time::Duration,
 }
 
 async fn echo(
@@ -18,6 +21,16 @@ async fn echo(
     server_state
         .requests_received
         .fetch_add(1, atomic::Ordering::SeqCst);
+    tokio::time::sleep(server_state.response_delay).await;
+    // Tests can ask for a slow response in order to exercise timeouts and concurrency limits
+    if let Some(delay_ms) = req
+        .headers()
+        .get("x-echo-delay-ms")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+    }
     let mut req_text = format!("{} {} {:?}\n", req.method(), req.uri(), req.version());
     for (header_name, header_value) in req.headers() {
         req_text += &format!(
@@ -45,7 +58,39 @@ impl EchoServer {
         EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
     }
 
+    /// Starts an echo server that waits `response_delay` before answering each request.
+    #[allow(dead_code)]
+    pub async fn new_with_delay(response_delay: std::time::Duration) -> EchoServer {
+        let mut rng = rand::thread_rng();
+        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        EchoServer::start(address, response_delay, false).await
+    }
+
+    /// Starts an echo server that only speaks HTTP/2 (with prior knowledge).
+    #[allow(dead_code)]
+    pub async fn new_http2_only() -> EchoServer {
+        let mut rng = rand::thread_rng();
+        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        EchoServer::start(address, std::time::Duration::ZERO, true).await
+    }
+
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
+        EchoServer::start(bind_addr_string, std::time::Duration::ZERO, false).await
+    }
+
+    /// The number of connections the server has accepted so far.
+    #[allow(dead_code)]
+    pub fn connections_accepted(&self) -> usize {
+        self.state
+            .connections_accepted
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    async fn start(
+        bind_addr_string: String,
+        response_delay: std::time::Duration,
+        http2_only: bool,
+    ) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -53,11 +98,16 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            connections_accepted: atomic::AtomicUsize::new(0),
+            response_delay,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
             let service = make_service_fn(|_| {
                 let server_task_state = server_task_state.clone();
+                server_task_state
+                    .connections_accepted
+                    .fetch_add(1, atomic::Ordering::SeqCst);
                 async move {
                     Ok::<_, hyper::Error>(service_fn(move |req| {
                         let server_task_state = server_task_state.clone();
@@ -66,6 +116,7 @@ impl EchoServer {
                 }
             });
             let server = hyper::Server::bind(&bind_addr)
+                .http2_only(http2_only)
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
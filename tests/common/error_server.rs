@@ -9,14 +9,19 @@ use tokio::sync::oneshot;
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    // If set, answer with a 503 asking to retry after this many seconds instead of a 500
+    pub retry_after: Option<u64>,
 }
 
 #[allow(dead_code)]
-async fn return_error() -> Result<Response<Body>, hyper::Error> {
-    Ok(Response::builder()
-        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::empty())
-        .unwrap())
+async fn return_error(server_state: Arc<ServerState>) -> Result<Response<Body>, hyper::Error> {
+    let response = match server_state.retry_after {
+        Some(retry_after) => Response::builder()
+            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+            .header("retry-after", retry_after),
+        None => Response::builder().status(http::StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    Ok(response.body(Body::empty()).unwrap())
 }
 
 pub struct ErrorServer {
@@ -33,8 +38,24 @@ impl ErrorServer {
         ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
     }
 
+    /// Starts a server that says it is overloaded, answering every request with a 503 and a
+    /// Retry-After header.
+    #[allow(dead_code)]
+    pub async fn new_overloaded(retry_after: u64) -> ErrorServer {
+        let mut rng = rand::thread_rng();
+        ErrorServer::start(
+            format!("127.0.0.1:{}", rng.gen_range(1024..65535)),
+            Some(retry_after),
+        )
+        .await
+    }
+
     #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> ErrorServer {
+        ErrorServer::start(bind_addr_string, None).await
+    }
+
+    async fn start(bind_addr_string: String, retry_after: Option<u64>) -> ErrorServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -42,6 +63,7 @@ impl ErrorServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            retry_after,
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
@@ -52,7 +74,7 @@ impl ErrorServer {
                         server_task_state
                             .requests_received
                             .fetch_add(1, atomic::Ordering::SeqCst);
-                        return_error()
+                        return_error(server_task_state.clone())
                     }))
                 }
             });
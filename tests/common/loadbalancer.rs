@@ -1,11 +1,13 @@
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 use rand::Rng;
+use std::process::ExitStatus;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
 pub struct LoadBalancer {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
 }
@@ -19,33 +21,57 @@ impl LoadBalancer {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> LoadBalancer {
+        let mut extra_args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            extra_args.push("--active-health-check-interval".to_string());
+            extra_args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            extra_args.push("--max-requests-per-minute".to_string());
+            extra_args.push(max_requests_per_minute.to_string());
+        }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        LoadBalancer::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts the load balancer with the given upstreams, passing any extra command-line arguments
+    /// through verbatim.
+    #[allow(dead_code)]
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> LoadBalancer {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        LoadBalancer::new_at_address(address, upstreams, extra_args).await
+    }
+
+    /// Starts the load balancer bound to the given address. Note that if the address uses port 0,
+    /// `self.address` won't be the address the balancer actually listens on.
+    #[allow(dead_code)]
+    pub async fn new_at_address(
+        address: String,
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> LoadBalancer {
         let mut cmd = Command::new(LoadBalancer::target_bin_path());
         cmd.arg("--bind").arg(&address);
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute loadbalancer binary {}",
-            LoadBalancer::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute loadbalancer binary {}",
+                LoadBalancer::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -84,11 +110,41 @@ impl LoadBalancer {
         LoadBalancer { child, address }
     }
 
+    #[allow(dead_code)]
+    pub fn pid(&self) -> u32 {
+        self.child.id().expect("Loadbalancer has already exited")
+    }
+
+    /// Sends a signal (e.g. SIGTERM to start a graceful shutdown) to the balancer process.
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: Signal) {
+        let pid = self.child.id().expect("Loadbalancer has already exited");
+        signal::kill(Pid::from_raw(pid as i32), signal).expect("Could not signal loadbalancer");
+    }
+
+    /// Waits for the balancer process to exit. Returns None if it is still running after
+    /// `timeout`.
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .ok()
+            .map(|status| status.expect("Could not wait for loadbalancer"))
+    }
+
+    /// Asks the balancer to shut down gracefully with SIGTERM, and waits up to `timeout` for it to
+    /// drain its connections and exit.
+    #[allow(dead_code)]
+    pub async fn shutdown(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        self.signal(Signal::SIGTERM);
+        self.wait_for_exit(timeout).await
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .get(&format!("http://{}{}", self.address, path))
+            .get(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "loadbalancer-tests")
             .send()
             .await?
@@ -100,7 +156,7 @@ impl LoadBalancer {
     pub async fn post(&self, path: &str, body: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
         client
-            .post(&format!("http://{}{}", self.address, path))
+            .post(format!("http://{}{}", self.address, path))
             .header("x-sent-by", "loadbalancer-tests")
             .body(body.to_string())
             .send()
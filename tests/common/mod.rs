@@ -1,10 +1,15 @@
+mod connect_proxy;
 mod echo_server;
 mod error_server;
 mod loadbalancer;
+mod redis_server;
 mod server;
 
 use std::sync;
 
+// `ConnectProxy` is only used by crate `single_upstream_tests`.
+#[allow(unused_imports)]
+pub use connect_proxy::ConnectProxy;
 pub use echo_server::EchoServer;
 // `ErrorServer`` is only used by crate `multiple_upstream_tests`.
 // crate `single_upstream_tests` use `common` but don't use `ErrorServer`, so the compiler
@@ -13,6 +18,9 @@ pub use echo_server::EchoServer;
 #[allow(unused_imports)]
 pub use error_server::ErrorServer;
 pub use loadbalancer::LoadBalancer;
+// `RedisServer` is only used by crate `single_upstream_tests`.
+#[allow(unused_imports)]
+pub use redis_server::RedisServer;
 pub use server::Server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
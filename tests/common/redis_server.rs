@@ -0,0 +1,114 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A stand-in for a Redis server that only knows INCR and EXPIRE (which it accepts but ignores).
+pub struct RedisServer {
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    counters: Arc<Mutex<HashMap<String, i64>>>,
+    commands_received: Arc<Mutex<usize>>,
+}
+
+impl RedisServer {
+    #[allow(dead_code)]
+    pub async fn new() -> RedisServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let counters = Arc::new(Mutex::new(HashMap::new()));
+        let commands_received = Arc::new(Mutex::new(0));
+        let server_task = {
+            let counters = counters.clone();
+            let commands_received = commands_received.clone();
+            tokio::spawn(async move {
+                // Held here so that stopping the server closes its connections too
+                let mut connections = tokio::task::JoinSet::new();
+                while let Ok((stream, _)) = listener.accept().await {
+                    connections.spawn(serve(stream, counters.clone(), commands_received.clone()));
+                }
+            })
+        };
+        RedisServer {
+            server_task,
+            address,
+            counters,
+            commands_received,
+        }
+    }
+
+    /// The sum of every counter, i.e. the number of INCRs received.
+    #[allow(dead_code)]
+    pub fn total_count(&self) -> i64 {
+        self.counters.lock().unwrap().values().sum()
+    }
+}
+
+async fn read_line(conn: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut line = String::new();
+    conn.read_line(&mut line).await.ok()?;
+    Some(line.strip_suffix("\r\n")?.to_string())
+}
+
+async fn serve(
+    stream: TcpStream,
+    counters: Arc<Mutex<HashMap<String, i64>>>,
+    commands_received: Arc<Mutex<usize>>,
+) {
+    let mut conn = BufReader::new(stream);
+    // Each command is an array of bulk strings
+    while let Some(header) = read_line(&mut conn).await {
+        let Some(len) = header.strip_prefix('*').and_then(|len| len.parse().ok()) else {
+            return;
+        };
+        let mut args = Vec::new();
+        for _ in 0..len {
+            let Some(arg_len) = read_line(&mut conn)
+                .await
+                .and_then(|line| line.strip_prefix('$')?.parse::<usize>().ok())
+            else {
+                return;
+            };
+            let mut arg = vec![0_u8; arg_len + 2];
+            if conn.read_exact(&mut arg).await.is_err() {
+                return;
+            }
+            arg.truncate(arg_len);
+            args.push(String::from_utf8_lossy(&arg).to_string());
+        }
+        *commands_received.lock().unwrap() += 1;
+        let reply = match args
+            .first()
+            .map(|command| command.to_uppercase())
+            .as_deref()
+        {
+            Some("INCR") if args.len() == 2 => {
+                let mut counters = counters.lock().unwrap();
+                let count = counters.entry(args[1].clone()).or_insert(0);
+                *count += 1;
+                format!(":{}\r\n", count)
+            }
+            Some("EXPIRE") => ":1\r\n".to_string(),
+            _ => "-ERR unknown command\r\n".to_string(),
+        };
+        if conn.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Server for RedisServer {
+    async fn stop(self: Box<Self>) -> usize {
+        self.server_task.abort();
+        let _ = self.server_task.await;
+        let commands_received = *self.commands_received.lock().unwrap();
+        commands_received
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
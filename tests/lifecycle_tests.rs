@@ -0,0 +1,446 @@
+mod common;
+
+use common::{init_logging, EchoServer, LoadBalancer, Server};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A request that is in flight when the balancer is asked to shut down should still be answered,
+/// and the balancer should exit once it has been.
+#[tokio::test]
+async fn test_graceful_shutdown_drains_in_flight_requests() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(1)).await;
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+
+    let address = balancer.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::get(format!("http://{}/slow", address))
+            .await
+            .expect("In-flight request was not answered")
+    });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    log::info!("Shutting down with a request in flight");
+    let status = balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit after draining");
+    assert!(status.success());
+
+    let response = in_flight.await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["connection"], "close");
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("GET /slow HTTP/1.1"));
+    assert!(TcpStream::connect(&balancer.address).await.is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Idle keep-alive connections shouldn't hold up a shutdown.
+#[tokio::test]
+async fn test_shutdown_closes_idle_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+
+    let mut conn = TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"GET /idle HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).contains("GET /idle HTTP/1.1") {
+        let bytes_read = conn.read(&mut buffer).await.unwrap();
+        assert!(
+            bytes_read > 0,
+            "Connection closed before the response arrived"
+        );
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    log::info!("Shutting down with an idle connection open");
+    let start = Instant::now();
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(conn.read(&mut buffer).await.unwrap_or(0), 0);
+    drop(conn);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A request that takes longer than --shutdown-timeout shouldn't keep the balancer running.
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(Duration::from_secs(5)).await;
+    let mut balancer =
+        LoadBalancer::new_with_args(&[&upstream.address], &["--shutdown-timeout", "1"]).await;
+
+    let address = balancer.address.clone();
+    let in_flight =
+        tokio::spawn(async move { reqwest::get(format!("http://{}/stuck", address)).await });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    log::info!("Shutting down with a request that won't finish in time");
+    let start = Instant::now();
+    balancer
+        .shutdown(Duration::from_secs(4))
+        .await
+        .expect("Loadbalancer did not exit after its shutdown timeout");
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(in_flight.await.unwrap().is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The admin API can start a graceful shutdown too.
+#[tokio::test]
+async fn test_admin_shutdown() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let mut balancer =
+        LoadBalancer::new_with_args(&[&upstream.address], &["--admin-bind", &admin_address]).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/shutdown", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to admin API");
+    assert_eq!(response.status().as_u16(), 202);
+    let status = balancer
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+    assert!(status.success());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --state-file, a client that was banned before a restart should still be banned after it.
+#[tokio::test]
+async fn test_state_survives_restart() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let state_file = std::env::temp_dir().join(format!(
+        "loadbalancer-state-{}.json",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let args = [
+        "--penalty-ban-threshold",
+        "1",
+        "--state-file",
+        state_file.to_str().unwrap(),
+    ];
+
+    let send_malformed = |address: String| async move {
+        let mut conn = TcpStream::connect(&address)
+            .await
+            .expect("Could not connect to loadbalancer");
+        conn.write_all(b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buffer = [0_u8; 4096];
+        let bytes_read = conn.read(&mut buffer).await.unwrap_or(0);
+        String::from_utf8_lossy(&buffer[..bytes_read]).to_string()
+    };
+
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &args).await;
+    assert!(send_malformed(balancer.address.clone())
+        .await
+        .starts_with("HTTP/1.1 400"));
+    assert_eq!(send_malformed(balancer.address.clone()).await, "");
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+    assert!(state_file.exists());
+
+    log::info!("Restarting the balancer");
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &args).await;
+    assert_eq!(send_malformed(balancer.address.clone()).await, "");
+    let response = reqwest::get(format!("http://{}/after-restart", balancer.address)).await;
+    assert!(
+        response.is_err(),
+        "Banned client was served after a restart"
+    );
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+
+    std::fs::remove_file(&state_file).unwrap();
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --pidfile, the balancer should write its process ID once it is up, and remove the file
+/// when it exits.
+#[tokio::test]
+async fn test_pidfile() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let pidfile = std::env::temp_dir().join(format!(
+        "loadbalancer-{}.pid",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let mut balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--pidfile", pidfile.to_str().unwrap()],
+    )
+    .await;
+
+    let pid = std::fs::read_to_string(&pidfile).expect("PID file was not written");
+    assert_eq!(pid.trim().parse::<u32>().unwrap(), balancer.pid());
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+    assert!(!pidfile.exists());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream that was dead when the state file was saved should stay out of rotation after a
+/// restart, unless the file is older than --state-file-max-age.
+#[tokio::test]
+async fn test_stale_upstream_health_is_ignored() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let state_file = std::env::temp_dir().join(format!(
+        "loadbalancer-state-{}.json",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let args = [
+        "--strategy",
+        "round_robin",
+        "--active-health-check-interval",
+        "60",
+        "--state-file",
+        state_file.to_str().unwrap(),
+        "--state-file-max-age",
+        "60",
+    ];
+    let write_state = |saved_secs_ago: u64| {
+        let saved_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            - Duration::from_secs(saved_secs_ago);
+        let snapshot = format!(
+            r#"{{"saved_at_unix_ms": {}, "clients": [], "upstreams": [
+                {{"address": "{}", "dead": true, "failed_secs_ago": null}}
+            ]}}"#,
+            saved_at.as_millis(),
+            upstreams[1].address
+        );
+        std::fs::write(&state_file, snapshot).unwrap();
+    };
+    let requests_to_balancer = || async {
+        let mut balancer =
+            LoadBalancer::new_with_args(&[&upstreams[0].address, &upstreams[1].address], &args)
+                .await;
+        for i in 0..4 {
+            reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+                .await
+                .expect("Error sending request to loadbalancer");
+        }
+        balancer
+            .shutdown(Duration::from_secs(5))
+            .await
+            .expect("Loadbalancer did not exit");
+    };
+
+    log::info!("Starting from a fresh state file, which marks the second upstream dead");
+    write_state(10);
+    requests_to_balancer().await;
+    log::info!("Starting from a state file that is too old to trust");
+    write_state(600);
+    requests_to_balancer().await;
+
+    std::fs::remove_file(&state_file).unwrap();
+    let [first, second] = upstreams;
+    assert_eq!(Box::new(first).stop().await, 6);
+    assert_eq!(Box::new(second).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --state-file, a client that used up its per-minute quota before a restart should still be
+/// throttled after it.
+#[tokio::test]
+async fn test_rate_limits_survive_restart() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let state_file = std::env::temp_dir().join(format!(
+        "loadbalancer-state-{}.json",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let args = [
+        "--max-requests-per-minute",
+        "2",
+        "--state-file",
+        state_file.to_str().unwrap(),
+    ];
+
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &args).await;
+    for i in 0..2 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+
+    log::info!("Restarting the balancer");
+    let mut balancer = LoadBalancer::new_with_args(&[&upstream.address], &args).await;
+    let response = reqwest::get(format!("http://{}/after-restart", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 429);
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+
+    std::fs::remove_file(&state_file).unwrap();
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// An upstream whose circuit breaker was open when the state file was saved should stay out of
+/// rotation after a restart, until its cooldown is over, in every entry that lists it.
+#[tokio::test]
+async fn test_open_breaker_survives_restart() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let state_file = std::env::temp_dir().join(format!(
+        "loadbalancer-state-{}.json",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let saved_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    let snapshot = format!(
+        r#"{{"saved_at_unix_ms": {}, "clients": [], "upstreams": [
+            {{"address": "{}", "dead": false, "failed_secs_ago": null,
+              "breaker": {{"half_open": false, "cooldown_secs_left": 60}}}}
+        ]}}"#,
+        saved_at.as_millis(),
+        upstreams[1].address
+    );
+    std::fs::write(&state_file, snapshot).unwrap();
+
+    let mut balancer = LoadBalancer::new_with_args(
+        &[
+            &upstreams[0].address,
+            &upstreams[1].address,
+            &upstreams[1].address,
+        ],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "60",
+            "--circuit-breaker-failures",
+            "3",
+            "--state-file",
+            state_file.to_str().unwrap(),
+        ],
+    )
+    .await;
+    for i in 0..4 {
+        reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+
+    std::fs::remove_file(&state_file).unwrap();
+    let [first, second] = upstreams;
+    assert_eq!(Box::new(first).stop().await, 4);
+    assert_eq!(Box::new(second).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Times in the state file that aren't valid durations should be skipped, not crash the balancer
+/// at startup.
+#[tokio::test]
+async fn test_invalid_state_times_are_skipped() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let state_file = std::env::temp_dir().join(format!(
+        "loadbalancer-state-{}.json",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let saved_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    let snapshot = format!(
+        r#"{{"saved_at_unix_ms": {}, "upstreams": [
+            {{"address": "{}", "dead": false, "failed_secs_ago": -5,
+              "ejected_secs_left": 1e300,
+              "breaker": {{"half_open": false, "cooldown_secs_left": -1}}}}
+        ], "clients": [
+            {{"ip": "127.0.0.1", "score": 0, "offenses": 0, "bans": 1,
+              "ban_remaining_secs": 1e300}}
+        ], "rate_limits": [
+            {{"ip": "127.0.0.1", "window_secs_ago": -1, "requests": 0, "tokens": 0}},
+            {{"ip": "127.0.0.2", "window_secs_ago": 1, "requests": 0, "tokens": -1e300}}
+        ]}}"#,
+        saved_at.as_millis(),
+        upstreams[1].address
+    );
+    std::fs::write(&state_file, snapshot).unwrap();
+
+    let mut balancer = LoadBalancer::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--strategy",
+            "round_robin",
+            "--circuit-breaker-failures",
+            "3",
+            "--rate-limit-rps",
+            "100",
+            "--state-file",
+            state_file.to_str().unwrap(),
+        ],
+    )
+    .await;
+    for i in 0..4 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    balancer
+        .shutdown(Duration::from_secs(5))
+        .await
+        .expect("Loadbalancer did not exit");
+
+    std::fs::remove_file(&state_file).unwrap();
+    let [first, second] = upstreams;
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 2);
+    log::info!("All done :)");
+}
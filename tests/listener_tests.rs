@@ -0,0 +1,115 @@
+mod common;
+
+use common::{init_logging, EchoServer, LoadBalancer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// If the bind address is busy when the balancer starts, it should keep retrying and start serving
+/// once the address is freed.
+#[tokio::test]
+async fn test_bind_retry() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+
+    log::info!("Occupying {} before starting the balancer", address);
+    let blocker = std::net::TcpListener::bind(&address).expect("Could not occupy address");
+    let balancer = LoadBalancer::new_at_address(
+        address.clone(),
+        &[&upstream.address],
+        &["--bind-retry", "10"],
+    )
+    .await;
+
+    log::info!("Freeing the address");
+    drop(blocker);
+    sleep(Duration::from_secs(3)).await;
+
+    let response_text = balancer
+        .get("/after-retry")
+        .await
+        .expect("Error sending request to loadbalancer. Bind retry may not be working");
+    assert!(response_text.contains("GET /after-retry HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Binding to port 0 should pick a free port and report it in the readiness report.
+#[tokio::test]
+async fn test_bind_port_zero() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let report_path = std::env::temp_dir().join(format!(
+        "loadbalancer-port-zero-{}.json",
+        rand::random::<u32>()
+    ));
+    let _balancer = LoadBalancer::new_at_address(
+        "127.0.0.1:0".to_string(),
+        &[&upstream.address],
+        &["--readiness-report", report_path.to_str().unwrap()],
+    )
+    .await;
+
+    let report = std::fs::read_to_string(&report_path).expect("Readiness report was not written");
+    let _ = std::fs::remove_file(&report_path);
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    let listen_address = report["listen_address"]
+        .as_str()
+        .expect("Readiness report is missing listen_address");
+    assert!(!listen_address.ends_with(":0"));
+
+    let response_text = reqwest::get(format!("http://{}/port-zero", listen_address))
+        .await
+        .expect("Error sending request to loadbalancer")
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /port-zero HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An IPv6 wildcard bind address should accept both IPv6 and IPv4 clients, unless --ipv6-only is
+/// given.
+#[tokio::test]
+async fn test_dual_stack_listener() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    let port = rand::thread_rng().gen_range(1024..65535);
+    let _balancer =
+        LoadBalancer::new_at_address(format!("[::]:{}", port), &[&upstream.address], &[]).await;
+    for client_address in [format!("[::1]:{}", port), format!("127.0.0.1:{}", port)] {
+        let response_text = reqwest::get(format!("http://{}/dual-stack", client_address))
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains("GET /dual-stack HTTP/1.1"));
+        // Clients connecting over IPv4 should be reported with their plain IPv4 address
+        if client_address.starts_with("127.") {
+            assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        }
+    }
+
+    let port = rand::thread_rng().gen_range(1024..65535);
+    let _v6_only_balancer = LoadBalancer::new_at_address(
+        format!("[::]:{}", port),
+        &[&upstream.address],
+        &["--ipv6-only"],
+    )
+    .await;
+    assert!(reqwest::get(format!("http://[::1]:{}/v6-only", port))
+        .await
+        .is_ok());
+    assert!(reqwest::get(format!("http://127.0.0.1:{}/v6-only", port))
+        .await
+        .is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -5,11 +5,7 @@ use common::{init_logging, EchoServer, ErrorServer, LoadBalancer, Server};
 use std::time::Duration;
 use tokio::time::sleep;
 
-async fn setup_with_params(
-    n_upstreams: usize,
-    active_health_check_interval: Option<usize>,
-    max_requests_per_minute: Option<usize>,
-) -> (LoadBalancer, Vec<Box<dyn Server>>) {
+async fn start_upstreams(n_upstreams: usize) -> (Vec<Box<dyn Server>>, Vec<String>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
     for _ in 0..n_upstreams {
@@ -19,6 +15,15 @@ async fn setup_with_params(
         .iter()
         .map(|upstream| upstream.address())
         .collect();
+    (upstreams, upstream_addresses)
+}
+
+async fn setup_with_params(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+) -> (LoadBalancer, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
     let upstream_addresses: Vec<&str> = upstream_addresses
         .iter()
         .map(|addr| addr.as_str())
@@ -32,6 +37,19 @@ async fn setup_with_params(
     (balancer, upstreams)
 }
 
+async fn setup_with_args(
+    n_upstreams: usize,
+    extra_args: &[&str],
+) -> (LoadBalancer, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancer = LoadBalancer::new_with_args(&upstream_addresses, extra_args).await;
+    (balancer, upstreams)
+}
+
 async fn setup(n_upstreams: usize) -> (LoadBalancer, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
@@ -80,216 +98,2139 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
-async fn try_failover(balancer: &LoadBalancer, upstreams: &mut Vec<Box<dyn Server>>) {
-    // Send some initial requests. Everything should work
-    log::info!("Sending some initial requests. These should definitely work.");
-    for i in 0..5 {
+/// With the round-robin strategy, requests should be spread exactly evenly across the upstreams
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let (balancer, mut upstreams) =
+        setup_with_args(n_upstreams, &["--strategy", "round_robin"]).await;
+
+    for i in 0..n_requests {
         let path = format!("/request-{}", i);
         let response_text = balancer
             .get(&path)
             .await
             .expect("Error sending request to loadbalancer");
         assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
     }
 
-    // Kill one of the upstreams
-    log::info!("Killing one of the upstream servers");
-    upstreams.pop().unwrap().stop().await;
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(
+        request_counters,
+        vec![n_requests / n_upstreams; n_upstreams]
+    );
 
-    // Make sure requests continue to work
-    for i in 0..6 {
-        log::info!("Sending request #{} after killing an upstream server", i);
-        let path = format!("/failover-{}", i);
+    log::info!("All done :)");
+}
+
+/// Weighted round-robin should hand each upstream a share of requests proportional to its weight
+#[tokio::test]
+async fn test_weighted_round_robin() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let weighted_upstreams = [
+        format!("{}=3", upstream_addresses[0]),
+        format!("{}=1", upstream_addresses[1]),
+    ];
+    let weighted_upstreams: Vec<&str> = weighted_upstreams.iter().map(|s| s.as_str()).collect();
+    let balancer =
+        LoadBalancer::new_with_args(&weighted_upstreams, &["--strategy", "round_robin"]).await;
+
+    for i in 0..20 {
+        let path = format!("/request-{}", i);
         let response_text = balancer
             .get(&path)
             .await
-            .expect("Error sending request to loadbalancer. Passive failover may not be working");
-        assert!(
-            response_text.contains(&format!("GET {} HTTP/1.1", path)),
-            "loadbalancer returned unexpected response. Failover may not be working."
-        );
-        assert!(
-            response_text.contains("x-sent-by: loadbalancer-tests"),
-            "loadbalancer returned unexpected response. Failover may not be working."
-        );
-        assert!(
-            response_text.contains("x-forwarded-for: 127.0.0.1"),
-            "loadbalancer returned unexpected response. Failover may not be working."
-        );
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
     }
-}
 
-/// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
-/// make sure requests continue to work
-#[tokio::test]
-async fn test_passive_health_checks() {
-    let n_upstreams = 2;
-    let (balancer, mut upstreams) = setup(n_upstreams).await;
-    try_failover(&balancer, &mut upstreams).await;
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters, vec![15, 5]);
+
     log::info!("All done :)");
 }
 
-/// Verify that the active health checks are monitoring HTTP status, rather than simply depending
-/// on whether connections can be established to determine whether an upstream is up:
-///
-/// * Send a few requests
-/// * Replace one of the upstreams with a server that only returns HTTP error 500s
-/// * Send some more requests. Make sure all the requests succeed
+/// Sequential requests never overlap, so least_request has only its decaying count of recent
+/// requests to go on; that alone should spread them in proportion to weight
 #[tokio::test]
-async fn test_active_health_checks_check_http_status() {
-    let n_upstreams = 2;
-    let (balancer, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
-    let failed_ip = upstreams[upstreams.len() - 1].address();
+async fn test_weighted_least_request() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let weighted_upstreams = [
+        format!("{}=3", upstream_addresses[0]),
+        format!("{}=1", upstream_addresses[1]),
+    ];
+    let weighted_upstreams: Vec<&str> = weighted_upstreams.iter().map(|s| s.as_str()).collect();
+    let balancer =
+        LoadBalancer::new_with_args(&weighted_upstreams, &["--strategy", "least_request"]).await;
 
-    // Send some initial requests. Everything should work
-    log::info!("Sending some initial requests. These should definitely work.");
-    for i in 0..4 {
+    for i in 0..20 {
         let path = format!("/request-{}", i);
         let response_text = balancer
             .get(&path)
             .await
             .expect("Error sending request to loadbalancer");
         assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
     }
 
-    log::info!("Replacing one of the upstreams with a server that returns Error 500s...");
-    upstreams.pop().unwrap().stop().await;
-    upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
-
-    log::info!("Waiting for health checks to realize server is dead...");
-    sleep(Duration::from_secs(3)).await;
-
-    // Make sure we get back successful requests
-    for i in 0..8 {
-        log::info!(
-            "Sending request #{} after swapping server for one that returns Error 500. We should \
-            get a successful response from the other upstream",
-            i
-        );
-        let path = format!("/failover-{}", i);
-        let response_text = balancer.get(&path).await.expect(
-            "Error sending request to loadbalancer. Active health checks may not be working",
-        );
-        assert!(
-            response_text.contains(&format!("GET {} HTTP/1.1", path)),
-            "loadbalancer returned unexpected response. Active health checks may not be working."
-        );
-        assert!(
-            response_text.contains("x-sent-by: loadbalancer-tests"),
-            "loadbalancer returned unexpected response. Active health checks may not be working."
-        );
-        assert!(
-            response_text.contains("x-forwarded-for: 127.0.0.1"),
-            "loadbalancer returned unexpected response. Active health checks may not be working."
-        );
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
     }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters, vec![15, 5]);
+
+    log::info!("All done :)");
 }
 
-/// Make sure active health checks restore upstreams that were previously failed but are now
-/// working again:
-///
-/// * Send a few requests
-/// * Kill one of the upstreams
-/// * Send some more requests
-/// * Bring the upstream back
-/// * Ensure requests are delivered again
+/// With least-connections, new connections should avoid an upstream that is busy with a slow
+/// request
 #[tokio::test]
-async fn test_active_health_checks_restore_failed_upstream() {
-    let n_upstreams = 2;
-    let (balancer, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
-    let failed_ip = upstreams[upstreams.len() - 1].address();
-    try_failover(&balancer, &mut upstreams).await;
-
-    log::info!("Re-starting the \"failed\" upstream server...");
-    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+async fn test_least_connections() {
+    let (balancer, mut upstreams) = setup_with_args(2, &["--strategy", "least_connections"]).await;
+    let balancer = std::sync::Arc::new(balancer);
 
-    log::info!("Waiting a few seconds for the active health check to run...");
-    sleep(Duration::from_secs(3)).await;
+    log::info!("Sending a slow request that keeps one upstream busy");
+    let slow_balancer = balancer.clone();
+    let slow_request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/slow", slow_balancer.address))
+            .header("x-echo-delay-ms", "2000")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .status()
+    });
+    sleep(Duration::from_millis(500)).await;
 
-    log::info!("Sending some more requests");
-    for i in 0..5 {
-        let path = format!("/after-restore-{}", i);
+    log::info!("Sending requests that should all go to the idle upstream");
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
         let response_text = balancer
             .get(&path)
             .await
             .expect("Error sending request to loadbalancer");
         assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        // Give the balancer a moment to notice the client hung up
+        sleep(Duration::from_millis(100)).await;
     }
+    assert_eq!(slow_request.await.expect("Task panicked").as_u16(), 200);
 
-    log::info!(
-        "Verifying that the previously-dead upstream got some requests after being restored"
-    );
-    let last_upstream_req_count = upstreams.pop().unwrap().stop().await;
-    assert!(
-        last_upstream_req_count > 0,
-        "We killed an upstream, then brought it back, but it never got any more requests!"
-    );
-
-    // Shut down
+    let mut request_counters = Vec::new();
     while let Some(upstream) = upstreams.pop() {
-        upstream.stop().await;
+        request_counters.push(upstream.stop().await);
     }
+    request_counters.sort();
+    assert_eq!(request_counters, vec![1, 4]);
 
     log::info!("All done :)");
 }
 
-/// Enable rate limiting and ensure that requests fail after sending more than the threshold
+/// With ip_hash, every connection from the same client should land on the same upstream
 #[tokio::test]
-async fn test_rate_limiting() {
-    let n_upstreams = 1;
-    let rate_limit_threshold = 5;
-    let num_extra_requests: usize = 3;
-    let (balancer, mut upstreams) =
-        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+async fn test_ip_hash_affinity() {
+    let (balancer, mut upstreams) = setup_with_args(3, &["--strategy", "ip_hash"]).await;
 
-    log::info!(
-        "Sending some basic requests to the server, within the rate limit threshold. These \
-        should succeed."
-    );
-    for i in 0..rate_limit_threshold {
+    for i in 0..10 {
         let path = format!("/request-{}", i);
         let response_text = balancer
             .get(&path)
             .await
             .expect("Error sending request to loadbalancer");
         assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
     }
 
-    log::info!(
-        "Sending more requests that exceed the rate limit threshold. The server should \
-        respond to these with an HTTP 429 (too many requests) error."
-    );
-    for i in 0..num_extra_requests {
-        let client = reqwest::Client::new();
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    request_counters.sort();
+    assert_eq!(request_counters, vec![0, 0, 10]);
+
+    log::info!("All done :)");
+}
+
+/// Make sure the hash strategy keeps requests with the same key on the same upstream, even when
+/// requests for different keys share a client connection.
+#[tokio::test]
+async fn test_header_hash_affinity() {
+    let (balancer, mut upstreams) = setup_with_args(
+        3,
+        &[
+            "--strategy",
+            "hash",
+            "--hash-key",
+            "header:x-user-id",
+            "--debug-headers-cidr",
+            "127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut upstream_for_user = std::collections::HashMap::new();
+    for i in 0..20 {
+        let user = format!("user-{}", i % 5);
         let response = client
-            .get(&format!("http://{}/overboard-{}", balancer.address, i))
-            .header("x-sent-by", "loadbalancer-tests")
+            .get(format!("http://{}/request-{}", balancer.address, i))
+            .header("x-user-id", &user)
             .send()
             .await
-            .expect(
-                "Error sending rate limited request to loadbalancer. You should be \
-                accepting the connection but sending back an HTTP error, rather than rejecting \
-                the connection outright.",
-            );
-        log::info!("{:?}", response);
-        log::info!("Checking to make sure the server responded with HTTP 429");
-        assert_eq!(response.status().as_u16(), 429);
+            .expect("Error sending request to loadbalancer");
+        let upstream = response.headers()["x-lb-upstream"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let expected = upstream_for_user
+            .entry(user.clone())
+            .or_insert_with(|| upstream.clone());
+        assert_eq!(*expected, upstream, "{} moved between upstreams", user);
     }
+    drop(client);
 
-    log::info!("Ensuring the extra requests didn't go through to the upstream servers");
-    let mut total_request_count = 0;
+    let mut total_requests = 0;
     while let Some(upstream) = upstreams.pop() {
-        total_request_count += upstream.stop().await;
+        total_requests += upstream.stop().await;
     }
-    assert_eq!(total_request_count, rate_limit_threshold);
+    assert_eq!(total_requests, 20);
 
     log::info!("All done :)");
 }
+
+/// With --hash-load-factor, requests for a hot key that would pile up on its upstream should spill
+/// over to the next upstreams on the ring, while a key with no load stays where it hashes to.
+#[tokio::test]
+async fn test_bounded_load_hashing() {
+    // With a load factor of 1.25, an upstream may have at most ceil(1.25 * 6 / 3) = 3 of the six
+    // connections
+    for (load_factor, max_per_upstream) in [("0", 6), ("1.25", 3)] {
+        log::info!(
+            "Sending concurrent requests for one key with load factor {}",
+            load_factor
+        );
+        let (balancer, mut upstreams) = setup_with_args(
+            3,
+            &[
+                "--strategy",
+                "hash",
+                "--hash-key",
+                "header:x-user-id",
+                "--hash-load-factor",
+                load_factor,
+                "--debug-headers",
+            ],
+        )
+        .await;
+        let send_request = |delay_ms: &'static str| {
+            reqwest::Client::new()
+                .get(format!("http://{}/hot", balancer.address))
+                .header("x-user-id", "hot-user")
+                .header("x-echo-delay-ms", delay_ms)
+                .send()
+        };
+        let home = send_request("0").await.unwrap().headers()["x-lb-upstream"].clone();
+
+        let mut requests = Vec::new();
+        for _ in 0..6 {
+            requests.push(tokio::spawn(send_request("1000")));
+            // Let each request's connection open before the next is balanced
+            sleep(Duration::from_millis(100)).await;
+        }
+        let mut served_by = std::collections::HashMap::new();
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            *served_by
+                .entry(response.headers()["x-lb-upstream"].clone())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(served_by[&home], max_per_upstream, "{:?}", served_by);
+        assert!(
+            served_by.values().all(|&count| count <= max_per_upstream),
+            "{:?}",
+            served_by
+        );
+        let response = send_request("0").await.unwrap();
+        assert_eq!(response.headers()["x-lb-upstream"], home);
+
+        while let Some(upstream) = upstreams.pop() {
+            upstream.stop().await;
+        }
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure the ewma strategy steers traffic away from a slow upstream once it has measured it.
+#[tokio::test]
+async fn test_ewma_prefers_fast_upstream() {
+    init_logging();
+    let slow_upstream = EchoServer::new_with_delay(Duration::from_millis(200)).await;
+    let fast_upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&slow_upstream.address, &fast_upstream.address],
+        &["--strategy", "ewma"],
+    )
+    .await;
+
+    for i in 0..20 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let slow_requests = Box::new(slow_upstream).stop().await;
+    let fast_requests = Box::new(fast_upstream).stop().await;
+    assert_eq!(slow_requests + fast_requests, 20);
+    assert!(
+        slow_requests <= 2,
+        "slow upstream received {} requests",
+        slow_requests
+    );
+
+    log::info!("All done :)");
+}
+
+/// Make sure random selection stops picking an upstream right after failing to connect to it.
+#[tokio::test]
+async fn test_random_skips_recently_failed_upstream() {
+    let (balancer, mut upstreams) = setup_with_args(3, &["--strategy", "random"]).await;
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+
+    // Without failover, the first request that lands on the dead upstream fails, but after that
+    // the dead upstream should be skipped
+    let mut failures = 0;
+    for i in 0..20 {
+        // Use a new client each time, so that every request gets a fresh upstream choice
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/request-{}", balancer.address, i))
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        if !response.status().is_success() {
+            failures += 1;
+        }
+    }
+    assert!(failures <= 1, "{} requests failed", failures);
+
+    let mut total_requests = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_requests += upstream.stop().await;
+    }
+    assert_eq!(total_requests, 20 - failures);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a route with its own strategy is balanced by that strategy, while other requests use
+/// the default one.
+#[tokio::test]
+async fn test_route_strategy() {
+    let (balancer, mut upstreams) = setup_with_args(
+        3,
+        &[
+            "--strategy",
+            "round_robin",
+            "--route-strategy",
+            "/sticky=ip_hash",
+            "--debug-headers",
+        ],
+    )
+    .await;
+
+    for i in 0..9 {
+        let response = reqwest::get(format!("http://{}/sticky/{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.headers()["x-lb-strategy"], "ip_hash");
+    }
+    for i in 0..6 {
+        let response = reqwest::get(format!("http://{}/other/{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.headers()["x-lb-strategy"], "round_robin");
+    }
+    for i in 0..3 {
+        let response = reqwest::get(format!("http://{}/stickynote/{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.headers()["x-lb-strategy"], "round_robin");
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    request_counters.sort();
+    assert_eq!(request_counters, vec![3, 3, 12]);
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that comes back after failing only gets a reduced share of traffic while
+/// it is in slow start.
+#[tokio::test]
+async fn test_slow_start_after_recovery() {
+    let (balancer, mut upstreams) = setup_with_args(
+        2,
+        &[
+            "--strategy",
+            "round_robin",
+            "--slow-start",
+            "60",
+            "--debug-headers",
+        ],
+    )
+    .await;
+    let recovering_address = upstreams[1].address();
+
+    log::info!("Killing one of the upstreams until the balancer notices");
+    upstreams.pop().unwrap().stop().await;
+    for i in 0..4 {
+        let _ = reqwest::get(format!("http://{}/down-{}", balancer.address, i)).await;
+    }
+
+    log::info!("Bringing it back");
+    upstreams.push(Box::new(
+        EchoServer::new_at_address(recovering_address.clone()).await,
+    ));
+    for i in 0..2 {
+        let _ = reqwest::get(format!("http://{}/recover-{}", balancer.address, i)).await;
+    }
+
+    let mut sent_to_recovering = 0;
+    for i in 0..20 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+        if response.headers()["x-lb-upstream"] == recovering_address.as_str() {
+            sent_to_recovering += 1;
+        }
+    }
+    // Without slow start, round-robin would send it 10 of the 20 requests
+    assert!(
+        sent_to_recovering <= 6,
+        "recovering upstream got {} of 20 requests",
+        sent_to_recovering
+    );
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure sticky sessions send requests carrying the affinity cookie back to the same upstream,
+/// and move the session when that upstream goes down.
+#[tokio::test]
+async fn test_cookie_affinity() {
+    let (balancer, mut upstreams) = setup_with_args(
+        3,
+        &[
+            "--strategy",
+            "round_robin",
+            "--sticky-sessions",
+            "--debug-headers",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/login", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    let cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    assert!(cookie.starts_with("lb-affinity="));
+    let pinned_upstream = response.headers()["x-lb-upstream"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let client = reqwest::Client::new();
+    for i in 0..5 {
+        let response = client
+            .get(format!("http://{}/page-{}", balancer.address, i))
+            .header("cookie", &cookie)
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(
+            response.headers()["x-lb-upstream"],
+            pinned_upstream.as_str()
+        );
+        assert!(response.headers().get("set-cookie").is_none());
+    }
+    drop(client);
+
+    log::info!("Stopping the pinned upstream");
+    let pinned_position = upstreams
+        .iter()
+        .position(|upstream| upstream.address() == pinned_upstream)
+        .unwrap();
+    upstreams.remove(pinned_position).stop().await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/after-failure", balancer.address))
+        .header("cookie", &cookie)
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_ne!(
+        response.headers()["x-lb-upstream"],
+        pinned_upstream.as_str()
+    );
+    assert!(response.headers().get("set-cookie").is_some());
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure the IP affinity table keeps sending a client to the same upstream, and reassigns it
+/// when that upstream goes down.
+#[tokio::test]
+async fn test_ip_affinity() {
+    let (balancer, mut upstreams) = setup_with_args(
+        3,
+        &[
+            "--strategy",
+            "round_robin",
+            "--ip-affinity-ttl",
+            "60",
+            "--debug-headers",
+        ],
+    )
+    .await;
+
+    let address = &balancer.address;
+    let upstream_for = |path: &'static str| async move {
+        let response = reqwest::get(format!("http://{}{}", address, path))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+        response.headers()["x-lb-upstream"]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let pinned_upstream = upstream_for("/first").await;
+    for _ in 0..5 {
+        assert_eq!(upstream_for("/again").await, pinned_upstream);
+    }
+
+    log::info!("Stopping the pinned upstream");
+    let pinned_position = upstreams
+        .iter()
+        .position(|upstream| upstream.address() == pinned_upstream)
+        .unwrap();
+    upstreams.remove(pinned_position).stop().await;
+    let new_upstream = upstream_for("/after-failure").await;
+    assert_ne!(new_upstream, pinned_upstream);
+    for _ in 0..5 {
+        assert_eq!(upstream_for("/again").await, new_upstream);
+    }
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams in the local zone are preferred, and that traffic spills over to other
+/// zones once no local upstream is available.
+#[tokio::test]
+async fn test_local_zone_preference() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(3).await;
+    let zoned_upstreams = [
+        format!("{},zone=zone-a", upstream_addresses[0]),
+        format!("{},zone=zone-a", upstream_addresses[1]),
+        format!("{},zone=zone-b", upstream_addresses[2]),
+    ];
+    let zoned_upstreams: Vec<&str> = zoned_upstreams.iter().map(|addr| addr.as_str()).collect();
+    let balancer = LoadBalancer::new_with_args(
+        &zoned_upstreams,
+        &["--strategy", "round_robin", "--local-zone", "zone-a"],
+    )
+    .await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+    log::info!("Stopping the local upstreams");
+    let remote_upstream = upstreams.pop().unwrap();
+    let mut local_counts = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        local_counts.push(upstream.stop().await);
+    }
+    assert_eq!(local_counts, vec![3, 3]);
+
+    // Each request that hits a stopped local upstream fails over, eventually to the remote one
+    for i in 0..6 {
+        let path = format!("/spillover-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(remote_upstream.stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Make sure backup upstreams get no traffic while a primary upstream is available, and that
+/// requests fall through to them once the last primary upstream fails.
+#[tokio::test]
+async fn test_backup_upstreams() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(3).await;
+    let backup = format!("{},backup=true", upstream_addresses[2]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1], &backup],
+        &["--strategy", "round_robin"],
+    )
+    .await;
+
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+    log::info!("Stopping the primary upstreams");
+    let backup_upstream = upstreams.pop().unwrap();
+    let mut primary_counts = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        primary_counts.push(upstream.stop().await);
+    }
+    assert_eq!(primary_counts, vec![3, 3]);
+
+    // The first request fails over from one stopped primary to the other, and then to the backup.
+    // After that, no primary is left and requests go straight to the backup.
+    for i in 0..4 {
+        let path = format!("/fallthrough-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(backup_upstream.stop().await, 4);
+    log::info!("All done :)");
+}
+
+async fn try_failover(balancer: &LoadBalancer, upstreams: &mut Vec<Box<dyn Server>>) {
+    // Send some initial requests. Everything should work
+    log::info!("Sending some initial requests. These should definitely work.");
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    // Kill one of the upstreams
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+
+    // Make sure requests continue to work
+    for i in 0..6 {
+        log::info!("Sending request #{} after killing an upstream server", i);
+        let path = format!("/failover-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer. Passive failover may not be working");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "loadbalancer returned unexpected response. Failover may not be working."
+        );
+        assert!(
+            response_text.contains("x-sent-by: loadbalancer-tests"),
+            "loadbalancer returned unexpected response. Failover may not be working."
+        );
+        assert!(
+            response_text.contains("x-forwarded-for: 127.0.0.1"),
+            "loadbalancer returned unexpected response. Failover may not be working."
+        );
+    }
+}
+
+/// Make sure passive health checks work. Send a few requests, then kill one of the upstreams and
+/// make sure requests continue to work
+#[tokio::test]
+async fn test_passive_health_checks() {
+    let n_upstreams = 2;
+    let (balancer, mut upstreams) = setup(n_upstreams).await;
+    try_failover(&balancer, &mut upstreams).await;
+    log::info!("All done :)");
+}
+
+/// Verify that the active health checks are monitoring HTTP status, rather than simply depending
+/// on whether connections can be established to determine whether an upstream is up:
+///
+/// * Send a few requests
+/// * Replace one of the upstreams with a server that only returns HTTP error 500s
+/// * Send some more requests. Make sure all the requests succeed
+#[tokio::test]
+async fn test_active_health_checks_check_http_status() {
+    let n_upstreams = 2;
+    let (balancer, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
+    let failed_ip = upstreams[upstreams.len() - 1].address();
+
+    // Send some initial requests. Everything should work
+    log::info!("Sending some initial requests. These should definitely work.");
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    log::info!("Replacing one of the upstreams with a server that returns Error 500s...");
+    upstreams.pop().unwrap().stop().await;
+    upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
+
+    log::info!("Waiting for health checks to realize server is dead...");
+    sleep(Duration::from_secs(3)).await;
+
+    // Make sure we get back successful requests
+    for i in 0..8 {
+        log::info!(
+            "Sending request #{} after swapping server for one that returns Error 500. We should \
+            get a successful response from the other upstream",
+            i
+        );
+        let path = format!("/failover-{}", i);
+        let response_text = balancer.get(&path).await.expect(
+            "Error sending request to loadbalancer. Active health checks may not be working",
+        );
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "loadbalancer returned unexpected response. Active health checks may not be working."
+        );
+        assert!(
+            response_text.contains("x-sent-by: loadbalancer-tests"),
+            "loadbalancer returned unexpected response. Active health checks may not be working."
+        );
+        assert!(
+            response_text.contains("x-forwarded-for: 127.0.0.1"),
+            "loadbalancer returned unexpected response. Active health checks may not be working."
+        );
+    }
+}
+
+/// Make sure active health checks restore upstreams that were previously failed but are now
+/// working again:
+///
+/// * Send a few requests
+/// * Kill one of the upstreams
+/// * Send some more requests
+/// * Bring the upstream back
+/// * Ensure requests are delivered again
+#[tokio::test]
+async fn test_active_health_checks_restore_failed_upstream() {
+    let n_upstreams = 2;
+    let (balancer, mut upstreams) = setup_with_params(n_upstreams, Some(1), None).await;
+    let failed_ip = upstreams[upstreams.len() - 1].address();
+    try_failover(&balancer, &mut upstreams).await;
+
+    log::info!("Re-starting the \"failed\" upstream server...");
+    upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
+
+    log::info!("Waiting a few seconds for the active health check to run...");
+    sleep(Duration::from_secs(3)).await;
+
+    log::info!("Sending some more requests");
+    for i in 0..5 {
+        let path = format!("/after-restore-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    log::info!(
+        "Verifying that the previously-dead upstream got some requests after being restored"
+    );
+    let last_upstream_req_count = upstreams.pop().unwrap().stop().await;
+    assert!(
+        last_upstream_req_count > 0,
+        "We killed an upstream, then brought it back, but it never got any more requests!"
+    );
+
+    // Shut down
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
+
+/// With active health checks enabled, an upstream that live traffic fails to reach should stay out
+/// of rotation until a health check passes, rather than only for the failed upstream cooldown.
+#[tokio::test]
+async fn test_passive_failure_marks_upstream_dead() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "60",
+            "--failed-upstream-cooldown",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+    // One of these discovers that the upstream is down
+    for i in 0..2 {
+        let _ = balancer.get(&format!("/discover-failure-{}", i)).await;
+    }
+
+    log::info!("Waiting for the failed upstream cooldown to pass");
+    sleep(Duration::from_millis(1500)).await;
+    for i in 0..6 {
+        let path = format!("/after-cooldown-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer. The dead upstream was used again");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    upstreams.pop().unwrap().stop().await;
+    log::info!("All done :)");
+}
+
+/// Health check responses should be judged by --health-expect-status and --health-expect-body.
+/// Here every status passes, so the error server is only marked dead for its empty body, and the
+/// echo server passes because it echoes the health check request line back.
+#[tokio::test]
+async fn test_health_check_expectations() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &error_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/healthz",
+            "--health-expect-status",
+            "200-599",
+            "--health-expect-body",
+            "^GET /healthz HTTP",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    sleep(Duration::from_millis(1500)).await;
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "The upstream with a non-matching health check body is still in rotation"
+        );
+    }
+
+    Box::new(error_upstream).stop().await;
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The health check request should use the configured method, Host header and extra headers. The
+/// echo server reflects the request back, so the body expectation only passes if they were sent.
+#[tokio::test]
+async fn test_custom_health_check_request() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &error_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/healthz",
+            "--health-check-method",
+            "POST",
+            "--health-check-host",
+            "health.internal",
+            "--health-check-header",
+            "X-Health-Token: s3cret",
+            "--health-expect-body",
+            "(?s)^POST /healthz HTTP/1.1.*host: health.internal.*x-health-token: s3cret",
+        ],
+    )
+    .await;
+
+    // Rather than guessing how long the first health checks take, wait until two requests in a row
+    // reach the echo upstream, which under round robin means the error upstream is out of rotation
+    log::info!("Waiting for the error upstream to leave rotation...");
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let mut echoed_in_a_row = 0;
+    while echoed_in_a_row < 2 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "The error upstream was never taken out of rotation"
+        );
+        let response_text = balancer
+            .get("/probe")
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response_text.contains("GET /probe HTTP/1.1") {
+            echoed_in_a_row += 1;
+        } else {
+            echoed_in_a_row = 0;
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "The echo upstream didn't pass its custom health check"
+        );
+    }
+
+    Box::new(error_upstream).stop().await;
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream with its own health endpoint should be checked there rather than at its serving
+/// address. Here the first upstream's health endpoint is a server that only returns errors, so it
+/// should be taken out of rotation even though it serves requests fine.
+#[tokio::test]
+async fn test_per_upstream_health_endpoint() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let health_upstream = ErrorServer::new().await;
+    let first = format!(
+        "{},health={}/status",
+        upstream_addresses[0],
+        health_upstream.address()
+    );
+    let balancer = LoadBalancer::new_with_args(
+        &[&first, &upstream_addresses[1]],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    sleep(Duration::from_millis(1500)).await;
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    upstreams.pop().unwrap().stop().await;
+    assert_eq!(
+        upstreams.pop().unwrap().stop().await,
+        0,
+        "The upstream whose health endpoint is failing still got requests"
+    );
+    Box::new(health_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream that accepts health check connections but never answers should fail its checks
+/// once --health-check-timeout-ms passes.
+#[tokio::test]
+async fn test_health_check_timeout() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let hung_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hung_address = hung_listener.local_addr().unwrap().to_string();
+    let hung_upstream = tokio::spawn(async move {
+        // Accept connections and hold them open without ever responding
+        let mut held = Vec::new();
+        while let Ok((conn, _)) = hung_listener.accept().await {
+            held.push(conn);
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &hung_address],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-timeout-ms",
+            "300",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a health check to time out...");
+    sleep(Duration::from_secs(1)).await;
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(2), balancer.get(&path))
+            .await
+            .expect("Request was sent to the hung upstream")
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    hung_upstream.abort();
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --unhealthy-threshold, a single failed health check shouldn't take an upstream out of
+/// rotation, but several in a row should.
+#[tokio::test]
+async fn test_unhealthy_threshold() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--unhealthy-threshold",
+            "4",
+            "--health-check-jitter",
+            "0",
+        ],
+    )
+    .await;
+
+    log::info!("Replacing one of the upstreams with a server that returns Error 500s...");
+    let failed_ip = upstream_addresses[1].clone();
+    upstreams.pop().unwrap().stop().await;
+    upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
+
+    log::info!("Waiting for a single health check to fail");
+    sleep(Duration::from_millis(1500)).await;
+    let mut errors = 0;
+    for i in 0..4 {
+        let path = format!("/after-one-check-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        if !response_text.contains(&format!("GET {} HTTP/1.1", path)) {
+            errors += 1;
+        }
+    }
+    assert!(
+        errors > 0,
+        "The failing upstream was taken out of rotation after a single failed check"
+    );
+
+    log::info!("Waiting for more health checks to fail");
+    sleep(Duration::from_secs(4)).await;
+    for i in 0..6 {
+        let path = format!("/after-threshold-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "The failing upstream is still in rotation after reaching the unhealthy threshold"
+        );
+    }
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Enable rate limiting and ensure that requests fail after sending more than the threshold
+#[tokio::test]
+async fn test_rate_limiting() {
+    let n_upstreams = 1;
+    let rate_limit_threshold = 5;
+    let num_extra_requests: usize = 3;
+    let (balancer, mut upstreams) =
+        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+
+    log::info!(
+        "Sending some basic requests to the server, within the rate limit threshold. These \
+        should succeed."
+    );
+    for i in 0..rate_limit_threshold {
+        let path = format!("/request-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: loadbalancer-tests"));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    log::info!(
+        "Sending more requests that exceed the rate limit threshold. The server should \
+        respond to these with an HTTP 429 (too many requests) error."
+    );
+    for i in 0..num_extra_requests {
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("http://{}/overboard-{}", balancer.address, i))
+            .header("x-sent-by", "loadbalancer-tests")
+            .send()
+            .await
+            .expect(
+                "Error sending rate limited request to loadbalancer. You should be \
+                accepting the connection but sending back an HTTP error, rather than rejecting \
+                the connection outright.",
+            );
+        log::info!("{:?}", response);
+        log::info!("Checking to make sure the server responded with HTTP 429");
+        assert_eq!(response.status().as_u16(), 429);
+    }
+
+    log::info!("Ensuring the extra requests didn't go through to the upstream servers");
+    let mut total_request_count = 0;
+    while let Some(upstream) = upstreams.pop() {
+        total_request_count += upstream.stop().await;
+    }
+    assert_eq!(total_request_count, rate_limit_threshold);
+
+    log::info!("All done :)");
+}
+
+/// An upstream that accepts connections but answers everything with a 500 should be ejected once
+/// its 5xx rate crosses --outlier-5xx-percent, even without any health checks.
+#[tokio::test]
+async fn test_outlier_ejection() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &error_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--outlier-5xx-percent",
+            "50",
+            "--outlier-min-requests",
+            "4",
+        ],
+    )
+    .await;
+
+    let mut server_errors = 0;
+    for i in 0..20 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().is_server_error() {
+            server_errors += 1;
+        }
+    }
+    assert_eq!(
+        server_errors, 4,
+        "The failing upstream should have been ejected after its fourth 5xx"
+    );
+
+    assert_eq!(Box::new(error_upstream).stop().await, 4);
+    assert_eq!(Box::new(echo_upstream).stop().await, 16);
+    log::info!("All done :)");
+}
+
+/// A keep-alive connection whose requests have been going to an upstream should move off it once
+/// the upstream is ejected, rather than keep using it until the client hangs up.
+#[tokio::test]
+async fn test_outlier_ejection_keep_alive() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    // Round robin starts with the first upstream, so the connection starts out on the failing one
+    let balancer = LoadBalancer::new_with_args(
+        &[&error_upstream.address(), &echo_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--outlier-5xx-percent",
+            "50",
+            "--outlier-min-requests",
+            "4",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut server_errors = 0;
+    for i in 0..20 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancer.address, i))
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().is_server_error() {
+            server_errors += 1;
+        }
+    }
+    assert_eq!(
+        server_errors, 4,
+        "The connection should have moved off the upstream once it was ejected"
+    );
+
+    assert_eq!(Box::new(error_upstream).stop().await, 4);
+    assert_eq!(Box::new(echo_upstream).stop().await, 16);
+    log::info!("All done :)");
+}
+
+/// Once more of the pool fails its health checks than --panic-threshold allows, the balancer
+/// should fail open and route to every upstream rather than pile everything onto the survivors.
+#[tokio::test]
+async fn test_panic_threshold() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstreams = [ErrorServer::new().await, ErrorServer::new().await];
+    let balancer = LoadBalancer::new_with_args(
+        &[
+            &echo_upstream.address(),
+            &error_upstreams[0].address(),
+            &error_upstreams[1].address(),
+        ],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-jitter",
+            "0",
+            "--panic-threshold",
+            "50",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to fail two of the three upstreams...");
+    sleep(Duration::from_millis(1500)).await;
+    let mut server_errors = 0;
+    for i in 0..6 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().is_server_error() {
+            server_errors += 1;
+        }
+    }
+    assert_eq!(
+        server_errors, 4,
+        "The failing upstreams should still get traffic while in panic mode"
+    );
+
+    for upstream in error_upstreams {
+        Box::new(upstream).stop().await;
+    }
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The panic threshold should apply to each pool on its own: a pool with most of its upstreams down
+/// should fail open even though most upstreams overall are up, without taking pools that are
+/// mostly healthy with it.
+#[tokio::test]
+async fn test_panic_threshold_per_pool() {
+    init_logging();
+    let echo_upstreams = [
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+    ];
+    let error_upstreams = [
+        ErrorServer::new().await,
+        ErrorServer::new().await,
+        ErrorServer::new().await,
+    ];
+    let in_pool_a = |address: String| format!("{},pool=a", address);
+    let balancer = LoadBalancer::new_with_args(
+        &[
+            &in_pool_a(echo_upstreams[0].address()),
+            &in_pool_a(error_upstreams[0].address()),
+            &in_pool_a(error_upstreams[1].address()),
+            &echo_upstreams[1].address(),
+            &echo_upstreams[2].address(),
+            &echo_upstreams[3].address(),
+            &error_upstreams[2].address(),
+        ],
+        &[
+            "--strategy",
+            "round_robin",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-jitter",
+            "0",
+            "--panic-threshold",
+            "50",
+            "--host-route",
+            "a.test=a",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to fail two upstreams in pool a and one in the other...");
+    sleep(Duration::from_millis(2500)).await;
+    let client = reqwest::Client::new();
+    let send_requests = |host: &'static str, count: usize| {
+        let client = client.clone();
+        let address = balancer.address.clone();
+        async move {
+            let mut server_errors = 0;
+            for i in 0..count {
+                let response = client
+                    .get(format!("http://{}/request-{}", address, i))
+                    .header("host", host)
+                    .header("connection", "close")
+                    .send()
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                if response.status().is_server_error() {
+                    server_errors += 1;
+                }
+            }
+            server_errors
+        }
+    };
+    assert_eq!(
+        send_requests("a.test", 6).await,
+        4,
+        "Pool a should be in panic mode, routing to its failing upstreams too"
+    );
+    assert_eq!(
+        send_requests("other.test", 8).await,
+        0,
+        "The default pool should still avoid its failing upstream"
+    );
+
+    for upstream in error_upstreams {
+        Box::new(upstream).stop().await;
+    }
+    for upstream in echo_upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// After --circuit-breaker-failures consecutive failures an upstream's breaker should open, and
+/// once the cooldown has passed, a single trial request should go through (and reopen it).
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &error_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--circuit-breaker-failures",
+            "2",
+            "--circuit-breaker-cooldown",
+            "2",
+        ],
+    )
+    .await;
+
+    let send_requests = |count: usize| {
+        let address = balancer.address.clone();
+        async move {
+            let mut server_errors = 0;
+            for i in 0..count {
+                let response = reqwest::get(format!("http://{}/request-{}", address, i))
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                if response.status().is_server_error() {
+                    server_errors += 1;
+                }
+            }
+            server_errors
+        }
+    };
+
+    assert_eq!(
+        send_requests(10).await,
+        2,
+        "The breaker should have opened after two failures"
+    );
+    log::info!("Waiting for the breaker's cooldown...");
+    sleep(Duration::from_millis(2500)).await;
+    assert_eq!(
+        send_requests(6).await,
+        1,
+        "Exactly one trial request should have been let through"
+    );
+
+    assert_eq!(Box::new(error_upstream).stop().await, 3);
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A keep-alive connection whose requests have been going to an upstream should stop sending it
+/// requests once its circuit breaker opens.
+#[tokio::test]
+async fn test_circuit_breaker_keep_alive() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    // Round robin starts with the first upstream, so the connection starts out on the failing one
+    let balancer = LoadBalancer::new_with_args(
+        &[&error_upstream.address(), &echo_upstream.address()],
+        &[
+            "--strategy",
+            "round_robin",
+            "--circuit-breaker-failures",
+            "2",
+            "--circuit-breaker-cooldown",
+            "60",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut server_errors = 0;
+    for i in 0..10 {
+        let response = client
+            .get(format!("http://{}/request-{}", balancer.address, i))
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().is_server_error() {
+            server_errors += 1;
+        }
+    }
+    assert_eq!(
+        server_errors, 2,
+        "The connection should have moved off the upstream once its breaker opened"
+    );
+
+    assert_eq!(Box::new(error_upstream).stop().await, 2);
+    assert_eq!(Box::new(echo_upstream).stop().await, 8);
+    log::info!("All done :)");
+}
+
+/// With --health-check-max-backoff, a dead upstream should be probed less and less often instead
+/// of on every interval.
+#[tokio::test]
+async fn test_dead_upstream_backoff() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let broken_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let broken_address = broken_listener.local_addr().unwrap().to_string();
+    let probes = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted_probes = probes.clone();
+    let broken_upstream = tokio::spawn(async move {
+        // Accept connections and close them straight away, which fails every health check
+        while let Ok((conn, _)) = broken_listener.accept().await {
+            counted_probes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            drop(conn);
+        }
+    });
+    let _balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &broken_address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-jitter",
+            "0",
+            "--health-check-max-backoff",
+            "8",
+        ],
+    )
+    .await;
+
+    // Checks run 1s, 3s and 7s after startup, where they would run every second without backoff
+    sleep(Duration::from_millis(5500)).await;
+    let probes = probes.load(std::sync::atomic::Ordering::SeqCst);
+    assert!(
+        (2..=3).contains(&probes),
+        "Expected 2 probes of the dead upstream, got {}",
+        probes
+    );
+
+    broken_upstream.abort();
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// When an upstream fails its health checks, the balancer should POST an event to
+/// --health-webhook and run --health-event-command.
+#[tokio::test]
+async fn test_health_event_hooks() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let webhook_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook_address = webhook_listener.local_addr().unwrap().to_string();
+    let webhook = tokio::spawn(async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut conn, _) = webhook_listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        while !received.ends_with(b"}") {
+            let bytes_read = conn.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "Webhook connection closed early");
+            received.extend_from_slice(&buffer[..bytes_read]);
+        }
+        conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        String::from_utf8(received).unwrap()
+    });
+    let event_file =
+        std::env::temp_dir().join(format!("loadbalancer-events-{}", rand::random::<u32>()));
+    let balancer = LoadBalancer::new_with_args(
+        &[&echo_upstream.address(), &error_upstream.address()],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-jitter",
+            "0",
+            "--health-webhook",
+            &format!("http://{}/hooks/health", webhook_address),
+            "--health-event-command",
+            &format!(
+                "echo \"$LB_UPSTREAM $LB_HEALTHY\" >> {}",
+                event_file.display()
+            ),
+        ],
+    )
+    .await;
+
+    let request = tokio::time::timeout(Duration::from_secs(3), webhook)
+        .await
+        .expect("Webhook was never called")
+        .unwrap();
+    log::info!("Webhook received: {}", request);
+    assert!(request.starts_with("POST /hooks/health HTTP/1.1"));
+    assert!(request.contains(&format!("\"upstream\":\"{}\"", error_upstream.address())));
+    assert!(request.contains("\"healthy\":false"));
+    sleep(Duration::from_millis(500)).await;
+    let events = std::fs::read_to_string(&event_file).expect("Event command was never run");
+    assert_eq!(events, format!("{} false\n", error_upstream.address()));
+
+    std::fs::remove_file(&event_file).unwrap();
+    drop(balancer);
+    Box::new(error_upstream).stop().await;
+    Box::new(echo_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Failover should try every upstream before giving up, and only then answer with a 502.
+#[tokio::test]
+async fn test_failover_exhausts_upstreams() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(3).await;
+    let addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancer = LoadBalancer::new_with_args(&addresses, &[]).await;
+
+    log::info!("Stopping all but one upstream");
+    let survivor = upstreams.remove(0);
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    for i in 0..4 {
+        let path = format!("/failover-{}", i);
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Stopping the last upstream");
+    survivor.stop().await;
+    let response = reqwest::get(format!("http://{}/nowhere", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 502);
+    log::info!("All done :)");
+}
+
+/// With --max-retries 0, a request whose upstream refuses the connection should get a 502 instead
+/// of failing over, and the denied retry should show up in the metrics.
+#[tokio::test]
+async fn test_max_retries() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &[
+            "--strategy",
+            "round_robin",
+            "--max-retries",
+            "0",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Stopping one of the upstreams");
+    upstreams.pop().unwrap().stop().await;
+    let mut bad_gateways = 0;
+    for i in 0..4 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().as_u16() == 502 {
+            bad_gateways += 1;
+        }
+    }
+    assert_eq!(bad_gateways, 1);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("loadbalancer_retries_total 0"));
+    assert!(metrics.contains("loadbalancer_retries_denied_total{reason=\"max_retries\"} 1"));
+
+    upstreams.pop().unwrap().stop().await;
+    log::info!("All done :)");
+}
+
+/// With --hedge-delay-ms, an idempotent request stuck on a slow upstream should also be sent to
+/// another upstream, which answers first. Non-idempotent requests shouldn't be hedged.
+#[tokio::test]
+async fn test_hedged_requests() {
+    init_logging();
+    let slow_upstream = EchoServer::new_with_delay(Duration::from_secs(2)).await;
+    let fast_upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&slow_upstream.address, &fast_upstream.address],
+        &["--strategy", "round_robin", "--hedge-delay-ms", "200"],
+    )
+    .await;
+
+    for i in 0..4 {
+        let path = format!("/hedged-{}", i);
+        let start = std::time::Instant::now();
+        let response_text = balancer
+            .get(&path)
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "Request {} waited for the slow upstream",
+            i
+        );
+    }
+
+    log::info!("Sending POSTs, which must not be hedged");
+    let start = std::time::Instant::now();
+    for i in 0..2 {
+        balancer
+            .post(&format!("/not-hedged-{}", i), "body")
+            .await
+            .expect("Error sending request to loadbalancer");
+    }
+    assert!(start.elapsed() >= Duration::from_secs(2));
+
+    // Each upstream got half of the requests, and the fast one also got the two hedges
+    assert_eq!(Box::new(slow_upstream).stop().await, 3);
+    assert_eq!(Box::new(fast_upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// With --retry-on 5xx, an idempotent request that gets a 500 should be retried on another
+/// upstream. A POST may already have been acted on, so it should get the 500 instead.
+#[tokio::test]
+async fn test_retry_on_server_error() {
+    init_logging();
+    let error_upstream = ErrorServer::new().await;
+    let echo_upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&error_upstream.address, &echo_upstream.address],
+        &["--strategy", "round_robin", "--retry-on", "5xx"],
+    )
+    .await;
+
+    for i in 0..4 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let mut server_errors = 0;
+    for i in 0..2 {
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/post-{}", balancer.address, i))
+            .body("hello")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        if response.status().as_u16() == 500 {
+            server_errors += 1;
+        }
+    }
+    assert_eq!(server_errors, 1);
+
+    assert_eq!(Box::new(echo_upstream).stop().await, 5);
+    Box::new(error_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// An attempt whose response doesn't start within --upstream-try-timeout-ms should be retried on
+/// another upstream (with --retry-on timeout), but not past --request-timeout-ms: the client gets
+/// a 504 once the request as a whole is out of time.
+#[tokio::test]
+async fn test_try_and_request_timeouts() {
+    let (upstreams, upstream_addresses) = start_upstreams(2).await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &[
+            "--strategy",
+            "round_robin",
+            "--upstream-try-timeout-ms",
+            "500",
+            "--request-timeout-ms",
+            "800",
+            "--retry-on",
+            "timeout",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request that both upstreams are too slow to answer");
+    let start = std::time::Instant::now();
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/slow", balancer.address))
+        .header("x-echo-delay-ms", "2000")
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    let elapsed = start.elapsed();
+    assert_eq!(response.status().as_u16(), 504);
+    assert!(elapsed >= Duration::from_millis(700), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1300), "{:?}", elapsed);
+
+    let response = reqwest::get(format!("http://{}/fast", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let mut requests_received = 0;
+    for upstream in upstreams {
+        requests_received += upstream.stop().await;
+    }
+    assert_eq!(requests_received, 3);
+    log::info!("All done :)");
+}
+
+/// An upstream that answers 503 with Retry-After should be taken out of rotation for that long
+/// (capped at --max-retry-after), and the request it turned away retried elsewhere, POSTs too.
+#[tokio::test]
+async fn test_retry_after_ejection() {
+    init_logging();
+    let overloaded_upstream = ErrorServer::new_overloaded(30).await;
+    let echo_upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&overloaded_upstream.address, &echo_upstream.address],
+        &["--strategy", "round_robin", "--max-retry-after", "1"],
+    )
+    .await;
+
+    let send_requests = |count: usize| {
+        let address = balancer.address.clone();
+        async move {
+            for i in 0..count {
+                let response = reqwest::Client::new()
+                    .post(format!("http://{}/request-{}", address, i))
+                    .body("hello")
+                    .send()
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                assert_eq!(response.status().as_u16(), 200);
+            }
+        }
+    };
+    send_requests(6).await;
+    log::info!("Waiting for the capped ejection to run out");
+    sleep(Duration::from_millis(1500)).await;
+    send_requests(2).await;
+
+    assert_eq!(Box::new(overloaded_upstream).stop().await, 2);
+    assert_eq!(Box::new(echo_upstream).stop().await, 8);
+    log::info!("All done :)");
+}
+
+/// Requests should go to the pool their Host is routed to, with exact hosts winning over
+/// wildcards, and to the default pool if no route matches.
+#[tokio::test]
+async fn test_host_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(3).await;
+    let api = format!("{},pool=api", upstream_addresses[0]);
+    let www = format!("{},pool=www", upstream_addresses[1]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&api, &www, &upstream_addresses[2]],
+        &[
+            "--host-route",
+            "*.example.com=www",
+            "--host-route",
+            "api.example.com=api",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for host in [
+        "api.example.com",
+        "API.example.com:8080",
+        "api.example.com.",
+        "www.example.com:8080",
+        "shop.example.com",
+        "example.com",
+        "other.test",
+    ] {
+        let response_text = client
+            .get(format!("http://{}/", balancer.address))
+            .header("host", host)
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            response_text.contains(&format!("host: {}\n", host)),
+            "{}",
+            response_text
+        );
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![3, 2, 2]);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_path_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(3).await;
+    let api = format!("{},pool=api", upstream_addresses[0]);
+    let admin = format!("{},pool=admin", upstream_addresses[1]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&api, &admin, &upstream_addresses[2]],
+        &[
+            "--path-route",
+            "/api=api,strip_prefix",
+            "--path-route",
+            "/api/admin/*=admin",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (path, forwarded_path) in [
+        ("/api/users?page=2", "/users?page=2"),
+        ("/api/", "/"),
+        ("/api/admin/users", "/api/admin/users"),
+        ("/apis", "/apis"),
+        ("/apiary", "/apiary"),
+        ("/static/app.js", "/static/app.js"),
+    ] {
+        let response_text = client
+            .get(format!("http://{}{}", balancer.address, path))
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            response_text.starts_with(&format!("GET {} HTTP/1.1\n", forwarded_path)),
+            "{}",
+            response_text
+        );
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![2, 1, 3]);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_regex_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(3).await;
+    let users = format!("{},pool=users", upstream_addresses[0]);
+    let api = format!("{},pool=api", upstream_addresses[1]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&users, &api, &upstream_addresses[2]],
+        &[
+            "--regex-route",
+            "users=^/v(?P<version>[0-9]+)/users(.*)$ /users$2?version=${version}",
+            "--regex-route",
+            "api=^/v[0-9]+/",
+            "--path-route",
+            "/v1/=default",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (path, forwarded_path) in [
+        ("/v2/users/7?fields=name", "/users/7?version=2&fields=name"),
+        ("/v10/users", "/users?version=10"),
+        ("/v1/orders", "/v1/orders"),
+        ("/vx/users", "/vx/users"),
+    ] {
+        let response_text = client
+            .get(format!("http://{}{}", balancer.address, path))
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            response_text.starts_with(&format!("GET {} HTTP/1.1\n", forwarded_path)),
+            "{}",
+            response_text
+        );
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![2, 1, 1]);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_match_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(3).await;
+    let acme = format!("{},pool=acme", upstream_addresses[0]);
+    let mobile = format!("{},pool=mobile", upstream_addresses[1]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&acme, &mobile, &upstream_addresses[2]],
+        &[
+            "--match-route",
+            "x-tenant=acme->acme",
+            "--match-route",
+            "user-agent~Mobile && path^=/app/ && host=shop.example.com->mobile",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (path, headers) in [
+        ("/", vec![("x-tenant", "acme")]),
+        (
+            "/app/cart",
+            vec![("x-tenant", "acme"), ("user-agent", "Mobile Safari")],
+        ),
+        (
+            "/app/cart",
+            vec![
+                ("host", "shop.example.com"),
+                ("user-agent", "Mobile Safari"),
+            ],
+        ),
+        ("/", vec![("x-tenant", "acme-corp")]),
+        (
+            "/app/cart",
+            vec![("host", "shop.example.com"), ("user-agent", "Desktop")],
+        ),
+        (
+            "/cart",
+            vec![("host", "shop.example.com"), ("user-agent", "Mobile")],
+        ),
+    ] {
+        let mut request = client.get(format!("http://{}{}", balancer.address, path));
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status(), 200);
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![2, 1, 3]);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_method_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(2).await;
+    let replica = format!("{},pool=replicas", upstream_addresses[1]);
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_addresses[0], &replica],
+        &["--match-route", "method~^(GET|HEAD)$->replicas"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for method in ["GET", "HEAD", "GET", "POST", "PUT", "DELETE", "PATCH"] {
+        let response = client
+            .request(
+                method.parse().unwrap(),
+                format!("http://{}/", balancer.address),
+            )
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status(), 200, "{}", method);
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![4, 3]);
+    log::info!("All done :)");
+}
+
+#[tokio::test]
+async fn test_time_routing() {
+    let (upstreams, upstream_addresses) = start_upstreams(2).await;
+    let nightly = format!("{},pool=nightly", upstream_addresses[1]);
+
+    // Windows in a +05:30 timezone, one from a minute ago to two minutes from now, and one that
+    // covers the rest of the day but not now
+    let now_secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 5 * 3600
+        + 30 * 60;
+    let minute_of_day = (now_secs % 86400) / 60;
+    let format_minute = |minute: u64| {
+        let minute = minute % 1440;
+        format!("{:02}:{:02}", minute / 60, minute % 60)
+    };
+    let inside = format!(
+        "time={}-{}->nightly",
+        format_minute(minute_of_day + 1439),
+        format_minute(minute_of_day + 2)
+    );
+    let outside = format!(
+        "time={}-{}->nightly",
+        format_minute(minute_of_day + 3),
+        format_minute(minute_of_day + 1438)
+    );
+
+    let client = reqwest::Client::new();
+    for (route, served_by) in [
+        (&inside, &upstream_addresses[1]),
+        (&outside, &upstream_addresses[0]),
+    ] {
+        let balancer = LoadBalancer::new_with_args(
+            &[&upstream_addresses[0], &nightly],
+            &[
+                "--match-route",
+                route,
+                "--route-timezone",
+                "+05:30",
+                "--debug-headers",
+            ],
+        )
+        .await;
+        for _ in 0..3 {
+            let response = client
+                .get(format!("http://{}/report", balancer.address))
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer");
+            assert_eq!(response.status(), 200);
+            assert_eq!(
+                response.headers()["x-lb-upstream"],
+                served_by.as_str(),
+                "{}",
+                route
+            );
+        }
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![3, 3]);
+    log::info!("All done :)");
+}
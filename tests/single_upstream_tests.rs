@@ -1,7 +1,8 @@
 mod common;
 
-use common::{init_logging, EchoServer, LoadBalancer, Server};
+use common::{init_logging, ConnectProxy, EchoServer, LoadBalancer, RedisServer, Server};
 use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 async fn setup() -> (LoadBalancer, EchoServer) {
     init_logging();
@@ -69,7 +70,7 @@ async fn test_multiple_requests_per_connection() {
                 );
                 let path = format!("/conn-{}/req-{}", task_num, req_num);
                 let response_text = client
-                    .get(&format!("http://{}{}", balancer_shared.address, path))
+                    .get(format!("http://{}{}", balancer_shared.address, path))
                     .header("x-sent-by", "loadbalancer-tests")
                     .send()
                     .await
@@ -99,3 +100,2802 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure the startup self-check writes a readiness report that reflects a healthy start.
+#[tokio::test]
+async fn test_readiness_report() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let report_path = std::env::temp_dir().join(format!(
+        "loadbalancer-readiness-{}.json",
+        rand::random::<u32>()
+    ));
+    let _balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--readiness-report", report_path.to_str().unwrap()],
+    )
+    .await;
+
+    log::info!("Checking the readiness report");
+    let report = std::fs::read_to_string(&report_path).expect("Readiness report was not written");
+    let _ = std::fs::remove_file(&report_path);
+    assert!(report.contains("\"ready\": true"));
+    assert!(report.contains("\"bind\""));
+    assert!(report.contains(&format!("\"resolve {}\"", upstream.address)));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a route concurrency limit rejects requests beyond the limit while leaving other
+/// routes unaffected.
+#[tokio::test]
+async fn test_route_concurrency_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&upstream.address],
+            &["--route-concurrency-limit", "/slow=1"],
+        )
+        .await,
+    );
+
+    log::info!("Sending a slow request that occupies the only slot for /slow");
+    let slow_balancer = balancer.clone();
+    let slow_request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/slow/first", slow_balancer.address))
+            .header("x-echo-delay-ms", "1500")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .status()
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    log::info!("Sending a second request to /slow, which should be rejected");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/slow/second", balancer.address))
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 503);
+
+    log::info!("Sending a request to another route, which should succeed");
+    let response_text = balancer
+        .get("/fast")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /fast HTTP/1.1"));
+    let response_text = balancer
+        .get("/slowly")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /slowly HTTP/1.1"));
+
+    assert_eq!(slow_request.await.expect("Task panicked").as_u16(), 200);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// When a limited route is saturated, a waiting high priority request should get the next free
+/// slot ahead of a normal one that queued earlier, and a low priority request should be shed
+/// right away instead of queueing.
+#[tokio::test]
+async fn test_priority_classes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&upstream.address],
+            &[
+                "--route-concurrency-limit",
+                "/slow=1",
+                "--route-queue-timeout-ms",
+                "5000",
+                "--priority-rule",
+                "header:x-class:interactive=high",
+                "--priority-rule",
+                "header:x-class:batch=low",
+            ],
+        )
+        .await,
+    );
+    let send = |class: &'static str, delay_ms: &'static str| {
+        let balancer = balancer.clone();
+        tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .get(format!("http://{}/slow/{}", balancer.address, class))
+                .header("x-class", class)
+                .header("x-echo-delay-ms", delay_ms)
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer");
+            (response.status().as_u16(), std::time::Instant::now())
+        })
+    };
+
+    log::info!("Occupying the only slot, then queueing a normal and a high priority request");
+    let first = send("first", "2000");
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let normal = send("normal", "500");
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let high = send("interactive", "500");
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    log::info!("Sending a low priority request, which should be shed");
+    let (status, _) = send("batch", "0").await.expect("Task panicked");
+    assert_eq!(status, 503);
+
+    let (first_status, _) = first.await.expect("Task panicked");
+    let (normal_status, normal_done) = normal.await.expect("Task panicked");
+    let (high_status, high_done) = high.await.expect("Task panicked");
+    assert_eq!((first_status, normal_status, high_status), (200, 200, 200));
+    assert!(
+        high_done < normal_done,
+        "High priority request was served after the normal one that queued before it"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams given by hostname are resolved and connected to.
+#[tokio::test]
+async fn test_hostname_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port = upstream.address.rsplit_once(':').unwrap().1;
+    let balancer = LoadBalancer::new(&[&format!("localhost:{}", port)], None, None).await;
+
+    let response_text = balancer
+        .get("/by-hostname")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /by-hostname HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure requests with repeated Host headers are rejected rather than forwarded ambiguously.
+#[tokio::test]
+async fn test_duplicate_host_rejected() {
+    let (balancer, upstream) = setup().await;
+
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: a.example\r\nHost: b.example\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let bytes_read = conn.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 400"));
+    // Hang up so the balancer drops its upstream connection and the upstream can shut down
+    drop(conn);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+    log::info!("All done :)");
+}
+
+/// Requests whose framing another parser could read differently should be rejected, even when the
+/// duplicate header policy would otherwise pick one of the values, and never reach the upstream.
+#[tokio::test]
+async fn test_request_smuggling_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--duplicate-header-policy", "first_wins"],
+    )
+    .await;
+
+    // Sends a raw request on a new connection, returning whatever came back before it closed
+    let send_request = |request: &'static [u8]| {
+        let address = balancer.address.clone();
+        async move {
+            let mut conn = tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to loadbalancer");
+            conn.write_all(request).await.unwrap();
+            let mut response = Vec::new();
+            let _ = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                conn.read_to_end(&mut response),
+            )
+            .await
+            .expect("The connection was left open");
+            String::from_utf8_lossy(&response).to_string()
+        }
+    };
+    log::info!("Sending conflicting Content-Length headers");
+    let response = send_request(
+        b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nContent-Length: 10\r\n\r\n\
+        helloGET / HTTP/1.1\r\nHost: test\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(response.matches("HTTP/1.1").count(), 1, "{}", response);
+    log::info!("Sending a conflicting Content-Length list");
+    let response =
+        send_request(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 5, 10\r\n\r\nhello").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    log::info!("Sending a folded header line");
+    let response = send_request(
+        b"GET / HTTP/1.1\r\nHost: test\r\nX-Folded: a\r\n Transfer-Encoding: chunked\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    let response =
+        send_request(b"GET / HTTP/1.1\r\n\tTransfer-Encoding: chunked\r\nHost: test\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("Sending a list of identical Content-Length values");
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 5, 5\r\n\r\nhello")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while !response.ends_with(b"hello") {
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("No response to the request")
+                .unwrap();
+        assert!(bytes_read > 0, "Connection closed");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+    assert!(response.contains("content-length: 5\n"), "{}", response);
+    log::info!("All done :)");
+}
+
+/// Hop-by-hop headers, and the headers a Connection header names, should be dropped in both
+/// directions, and a client's Connection: close should only close its own connection.
+#[tokio::test]
+async fn test_hop_by_hop_headers() {
+    init_logging();
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    let (requests_tx, mut requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut num_connections = 0;
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            num_connections += 1;
+            let connection_id = num_connections;
+            let requests_tx = requests_tx.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                loop {
+                    if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n")
+                    {
+                        let head = String::from_utf8_lossy(&received[..end + 4]).to_string();
+                        received.drain(..end + 4);
+                        if head.starts_with("GET /hop") {
+                            requests_tx.send((connection_id, head)).unwrap();
+                        }
+                        let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\
+                            Connection: X-Internal\r\nKeep-Alive: timeout=5\r\n\
+                            X-Internal: 1\r\nX-Public: 1\r\n\r\nok";
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                        continue;
+                    }
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => received.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+            });
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(&[&upstream_address], &[]).await;
+
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    log::info!("Sending a request with hop-by-hop headers");
+    conn.write_all(
+        b"GET /hop HTTP/1.1\r\nHost: test\r\nConnection: keep-alive, X-Secret\r\n\
+        Keep-Alive: timeout=5\r\nProxy-Connection: keep-alive\r\nTE: trailers, deflate\r\n\
+        Upgrade: h2c\r\nX-Secret: s\r\nX-Kept: k\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while !response.ends_with(b"ok") {
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("No response to the request")
+                .unwrap();
+        assert!(bytes_read > 0, "Connection closed");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+    assert!(response.contains("x-public: 1\r\n"), "{}", response);
+    for name in ["connection:", "keep-alive:", "x-internal:"] {
+        assert!(!response.contains(name), "{}", response);
+    }
+    let (first_connection, request) = requests_rx.recv().await.unwrap();
+    let request = request.to_lowercase();
+    assert!(request.contains("x-kept: k\r\n"), "{}", request);
+    assert!(request.contains("te: trailers\r\n"), "{}", request);
+    for name in [
+        "connection:",
+        "keep-alive:",
+        "proxy-connection:",
+        "upgrade:",
+        "x-secret:",
+    ] {
+        assert!(!request.contains(name), "{}", request);
+    }
+
+    log::info!("Asking to close the connection");
+    conn.write_all(b"GET /hop HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        conn.read_to_end(&mut response),
+    )
+    .await
+    .expect("The connection was left open")
+    .unwrap();
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.contains("connection: close\r\n"), "{}", response);
+    assert!(response.ends_with("ok"), "{}", response);
+    // The upstream wasn't asked to close, so its connection was kept for the next request
+    let (second_connection, request) = requests_rx.recv().await.unwrap();
+    assert!(
+        !request.to_lowercase().contains("connection:"),
+        "{}",
+        request
+    );
+    assert_eq!(first_connection, second_connection);
+    log::info!("All done :)");
+}
+
+/// Forwarded requests and responses should say they passed through us in a Via header, and a
+/// request that already has been should be turned away rather than go round in a loop.
+#[tokio::test]
+async fn test_via_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/via", balancer.address))
+        .header("via", "1.1 edge")
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["via"], "1.1 loadbalancer");
+    let body = response.text().await.unwrap();
+    assert!(
+        body.contains("via: 1.1 edge, 1.1 loadbalancer\n"),
+        "{}",
+        body
+    );
+
+    log::info!("Sending a request that has been through us before");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/via", balancer.address))
+        .header("via", "1.1 edge, 1.1 loadbalancer (proxy)")
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status(), 508);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("Going by another name");
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &["--via-name", "lb-a"]).await;
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/via", balancer.address))
+        .header("via", "1.1 loadbalancer")
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["via"], "1.1 lb-a");
+    let body = response.text().await.unwrap();
+    assert!(
+        body.contains("via: 1.1 loadbalancer, 1.1 lb-a\n"),
+        "{}",
+        body
+    );
+    log::info!("All done :)");
+}
+
+/// The upstream should be told about the client in Forwarded and X-Forwarded-* headers, which only
+/// extend what the client sent if it is a trusted proxy.
+#[tokio::test]
+async fn test_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Sends a request claiming to have been forwarded from elsewhere, returning the echoed request
+    let send_request = |address: String| async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/forwarded", address))
+            .header("x-forwarded-for", "1.2.3.4")
+            .header("forwarded", "for=1.2.3.4;proto=https")
+            .header("x-forwarded-proto", "https")
+            .header("x-forwarded-host", "example.com")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap()
+    };
+
+    log::info!("Sending forwarding headers from an untrusted client");
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+    let text = send_request(balancer.address.clone()).await;
+    let expected = [
+        "x-forwarded-for: 127.0.0.1\n".to_string(),
+        format!(
+            "forwarded: for=127.0.0.1;proto=http;host=\"{}\"\n",
+            balancer.address
+        ),
+        "x-forwarded-proto: http\n".to_string(),
+        format!("x-forwarded-host: {}\n", balancer.address),
+    ];
+    for header in expected {
+        assert!(text.contains(&header), "{}", text);
+    }
+
+    log::info!("Sending forwarding headers from a trusted proxy");
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--trusted-proxies", "10.0.0.0/8,127.0.0.0/8"],
+    )
+    .await;
+    let text = send_request(balancer.address.clone()).await;
+    let expected = [
+        "x-forwarded-for: 1.2.3.4, 127.0.0.1\n".to_string(),
+        format!(
+            "forwarded: for=1.2.3.4;proto=https, for=127.0.0.1;proto=http;host=\"{}\"\n",
+            balancer.address
+        ),
+        "x-forwarded-proto: https\n".to_string(),
+        "x-forwarded-host: example.com\n".to_string(),
+    ];
+    for header in expected {
+        assert!(text.contains(&header), "{}", text);
+    }
+    log::info!("All done :)");
+}
+
+/// Requests whose method isn't on the allowed list should be answered with 405 and never reach the
+/// upstream.
+#[tokio::test]
+async fn test_allowed_methods() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--allowed-methods", "GET,HEAD", "--allowed-methods", "POST"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for method in ["GET", "HEAD", "POST"] {
+        let response = client
+            .request(
+                method.parse().unwrap(),
+                format!("http://{}/", balancer.address),
+            )
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status(), 200, "{}", method);
+    }
+    for method in ["TRACE", "DELETE", "BREW", "get"] {
+        let response = client
+            .request(
+                method.parse().unwrap(),
+                format!("http://{}/", balancer.address),
+            )
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status(), 405, "{}", method);
+        assert_eq!(response.headers()["allow"], "GET, HEAD, POST");
+    }
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+    log::info!("All done :)");
+}
+
+/// Rewrite rules should change the path and Host header the upstream sees, in the order they were
+/// given, while the forwarding headers keep the host the client asked for.
+#[tokio::test]
+async fn test_rewrite_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--rewrite",
+            "prefix /api/v1 /v1",
+            "--rewrite",
+            "prefix /internal",
+            "--rewrite",
+            "regex ^/v1/users/([0-9]+)$ /v1/user?id=$1",
+            "--rewrite",
+            "prefix /old /new pool=default",
+            "--rewrite",
+            "prefix /docs/ /v2/",
+            "--rewrite",
+            "prefix /static/ /assets",
+            "--rewrite",
+            "host backend.internal",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (path, forwarded_path) in [
+        ("/api/v1/orders?page=2", "/v1/orders?page=2"),
+        ("/api/v1/users/42", "/v1/user?id=42"),
+        ("/api/v1/users/42?fields=name", "/v1/user?id=42&fields=name"),
+        ("/internal/status", "/status"),
+        ("/internal", "/"),
+        ("/old/page", "/new/page"),
+        ("/api/v2/orders", "/api/v2/orders"),
+        ("/api/v1beta/orders", "/api/v1beta/orders"),
+        ("/internalx", "/internalx"),
+        ("/docs/users", "/v2/users"),
+        ("/docs/", "/v2/"),
+        ("/docs", "/docs"),
+        ("/static/app.js", "/assets/app.js"),
+        ("/static/css/site.css", "/assets/css/site.css"),
+    ] {
+        let response_text = client
+            .get(format!("http://{}{}", balancer.address, path))
+            .header("host", "shop.example.com")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .text()
+            .await
+            .unwrap();
+        assert!(
+            response_text.starts_with(&format!("GET {} HTTP/1.1\n", forwarded_path)),
+            "{}",
+            response_text
+        );
+        assert!(
+            response_text.contains("host: backend.internal\n"),
+            "{}",
+            response_text
+        );
+        assert!(
+            response_text.contains("x-forwarded-host: shop.example.com\n"),
+            "{}",
+            response_text
+        );
+    }
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure debug headers describing the balancing decision are added for trusted clients.
+#[tokio::test]
+async fn test_debug_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--strategy",
+            "round_robin",
+            "--debug-headers-cidr",
+            "127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/debug", balancer.address))
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    let headers = response.headers();
+    assert_eq!(headers["x-lb-upstream"], upstream.address.as_str());
+    assert_eq!(headers["x-lb-strategy"], "round_robin");
+    assert_eq!(headers["x-lb-attempts"], "1");
+    assert!(headers["x-lb-timing"]
+        .to_str()
+        .unwrap()
+        .contains("upstream="));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure upstream connections are tunnelled through a configured egress proxy, with the
+/// configured credentials.
+#[tokio::test]
+async fn test_http_connect_egress_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let proxy = ConnectProxy::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--egress-proxy",
+            &format!("http://user:secret@{}", proxy.address),
+        ],
+    )
+    .await;
+
+    let response_text = balancer
+        .get("/via-proxy")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /via-proxy HTTP/1.1"));
+    assert_eq!(
+        proxy.tunnels(),
+        vec![(
+            upstream.address.clone(),
+            Some("Basic dXNlcjpzZWNyZXQ=".to_string())
+        )]
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure requests for a route in a scheduled maintenance window get a 503 with Retry-After,
+/// while other routes are still forwarded.
+#[tokio::test]
+async fn test_maintenance_window() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    // Build a window from one minute ago to two minutes from now, in a +05:30 timezone
+    let now_secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 5 * 3600
+        + 30 * 60;
+    let minute_of_day = (now_secs % 86400) / 60;
+    let format_minute = |minute: u64| {
+        let minute = minute % 1440;
+        format!("{:02}:{:02}", minute / 60, minute % 60)
+    };
+    let window = format!(
+        "/maint={}-{}",
+        format_minute(minute_of_day + 1439),
+        format_minute(minute_of_day + 2)
+    );
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--maintenance-window",
+            &window,
+            "--maintenance-timezone",
+            "+05:30",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/maint/page", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 503);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 120);
+
+    let response_text = balancer
+        .get("/other")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /other HTTP/1.1"));
+    let response_text = balancer
+        .get("/maintainers")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /maintainers HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure configured headers are injected into forwarded requests for their route, and that
+/// file-backed values are picked up again when the file changes.
+#[tokio::test]
+async fn test_inject_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let token_path =
+        std::env::temp_dir().join(format!("loadbalancer-token-{}.txt", rand::random::<u32>()));
+    std::fs::write(&token_path, "token-v1\n").unwrap();
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--inject-header",
+            "x-api-version:2024-01-01",
+            "--inject-header",
+            &format!("/api=x-internal-token:file:{}", token_path.display()),
+        ],
+    )
+    .await;
+
+    let response_text = balancer
+        .get("/api/users")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("x-api-version: 2024-01-01"));
+    assert!(response_text.contains("x-internal-token: token-v1"));
+
+    let response_text = balancer
+        .get("/static/logo.png")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("x-api-version: 2024-01-01"));
+    assert!(!response_text.contains("x-internal-token"));
+    let response_text = balancer
+        .get("/apiary")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(!response_text.contains("x-internal-token"));
+
+    log::info!("Rotating the token");
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    std::fs::write(&token_path, "token-v2\n").unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let response_text = balancer
+        .get("/api/users")
+        .await
+        .expect("Error sending request to loadbalancer");
+    let _ = std::fs::remove_file(&token_path);
+    assert!(response_text.contains("x-internal-token: token-v2"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a POST that repeats an Idempotency-Key is answered with the original response
+/// instead of being forwarded again.
+#[tokio::test]
+async fn test_idempotency_key_replay() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer =
+        LoadBalancer::new_with_args(&[&upstream.address], &["--idempotency-keys", "replay"]).await;
+
+    let post = |key: &'static str, body: &'static str| {
+        reqwest::Client::new()
+            .post(format!("http://{}/orders", balancer.address))
+            .header("idempotency-key", key)
+            .body(body)
+            .send()
+    };
+    let original = post("order-1", "first").await.unwrap();
+    assert!(original.headers().get("idempotent-replayed").is_none());
+    let original_text = original.text().await.unwrap();
+    assert!(original_text.contains("\n\nfirst"));
+
+    log::info!("Retrying the same order");
+    let retry = post("order-1", "second").await.unwrap();
+    assert_eq!(retry.status().as_u16(), 200);
+    assert_eq!(retry.headers()["idempotent-replayed"], "true");
+    assert_eq!(retry.text().await.unwrap(), original_text);
+
+    log::info!("Sending a different order, and a GET with the same key");
+    let other = post("order-2", "third")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(other.contains("\n\nthird"));
+    let response_text = reqwest::Client::new()
+        .get(format!("http://{}/orders", balancer.address))
+        .header("idempotency-key", "order-1")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(response_text.contains("GET /orders HTTP/1.1"));
+
+    log::info!("Sending the same key with another client's credentials");
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/orders", balancer.address))
+        .header("idempotency-key", "order-1")
+        .header("authorization", "Bearer someone-else")
+        .body("fourth")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    assert!(response.text().await.unwrap().contains("\n\nfourth"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// Make sure --server-timing breaks each request's time down by phase, and attributes a slow
+/// upstream's delay to its time to first byte.
+#[tokio::test]
+async fn test_server_timing() {
+    init_logging();
+    let upstream = EchoServer::new_with_delay(std::time::Duration::from_millis(300)).await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &["--server-timing"]).await;
+
+    let response = reqwest::get(format!("http://{}/timed", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    let server_timing = response.headers()["server-timing"].to_str().unwrap();
+    log::info!("Server-Timing: {}", server_timing);
+    let phases: Vec<(&str, f64)> = server_timing
+        .split(", ")
+        .map(|phase| {
+            let (name, duration) = phase.split_once(";dur=").unwrap();
+            (name, duration.parse().unwrap())
+        })
+        .collect();
+    let names: Vec<&str> = phases.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "client_read",
+            "connect",
+            "upstream_write",
+            "ttfb",
+            "transfer"
+        ]
+    );
+    let ttfb = phases.iter().find(|(name, _)| *name == "ttfb").unwrap().1;
+    assert!(
+        ttfb >= 300.0,
+        "ttfb of {}ms doesn't include the delay",
+        ttfb
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Matching exchanges should be written to the capture directory as replayable raw HTTP, with
+/// credentials redacted.
+#[tokio::test]
+async fn test_exchange_capture() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let capture_dir =
+        std::env::temp_dir().join(format!("loadbalancer-capture-{}", rand::random::<u32>()));
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--capture-dir",
+            capture_dir.to_str().unwrap(),
+            "--capture-path-prefix",
+            "/captured",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for path in ["/captured/order", "/ignored", "/capturedx"] {
+        let response = client
+            .post(format!("http://{}{}", balancer.address, path))
+            .header("authorization", "Bearer very-secret")
+            .body("order=42")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let mut files: Vec<String> = std::fs::read_dir(&capture_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path().display().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files.len(), 3, "Unexpected capture files: {:?}", files);
+    let read = |extension: &str| {
+        let path = files.iter().find(|file| file.ends_with(extension)).unwrap();
+        std::fs::read_to_string(path).unwrap()
+    };
+    let request = read(".request");
+    assert!(request.starts_with("POST /captured/order HTTP/1.1\r\n"));
+    assert!(request.contains("authorization: [redacted]\r\n"));
+    assert!(!request.contains("very-secret"));
+    assert!(request.ends_with("\r\n\r\norder=42"));
+    assert!(read(".response").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(read(".json").contains("\"upstream\""));
+
+    std::fs::remove_dir_all(&capture_dir).unwrap();
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// The global response header policy should strip, set and default headers on proxied responses.
+#[tokio::test]
+async fn test_response_header_policy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--response-header-remove",
+            "date",
+            "--response-header-set",
+            "X-Served-By: lb-test-1",
+            "--response-header-default",
+            "Cache-Control: no-store",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/policy", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response.headers().contains_key("date"));
+    assert_eq!(response.headers()["x-served-by"], "lb-test-1");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("GET /policy HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --fallback-response-file, a request that can't reach the upstream should get the file,
+/// with the configured status, instead of a bare 502.
+#[tokio::test]
+async fn test_fallback_response() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let page = "<html><body>We'll be right back</body></html>";
+    let page_path = std::env::temp_dir().join(format!(
+        "loadbalancer-fallback-{}.html",
+        rand::random::<u32>()
+    ));
+    std::fs::write(&page_path, page).unwrap();
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--fallback-response-file",
+            page_path.to_str().unwrap(),
+            "--fallback-response-status",
+            "503",
+        ],
+    )
+    .await;
+
+    let response_text = balancer
+        .get("/up")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /up HTTP/1.1"));
+
+    log::info!("Stopping the upstream");
+    Box::new(upstream).stop().await;
+    let response = reqwest::get(format!("http://{}/down", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(response.text().await.unwrap(), page);
+
+    std::fs::remove_file(&page_path).unwrap();
+    log::info!("All done :)");
+}
+
+/// With --rate-limit-rps and --rate-limit-burst, a client should be able to send a burst of
+/// requests at once, then be held to the sustained rate.
+#[tokio::test]
+async fn test_token_bucket_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--rate-limit-rps", "0.5", "--rate-limit-burst", "3"],
+    )
+    .await;
+
+    let send_requests = |count: usize| {
+        let address = balancer.address.clone();
+        async move {
+            let mut statuses = Vec::new();
+            for i in 0..count {
+                let response = reqwest::get(format!("http://{}/request-{}", address, i))
+                    .await
+                    .expect("Error sending request to loadbalancer");
+                statuses.push(response.status().as_u16());
+            }
+            statuses
+        }
+    };
+    assert_eq!(send_requests(5).await, [200, 200, 200, 429, 429]);
+    log::info!("Waiting for a token to be added back");
+    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+    assert_eq!(send_requests(2).await, [200, 429]);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// A --route-rate-limit should only apply to requests for its route and method.
+#[tokio::test]
+async fn test_route_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--route-rate-limit", "POST:/login=0.5/2"],
+    )
+    .await;
+
+    let send = |method: reqwest::Method, path: &'static str| {
+        let address = balancer.address.clone();
+        async move {
+            reqwest::Client::new()
+                .request(method, format!("http://{}{}", address, path))
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer")
+                .status()
+                .as_u16()
+        }
+    };
+    for expected in [200, 200, 429] {
+        assert_eq!(send(reqwest::Method::POST, "/login").await, expected);
+    }
+    assert_eq!(send(reqwest::Method::GET, "/login").await, 200);
+    assert_eq!(send(reqwest::Method::POST, "/logout").await, 200);
+    assert_eq!(send(reqwest::Method::POST, "/login-help").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// With --max-concurrent-requests, a request that arrives while the balancer is at capacity should
+/// be shed with a 503 instead of waiting, and the in-flight gauge should show what is in flight.
+#[tokio::test]
+async fn test_load_shedding() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&upstream.address],
+            &[
+                "--max-concurrent-requests",
+                "1",
+                "--admin-bind",
+                &admin_address,
+            ],
+        )
+        .await,
+    );
+
+    log::info!("Sending a slow request that takes up the only slot");
+    let slow_balancer = balancer.clone();
+    let slow_request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/slow", slow_balancer.address))
+            .header("x-echo-delay-ms", "1500")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .status()
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let response = reqwest::get(format!("http://{}/shed", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 503);
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("loadbalancer_in_flight_requests 1\n"));
+    assert!(metrics.contains("loadbalancer_terminated_exchanges_total{reason=\"load_shed\"} 1\n"));
+
+    assert_eq!(slow_request.await.expect("Task panicked").as_u16(), 200);
+    let response_text = balancer
+        .get("/after")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /after HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --max-connections-per-ip, a client that already holds that many connections should have
+/// any more closed straight away, until one of its connections closes.
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer =
+        LoadBalancer::new_with_args(&[&upstream.address], &["--max-connections-per-ip", "2"]).await;
+
+    // Sends a request on a new connection, returning the connection and whatever came back
+    let send_request = |path: &'static str| {
+        let address = balancer.address.clone();
+        async move {
+            let mut conn = tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to loadbalancer");
+            let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+            // The balancer may already have closed a refused connection
+            let _ = conn.write_all(request.as_bytes()).await;
+            let mut buffer = [0_u8; 4096];
+            let bytes_read = conn.read(&mut buffer).await.unwrap_or(0);
+            (
+                conn,
+                String::from_utf8_lossy(&buffer[..bytes_read]).to_string(),
+            )
+        }
+    };
+    let (first, response) = send_request("/first").await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let (_second, response) = send_request("/second").await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let (_, response) = send_request("/third").await;
+    assert_eq!(response, "");
+
+    log::info!("Closing one of the connections to make room");
+    drop(first);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let (_, response) = send_request("/fourth").await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A request for an upstream at its max_conns limit should wait up to --upstream-queue-timeout-ms
+/// for a connection to free up, then get a 503.
+#[tokio::test]
+async fn test_upstream_connection_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&format!("{},max_conns=1", upstream.address)],
+            &["--upstream-queue-timeout-ms", "300"],
+        )
+        .await,
+    );
+
+    log::info!("Sending a slow request that takes up the upstream's only connection");
+    let slow_balancer = balancer.clone();
+    let slow_request = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(format!("http://{}/slow", slow_balancer.address))
+            .header("x-echo-delay-ms", "1500")
+            .send()
+            .await
+            .expect("Error sending request to loadbalancer")
+            .status()
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+    let start = std::time::Instant::now();
+    let response = reqwest::get(format!("http://{}/queued", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+    assert_eq!(slow_request.await.expect("Task panicked").as_u16(), 200);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let response_text = balancer
+        .get("/after")
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert!(response_text.contains("GET /after HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// With --max-concurrent-requests and priority rules, low priority requests should be shed while
+/// there is still headroom left for high priority ones.
+#[tokio::test]
+async fn test_priority_load_shedding() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&upstream.address],
+            &[
+                "--max-concurrent-requests",
+                "4",
+                "--priority-headroom-percent",
+                "25",
+                "--priority-rule",
+                "header:x-class:interactive=high",
+                "--priority-rule",
+                "header:x-class:batch=low",
+            ],
+        )
+        .await,
+    );
+    let send = |class: &'static str, delay_ms: &'static str| {
+        let balancer = balancer.clone();
+        tokio::spawn(async move {
+            reqwest::Client::new()
+                .get(format!("http://{}/{}", balancer.address, class))
+                .header("x-class", class)
+                .header("x-echo-delay-ms", delay_ms)
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer")
+                .status()
+                .as_u16()
+        })
+    };
+
+    log::info!("Filling the share of the cap that low priority requests may use");
+    let batch = [send("batch", "1500"), send("batch", "1500")];
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    assert_eq!(send("batch", "0").await.expect("Task panicked"), 503);
+    assert_eq!(send("interactive", "0").await.expect("Task panicked"), 200);
+    for request in batch {
+        assert_eq!(request.await.expect("Task panicked"), 200);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A connection to an upstream at its max_conns limit should go to a queued high priority request
+/// ahead of normal ones that queued before it, and to normal ones in the order they queued, while
+/// a low priority request is turned away rather than queued.
+#[tokio::test]
+async fn test_upstream_queue_priority() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = Arc::new(
+        LoadBalancer::new_with_args(
+            &[&format!("{},max_conns=1", upstream.address)],
+            &[
+                "--upstream-queue-timeout-ms",
+                "5000",
+                "--priority-rule",
+                "header:x-class:interactive=high",
+                "--priority-rule",
+                "header:x-class:batch=low",
+            ],
+        )
+        .await,
+    );
+    let send = |class: &'static str, delay_ms: &'static str| {
+        let balancer = balancer.clone();
+        tokio::spawn(async move {
+            let response = reqwest::Client::new()
+                .get(format!("http://{}/{}", balancer.address, class))
+                .header("x-class", class)
+                .header("x-echo-delay-ms", delay_ms)
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer");
+            (response.status().as_u16(), std::time::Instant::now())
+        })
+    };
+
+    log::info!("Taking up the upstream's only connection, then queueing two normal and a high priority request");
+    let first = send("first", "1500");
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let normal = send("normal", "300");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let later_normal = send("normal", "300");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let high = send("interactive", "300");
+
+    log::info!("Sending a low priority request, which shouldn't wait for a connection at all");
+    let batch_start = std::time::Instant::now();
+    let (batch_status, batch_done) = send("batch", "0").await.expect("Task panicked");
+    assert_eq!(batch_status, 503);
+    assert!(batch_done - batch_start < std::time::Duration::from_millis(500));
+
+    let (first_status, _) = first.await.expect("Task panicked");
+    let (normal_status, normal_done) = normal.await.expect("Task panicked");
+    let (later_status, later_done) = later_normal.await.expect("Task panicked");
+    let (high_status, high_done) = high.await.expect("Task panicked");
+    assert_eq!(
+        (first_status, normal_status, later_status, high_status),
+        (200, 200, 200, 200)
+    );
+    assert!(
+        high_done < normal_done,
+        "High priority request got a connection after the normal one that queued before it"
+    );
+    assert!(
+        normal_done < later_done,
+        "Normal priority requests didn't get connections in the order they queued"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// A client that trickles its request head in, or stalls partway through it, should get a 408 and
+/// have its connection closed, without the request reaching the upstream.
+#[tokio::test]
+async fn test_slow_request_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--request-header-timeout-ms",
+            "1000",
+            "--request-idle-timeout-ms",
+            "300",
+        ],
+    )
+    .await;
+
+    // Reads until the balancer closes the connection
+    let read_to_close = |mut conn: tokio::net::TcpStream| async move {
+        let mut response = Vec::new();
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            conn.read_to_end(&mut response),
+        )
+        .await
+        .expect("Loadbalancer did not close the connection");
+        String::from_utf8_lossy(&response).to_string()
+    };
+
+    log::info!("Trickling a request head in under the idle timeout");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    let start = std::time::Instant::now();
+    for byte in b"GET /trickle HTTP/1.1\r\nHost: test\r\n".iter().cycle() {
+        if conn.write_all(&[*byte]).await.is_err() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let response = read_to_close(conn).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+
+    log::info!("Stalling partway through a request head");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    let start = std::time::Instant::now();
+    conn.write_all(b"GET /stall HTTP/1.1\r\nHo").await.unwrap();
+    let response = read_to_close(conn).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+    log::info!("Sending a request promptly");
+    let response = reqwest::get(format!("http://{}/prompt", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Request heads over --max-request-header-bytes, --max-request-header-line-bytes or
+/// --max-request-headers should get a 431 instead of reaching the upstream.
+#[tokio::test]
+async fn test_request_header_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-request-header-bytes",
+            "600",
+            "--max-request-header-line-bytes",
+            "200",
+            "--max-request-headers",
+            "5",
+        ],
+    )
+    .await;
+
+    // Sends a raw request head on a new connection, returning the response
+    let send_request = |request: String| {
+        let address = balancer.address.clone();
+        async move {
+            let mut conn = tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to loadbalancer");
+            conn.write_all(request.as_bytes()).await.unwrap();
+            let mut buffer = [0_u8; 4096];
+            let bytes_read = conn.read(&mut buffer).await.unwrap_or(0);
+            String::from_utf8_lossy(&buffer[..bytes_read]).to_string()
+        }
+    };
+    let header = |name: &str, value_len: usize| format!("{}: {}", name, "x".repeat(value_len));
+    let head = |headers: Vec<String>| format!("GET / HTTP/1.1\r\n{}\r\n\r\n", headers.join("\r\n"));
+
+    log::info!("Sending a header line over the line limit");
+    let response = send_request(head(vec![header("Host", 4), header("X-Long", 250)])).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    log::info!("Sending too many headers");
+    let headers = (0..6)
+        .map(|i| header(&format!("X-Header-{}", i), 4))
+        .collect();
+    let response = send_request(head(headers)).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    // Only send as much as the balancer will read, so that it doesn't reset the connection by
+    // closing it with bytes left unread
+    log::info!("Sending a head over the size limit");
+    let headers = (0..4)
+        .map(|i| header(&format!("X-Header-{}", i), 180))
+        .collect();
+    let response = send_request(head(headers)[..600].to_string()).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+    log::info!("Sending a head within every limit");
+    let response = send_request(head(vec![header("Host", 4), header("X-Header", 150)])).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A request body over --max-request-body-bytes should get a 413 without reaching the upstream,
+/// and an upstream response over --max-response-body-bytes should be answered with a 502.
+#[tokio::test]
+async fn test_body_size_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-request-body-bytes",
+            "5000",
+            "--max-response-body-bytes",
+            "1000",
+        ],
+    )
+    .await;
+    let post = |body_len: usize| {
+        let address = balancer.address.clone();
+        async move {
+            reqwest::Client::new()
+                .post(format!("http://{}/upload", address))
+                .body("x".repeat(body_len))
+                .send()
+                .await
+                .expect("Error sending request to loadbalancer")
+                .status()
+                .as_u16()
+        }
+    };
+
+    log::info!("Sending a small body");
+    assert_eq!(post(10).await, 200);
+    log::info!("Sending a body whose echo is over the response limit");
+    assert_eq!(post(2000).await, 502);
+
+    log::info!("Sending a body over the request limit");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"POST /upload HTTP/1.1\r\nHost: test\r\nContent-Length: 6000\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Clients outside --allow-cidr, or inside --deny-cidr, should have their connections closed
+/// straight away, and be counted in the metrics.
+#[tokio::test]
+async fn test_client_access_lists() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--allow-cidr",
+            "127.0.0.0/8",
+            "--deny-cidr",
+            "127.0.0.2",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Connecting from an allowed address");
+    let response = reqwest::get(format!("http://{}/allowed", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("Connecting from a denied address");
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+    let mut conn = socket
+        .connect(balancer.address.parse().unwrap())
+        .await
+        .expect("Could not connect to loadbalancer");
+    // The balancer may already have closed the connection
+    let _ = conn
+        .write_all(b"GET /denied HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await;
+    let mut buffer = [0_u8; 4096];
+    assert_eq!(conn.read(&mut buffer).await.unwrap_or(0), 0);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to admin API")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("loadbalancer_denied_connections_total 1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Balancers sharing a --rate-limit-redis should enforce --max-requests-per-minute together, and a
+/// balancer that can't reach its Redis should fall back to counting requests itself.
+#[tokio::test]
+async fn test_shared_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let redis = RedisServer::new().await;
+    let args = [
+        "--max-requests-per-minute",
+        "4",
+        "--rate-limit-redis",
+        &redis.address,
+        // Generous, so that a slow round trip on a busy test machine doesn't fall back to local
+        // counting
+        "--rate-limit-redis-timeout-ms",
+        "2000",
+    ];
+    let balancers = [
+        LoadBalancer::new_with_args(&[&upstream.address], &args).await,
+        LoadBalancer::new_with_args(&[&upstream.address], &args).await,
+    ];
+    let status = |balancer: &LoadBalancer| {
+        let address = balancer.address.clone();
+        async move {
+            reqwest::get(format!("http://{}/", address))
+                .await
+                .expect("Error sending request to loadbalancer")
+                .status()
+                .as_u16()
+        }
+    };
+
+    // Shared counts follow the wall clock's minutes; don't let one end partway through the test
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    if seconds % 60 > 50 {
+        tokio::time::sleep(std::time::Duration::from_secs(61 - seconds % 60)).await;
+    }
+
+    log::info!("Sending requests through both balancers");
+    for balancer in balancers.iter().chain(&balancers) {
+        assert_eq!(status(balancer).await, 200);
+    }
+    for balancer in &balancers {
+        assert_eq!(status(balancer).await, 429);
+    }
+    assert_eq!(redis.total_count(), 6);
+
+    log::info!("Sending requests through a balancer whose Redis is down");
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-redis",
+            "127.0.0.1:1",
+        ],
+    )
+    .await;
+    assert_eq!(status(&balancer).await, 200);
+    assert_eq!(status(&balancer).await, 200);
+    assert_eq!(status(&balancer).await, 429);
+
+    Box::new(redis).stop().await;
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// A 429 should say when the client may come back and where it stands against its limit, and with
+/// --rate-limit-headers so should every proxied response.
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "3", "--rate-limit-headers"],
+    )
+    .await;
+    let header = |response: &reqwest::Response, name: &str| -> u64 {
+        response.headers()[name].to_str().unwrap().parse().unwrap()
+    };
+
+    for remaining in (0..3).rev() {
+        let response = reqwest::get(format!("http://{}/", balancer.address))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(header(&response, "ratelimit-limit"), 3);
+        assert_eq!(header(&response, "ratelimit-remaining"), remaining);
+        assert!((1..=60).contains(&header(&response, "ratelimit-reset")));
+    }
+
+    let response = reqwest::get(format!("http://{}/", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(header(&response, "ratelimit-limit"), 3);
+    assert_eq!(header(&response, "ratelimit-remaining"), 0);
+    let reset = header(&response, "ratelimit-reset");
+    assert!((1..=60).contains(&reset));
+    assert_eq!(header(&response, "retry-after"), reset);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A chunked request body should reach the upstream decoded, with a Content-Length, while requests
+/// whose length is ambiguous or that use another transfer coding should be turned away.
+#[tokio::test]
+async fn test_chunked_request_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &[]).await;
+
+    log::info!("Sending a chunked body in pieces");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    let pieces: [&[u8]; 4] = [
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhel",
+        b"lo\r\n7\r\n, world\r\n",
+        b"0\r\nX-Checksum: abc\r\n",
+        b"\r\n",
+    ];
+    for piece in pieces {
+        conn.write_all(piece).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while !response.ends_with(b"hello, world") {
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("No response to the chunked request")
+                .unwrap();
+        assert!(
+            bytes_read > 0,
+            "Connection closed before the response arrived"
+        );
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    // The trailer field keeps the request chunked, so that it can be forwarded after the body
+    assert!(response.contains("transfer-encoding: chunked\n"));
+    assert!(!response.contains("content-length: 12\n"));
+
+    // Sends a raw request on a new connection, returning whatever came back before it closed
+    let send_request = |request: &'static [u8]| {
+        let address = balancer.address.clone();
+        async move {
+            let mut conn = tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to loadbalancer");
+            conn.write_all(request).await.unwrap();
+            let mut response = Vec::new();
+            let _ = conn.read_to_end(&mut response).await;
+            String::from_utf8_lossy(&response).to_string()
+        }
+    };
+    log::info!("Sending both Transfer-Encoding and Content-Length");
+    let response = send_request(
+        b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    log::info!("Sending an unsupported transfer coding");
+    let response =
+        send_request(b"POST / HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: gzip\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 501"), "{}", response);
+    log::info!("Sending a chunk size that overflows once added to the body so far");
+    let response = send_request(
+        b"POST / HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 400") || response.starts_with("HTTP/1.1 413"),
+        "{}",
+        response
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Chunked and read-until-close response bodies should reach the client as they arrive, rather than
+/// once the upstream has sent all of them.
+#[tokio::test]
+async fn test_streamed_responses() {
+    init_logging();
+    // An upstream that sends the first part of each body, then holds the rest back until released
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let release = Arc::new(tokio::sync::Notify::new());
+    let upstream_task = {
+        let release = release.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let release = release.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0_u8; 4096];
+                    loop {
+                        let Ok(bytes_read @ 1..) = stream.read(&mut buffer).await else {
+                            return;
+                        };
+                        request.extend_from_slice(&buffer[..bytes_read]);
+                        if !request.ends_with(b"\r\n\r\n") {
+                            continue;
+                        }
+                        let chunked = request.starts_with(b"GET /chunked ");
+                        request.clear();
+                        if chunked {
+                            stream
+                                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n")
+                                .await
+                                .unwrap();
+                            release.notified().await;
+                            stream.write_all(b"6\r\nsecond\r\n0\r\n\r\n").await.unwrap();
+                        } else {
+                            stream
+                                .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nfirst")
+                                .await
+                                .unwrap();
+                            release.notified().await;
+                            stream.write_all(b"second").await.unwrap();
+                            return;
+                        }
+                    }
+                });
+            }
+        })
+    };
+    let balancer = LoadBalancer::new_with_args(&[&upstream_address], &[]).await;
+
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    // Reads until the response so far ends with `marker`
+    async fn read_until(conn: &mut tokio::net::TcpStream, marker: &[u8]) -> String {
+        let mut response = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        while !response.ends_with(marker) {
+            let bytes_read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                    .await
+                    .expect("Body was not streamed")
+                    .unwrap();
+            assert!(bytes_read > 0, "Connection closed mid-response");
+            response.extend_from_slice(&buffer[..bytes_read]);
+        }
+        String::from_utf8_lossy(&response).to_lowercase()
+    }
+
+    log::info!("Fetching a chunked response");
+    conn.write_all(b"GET /chunked HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_until(&mut conn, b"first\r\n").await;
+    assert!(head.starts_with("http/1.1 200"));
+    assert!(head.contains("transfer-encoding: chunked"));
+    release.notify_one();
+    assert_eq!(
+        read_until(&mut conn, b"0\r\n\r\n").await,
+        "6\r\nsecond\r\n0\r\n\r\n"
+    );
+
+    log::info!("Fetching a read-until-close response on the same connection");
+    conn.write_all(b"GET /until-close HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_until(&mut conn, b"first").await;
+    assert!(head.starts_with("http/1.1 200"));
+    assert!(head.contains("connection: close"));
+    release.notify_one();
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, b"second");
+
+    upstream_task.abort();
+    log::info!("All done :)");
+}
+
+/// Bodies over --stream-body-threshold-bytes should be piped through as they arrive, in both
+/// directions, without the connection losing track of where each message ends.
+#[tokio::test]
+async fn test_streamed_bodies() {
+    init_logging();
+    // An upstream that reports when it has half of the request body, and once it has all of it,
+    // echoes the first half straight away and the second half when released
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let (half_received_tx, mut half_received) = tokio::sync::mpsc::unbounded_channel();
+    let release = Arc::new(tokio::sync::Notify::new());
+    let upstream_task = {
+        let release = release.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let half_received_tx = half_received_tx.clone();
+                let release = release.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buffer = [0_u8; 4096];
+                    loop {
+                        let Some(head_len) = received
+                            .windows(4)
+                            .position(|window| window == b"\r\n\r\n")
+                            .map(|pos| pos + 4)
+                        else {
+                            let Ok(bytes_read @ 1..) = stream.read(&mut buffer).await else {
+                                return;
+                            };
+                            received.extend_from_slice(&buffer[..bytes_read]);
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&received[..head_len]).to_lowercase();
+                        let body_len: usize = head
+                            .split("content-length: ")
+                            .nth(1)
+                            .and_then(|rest| rest.split("\r\n").next()?.parse().ok())
+                            .unwrap_or(0);
+                        let mut told = false;
+                        while received.len() < head_len + body_len {
+                            if !told && received.len() >= head_len + body_len / 2 {
+                                told = true;
+                                half_received_tx.send(()).unwrap();
+                            }
+                            let Ok(bytes_read @ 1..) = stream.read(&mut buffer).await else {
+                                return;
+                            };
+                            received.extend_from_slice(&buffer[..bytes_read]);
+                        }
+                        let body: Vec<u8> = received
+                            .drain(..head_len + body_len)
+                            .skip(head_len)
+                            .collect();
+                        let (first, second) = body.split_at(body_len / 2);
+                        let head =
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body_len);
+                        stream.write_all(head.as_bytes()).await.unwrap();
+                        stream.write_all(first).await.unwrap();
+                        if body_len > 100 {
+                            release.notified().await;
+                        }
+                        stream.write_all(second).await.unwrap();
+                    }
+                });
+            }
+        })
+    };
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_address],
+        &["--stream-body-threshold-bytes", "1000"],
+    )
+    .await;
+
+    // Reads until `len` more bytes have arrived
+    async fn read_bytes(conn: &mut tokio::net::TcpStream, len: usize) -> Vec<u8> {
+        let mut response = vec![0_u8; len];
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            conn.read_exact(&mut response),
+        )
+        .await
+        .expect("Body was not streamed")
+        .unwrap();
+        response
+    }
+
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    log::info!("Sending the first half of a large body");
+    conn.write_all(b"POST /upload HTTP/1.1\r\nHost: test\r\nContent-Length: 6000\r\n\r\n")
+        .await
+        .unwrap();
+    conn.write_all(&[b'a'; 3000]).await.unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), half_received.recv())
+        .await
+        .expect("Request body was not streamed to the upstream");
+    log::info!("Sending the second half");
+    conn.write_all(&[b'b'; 3000]).await.unwrap();
+    let head = b"HTTP/1.1 200 OK\r\ncontent-length: 6000\r\nvia: 1.1 loadbalancer\r\n\r\n";
+    let response = read_bytes(&mut conn, head.len() + 3000).await;
+    assert!(
+        response.starts_with(head),
+        "{}",
+        String::from_utf8_lossy(&response)
+    );
+    assert!(response.ends_with(&[b'a'; 3000]));
+    release.notify_one();
+    assert_eq!(read_bytes(&mut conn, 3000).await, [b'b'; 3000]);
+
+    log::info!("Sending a small request on the same connection");
+    conn.write_all(b"POST /small HTTP/1.1\r\nHost: test\r\nContent-Length: 4\r\n\r\nping")
+        .await
+        .unwrap();
+    let head = b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\nvia: 1.1 loadbalancer\r\n\r\n";
+    let response = read_bytes(&mut conn, head.len() + 4).await;
+    assert_eq!(
+        String::from_utf8_lossy(&response),
+        String::from_utf8_lossy(&[&head[..], b"ping"].concat())
+    );
+
+    upstream_task.abort();
+    log::info!("All done :)");
+}
+
+/// With --upstream-pool-size, a client connection should pick up the upstream connection an
+/// earlier client left behind, unless it has been idle for longer than the idle timeout.
+#[tokio::test]
+async fn test_upstream_connection_pool() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>().max(1024));
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-pool-size",
+            "4",
+            "--upstream-pool-idle-timeout-ms",
+            "300",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let reuses = || async {
+        let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+            .await
+            .expect("Error sending request to admin API")
+            .text()
+            .await
+            .unwrap();
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("loadbalancer_pooled_connection_reuses_total "))
+            .map(|count| count.parse::<u64>().unwrap())
+    };
+
+    log::info!("Sending requests on separate client connections");
+    for i in 0..3 {
+        let response = reqwest::get(format!("http://{}/request-{}", balancer.address, i))
+            .await
+            .expect("Error sending request to loadbalancer");
+        assert_eq!(response.status().as_u16(), 200);
+        // Give the balancer a moment to notice the client is gone and pool its connection
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(reuses().await, Some(2));
+
+    log::info!("Sending a request after the pooled connection has expired");
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let response = reqwest::get(format!("http://{}/late", balancer.address))
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(reuses().await, Some(2));
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// Once the upstream answers an Upgrade request with a 101, the balancer should relay bytes in both
+/// directions, including any the upstream sent right behind its response head.
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    // An upstream that accepts the upgrade, greets the client, then echoes whatever it is sent
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        while !head.ends_with(b"\r\n\r\n") {
+            let bytes_read = stream.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "Client hung up before the upgrade");
+            head.extend_from_slice(&buffer[..bytes_read]);
+        }
+        assert!(String::from_utf8_lossy(&head)
+            .to_lowercase()
+            .contains("upgrade: websocket"));
+        stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nwelcome")
+            .await
+            .unwrap();
+        loop {
+            let Ok(bytes_read @ 1..) = stream.read(&mut buffer).await else {
+                return;
+            };
+            stream.write_all(&buffer[..bytes_read]).await.unwrap();
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(&[&upstream_address], &[]).await;
+
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(
+        b"GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    let mut read_until = async |conn: &mut tokio::net::TcpStream, marker: &[u8]| {
+        while !received.ends_with(marker) {
+            let bytes_read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                    .await
+                    .expect("Nothing relayed")
+                    .unwrap();
+            assert!(bytes_read > 0, "Connection closed");
+            received.extend_from_slice(&buffer[..bytes_read]);
+        }
+        String::from_utf8_lossy(&std::mem::take(&mut received)).to_string()
+    };
+    let response = read_until(&mut conn, b"welcome").await;
+    assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+    assert!(response.to_lowercase().contains("upgrade: websocket"));
+
+    log::info!("Exchanging messages over the upgraded connection");
+    for message in ["hello", "GET / HTTP/1.1\r\n\r\n"] {
+        conn.write_all(message.as_bytes()).await.unwrap();
+        assert_eq!(read_until(&mut conn, message.as_bytes()).await, message);
+    }
+
+    drop(conn);
+    tokio::time::timeout(std::time::Duration::from_secs(5), upstream_task)
+        .await
+        .expect("Upstream side of the tunnel was not closed")
+        .unwrap();
+    log::info!("All done :)");
+}
+
+/// With --allow-connect, a CONNECT to an allowed port should be answered with a 200 and then
+/// tunneled to the host and port it names, while other ports are refused.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // What the tunnel leads to: a plain TCP echo server
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+    let target_task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream.address],
+        &[
+            "--allow-connect",
+            "--connect-allowed-port",
+            &target.port().to_string(),
+        ],
+    )
+    .await;
+
+    log::info!("Opening a tunnel, sending some bytes before the answer");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\nearly", target).as_bytes())
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    let mut read_until = async |conn: &mut tokio::net::TcpStream, marker: &[u8]| {
+        while !received.ends_with(marker) {
+            let bytes_read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                    .await
+                    .expect("Nothing relayed")
+                    .unwrap();
+            assert!(bytes_read > 0, "Connection closed");
+            received.extend_from_slice(&buffer[..bytes_read]);
+        }
+        String::from_utf8_lossy(&std::mem::take(&mut received)).to_string()
+    };
+    let response = read_until(&mut conn, b"early").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    conn.write_all(b"hello").await.unwrap();
+    assert_eq!(read_until(&mut conn, b"hello").await, "hello");
+    drop(conn);
+    tokio::time::timeout(std::time::Duration::from_secs(5), target_task)
+        .await
+        .expect("Tunnel was not closed")
+        .unwrap();
+
+    log::info!("Asking for a tunnel to a port that isn't allowed");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(b"CONNECT 127.0.0.1:25 HTTP/1.1\r\nHost: 127.0.0.1:25\r\n\r\n")
+        .await
+        .unwrap();
+    let bytes_read = conn.read(&mut buffer).await.unwrap();
+    let response = String::from_utf8_lossy(&buffer[..bytes_read]);
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// With --http2, a client speaking HTTP/2 with prior knowledge should have each of its streams,
+/// concurrent ones included, proxied to the upstream as HTTP/1.1, while HTTP/1.1 clients carry on
+/// as before.
+#[tokio::test]
+async fn test_http2_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancer = LoadBalancer::new_with_args(&[&upstream.address], &["--http2"]).await;
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+
+    log::info!("Sending a GET over HTTP/2");
+    let response = client
+        .get(format!("http://{}/first?query=1", balancer.address))
+        .send()
+        .await
+        .expect("Error sending request to loadbalancer");
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.status(), 200);
+    let text = response.text().await.unwrap();
+    assert!(text.starts_with("GET /first?query=1 HTTP/1.1"), "{}", text);
+    assert!(
+        text.contains(&format!("host: {}", balancer.address)),
+        "{}",
+        text
+    );
+    assert!(text.contains("x-forwarded-for: 127.0.0.1"), "{}", text);
+
+    log::info!("Sending a POST over HTTP/2, with a body of unknown length");
+    let h2_client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    let (mut sender, body) = hyper::Body::channel();
+    let request = hyper::Request::post(format!("http://{}/upload", balancer.address))
+        .body(body)
+        .unwrap();
+    let response = tokio::spawn(h2_client.request(request));
+    sender.send_data("hello ".into()).await.unwrap();
+    sender.send_data("world".into()).await.unwrap();
+    drop(sender);
+    let response = response.await.unwrap().unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let text = String::from_utf8_lossy(&body);
+    assert!(text.starts_with("POST /upload HTTP/1.1"), "{}", text);
+    assert!(text.ends_with("\n\nhello world"), "{}", text);
+
+    log::info!("Sending several requests at once on the same connection");
+    let requests: Vec<_> = (0..5)
+        .map(|i| {
+            tokio::spawn(
+                client
+                    .get(format!("http://{}/concurrent/{}", balancer.address, i))
+                    .send(),
+            )
+        })
+        .collect();
+    for (i, response) in requests.into_iter().enumerate() {
+        let text = response.await.unwrap().unwrap().text().await.unwrap();
+        assert!(
+            text.starts_with(&format!("GET /concurrent/{} HTTP/1.1", i)),
+            "{}",
+            text
+        );
+    }
+
+    log::info!("Sending a request over HTTP/1.1");
+    let text = balancer.get("/plain").await.unwrap();
+    assert!(text.starts_with("GET /plain HTTP/1.1"), "{}", text);
+
+    assert_eq!(Box::new(upstream).stop().await, 8);
+    log::info!("All done :)");
+}
+
+/// Requests from separate client connections to a protocol=h2c upstream should be sent as streams
+/// on a single shared HTTP/2 connection, bodies (including one too big to buffer) and all.
+#[tokio::test]
+async fn test_http2_upstream() {
+    init_logging();
+    let upstream = EchoServer::new_http2_only().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&format!("{},protocol=h2c", upstream.address)],
+        &["--stream-body-threshold-bytes", "100000"],
+    )
+    .await;
+
+    log::info!("Sending requests from several connections at once");
+    let requests: Vec<_> = (0..5)
+        .map(|i| {
+            tokio::spawn(
+                reqwest::Client::new()
+                    .get(format!("http://{}/concurrent/{}", balancer.address, i))
+                    .send(),
+            )
+        })
+        .collect();
+    for (i, response) in requests.into_iter().enumerate() {
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_11);
+        let text = response.text().await.unwrap();
+        assert!(
+            text.starts_with(&format!(
+                "GET http://{}/concurrent/{} HTTP/2.0",
+                balancer.address, i
+            )),
+            "{}",
+            text
+        );
+        assert!(text.contains("x-forwarded-for: 127.0.0.1"), "{}", text);
+    }
+
+    log::info!("Sending a small and a large POST");
+    let text = balancer.post("/small", "hello").await.unwrap();
+    assert!(text.ends_with("\n\nhello"), "{}", text);
+    let body = "x".repeat(300_000);
+    let text = balancer.post("/large", &body).await.unwrap();
+    assert!(text.contains("HTTP/2.0"), "{}", &text[..100]);
+    assert!(text.ends_with(&format!("\n\n{}", body)));
+
+    assert_eq!(upstream.connections_accepted(), 1);
+    assert_eq!(Box::new(upstream).stop().await, 7);
+    log::info!("All done :)");
+}
+
+/// A pool's --pool-protocol should apply to its upstreams that don't set a protocol of their own,
+/// and a pool protocol that an upstream contradicts, or that needs TLS, should stop the balancer
+/// from starting.
+#[tokio::test]
+async fn test_pool_protocol() {
+    init_logging();
+    let upstream = EchoServer::new_http2_only().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&format!("{},pool=grpc", upstream.address)],
+        &["--pool-protocol", "grpc=h2c", "--default-pool", "grpc"],
+    )
+    .await;
+    let text = balancer.get("/inherited").await.unwrap();
+    assert!(text.starts_with("GET http://"), "{}", text);
+    assert!(text.contains("/inherited HTTP/2.0"), "{}", text);
+
+    for (upstream_arg, pool_protocol) in [
+        (
+            format!("{},protocol=http1", upstream.address),
+            "default=h2c",
+        ),
+        (upstream.address.clone(), "default=h2"),
+        (upstream.address.clone(), "other=h2c"),
+    ] {
+        log::info!(
+            "Starting with {} and --pool-protocol {}",
+            upstream_arg,
+            pool_protocol
+        );
+        let mut balancer =
+            LoadBalancer::new_with_args(&[&upstream_arg], &["--pool-protocol", pool_protocol])
+                .await;
+        let status = balancer
+            .wait_for_exit(std::time::Duration::from_secs(5))
+            .await
+            .expect("Loadbalancer started despite a bad pool protocol");
+        assert!(!status.success());
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Starts an h2c gRPC server whose calls echo each message as it arrives, returning its address.
+async fn start_grpc_echo_server() -> String {
+    use hyper::body::HttpBody;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service =
+                hyper::service::service_fn(|request: hyper::Request<hyper::Body>| async {
+                    let (mut sender, body) = hyper::Body::channel();
+                    tokio::spawn(async move {
+                        // Echo each message as it arrives, then end the call with its status
+                        let mut request_body = request.into_body();
+                        while let Some(Ok(data)) = request_body.data().await {
+                            if sender.send_data(data).await.is_err() {
+                                return;
+                            }
+                        }
+                        let mut trailers = hyper::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        let _ = sender.send_trailers(trailers).await;
+                    });
+                    let mut response = hyper::Response::new(body);
+                    response
+                        .headers_mut()
+                        .insert("content-type", "application/grpc".parse().unwrap());
+                    Ok::<_, hyper::Error>(response)
+                });
+            tokio::spawn(
+                hyper::server::conn::Http::new()
+                    .http2_only(true)
+                    .serve_connection(stream, service),
+            );
+        }
+    });
+    address
+}
+
+/// Starts a streaming gRPC call through the balancer at `address`, returning the sender for its
+/// request messages and the pending response.
+fn grpc_call(
+    client: &hyper::Client<hyper::client::HttpConnector>,
+    address: &str,
+) -> (hyper::body::Sender, hyper::client::ResponseFuture) {
+    let (sender, body) = hyper::Body::channel();
+    let request = hyper::Request::post(format!("http://{}/echo.Echo/Chat", address))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(body)
+        .unwrap();
+    (sender, client.request(request))
+}
+
+/// With --grpc, a gRPC call from an HTTP/2 client should stream both ways through to an h2c
+/// upstream, with the trailers carrying its status, while a call that no HTTP/2 upstream can take
+/// should fail with UNAVAILABLE.
+#[tokio::test]
+async fn test_grpc_passthrough() {
+    use hyper::body::HttpBody;
+    init_logging();
+    let upstream_address = start_grpc_echo_server().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&format!("{},protocol=h2c", upstream_address)],
+        &["--http2", "--grpc"],
+    )
+    .await;
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    let call = |address: &str| grpc_call(&client, address);
+
+    log::info!("Making a streaming call");
+    let (mut sender, response) = call(&balancer.address);
+    let response = response.await.unwrap();
+    assert_eq!(response.status(), 200);
+    let mut body = response.into_body();
+    for message in ["first", "second"] {
+        sender.send_data(message.into()).await.unwrap();
+        let echoed = tokio::time::timeout(std::time::Duration::from_secs(5), body.data())
+            .await
+            .expect("Message was not echoed while the call was open")
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, message);
+    }
+    drop(sender);
+    // HTTP/2 allows empty DATA frames, which are passed through like any other
+    while let Some(data) = body.data().await {
+        assert_eq!(data.unwrap(), "");
+    }
+    let trailers = body.trailers().await.unwrap().expect("No trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+
+    log::info!("Making a call when no upstream speaks HTTP/2");
+    let upstream = EchoServer::new().await;
+    let http1_balancer =
+        LoadBalancer::new_with_args(&[&upstream.address], &["--http2", "--grpc"]).await;
+    let (sender, response) = call(&http1_balancer.address);
+    drop(sender);
+    let response = response.await.unwrap();
+    assert_eq!(response.headers()["grpc-status"], "14");
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// gRPC calls should go through the same admission checks as other requests, and count against
+/// their upstream's connection limit for as long as they are open.
+#[tokio::test]
+async fn test_grpc_calls_are_limited() {
+    use hyper::body::HttpBody;
+    init_logging();
+    let upstream_address = start_grpc_echo_server().await;
+    let balancer = LoadBalancer::new_with_args(
+        &[&format!("{},protocol=h2c,max_conns=1", upstream_address)],
+        &["--http2", "--grpc"],
+    )
+    .await;
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+
+    log::info!("Holding a call open, taking up the upstream's only connection");
+    let (mut sender, response) = grpc_call(&client, &balancer.address);
+    let mut body = response.await.unwrap().into_body();
+    sender.send_data("held".into()).await.unwrap();
+    let echoed = tokio::time::timeout(std::time::Duration::from_secs(5), body.data())
+        .await
+        .expect("Message was not echoed while the call was open")
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, "held");
+
+    let (second_sender, response) = grpc_call(&client, &balancer.address);
+    drop(second_sender);
+    let response = response.await.unwrap();
+    assert_eq!(response.headers()["grpc-status"], "14");
+
+    log::info!("Ending the call, which should free up the connection");
+    drop(sender);
+    while body.data().await.is_some() {}
+    let (third_sender, response) = grpc_call(&client, &balancer.address);
+    drop(third_sender);
+    let response = response.await.unwrap();
+    assert!(response.headers().get("grpc-status").is_none());
+    let mut body = response.into_body();
+    while body.data().await.is_some() {}
+    let trailers = body.trailers().await.unwrap().expect("No trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+
+    log::info!("Making a call with a method that isn't allowed");
+    let restricted = LoadBalancer::new_with_args(
+        &[&format!("{},protocol=h2c", upstream_address)],
+        &["--http2", "--grpc", "--allowed-methods", "GET"],
+    )
+    .await;
+    let (sender, response) = grpc_call(&client, &restricted.address);
+    drop(sender);
+    assert_eq!(response.await.unwrap().status(), 405);
+    log::info!("All done :)");
+}
+
+/// Server-sent events should reach the client as the upstream sends them, even ones with a
+/// Content-Length small enough to be buffered otherwise, and an event stream should outlive the
+/// request timeout but be cut off at the maximum stream duration.
+#[tokio::test]
+async fn test_event_streams() {
+    init_logging();
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 4096];
+                let bytes_read = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
+                let length = if request.starts_with("GET /length") {
+                    "content-length: 18\r\n"
+                } else {
+                    ""
+                };
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n{}\r\n",
+                    length
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(b"data: 1\n\n").await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+                let _ = stream.write_all(b"data: 2\n\n").await;
+                // Say nothing more, but don't hang up either
+                tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+            });
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(
+        &[&upstream_address],
+        &[
+            "--request-timeout-ms",
+            "200",
+            "--max-stream-duration-ms",
+            "1000",
+        ],
+    )
+    .await;
+    async fn read_until(conn: &mut tokio::net::TcpStream, marker: &str) -> String {
+        let mut received = Vec::new();
+        let mut buffer = [0_u8; 4096];
+        while !String::from_utf8_lossy(&received).contains(marker) {
+            let bytes_read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                    .await
+                    .expect("Nothing streamed")
+                    .unwrap();
+            assert!(bytes_read > 0, "Connection closed");
+            received.extend_from_slice(&buffer[..bytes_read]);
+        }
+        String::from_utf8_lossy(&received).to_string()
+    }
+
+    for path in ["/events", "/length"] {
+        log::info!("Subscribing to {}", path);
+        let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+            .await
+            .expect("Could not connect to loadbalancer");
+        conn.write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let start = std::time::Instant::now();
+        let first = read_until(&mut conn, "data: 1\n\n").await;
+        assert!(first.starts_with("HTTP/1.1 200"), "{}", first);
+        assert!(!first.contains("data: 2"), "{}", first);
+        assert!(start.elapsed() < std::time::Duration::from_millis(300));
+        read_until(&mut conn, "data: 2\n\n").await;
+        if path == "/events" {
+            // Nothing more comes, until the stream is cut off
+            let mut buffer = [0_u8; 64];
+            let bytes_read =
+                tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                    .await
+                    .expect("Stream was not cut off")
+                    .unwrap_or(0);
+            assert_eq!(bytes_read, 0);
+            assert!(start.elapsed() >= std::time::Duration::from_millis(900));
+        }
+    }
+    log::info!("All done :)");
+}
+
+/// Trailer fields on chunked requests and responses should reach the other side, including when
+/// the client speaks HTTP/2 and the upstream HTTP/1.1.
+#[tokio::test]
+async fn test_trailers() {
+    use hyper::body::HttpBody;
+    init_logging();
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    let (requests_tx, mut requests_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream.accept().await {
+            let requests_tx = requests_tx.clone();
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                loop {
+                    // Chunked requests end with the last chunk and its trailers; health checks
+                    // have no body
+                    let text = String::from_utf8_lossy(&received).to_string();
+                    let chunked = text.contains("transfer-encoding: chunked");
+                    let complete = if chunked {
+                        text.contains("\r\n0\r\n") && text.ends_with("\r\n\r\n")
+                    } else {
+                        text.ends_with("\r\n\r\n")
+                    };
+                    if complete {
+                        if chunked {
+                            requests_tx.send(text).unwrap();
+                        }
+                        received.clear();
+                        let response = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\
+                            trailer: grpc-status\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => received.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+            });
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(&[&upstream_address], &["--http2"]).await;
+
+    log::info!("Sending a chunked request with trailers over HTTP/1.1");
+    let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+        .await
+        .expect("Could not connect to loadbalancer");
+    conn.write_all(
+        b"POST /upload HTTP/1.1\r\nHost: test\r\ntransfer-encoding: chunked\r\n\r\n\
+        5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while !received.ends_with(b"grpc-status: 0\r\n\r\n") {
+        let bytes_read =
+            tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("No complete response")
+                .unwrap();
+        assert!(bytes_read > 0, "Connection closed");
+        received.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let request = requests_rx.recv().await.unwrap();
+    assert!(
+        request.contains("transfer-encoding: chunked"),
+        "{}",
+        request
+    );
+    assert!(
+        request.ends_with("\r\n\r\n5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n"),
+        "{}",
+        request
+    );
+
+    log::info!("Sending a request with trailers over HTTP/2");
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    let (mut sender, body) = hyper::Body::channel();
+    let request = hyper::Request::post(format!("http://{}/upload", balancer.address))
+        .body(body)
+        .unwrap();
+    let response = tokio::spawn(client.request(request));
+    sender.send_data("hello".into()).await.unwrap();
+    let mut trailers = hyper::HeaderMap::new();
+    trailers.insert("x-checksum", "abc".parse().unwrap());
+    sender.send_trailers(trailers).await.unwrap();
+    drop(sender);
+    let mut body = response.await.unwrap().unwrap().into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(data, b"hello");
+    let trailers = body.trailers().await.unwrap().expect("No trailers");
+    assert_eq!(trailers["grpc-status"], "0");
+    let request = requests_rx.recv().await.unwrap();
+    assert!(
+        request.ends_with("0\r\nx-checksum: abc\r\n\r\n"),
+        "{}",
+        request
+    );
+    log::info!("All done :)");
+}
+
+/// A chunked response that breaks its framing partway, with a chunk that doesn't end in CRLF, should
+/// be cut off rather than relayed as though it were valid, and the largest possible chunk size
+/// should be followed like any other until the upstream hangs up partway through it.
+#[tokio::test]
+async fn test_malformed_chunked_response() {
+    init_logging();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let upstream_task = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let Ok(bytes_read @ 1..) = stream.read(&mut buffer).await else {
+                        return;
+                    };
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                }
+                let (rest, hang_up): (&[u8], bool) = if request.starts_with(b"GET /bad-crlf ") {
+                    (b"6\r\nsecondXX0\r\n\r\n", false)
+                } else if request.starts_with(b"GET /huge-chunk ") {
+                    (b"ffffffffffffffff\r\nsecond", true)
+                } else {
+                    // Health checks
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        )
+                        .await;
+                    return;
+                };
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nfirst\r\n",
+                    )
+                    .await
+                    .unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                let _ = stream.write_all(rest).await;
+                if !hang_up {
+                    // Hold the connection open, so that only the broken framing can end the
+                    // response
+                    tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                }
+            });
+        }
+    });
+    let balancer = LoadBalancer::new_with_args(&[&upstream_address], &[]).await;
+
+    for (path, relayed) in [("/bad-crlf", false), ("/huge-chunk", true)] {
+        log::info!("Fetching {}", path);
+        let mut conn = tokio::net::TcpStream::connect(&balancer.address)
+            .await
+            .expect("Could not connect to loadbalancer");
+        conn.write_all(format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            conn.read_to_end(&mut response),
+        )
+        .await
+        .expect("The broken response was not cut off")
+        .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("5\r\nfirst\r\n"), "{}", response);
+        assert_eq!(response.contains("second"), relayed, "{}", response);
+        assert!(!response.ends_with("0\r\n\r\n"), "{}", response);
+    }
+    upstream_task.abort();
+    log::info!("All done :)");
+}